@@ -3,6 +3,29 @@
 // Stubs for llm
 
 namespace {
+    /**
+     * Usage of tagged calls made by this process, as ['tag' => ['value' =>
+     * ['requests', 'input_tokens', 'output_tokens', 'total_tokens', 'cost']]].
+     * Pass true to reset the totals after reading them.
+     */
+    function llm_usage_by_tag(?bool $reset = null): mixed {}
+
+    /**
+     * What every completion result has, so code handling Response,
+     * StructuredResponse and ToolResponse alike can typehint one interface
+     */
+    interface CompletionResultInterface {
+        public function getContent(): string;
+
+        public function getUsage(): \Usage;
+
+        public function getModel(): string;
+
+        public function getFinishReason(): string;
+
+        public function getResponseId(): ?string;
+    }
+
     /**
      * Main LLM class for interacting with language models
      */
@@ -12,394 +35,2169 @@ namespace {
          */
         public function complete(mixed $messages): \Response {}
 
+        /**
+         * Start a completion in the background and return immediately.
+         * Several futures can run concurrently; collect them with wait().
+         */
+        public function completeAsync(mixed $messages): \LLMFuture {}
+
+        /**
+         * Run several conversations concurrently, at most `concurrency` at a
+         * time. Returns a list with, in input order, a Response or the exception
+         * for each conversation that failed.
+         */
+        public function completeMany(array $conversations, ?int $concurrency = null): mixed {}
+
+        /**
+         * Send the same conversation to each of `models`, `iterations` times
+         * (default 1), all requests at once, and compare them. Returns
+         * ['model' => ['runs', 'failures', 'latency_ms' => ['min', 'avg', 'p50',
+         * 'max'], 'tokens' => ['input', 'output'], 'cost', 'quality',
+         * 'responses']], with tokens and cost averaged per run, 'quality' left
+         * null for your own scoring and a Response or the exception for each
+         * run. This instance's settings apply to every model.
+         */
+        public function benchmark(array $models, mixed $messages, ?int $iterations = null): array {}
+
+        /**
+         * Send the conversation to this instance's model and to `fastModel` at
+         * once. The instance model's answer is used if it arrives within
+         * `deadlineMs`; after that, the fast model's.
+         */
+        public function completeSpeculative(mixed $messages, string $fast_model, int $deadline_ms): \SpeculativeResponse {}
+
+        /**
+         * Generate a short title for a conversation, using the model set with
+         * setTitleModel() or this instance's model. Long histories are cut
+         * down to their opening messages.
+         */
+        public function title(mixed $messages): string {}
+
+        /**
+         * Detect the language of a text, returning its ISO 639-1 code and a
+         * confidence. Mode 'auto' (default) tries a local heuristic first and
+         * asks the model only when it is unsure; 'local' never sends a
+         * request, 'model' always does.
+         */
+        public function detectLanguage(string $text, ?string $mode = null): \LanguageDetection {}
+
+        /**
+         * Translate a text into `targetLang`. Long texts are translated in
+         * chunks split at paragraph or sentence boundaries and reassembled.
+         * Options: 'glossary' (term => required translation), 'source_lang'
+         * and 'chunk_size' (characters, default 2000).
+         */
+        public function translate(string $text, string $targetLang, ?array $options = null): \Translation {}
+
+        /**
+         * Apply `instruction` to a text too long for one request: the text is
+         * split into chunks of about 'chunk_tokens' tokens (default 4000), each
+         * chunk is processed on its own (map), and the results are combined
+         * into one answer (reduce), in groups first when they are too long
+         * together. Option 'reduce_instruction' replaces the combining prompt.
+         * A text that fits in one chunk takes a single request. The response
+         * is the final one, with the usage of every request.
+         *
+         * @param array{chunk_tokens?: int, reduce_instruction?: string}|null $options
+         */
+        public function mapReduce(string $text, string $instruction, ?array $options = null): \Response {}
+
+        /**
+         * Have the model pick exactly one of `options` for the conversation and
+         * return its index. The choice is constrained with a JSON schema enum,
+         * enforced by the provider's JSON mode where it has one and validated
+         * locally otherwise.
+         */
+        public function decide(mixed $messages, array $options): int {}
+
+        /**
+         * Classify a text with one of `labels`, a list of labels or label =>
+         * description, and return the label. With `withConfidence`, returns
+         * ['label' => string, 'confidence' => float] with the model's own
+         * confidence from 0 to 1. Constrained like decide().
+         */
+        public function classify(string $text, array $labels, ?bool $withConfidence = null): string|array {}
+
+        /**
+         * Extract the fields described by `schema` (JSON string, array or
+         * Schema) from a text, returning them as an array matching the schema
+         */
+        public function extract(string $text, mixed $schema): array {}
+
+        /**
+         * Detect objects in an image (http(s) URL, data: URL or local file)
+         * with a vision model, returning a list of ['label', 'confidence',
+         * 'box' => ['x', 'y', 'width', 'height']] with the box's top-left
+         * corner. `labels` restricts the objects to those labels. Options:
+         * 'coordinates' ('normalized' fractions of the image size, the default;
+         * 'per_mille'; or 'pixels'), 'width' and 'height' (the image size, for
+         * pixels when the image is given by URL) and 'max_objects' (default 50).
+         */
+        public function annotateImage(string $image, ?array $labels = null, ?array $options = null): mixed {}
+
+        /**
+         * Stream a completion, invoking the callback with every content delta.
+         * Returns the assembled Response once the stream has finished.
+         */
+        public function stream(mixed $messages, callable $on_delta): \Response {}
+
+        /**
+         * Stream a completion as an Iterator of content deltas, for use with foreach
+         */
+        public function streamIterator(mixed $messages): \StreamIterator {}
+
+        /**
+         * Complete with documents the model can cite (Anthropic models).
+         * Documents are strings or ['text' => ..., 'title' => ..., 'context' =>
+         * ...] arrays; getCitations() on the response maps the answer back to
+         * the supporting passages.
+         */
+        public function completeWithDocuments(mixed $messages, array $documents): \Response {}
+
+        /**
+         * Create an embeddings client sharing this instance's runtime.
+         * Defaults to the provider's standard embedding model.
+         */
+        public function embeddings(?string $model = null): \Embeddings {}
+
         /**
          * Create a builder for structured output
          */
-        public function structured(?string $schema = null): \StructuredBuilder {}
+        public function structured(string|\Schema|array|null $schema = null): \StructuredBuilder {}
+
+        /**
+         * Create a builder for tool calling
+         */
+        public function withTools(?array $tools = null): \ToolBuilder {}
+
+        /**
+         * Include full provider error details in exception messages for the
+         * current request. API keys are masked either way.
+         */
+        public static function setVerboseErrors(bool $enabled): void {}
+
+        /**
+         * Set a callable `fn(string $text): string` used to redact sensitive
+         * content in exception messages, logs and transcripts for the current
+         * request. Pass null to remove it.
+         */
+        public static function setRedactor(mixed $redactor = null): void {}
+
+        /**
+         * Set a callable `fn(string $level, string $message): void` receiving the
+         * extension's warnings, and the usage of tagged calls at 'info' level,
+         * for the current request. Without one, warnings are raised as
+         * E_USER_WARNING. Pass null to remove it.
+         */
+        public static function setLogger(mixed $logger = null): void {}
+
+        /**
+         * Set a callable `fn(array $record): void` called after every completed
+         * provider request of the current PHP request, with ['model',
+         * 'input_tokens', 'output_tokens', 'reasoning_tokens', 'cached_tokens',
+         * 'total_tokens', 'cost', 'request_time_ms', 'tenant', 'tags',
+         * 'timestamp'], e.g. to write usage to a billing table. Cached responses
+         * are not reported, nor are requests made by the hook itself. Pass null
+         * to remove it.
+         */
+        public static function setUsageHook(mixed $hook = null): void {}
+
+        /**
+         * Set what happens when a parameter is not supported by the provider:
+         * 'strict' throws, 'drop' (default) leaves it out, 'warn' leaves it out
+         * and logs a warning
+         */
+        public function setParameterPolicy(string $policy): \Llm {}
+
+        /**
+         * Retry failed requests with exponential backoff. Options: max_attempts
+         * (default 1), backoff_ms (500), max_backoff_ms (30000) and retry_on
+         * (['timeout', '429', '5xx']; also 'network', '4xx' or any status code).
+         * A 429's Retry-After delay takes precedence over the backoff.
+         */
+        public function withRetry(array $options): \Llm {}
+
+        /**
+         * Set the per-minute token and cost budget for a tenant key, shared by
+         * all requests served by this PHP process. Pass nulls to remove it.
+         */
+        public static function setTenantBudget(string $key, ?int $tokensPerMinute = null, ?float $costPerMinute = null): void {}
+
+        /**
+         * Usage of every call made by this PHP process since it started or was
+         * last reset, as ['requests', 'input_tokens', 'output_tokens',
+         * 'total_tokens', 'cost', 'models' => ['model' => [...]]]. Pass true to
+         * reset the totals after reading them.
+         */
+        public static function getGlobalUsage(?bool $reset = null): array {}
+
+        /**
+         * Start counting the global usage from zero
+         */
+        public static function resetGlobalUsage(): void {}
+
+        /**
+         * Remaining budget of a tenant as ['tokens' => ?float, 'cost' => ?float],
+         * or null when the tenant has no budget
+         */
+        public static function getTenantBudget(string $key): ?array {}
+
+        /**
+         * Charge this instance's calls against a tenant budget. Calls fail with
+         * LLMBudgetExceededException once the budget is used up.
+         */
+        public function setTenantKey(?string $key = null): \Llm {}
+
+        /**
+         * Reject this instance's calls with LLMBudgetExceededException once the
+         * guard's cost ceiling is reached. Builders created afterwards inherit
+         * it; pass null to remove it.
+         */
+        public function withBudgetGuard(?\BudgetGuard $guard): \LLM {}
+
+        /**
+         * Limit this instance's calls to `requestsPerMinute` requests and
+         * `tokensPerMinute` tokens, counted across the user's processes on the
+         * host for the provider and API key, so calls are held back before the provider
+         * rejects them. A call waits until the limit allows it, or for at most
+         * `maxWait` seconds before throwing LLMConnectionException with code
+         * LLMException::ERR_RATE_LIMIT. 0 leaves a dimension unlimited; two
+         * zeros remove the limit. Builders created afterwards inherit it.
+         */
+        public function withRateLimit(int $requestsPerMinute, int $tokensPerMinute, ?float $maxWait = null): \LLM {}
+
+        /**
+         * Tag this instance's calls for cost attribution, e.g. ['feature' =>
+         * 'checkout-bot', 'team' => 'growth']. Usage is summed per tag value
+         * (see llm_usage_by_tag()) and tagged calls are logged at 'info' level.
+         * Builders created afterwards inherit the tags; an empty array removes
+         * them.
+         */
+        public function withTags(array $tags): \Llm {}
+
+        /**
+         * Shorten long user and system messages to about `ratio` of their
+         * length (e.g. 0.5) before sending, dropping their least informative
+         * sentences. Aimed at large retrieved contexts; short paragraphs such
+         * as the question itself are kept. Options: 'min_chars', the length
+         * from which a message is compressed (default 1000). Builders created
+         * afterwards inherit it; pass 1 to disable.
+         */
+        public function withCompression(float $ratio, ?array $options = null): \Llm {}
+
+        /**
+         * Send structured-output and tool schemas without descriptions and
+         * titles, with local $refs inlined. Cuts prompt tokens and helps large
+         * schemas fit provider limits; schemas over a provider's published
+         * limits (OpenAI: 10 levels of nesting, 5000 properties, 1000 enum
+         * values) are rejected before sending either way. Builders created
+         * afterwards inherit it.
+         */
+        public function simplifySchemas(?bool $enabled = null): \Llm {}
+
+        /**
+         * Fix message sequences providers reject before sending: consecutive
+         * messages of the same role are merged, and tool results that do not
+         * answer a tool call of the preceding assistant message are dropped
+         * with a warning. Builders created afterwards inherit it.
+         */
+        public function repairMessages(?bool $enabled = null): \Llm {}
+
+        /**
+         * Keep the raw request and response of each completion on the Response
+         * (see Response::getExchange()), for debugging what was actually sent to
+         * and returned by the provider. Builders created afterwards inherit it.
+         */
+        public function setDebug(?bool $enabled = null): \Llm {}
+
+        /**
+         * Instruct the model to always reply in `language`, an ISO 639-1 code
+         * such as 'de' or 'pt-BR'; null removes the instruction. With
+         * `validate`, complete() detects the reply's language locally and
+         * retries once when it is confidently another one; languages the local
+         * detector doesn't know are not checked. Builders created afterwards
+         * inherit the instruction.
+         */
+        public function setOutputLanguage(?string $language, ?bool $validate = null): \Llm {}
+
+        /**
+         * The messages as they would be sent after compression (see
+         * withCompression()), as a list of ['role', 'content'] arrays
+         */
+        public function compress(mixed $messages): array {}
+
+        /**
+         * Cache complete() responses for `ttl` seconds, keyed on the model,
+         * provider account, sampling parameters and messages. Backends:
+         * 'memory' (this process), 'apcu' (shared by a PHP-FPM pool) or 'file'
+         * (llm.cache_dir, or a private directory under the temp directory).
+         * Pass a ttl of 0 to disable.
+         */
+        public function withCache(string $backend, int $ttl): \Llm {}
+
+        /**
+         * Also answer complete() from the cache when the final user message is
+         * similar enough to one answered before: its embedding's cosine
+         * similarity must reach `threshold` (e.g. 0.95), and the model,
+         * parameters and earlier messages must match exactly. Entries live in
+         * this process for `ttl` seconds. The embedding model defaults to the
+         * provider's (see embeddings()). Pass a ttl of 0 to disable.
+         */
+        public function withSemanticCache(float $threshold, int $ttl, ?string $embeddingModel = null): \Llm {}
+
+        /**
+         * Limit each attempt of a request to this many seconds (0 for no
+         * limit). Streams apply it to the gap between chunks instead of the
+         * whole response.
+         */
+        public function setTimeout(int $seconds): \Llm {}
+
+        /**
+         * Limit a request to this many seconds over all of its retry attempts
+         * and the waits between them (0 for no limit). Streams are limited
+         * until they start.
+         */
+        public function setDeadline(int $seconds): \Llm {}
+
+        /**
+         * Limit connection setup to this many seconds (0 for no limit).
+         * Applies to streaming and embeddings requests.
+         */
+        public function setConnectTimeout(int $seconds): \Llm {}
+
+        /**
+         * Set configuration options
+         */
+        public function withOptions(array $options): \Llm {}
+
+        /**
+         * Override how a message kind ('system' or 'tool_result') is rendered,
+         * for one provider or for all providers when none is given.
+         * Placeholders: {content}, {tool_call_id}, {provider}, {model}
+         */
+        public function setPromptTemplate(string $kind, string $template, ?string $provider = null): \Llm {}
+
+        /**
+         * Set temperature
+         */
+        public function setTemperature(float $_temperature): \Llm {}
+
+        /**
+         * Set max tokens
+         */
+        public function setMaxTokens(int $_max_tokens): \Llm {}
+
+        /**
+         * Compute max_tokens per request as the model's context window minus
+         * the prompt tokens minus a safety margin, capped by the model's
+         * output limit. setMaxTokens() turns it off.
+         */
+        public function setMaxTokensAuto(?int $safety_margin = null, ?int $context_window = null): \Llm {}
+
+        /**
+         * Set top_p
+         */
+        public function setTopP(float $_top_p): \Llm {}
+
+        /**
+         * Set top_k
+         */
+        public function setTopK(int $_top_k): \Llm {}
+
+        /**
+         * Set frequency penalty
+         */
+        public function setFrequencyPenalty(float $_penalty): \Llm {}
+
+        /**
+         * Set presence penalty
+         */
+        public function setPresencePenalty(float $_penalty): \Llm {}
+
+        /**
+         * Stop generating at any of these sequences (a string or an array of
+         * strings; an empty array clears them)
+         */
+        public function setStop(array|string $_stop): \Llm {}
+
+        /**
+         * Model used by title(), typically a cheap one ("provider:model")
+         */
+        public function setTitleModel(string $_model): \Llm {}
+
+        /**
+         * Sample with a fixed seed, so repeated requests with the same input
+         * return the same output where the provider supports it
+         */
+        public function setSeed(int $_seed): \Llm {}
+
+        /**
+         * Make completions as reproducible as the provider allows: temperature
+         * 0 and a fixed seed (default 0). Compare getSystemFingerprint() across
+         * responses to tell backend changes apart.
+         */
+        public function deterministic(?int $_seed = null): \Llm {}
+
+        /**
+         * Create a new LLM instance
+         */
+        public function __construct(string $model, ?array $options = null) {}
+    }
+
+    /**
+     * Response from LLM completion
+     */
+    class Response implements \JsonSerializable, \CompletionResultInterface {
+        public function getContent(): string {}
+
+        public function getUsage(): \Usage {}
+
+        public function getModel(): string {}
+
+        public function getFinishReason(): string {}
+
+        /**
+         * Provider's id of the response, e.g. 'chatcmpl-…' or 'msg_…'; null
+         * when the provider sent none or the response was streamed
+         */
+        public function getResponseId(): ?string {}
+
+        /**
+         * Whether the provider refused or content-filtered the completion
+         */
+        public function isRefusal(): bool {}
+
+        /**
+         * Refusal message or filtered categories, if the completion was refused
+         */
+        public function getRefusalReason(): ?string {}
+
+        /**
+         * Reasoning ("thinking") text, for models and providers that return it
+         */
+        public function getReasoning(): ?string {}
+
+        /**
+         * Tokens spent on reasoning (shortcut for getUsage()->getReasoningTokens())
+         */
+        public function getReasoningTokens(): int {}
+
+        /**
+         * Backend configuration the provider served the request with, for
+         * checking that seeded requests are reproducible
+         */
+        public function getSystemFingerprint(): ?string {}
 
         /**
-         * Create a builder for tool calling
+         * Whether this response was served by withCache() or withSemanticCache().
+         * Usage is that of the original request; a cached response costs nothing.
+         */
+        public function isCached(): bool {}
+
+        /**
+         * Rate-limit state the provider sent with the response, as
+         * ['limit_requests', 'remaining_requests', 'reset_requests',
+         * 'limit_tokens', 'remaining_tokens', 'reset_tokens', 'retry_after'],
+         * with only the reported keys and resets in seconds. Null when the
+         * headers are not available, which is the case for responses that were
+         * not streamed.
+         */
+        public function getRateLimitInfo(): ?array {}
+
+        /**
+         * Raw provider exchange as ['provider', 'request', 'response',
+         * 'timestamp', 'request_time_ms'], with the request and response bodies
+         * decoded to arrays. Only kept with LLM::setDebug(); null otherwise and
+         * for streamed or cached responses.
+         */
+        public function getExchange(): ?array {}
+
+        /**
+         * Passages of the documents given to completeWithDocuments() that
+         * support the answer, as a list of ['document_index', 'document_title',
+         * 'cited_text', 'start', 'end', 'answer_start', 'answer_end']. Offsets
+         * count characters (use mb_substr()); end offsets are exclusive.
+         */
+        public function getCitations(): mixed {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        /**
+         * State for serialize(), e.g. to keep the object in a session or queue
+         */
+        public function __serialize(): array {}
+
+        public function __unserialize(array $data): void {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Token usage information
+     */
+    class Usage implements \JsonSerializable {
+        public function getPromptTokens(): int {}
+
+        public function getOutputTokens(): int {}
+
+        /**
+         * Tokens spent on reasoning; already included in the output tokens
+         */
+        public function getReasoningTokens(): int {}
+
+        public function getTotalTokens(): int {}
+
+        /**
+         * Prompt tokens served from the provider's prompt cache
+         */
+        public function getCachedTokens(): int {}
+
+        /**
+         * Cost in USD, when reported for the provider and model
+         */
+        public function getCost(): ?float {}
+
+        /**
+         * Provider request time in milliseconds, when measured
+         */
+        public function getRequestTimeMs(): ?int {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        /**
+         * State for serialize(), e.g. to keep the object in a session or queue
+         */
+        public function __serialize(): array {}
+
+        public function __unserialize(array $data): void {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * JSON schema for an object, built property by property
+     */
+    class Schema {
+        /**
+         * Empty object schema
+         */
+        public static function object(?string $description = null): \Schema {}
+
+        /**
+         * Schema for the public properties of a class, see the README for the
+         * supported types and attributes. StructuredResponse::into() creates an
+         * instance from the output.
+         */
+        public static function fromClass(string $class_name): \Schema {}
+
+        public function string(string $name, ?string $description = null, ?bool $required = null): \Schema {}
+
+        public function integer(string $name, ?string $description = null, ?bool $required = null): \Schema {}
+
+        public function number(string $name, ?string $description = null, ?bool $required = null): \Schema {}
+
+        public function boolean(string $name, ?string $description = null, ?bool $required = null): \Schema {}
+
+        /**
+         * String property limited to the given values
+         */
+        public function enum(string $name, array $values, ?string $description = null, ?bool $required = null): \Schema {}
+
+        /**
+         * Property limited to the cases of a backed enum. StructuredResponse's
+         * getStructured() returns the matching enum case for it.
+         */
+        public function enumFromClass(string $name, string $class, ?string $description = null, ?bool $required = null): \Schema {}
+
+        /**
+         * Array property whose items are a scalar type ('string', 'integer',
+         * 'number' or 'boolean') or objects described by a Schema
+         */
+        public function arrayOf(string $name, string|\Schema $items, ?string $description = null, ?bool $required = null): \Schema {}
+
+        /**
+         * Nested object property
+         */
+        public function property(string $name, \Schema $schema, ?string $description = null, ?bool $required = null): \Schema {}
+
+        /**
+         * Names of the required properties
+         */
+        public function getRequired(): array {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        public function __toString(): string {}
+    }
+
+    /**
+     * Builder for structured output
+     */
+    class StructuredBuilder {
+        /**
+         * Complete with structured output
+         */
+        public function complete(mixed $messages): \StructuredResponse {}
+
+        /**
+         * Stream the structured output, calling `fn(string $field, mixed
+         * $value)` as soon as each top-level field of the JSON object is
+         * complete, e.g. to fill in a form progressively. The JSON is asked for
+         * in the prompt, so isFallback() is true. Providers without streaming
+         * support answer in one piece, and the fields are reported once it has
+         * arrived. Returns the StructuredResponse, checked against the schema.
+         */
+        public function stream(mixed $messages, callable $onField): \StructuredResponse {}
+
+        /**
+         * Stream the structured output, calling `fn(array $partial)` with the
+         * object parsed so far whenever it grows, so a UI can render the result
+         * while the model is writing. Strings and numbers may be cut short and
+         * a member appears once its value has started; the last call receives
+         * the complete object. Otherwise works like stream().
+         */
+        public function streamPartial(mixed $messages, callable $onPartial): \StructuredResponse {}
+
+        /**
+         * Apply the schema to many inputs concurrently, at most `concurrency`
+         * at a time. Each input is a conversation or a string sent as a user
+         * message. Returns an array with the input keys, holding a
+         * StructuredResponse or the exception for each input that failed.
+         */
+        public function completeMany(array $inputs, ?int $concurrency = null): mixed {}
+
+        /**
+         * Set JSON schema
+         */
+        public function withSchema(string|\Schema|array $schema): \StructuredBuilder {}
+
+        /**
+         * System prompt sent ahead of the messages, merged with a leading system
+         * message if they have one. An empty string removes it.
+         */
+        public function withSystemPrompt(string $prompt): \StructuredBuilder {}
+
+        /**
+         * Set format ('json' or 'json_schema')
+         */
+        public function withFormat(string $format): \StructuredBuilder {}
+
+        /**
+         * Cache responses in process memory for `ttl` seconds, keyed on the
+         * model, provider account, schema, parameters and messages. Pass 0 to
+         * disable.
+         */
+        public function withCache(int $ttl): \StructuredBuilder {}
+
+        /**
+         * When a reply is not valid JSON or does not match the schema, send the
+         * errors back to the model and ask again, up to `maxRetries` times
+         * before throwing LLMStructuredOutputException. Applies to complete()
+         * and completeMany(); 0 (default) throws on the first invalid reply.
+         */
+        public function withAutoRepair(int $max_retries): \StructuredBuilder {}
+
+        /**
+         * Ask the provider to enforce the schema exactly (OpenAI's strict
+         * structured outputs). The schema is adjusted to what strict mode
+         * accepts: objects allow no additional properties and list every
+         * property as required, with optional ones made nullable, so they come
+         * back as null rather than missing.
+         */
+        public function withStrict(?bool $enabled = null): \StructuredBuilder {}
+
+        /**
+         * Name what the schema describes, e.g. 'invoice': letters, digits,
+         * underscores and dashes, at most 64. Sent as the schema's title, which
+         * direct requests also use as the response format's name.
+         */
+        public function withSchemaName(string $name): \StructuredBuilder {}
+
+        /**
+         * Repair almost-valid JSON (code fences, trailing commas, unquoted
+         * keys, single quotes, unclosed brackets) instead of rejecting the reply
+         */
+        public function withLenientParsing(?bool $enabled = null): \StructuredBuilder {}
+
+        /**
+         * What to do when the model has no native JSON mode: 'prompt' (default)
+         * asks for JSON in the system prompt and parses and validates the reply
+         * locally, 'error' throws LLMStructuredOutputException
+         */
+        public function setFallbackStrategy(string $strategy): \StructuredBuilder {}
+
+        /**
+         * Limit each attempt of a request to this many seconds (0 for no limit)
+         */
+        public function setTimeout(int $seconds): \StructuredBuilder {}
+
+        /**
+         * Limit a request to this many seconds over all of its retry attempts
+         * (0 for no limit)
+         */
+        public function setDeadline(int $seconds): \StructuredBuilder {}
+
+        /**
+         * Limit connection setup to this many seconds (0 for no limit)
+         */
+        public function setConnectTimeout(int $seconds): \StructuredBuilder {}
+
+        /**
+         * Set temperature
+         */
+        public function setTemperature(float $temperature): \StructuredBuilder {}
+
+        /**
+         * Set max tokens
+         */
+        public function setMaxTokens(int $max_tokens): \StructuredBuilder {}
+
+        /**
+         * Set top_k
+         */
+        public function setTopK(int $top_k): \StructuredBuilder {}
+
+        /**
+         * Set frequency penalty
+         */
+        public function setFrequencyPenalty(float $penalty): \StructuredBuilder {}
+
+        /**
+         * Set presence penalty
+         */
+        public function setPresencePenalty(float $penalty): \StructuredBuilder {}
+
+        /**
+         * Stop generating at any of these sequences (a string or an array of
+         * strings; an empty array clears them)
+         */
+        public function setStop(array|string $stop): \StructuredBuilder {}
+
+        /**
+         * Sample with a fixed seed, so repeated requests with the same input
+         * return the same output where the provider supports it
+         */
+        public function setSeed(int $seed): \StructuredBuilder {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Structured response with JSON output
+     */
+    class StructuredResponse implements \JsonSerializable, \CompletionResultInterface {
+        public function getContent(): string {}
+
+        /**
+         * Structured output as a PHP array (rebuilt on every call), with
+         * properties declared with Schema::enumFromClass() as enum cases
+         */
+        public function getStructured(): mixed {}
+
+        /**
+         * Instance of `className` created from the structured output, with
+         * nested objects and enums typed as the class declares them (see
+         * Schema::fromClass())
+         */
+        public function into(string $class_name): object {}
+
+        /**
+         * Structured output as a raw JSON string
+         */
+        public function getStructuredJson(): string {}
+
+        public function getUsage(): \Usage {}
+
+        public function getModel(): string {}
+
+        public function getFinishReason(): string {}
+
+        /**
+         * Provider's id of the response; null when the provider sent none or
+         * the response was streamed
+         */
+        public function getResponseId(): ?string {}
+
+        /**
+         * Reasoning ("thinking") text, for models and providers that return it
+         */
+        public function getReasoning(): ?string {}
+
+        /**
+         * Tokens spent on reasoning (shortcut for getUsage()->getReasoningTokens())
+         */
+        public function getReasoningTokens(): int {}
+
+        /**
+         * Whether this response was served from the cache. Usage is that of the
+         * original request; a cached response costs nothing.
+         */
+        public function isCached(): bool {}
+
+        /**
+         * Whether the JSON came from prompting rather than the provider's
+         * native JSON mode
+         */
+        public function isFallback(): bool {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Builder for tool calling
+     */
+    class ToolBuilder {
+        /**
+         * Complete with tool calling. With auto-execute on, tool calls are
+         * answered by their registered handlers and the conversation continues
+         * until the model stops calling tools.
+         */
+        public function complete(mixed $messages): \ToolResponse {}
+
+        /**
+         * Continue a tool loop paused with unanswered tool calls, e.g. held for
+         * dry-run approval or restored with ToolResponse::fromState().
+         * `$approvals` maps tool call ids to `true` (run the registered
+         * handler), a result (string or array) to send instead, or `false` to
+         * reject the call. Calls missing from the map are rejected. The loop
+         * keeps the model and sampling options it was started with.
+         */
+        public function resume(\ToolResponse $pending, array $approvals): \ToolResponse {}
+
+        /**
+         * Stream a tool-calling completion. `$onDelta` receives content chunks as
+         * they arrive; `$onToolCall` receives each ToolCall once all of its
+         * argument fragments have been assembled. Returns the final ToolResponse.
+         */
+        public function stream(mixed $messages, callable $on_delta, callable $on_tool_call): \ToolResponse {}
+
+        /**
+         * Add a tool, optionally with the handler that executes it
+         */
+        public function addTool(\Tool $tool, ?callable $handler = null): \ToolBuilder {}
+
+        /**
+         * Add every tool of a registry with its handler, replacing tools of
+         * the same name. The builder gets its own copy: later changes to the
+         * registry don't affect it.
+         */
+        public function addRegistry(\ToolRegistry $registry): \ToolBuilder {}
+
+        /**
+         * Register the callable executing a tool in the auto-execute loop.
+         * It receives the ToolCall; non-string results are JSON-encoded.
+         */
+        public function setHandler(string $name, callable $handler): \ToolBuilder {}
+
+        /**
+         * Summarize results of tool `name` longer than `max_tokens` with
+         * `summarizer`, usually a cheaper model, before they are fed back in
+         * the auto-execute loop. The summary is limited to `max_tokens`.
+         */
+        public function summarizeResults(string $name, int $max_tokens, \Llm $summarizer): \ToolBuilder {}
+
+        /**
+         * Hold tool calls for approval instead of executing them: complete()
+         * returns a ToolResponse with requiresApproval() set, to be continued
+         * with resume()
+         */
+        public function setDryRun(bool $dry_run): \ToolBuilder {}
+
+        /**
+         * Maximum provider requests per complete() or resume() call in the
+         * tool loop (default 10)
+         */
+        public function setMaxToolRounds(int $rounds): \ToolBuilder {}
+
+        /**
+         * Send only the `topK` tools whose descriptions are most similar to the
+         * last user message, by embedding similarity, to keep prompts small when
+         * many tools are registered. A tool forced by setToolChoice() is always
+         * sent. The embedding model defaults to the provider's (see
+         * embeddings()). Pass 0 to send every tool again.
+         */
+        public function selectTools(int $top_k, ?string $embedding_model = null): \ToolBuilder {}
+
+        /**
+         * Set all tools
+         */
+        public function setTools(array $tools): \ToolBuilder {}
+
+        /**
+         * System prompt sent ahead of the messages, merged with a leading system
+         * message if they have one. An empty string removes it.
+         */
+        public function withSystemPrompt(string $prompt): \ToolBuilder {}
+
+        /**
+         * Set auto execute
+         */
+        public function setAutoExecute(bool $auto): \ToolBuilder {}
+
+        /**
+         * Re-send the request up to this many times when the provider returns
+         * tool-call arguments that are not valid JSON (default 0)
+         */
+        public function setToolCallRetries(int $attempts): \ToolBuilder {}
+
+        /**
+         * Repair almost-valid tool-call arguments (code fences, trailing
+         * commas, unquoted keys, single quotes, unclosed brackets) before
+         * treating them as malformed
+         */
+        public function withLenientParsing(?bool $enabled = null): \ToolBuilder {}
+
+        /**
+         * Limit each attempt of a request to this many seconds (0 for no limit)
+         */
+        public function setTimeout(int $seconds): \ToolBuilder {}
+
+        /**
+         * Limit a request to this many seconds over all of its retry attempts
+         * (0 for no limit)
+         */
+        public function setDeadline(int $seconds): \ToolBuilder {}
+
+        /**
+         * Limit connection setup to this many seconds (0 for no limit)
+         */
+        public function setConnectTimeout(int $seconds): \ToolBuilder {}
+
+        /**
+         * Set temperature
+         */
+        public function setTemperature(float $temperature): \ToolBuilder {}
+
+        /**
+         * Set max tokens
+         */
+        public function setMaxTokens(int $max_tokens): \ToolBuilder {}
+
+        /**
+         * Set top_k
+         */
+        public function setTopK(int $top_k): \ToolBuilder {}
+
+        /**
+         * Set frequency penalty
+         */
+        public function setFrequencyPenalty(float $penalty): \ToolBuilder {}
+
+        /**
+         * Set presence penalty
+         */
+        public function setPresencePenalty(float $penalty): \ToolBuilder {}
+
+        /**
+         * Stop generating at any of these sequences (a string or an array of
+         * strings; an empty array clears them)
+         */
+        public function setStop(array|string $stop): \ToolBuilder {}
+
+        /**
+         * Sample with a fixed seed, so repeated requests with the same input
+         * return the same output where the provider supports it
+         */
+        public function setSeed(int $seed): \ToolBuilder {}
+
+        /**
+         * Control tool use: 'auto' (the default), 'none', 'required', or
+         * 'function' with `$toolName` to force a call to that tool. A forced
+         * call applies to the first request only; after tool results the model
+         * is free to answer.
+         */
+        public function setToolChoice(string $mode, ?string $toolName = null): \ToolBuilder {}
+
+        /**
+         * Allow or forbid several tool calls in one response. With `false` the
+         * model calls at most one tool at a time, for workflows whose calls
+         * depend on each other. Unset, the provider default applies.
+         */
+        public function setParallelToolCalls(bool $enabled): \ToolBuilder {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Tool definition
+     */
+    class Tool implements \JsonSerializable {
+        /**
+         * Create from array
+         */
+        public static function fromArray(array $data): \Tool {}
+
+        /**
+         * Tool calling `callable`, with the parameters derived from its
+         * signature: names, types (also backed enums and classes, as with
+         * Schema::fromClass()), defaults and #[Description] / #[Enum]
+         * attributes. Named functions give the default name; the description
+         * defaults to a #[Description] attribute or the doc comment. ToolBuilder
+         * calls it with the arguments by name, unless a handler is set.
+         */
+        public static function fromCallable(callable $callable, ?string $name = null, ?string $description = null): \Tool {}
+
+        /**
+         * fromCallable() for a public method of an object, named after the
+         * method by default
+         */
+        public static function fromMethod(object $object, string $method, ?string $name = null, ?string $description = null): \Tool {}
+
+        public function getName(): string {}
+
+        public function getDescription(): string {}
+
+        public function getParameters(): string {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        /**
+         * State for serialize(), e.g. to keep the object in a session or queue
+         */
+        public function __serialize(): array {}
+
+        public function __unserialize(array $data): void {}
+
+        /**
+         * Create a tool definition
+         */
+        public function __construct(string $name, string $description, mixed $parameters) {}
+    }
+
+    /**
+     * Tool call from LLM
+     */
+    class ToolCall implements \JsonSerializable {
+        public function getId(): string {}
+
+        public function getName(): string {}
+
+        public function getArguments(): mixed {}
+
+        /**
+         * Arguments as the raw JSON object string
+         */
+        public function getArgumentsJson(): string {}
+
+        /**
+         * A single argument, or `$default` when it is missing. An int, float or
+         * bool default also sets the type: numeric strings and booleans are
+         * coerced to it, and values that cannot be coerced yield the default.
+         */
+        public function getArgument(string $key, mixed $default = null): mixed {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        /**
+         * State for serialize(), e.g. to keep the object in a session or queue
+         */
+        public function __serialize(): array {}
+
+        public function __unserialize(array $data): void {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Named tools with their handlers, registered once and reused by many
+     * ToolBuilders. ToolRegistry::global() is shared across requests and LLM
+     * instances; fork() and new registries are private copies.
+     */
+    class ToolRegistry {
+        /**
+         * The process-wide registry. Definitions registered here are visible
+         * to every request and thread; handlers stay with the thread that
+         * registered them until its request ends, so register them at
+         * worker start-up. Using a tool registered with a handler where that
+         * handler is gone throws an LLMException.
+         */
+        public static function global(): \ToolRegistry {}
+
+        /**
+         * Register a tool, optionally with the handler that executes it. A
+         * tool with the same name is replaced.
+         */
+        public function register(\Tool $tool, ?callable $handler = null): \ToolRegistry {}
+
+        /**
+         * Remove a tool and its handler
+         */
+        public function unregister(string $name): \ToolRegistry {}
+
+        /**
+         * Copy of this registry for the current request. Changes to the copy
+         * don't reach the original, nor the other way round; the definitions
+         * are only copied once one side is modified.
+         */
+        public function fork(): \ToolRegistry {}
+
+        public function has(string $name): bool {}
+
+        public function get(string $name): ?\Tool {}
+
+        /**
+         * Names of the registered tools, in registration order
+         */
+        public function names(): array {}
+
+        public function count(): int {}
+
+        /**
+         * Create an empty registry local to the current request
+         */
+        public function __construct() {}
+    }
+
+    /**
+     * Response with tool calls
+     */
+    class ToolResponse implements \JsonSerializable, \CompletionResultInterface {
+        public function getContent(): string {}
+
+        public function getToolCalls(): array {}
+
+        public function getUsage(): \Usage {}
+
+        public function getModel(): string {}
+
+        /**
+         * 'tool_calls' when the model wants tools run, otherwise the provider's
+         * reason, e.g. 'stop' or 'length'
+         */
+        public function getFinishReason(): string {}
+
+        public function getId(): ?string {}
+
+        /**
+         * Provider's id of the response (same as getId())
+         */
+        public function getResponseId(): ?string {}
+
+        public function hasToolCalls(): bool {}
+
+        /**
+         * Whether dry-run is holding these tool calls for approval; continue
+         * with ToolBuilder::resume()
+         */
+        public function requiresApproval(): bool {}
+
+        /**
+         * Serialize the paused tool loop (conversation, pending tool calls and
+         * request options) to resume it in a later request
+         */
+        public function getState(): string {}
+
+        /**
+         * Restore a paused tool loop saved with getState()
+         */
+        public static function fromState(string $state): \ToolResponse {}
+
+        /**
+         * Names of the tools sent with the request when selectTools() picked a
+         * subset of them; null when every tool was sent
+         */
+        public function getSelectedTools(): ?array {}
+
+        /**
+         * Tool calls run by the auto-execute loop of this complete() or
+         * resume() call, in order: id, name, round (0 for calls approved in
+         * resume()), started_ms since the loop started, handler_ms spent in the
+         * PHP handler, duration_ms until the result was ready, deferred when
+         * the handler returned an LLMFuture, and summarized
+         */
+        public function getExecutionTrace(): array {}
+
+        /**
+         * Reasoning ("thinking") text, for models and providers that return it
+         */
+        public function getReasoning(): ?string {}
+
+        /**
+         * Tokens spent on reasoning (shortcut for getUsage()->getReasoningTokens())
+         */
+        public function getReasoningTokens(): int {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Message in conversation
+     */
+    class Message implements \JsonSerializable {
+        /**
+         * Create a user message
+         */
+        public static function user(string $content): \Message {}
+
+        /**
+         * Create an assistant message
+         */
+        public static function assistant(string $content): \Message {}
+
+        /**
+         * Create a system message
+         */
+        public static function system(string $content): \Message {}
+
+        /**
+         * Create a tool result message
+         */
+        public static function tool(string $tool_call_id, string $result): \Message {}
+
+        /**
+         * Split a text too long for one message into user messages of at most
+         * `maxTokens` tokens, marked "[Part 2 of 5]" and, except the last,
+         * "[Continued in the next message]". A text that fits gives a single
+         * unmarked message. Tokens are counted for `model`, by default with
+         * o200k_base.
+         */
+        public static function split(string $text, int $maxTokens, ?string $model = null): \MessageCollection {}
+
+        /**
+         * Create a user message with an image from an http(s) URL, a data: URL
+         * or a local file path, which must be within open_basedir. Options
+         * 'max_dimension' and 'quality' shrink the image data before it is
+         * encoded.
+         *
+         * @param array{max_dimension?: int, quality?: int}|null $options
+         */
+        public static function userWithImage(string $text, string $image_path_or_url, ?array $options = null): \Message {}
+
+        /**
+         * Attach an image by URL
+         */
+        public function addImageUrl(string $url): \Message {}
+
+        /**
+         * Attach base64-encoded image data, e.g. media type 'image/png', with
+         * the same options as userWithImage()
+         *
+         * @param array{max_dimension?: int, quality?: int}|null $options
+         */
+        public function addImageBase64(string $data, string $media_type, ?array $options = null): \Message {}
+
+        /**
+         * Create from ToolResponse
+         */
+        public static function fromResponse(\ToolResponse $response): \Message {}
+
+        /**
+         * Create from array
+         */
+        public static function fromArray(array $data): \Message {}
+
+        public function getRole(): string {}
+
+        public function getContent(): string {}
+
+        public function getToolCalls(): ?string {}
+
+        public function getId(): ?string {}
+
+        public function getToolCallId(): ?string {}
+
+        /**
+         * Attached images as ['url' => ...] or ['data' => ..., 'media_type' => ...]
+         */
+        public function getImages(): mixed {}
+
+        public function toArray(): mixed {}
+
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        /**
+         * State for serialize(), e.g. to keep the object in a session or queue
+         */
+        public function __serialize(): array {}
+
+        public function __unserialize(array $data): void {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Collection of messages. Works as a list: count($collection),
+     * $collection[2] and foreach.
+     */
+    class MessageCollection implements \JsonSerializable, \Countable, \ArrayAccess, \IteratorAggregate {
+        /**
+         * Create from array
+         */
+        public static function fromArray(array $messages): \MessageCollection {}
+
+        /**
+         * Add a message
+         */
+        public function add(\Message $message): \MessageCollection {}
+
+        /**
+         * Add a user message
+         */
+        public function addUser(string $content): \MessageCollection {}
+
+        /**
+         * Add an assistant message
+         */
+        public function addAssistant(string $content): \MessageCollection {}
+
+        /**
+         * Add a system message
+         */
+        public function addSystem(string $content): \MessageCollection {}
+
+        /**
+         * Add a tool result message
+         */
+        public function addToolResult(string $tool_call_id, string $result): \MessageCollection {}
+
+        /**
+         * Mark prompt cache breakpoints at the stable prefixes of the
+         * conversation on each request: the system prompt, which also covers
+         * the tools sent before it, and the last two user or tool turns, so
+         * each request reads the history the previous one cached. Providers
+         * with explicit caching (Anthropic) bill the cached prefix at the
+         * cache-read rate; the others ignore the markers.
+         */
+        public function withPromptCaching(?bool $enabled = null): \MessageCollection {}
+
+        /**
+         * Trim the conversation to about `max_tokens`, oldest messages first.
+         * System messages and the latest message are always kept, and tool
+         * results go with the turn that requested them. Strategy 'drop_oldest'
+         * (default) discards the messages; 'summarize' replaces them with a
+         * summary written by `llm`, kept as a system message after the others.
+         * Tokens are counted for the LLM's model when one is passed, otherwise
+         * with o200k_base.
+         */
+        public function truncateToTokens(int $max_tokens, ?string $strategy = null, ?\Llm $llm = null): \MessageCollection {}
+
+        /**
+         * Get message at index
+         */
+        public function get(int $index): ?\Message {}
+
+        /**
+         * Get all messages
+         */
+        public function all(): array {}
+
+        /**
+         * Get message count
+         */
+        public function count(): int {}
+
+        /**
+         * Whether there is a message at `offset`
+         */
+        public function offsetExists(mixed $offset): bool {}
+
+        /**
+         * Message at `offset`, or null
+         */
+        public function offsetGet(mixed $offset): ?\Message {}
+
+        /**
+         * Replace the message at `offset`, or append with `$collection[] =`
+         * or an offset one past the end. Takes a Message or a ['role',
+         * 'content'] array.
+         */
+        public function offsetSet(mixed $offset, mixed $value): void {}
+
+        /**
+         * Remove the message at `offset`. Later messages move up, so offsets
+         * stay 0 to count() - 1.
+         */
+        public function offsetUnset(mixed $offset): void {}
+
+        /**
+         * Remove the message at `index`; later messages move up
+         */
+        public function remove(int $index): \MessageCollection {}
+
+        /**
+         * Insert a message before `index`; count() appends
+         */
+        public function insert(int $index, \Message $message): \MessageCollection {}
+
+        /**
+         * New collection with `length` messages from `offset`, or up to the
+         * end without a length. Offsets and lengths work as in array_slice().
+         */
+        public function slice(int $offset, ?int $length = null): \MessageCollection {}
+
+        /**
+         * New collection with the messages for which `fn(Message $m): bool`
+         * returns true
+         */
+        public function filter(callable $callback): \MessageCollection {}
+
+        /**
+         * New collection with each message replaced by what
+         * `fn(Message $m): Message|array` returns
+         */
+        public function map(callable $callback): \MessageCollection {}
+
+        /**
+         * Append the messages of another collection
+         */
+        public function merge(\MessageCollection $other): \MessageCollection {}
+
+        /**
+         * Iterator over a snapshot of the messages, for foreach
+         */
+        public function getIterator(): \MessageIterator {}
+
+        /**
+         * Read-only view of the messages as they are now, sharing them with
+         * this collection instead of copying: later changes to the collection
+         * copy its messages first and leave the snapshot as it was
+         */
+        public function snapshot(): \MessageSnapshot {}
+
+        /**
+         * Convert to array
+         */
+        public function toArray(): mixed {}
+
+        /**
+         * Convert to JSON
+         */
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+
+        /**
+         * State for serialize(), e.g. to keep the object in a session or queue
+         */
+        public function __serialize(): array {}
+
+        public function __unserialize(array $data): void {}
+
+        /**
+         * Restore a collection saved with toJson(), tool calls and tool
+         * results included
+         */
+        public static function fromJson(string $json): \MessageCollection {}
+
+        /**
+         * Render as a Markdown transcript, including tool calls and results
+         */
+        public function toMarkdown(): string {}
+
+        /**
+         * Render as an HTML transcript fragment. Elements carry classes such as
+         * `message-user` and `tool-call` for styling.
+         */
+        public function toHtml(): string {}
+
+        /**
+         * Create a new message collection
+         */
+        public function __construct(?array $messages = null) {}
+    }
+
+    /**
+     * Read-only messages returned by MessageCollection::snapshot(). Shares
+     * the messages with the collection, so it is cheap to take of a long
+     * history and to pass to concurrent async completions. Works as a list
+     * like the collection; changing it throws.
+     */
+    class MessageSnapshot implements \JsonSerializable, \Countable, \ArrayAccess, \IteratorAggregate {
+        /**
+         * Get message at index
+         */
+        public function get(int $index): ?\Message {}
+
+        /**
+         * Get all messages
+         */
+        public function all(): array {}
+
+        public function count(): int {}
+
+        public function offsetExists(mixed $offset): bool {}
+
+        public function offsetGet(mixed $offset): ?\Message {}
+
+        public function offsetSet(mixed $offset, mixed $value): void {}
+
+        public function offsetUnset(mixed $offset): void {}
+
+        public function getIterator(): \MessageIterator {}
+
+        /**
+         * Collection holding these messages, to change; they are copied on its
+         * first change
+         */
+        public function toCollection(): \MessageCollection {}
+
+        /**
+         * Convert to array
+         */
+        public function toArray(): mixed {}
+
+        /**
+         * Convert to JSON, in the format of MessageCollection::toJson()
+         */
+        public function toJson(): string {}
+
+        /**
+         * Data for json_encode(), which then gives the same JSON as toJson()
+         */
+        public function jsonSerialize(): mixed {}
+    }
+
+    /**
+     * Iterator returned by MessageCollection::getIterator()
+     */
+    class MessageIterator implements \Iterator {
+        public function current(): ?\Message {}
+
+        public function key(): int {}
+
+        public function next(): void {}
+
+        public function rewind(): void {}
+
+        public function valid(): bool {}
+
+        public function __construct() {}
+    }
+
+    /**
+     * Chat session owning its history: say() sends a user message with the
+     * history so far and records the reply
+     */
+    class Conversation {
+        /**
+         * Send a user message with the history so far and return the reply.
+         * Both are appended to the history; on failure the history is left as
+         * it was before the call.
          */
-        public function withTools(?array $tools = null): \ToolBuilder {}
+        public function say(string $content): \Response {}
 
         /**
-         * Set configuration options
+         * Forget the history, keeping the system prompt
          */
-        public function withOptions(array $options): \Llm {}
+        public function reset(): void {}
 
         /**
-         * Set temperature
+         * Copy of the history, including the system prompt
          */
-        public function setTemperature(float $_temperature): \Llm {}
+        public function getHistory(): \MessageCollection {}
 
         /**
-         * Set max tokens
+         * Save the system prompt and history, tool calls and results
+         * included, e.g. to a session between requests
          */
-        public function setMaxTokens(int $_max_tokens): \Llm {}
+        public function toJson(): string {}
 
         /**
-         * Set top_p
+         * Continue a conversation saved with toJson() using `llm`. Token
+         * limits and trimmers are not saved; set them again.
          */
-        public function setTopP(float $_top_p): \Llm {}
+        public static function fromJson(string $json, \Llm $llm): \Conversation {}
 
         /**
-         * Set frequency penalty
+         * Trim the history to about `max_tokens` before each request (see
+         * MessageCollection::truncateToTokens()). Strategy 'drop_oldest'
+         * (default) or 'summarize', which uses the conversation's LLM.
          */
-        public function setFrequencyPenalty(float $_penalty): \Llm {}
+        public function withTokenLimit(int $max_tokens, ?string $strategy = null): \Conversation {}
 
         /**
-         * Set presence penalty
+         * Trim the history with a callable `fn(MessageCollection $history):
+         * MessageCollection` before each request, instead of a token limit
          */
-        public function setPresencePenalty(float $_penalty): \Llm {}
+        public function withTrimmer(callable $trimmer): \Conversation {}
 
         /**
-         * Create a new LLM instance
+         * Start a conversation with `llm`, optionally under a system prompt
          */
-        public function __construct(string $model, ?array $_options = null) {}
+        public function __construct(\Llm $llm, ?string $system_prompt = null) {}
     }
 
     /**
-     * Response from LLM completion
+     * Answer from a speculative dual dispatch, with the usage of both models
      */
-    class Response {
+    class SpeculativeResponse {
+        /**
+         * The answer that was chosen
+         */
+        public function getResponse(): \Response {}
+
         public function getContent(): string {}
 
-        public function getUsage(): \Usage {}
+        /**
+         * 'strong' or 'fast'
+         */
+        public function getWinner(): string {}
 
-        public function getModel(): string {}
+        /**
+         * Whether the fast model's answer was used
+         */
+        public function isFallback(): bool {}
 
-        public function getFinishReason(): string {}
+        /**
+         * Usage of the strong model, or null when its request failed. An
+         * aborted request reports its prompt, estimated locally.
+         */
+        public function getStrongUsage(): ?\Usage {}
 
-        public function toArray(): mixed {}
+        /**
+         * Usage of the fast model, or null when its request failed. An aborted
+         * request reports its prompt, estimated locally.
+         */
+        public function getFastUsage(): ?\Usage {}
 
-        public function toJson(): string {}
+        /**
+         * Usage of both models together, as charged to budgets and totals
+         */
+        public function getUsage(): \Usage {}
 
         public function __construct() {}
     }
 
     /**
-     * Token usage information
+     * Result of LLM::detectLanguage()
      */
-    class Usage {
-        public function getPromptTokens(): int {}
+    class LanguageDetection {
+        /**
+         * ISO 639-1 code, e.g. 'en'
+         */
+        public function getLanguage(): string {}
 
-        public function getOutputTokens(): int {}
+        /**
+         * Confidence from 0 to 1
+         */
+        public function getConfidence(): float {}
 
-        public function getTotalTokens(): int {}
+        /**
+         * 'local' or 'model'
+         */
+        public function getSource(): string {}
 
         public function toArray(): mixed {}
 
-        public function toJson(): string {}
-
         public function __construct() {}
     }
 
     /**
-     * Builder for structured output
+     * Result of LLM::translate()
      */
-    class StructuredBuilder {
-        /**
-         * Complete with structured output
-         */
-        public function complete(mixed $messages): \StructuredResponse {}
+    class Translation {
+        public function getText(): string {}
 
         /**
-         * Set JSON schema
+         * Usage summed over every request made for the translation
          */
-        public function withSchema(string $schema): \StructuredBuilder {}
+        public function getUsage(): \Usage {}
 
-        /**
-         * Set format ('json' or 'json_schema')
-         */
-        public function withFormat(string $format): \StructuredBuilder {}
+        public function getModel(): string {}
 
         /**
-         * Set temperature
+         * Number of chunks the text was translated in
          */
-        public function setTemperature(float $temperature): \StructuredBuilder {}
+        public function getChunkCount(): int {}
 
         /**
-         * Set max tokens
+         * Glossary terms the model still left out after a corrective retry
          */
-        public function setMaxTokens(int $max_tokens): \StructuredBuilder {}
+        public function getMissingTerms(): array {}
+
+        public function toArray(): mixed {}
 
         public function __construct() {}
     }
 
     /**
-     * Structured response with JSON output
+     * Handle to a completion running in the background on the shared runtime
      */
-    class StructuredResponse {
-        public function getContent(): string {}
-
-        public function getStructured(): mixed {}
-
-        public function getUsage(): \Usage {}
-
-        public function getModel(): string {}
+    class LLMFuture {
+        /**
+         * Whether the completion has finished (successfully or not)
+         */
+        public function isReady(): bool {}
 
-        public function toArray(): mixed {}
+        /**
+         * Block until the completion finishes and return its Response. With a
+         * timeout, returns null if it has not finished in time; the completion
+         * keeps running and wait() can be called again.
+         */
+        public function wait(?int $timeout_ms = null): ?\Response {}
 
-        public function toJson(): string {}
+        /**
+         * Abort the request if it is still running. Returns false when it had
+         * already finished or been cancelled.
+         */
+        public function cancel(): bool {}
 
         public function __construct() {}
     }
 
     /**
-     * Builder for tool calling
+     * Pull-based iterator over streamed content deltas.
+     *
+     * Each `next()` blocks on the runtime until the next delta arrives; the bounded
+     * channel behind it applies backpressure to the HTTP stream.
      */
-    class ToolBuilder {
+    class StreamIterator implements \Iterator {
         /**
-         * Complete with tool calling
+         * Current content delta
          */
-        public function complete(mixed $messages): \ToolResponse {}
+        public function current(): ?string {}
 
         /**
-         * Add a tool
+         * Index of the current delta
          */
-        public function addTool(\Tool $tool): \ToolBuilder {}
+        public function key(): int {}
 
         /**
-         * Set all tools
+         * Advance to the next delta
          */
-        public function setTools(array $tools): \ToolBuilder {}
+        public function next(): void {}
 
         /**
-         * Set auto execute
+         * Start the stream; a stream can only be iterated once
          */
-        public function setAutoExecute(bool $auto): \ToolBuilder {}
+        public function rewind(): void {}
 
         /**
-         * Set temperature
+         * Whether a delta is available
          */
-        public function setTemperature(float $temperature): \ToolBuilder {}
+        public function valid(): bool {}
 
         /**
-         * Set max tokens
+         * Final Response, available once the stream has been fully consumed
          */
-        public function setMaxTokens(int $max_tokens): \ToolBuilder {}
+        public function getResponse(): ?\Response {}
 
         public function __construct() {}
     }
 
     /**
-     * Tool definition
+     * Embeddings client
      */
-    class Tool {
+    class Embeddings {
         /**
-         * Create from array
+         * Embed a single text
          */
-        public static function fromArray(array $data): \Tool {}
+        public function embed(string $text): \EmbeddingResponse {}
 
-        public function getName(): string {}
+        /**
+         * Embed several texts in one request
+         */
+        public function embedBatch(array $texts): \EmbeddingResponse {}
 
-        public function getDescription(): string {}
+        public function getModel(): string {}
 
-        public function getParameters(): string {}
+        /**
+         * Request vectors of this many dimensions. Vectors the provider returns
+         * longer are truncated and re-normalized. Pass null to use the model default.
+         */
+        public function setDimensions(?int $dimensions = null): \Embeddings {}
 
-        public function toArray(): mixed {}
+        /**
+         * Hint whether texts are search queries or documents ('query' or
+         * 'document'), for providers with asymmetric embeddings (Voyage, Jina).
+         * Pass null to remove the hint.
+         */
+        public function setInputType(?string $input_type = null): \Embeddings {}
 
-        public function toJson(): string {}
+        /**
+         * L2-normalize returned vectors to unit length
+         */
+        public function setNormalize(bool $normalize): \Embeddings {}
 
         /**
-         * Create a tool definition
+         * Return vectors as 'float' arrays (default), 'float32' packed binary
+         * strings (little-endian, same as `pack('g*', ...)`) or 'base64' of those
+         * bytes. Packed formats skip building PHP arrays entirely.
          */
-        public function __construct(string $name, string $description, mixed $parameters) {}
+        public function setOutputFormat(string $format): \Embeddings {}
+
+        /**
+         * Create an embeddings client for a "provider:model" string
+         */
+        public function __construct(string $model, ?array $options = null) {}
     }
 
     /**
-     * Tool call from LLM
+     * Embedding vectors with token usage
      */
-    class ToolCall {
-        public function getId(): string {}
-
-        public function getName(): string {}
-
-        public function getArguments(): mixed {}
-
-        public function toArray(): mixed {}
-
-        public function toJson(): string {}
+    class EmbeddingResponse {
+        /**
+         * First embedding vector, in the client's output format
+         */
+        public function getEmbedding(): mixed {}
 
-        public function __construct() {}
-    }
+        /**
+         * All embedding vectors in input order, in the client's output format
+         */
+        public function getEmbeddings(): mixed {}
 
-    /**
-     * Response with tool calls
-     */
-    class ToolResponse {
-        public function getContent(): string {}
+        /**
+         * Name of the output format: 'float', 'float32' or 'base64'
+         */
+        public function getFormat(): string {}
 
-        public function getToolCalls(): array {}
+        public function getDimensions(): int {}
 
         public function getUsage(): \Usage {}
 
         public function getModel(): string {}
 
-        public function getId(): ?string {}
-
-        public function hasToolCalls(): bool {}
-
         public function toArray(): mixed {}
 
-        public function toJson(): string {}
-
         public function __construct() {}
     }
 
     /**
-     * Message in conversation
+     * Vector store in a Manticore Search table, filled and queried over its
+     * HTTP JSON API
      */
-    class Message {
+    class ManticoreVectorStore {
         /**
-         * Create a user message
+         * Embed `text` and store it under `id`, replacing any previous document
+         * with that id
          */
-        public static function user(string $content): \Message {}
+        public function store(int $id, string $text): void {}
 
         /**
-         * Create an assistant message
+         * The `k` stored texts nearest to `query`, as a list of ['id', 'text',
+         * 'distance'] arrays, nearest first
          */
-        public static function assistant(string $content): \Message {}
+        public function search(string $query, int $k): mixed {}
+
+        public function getTable(): string {}
 
         /**
-         * Create a system message
+         * Store for `table`, embedding texts with `embeddings`. Options: 'url'
+         * of the Manticore HTTP listener (default http://127.0.0.1:9308). The
+         * table is created on the first store() if it doesn't exist.
          */
-        public static function system(string $content): \Message {}
+        public function __construct(string $table, \Embeddings $embeddings, ?array $options = null) {}
+    }
 
+    /**
+     * Retrieval-augmented generation: fetch the chunks nearest to a question
+     * from a store, put them in the prompt and ask the model
+     */
+    class RAG {
         /**
-         * Create a tool result message
+         * Retrieve the chunks for `question`, ask the model with them in the
+         * prompt and return the answer with the chunks used
          */
-        public static function tool(string $tool_call_id, string $result): \Message {}
+        public function ask(string $question): \RAGAnswer {}
 
         /**
-         * Create from ToolResponse
+         * Number of chunks to retrieve per question (default 4)
          */
-        public static function fromResponse(\ToolResponse $response): \Message {}
+        public function setK(int $k): \RAG {}
 
         /**
-         * Create from array
+         * Prompt template with `{context}` (the numbered chunks) and
+         * `{question}` placeholders
          */
-        public static function fromArray(array $data): \Message {}
+        public function withTemplate(string $template): \RAG {}
 
-        public function getRole(): string {}
+        /**
+         * Embeddings client for questions sent to a callable store. Defaults to
+         * the LLM's provider default (see LLM::embeddings()).
+         */
+        public function withEmbeddings(\Embeddings $embeddings): \RAG {}
 
-        public function getContent(): string {}
+        /**
+         * Answer questions with `llm` from chunks in `store`: a
+         * ManticoreVectorStore, or a callable `fn(string $question, array
+         * $embedding, int $k): array` returning chunks as strings or arrays with
+         * a 'text' key
+         */
+        public function __construct(\Llm $llm, mixed $store) {}
+    }
 
-        public function getToolCalls(): ?string {}
+    /**
+     * Result of RAG::ask()
+     */
+    class RAGAnswer {
+        public function getAnswer(): string {}
 
-        public function getId(): ?string {}
+        public function getResponse(): \Response {}
 
-        public function getToolCallId(): ?string {}
+        /**
+         * Chunks put in the prompt, as returned by the store, in prompt order
+         */
+        public function getSources(): mixed {}
 
         public function toArray(): mixed {}
 
-        public function toJson(): string {}
-
         public function __construct() {}
     }
 
     /**
-     * Collection of messages
+     * Comparison of two responses, for evals and prompt regression review
      */
-    class MessageCollection {
+    class ResponseDiff {
         /**
-         * Create from array
+         * Compare two Response or StructuredResponse objects
          */
-        public static function fromArray(array $messages): \MessageCollection {}
+        public static function compare(mixed $old, mixed $new): \ResponseDiff {}
 
         /**
-         * Add a message
+         * Whether content and structured output are identical
          */
-        public function add(\Message $message): \MessageCollection {}
+        public function isIdentical(): bool {}
 
         /**
-         * Add a user message
+         * Word-level similarity of the content, from 0.0 to 1.0
          */
-        public function addUser(string $content): \MessageCollection {}
+        public function getSimilarity(): float {}
 
         /**
-         * Add an assistant message
+         * Content diff as a list of ['op' => 'equal'|'insert'|'delete', 'text' => string]
          */
-        public function addAssistant(string $content): \MessageCollection {}
+        public function getContentDiff(): mixed {}
 
         /**
-         * Add a system message
+         * Structured field changes as a list of
+         * ['path' => string, 'change' => 'added'|'removed'|'changed', 'old' => mixed, 'new' => mixed]
          */
-        public function addSystem(string $content): \MessageCollection {}
+        public function getFieldChanges(): mixed {}
+
+        public function toArray(): mixed {}
+
+        public function __construct() {}
+    }
 
+    /**
+     * Offline token counting with the BPE vocabularies of OpenAI models.
+     * `$model` is a model ("openai:gpt-4o", "gpt-3.5-turbo") or an encoding
+     * name ("o200k_base", "cl100k_base", "p50k_base", "r50k_base"); other
+     * providers' models are approximated with o200k_base.
+     */
+    class Tokenizer {
         /**
-         * Add a tool result message
+         * Number of tokens in `text`. Special tokens such as <|endoftext|> are
+         * counted as plain text, as the APIs do for message content.
          */
-        public function addToolResult(string $tool_call_id, string $result): \MessageCollection {}
+        public static function count(string $text, string $model): int {}
 
         /**
-         * Get message at index
+         * Token ids of `text`
          */
-        public function get(int $index): ?\Message {}
+        public static function encode(string $text, string $model): array {}
 
         /**
-         * Get all messages
+         * Text of the token ids. Fails on ids outside the vocabulary and on
+         * sequences that cut a character in half.
          */
-        public function all(): array {}
+        public static function decode(array $tokens, string $model): string {}
 
         /**
-         * Get message count
+         * Name of the encoding used for `model`
          */
-        public function count(): int {}
+        public static function encodingFor(string $model): string {}
+    }
 
+    /**
+     * Verification of provider webhook deliveries
+     */
+    class Webhook {
         /**
-         * Convert to array
+         * Whether a delivery is authentic. `$headers` maps header names (any
+         * case) to values; `$secret` is the endpoint's signing secret
+         * (`whsec_...`). Deliveries older or newer than `$tolerance` seconds
+         * (default 300, 0 to skip) are rejected to stop replays.
          */
-        public function toArray(): mixed {}
+        public static function verify(string $payload, array $headers, string $secret, ?int $tolerance = null): bool {}
 
         /**
-         * Convert to JSON
+         * Verify a delivery and return its decoded JSON event. Throws
+         * LLMValidationException with the reason when verification fails.
          */
-        public function toJson(): string {}
+        public static function unwrap(string $payload, array $headers, string $secret, ?int $tolerance = null): mixed {}
+    }
 
+    /**
+     * Cost ceiling for the calls of the LLM instances it is attached to.
+     * Guards created with the same key share their spending across the
+     * process; a guard without a key counts only its own calls.
+     */
+    class BudgetGuard {
         /**
-         * Create a new message collection
+         * Guard with a cost ceiling in the provider's currency (USD for the
+         * built-in pricing). With a key, spending is shared with every guard
+         * of the same key in this PHP process and kept across requests.
          */
-        public function __construct(?array $messages = null) {}
+        public function __construct(float $maxCost, ?string $key = null) {}
+
+        public function getMaxCost(): float {}
+
+        public function getKey(): ?string {}
+
+        /**
+         * Cost of the calls counted so far
+         */
+        public function getSpent(): float {}
+
+        /**
+         * Cost left before calls are rejected; negative after an overdraw
+         */
+        public function getRemaining(): float {}
+
+        /**
+         * Whether further calls are rejected
+         */
+        public function isExceeded(): bool {}
+
+        /**
+         * Start counting from zero, for every guard sharing the key
+         */
+        public function reset(): void {}
     }
 
     class LLMException extends \Exception {
-        protected $message;
+        /** Not a provider error */
+        const ERR_UNKNOWN = 0;
 
-        protected $code;
+        /** Connection failed or was interrupted */
+        const ERR_NETWORK = 1;
 
-        public function __construct(?string $message = null, ?int $code = null) {}
-    }
+        /** Provider did not answer in time */
+        const ERR_TIMEOUT = 2;
 
-    class LLMConnectionException extends \Exception {
-        protected $code;
+        /** API error status not covered by a more specific code */
+        const ERR_API = 3;
 
-        protected $message;
+        /** API key missing, invalid or not allowed (HTTP 401 or 403) */
+        const ERR_AUTHENTICATION = 4;
 
-        public function __construct(?string $message = null, ?int $code = null) {}
-    }
+        /** Rate limit or quota exceeded (HTTP 429) */
+        const ERR_RATE_LIMIT = 5;
+
+        /** Provider-side failure (HTTP 5xx) */
+        const ERR_SERVER = 6;
+
+        /** Model unknown to the provider */
+        const ERR_MODEL_NOT_SUPPORTED = 7;
+
+        /** Other provider error */
+        const ERR_PROVIDER = 8;
+
+        /**
+         * Provider that returned the error, e.g. 'openai'
+         */
+        public function getProvider(): ?string {}
+
+        /**
+         * HTTP status of the provider's error response
+         */
+        public function getHttpStatus(): ?int {}
 
-    class LLMValidationException extends \Exception {
-        protected $message;
+        /**
+         * Provider's id of the failed request, for support tickets
+         */
+        public function getRequestId(): ?string {}
 
-        protected $code;
+        /**
+         * Provider's error type, e.g. 'rate_limit_error' or
+         * 'insufficient_quota'
+         */
+        public function getErrorType(): ?string {}
 
-        public function __construct(?string $message = null, ?int $code = null) {}
+        /**
+         * Provider's error response body, with API keys masked and cut to
+         * 2000 characters unless verbose errors are on
+         */
+        public function getRawBody(): ?string {}
     }
 
-    class LLMStructuredOutputException extends \Exception {
-        protected $code;
+    class LLMConnectionException extends \LLMException {
+        /**
+         * Seconds the provider asked to wait before retrying, from the
+         * `Retry-After` header or a "try again in 1.5s" message of a 429
+         */
+        public function getRetryAfter(): ?float {}
 
-        protected $message;
+        /**
+         * Limit a timeout hit: 'attempt' when a single request ran past
+         * setTimeout(), 'deadline' when setDeadline() ran out across the
+         * retries; null for other errors
+         */
+        public function getTimeoutLimit(): ?string {}
+    }
 
-        public function __construct(?string $message = null, ?int $code = null) {}
+    class LLMValidationException extends \LLMException {
     }
 
-    class LLMToolCallException extends \Exception {
-        protected $code;
+    class LLMStructuredOutputException extends \LLMException {
+        /**
+         * Ways the output did not match the schema, as "/path: message"
+         * with the path a JSON pointer into the output; empty for other
+         * structured output errors
+         */
+        public function getViolations(): array {}
+    }
 
-        protected $message;
+    class LLMToolCallException extends \LLMException {
+    }
 
-        public function __construct(?string $message = null, ?int $code = null) {}
+    class LLMBudgetExceededException extends \LLMException {
     }
 }
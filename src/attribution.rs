@@ -0,0 +1,284 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use octolib::llm::TokenUsage;
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+
+use crate::budget::BudgetGuard;
+use crate::throttle::RateLimit;
+
+/// Usage summed for one tag value or model
+#[derive(Debug, Clone, Default, PartialEq)]
+struct UsageTotals {
+    requests: u64,
+    input_tokens: u64,
+    output_tokens: u64,
+    total_tokens: u64,
+    cost: f64,
+}
+
+impl UsageTotals {
+    fn add(&mut self, usage: &TokenUsage) {
+        self.requests += 1;
+        self.input_tokens += usage.input_tokens as u64;
+        self.output_tokens += usage.output_tokens as u64;
+        self.total_tokens += usage.total_tokens as u64;
+        self.cost += usage.cost.unwrap_or(0.0);
+    }
+
+    fn merge(&mut self, other: &UsageTotals) {
+        self.requests += other.requests;
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cost += other.cost;
+    }
+
+    fn to_array(&self) -> PhpResult<PhpArray> {
+        let mut arr = PhpArray::new();
+        arr.insert("requests", self.requests as i64)?;
+        arr.insert("input_tokens", self.input_tokens as i64)?;
+        arr.insert("output_tokens", self.output_tokens as i64)?;
+        arr.insert("total_tokens", self.total_tokens as i64)?;
+        arr.insert("cost", self.cost)?;
+        Ok(arr)
+    }
+}
+
+/// Usage per (tag, value), shared by every request served by this process
+static TOTALS: LazyLock<Mutex<BTreeMap<(String, String), UsageTotals>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Usage per model of every call, shared by every request served by this
+/// process
+static MODEL_TOTALS: LazyLock<Mutex<BTreeMap<String, UsageTotals>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+fn add_to_totals(
+    totals: &mut BTreeMap<(String, String), UsageTotals>,
+    tags: &BTreeMap<String, String>,
+    usage: &TokenUsage,
+) {
+    for (tag, value) in tags {
+        totals
+            .entry((tag.clone(), value.clone()))
+            .or_default()
+            .add(usage);
+    }
+}
+
+/// Tags from a ['tag' => 'value', ...] array
+pub(crate) fn parse_tags(tags: &PhpArray) -> PhpResult<BTreeMap<String, String>> {
+    let mut parsed = BTreeMap::new();
+    for (tag, value) in tags.iter() {
+        let tag = tag.to_string();
+        let value = value.string().ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Value of tag '{tag}' must be a string"
+            ))
+        })?;
+        if tag.is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Tag names must not be empty".to_string()));
+        }
+        parsed.insert(tag, value);
+    }
+    Ok(parsed)
+}
+
+/// Who a call's usage is charged to: the tenant's budget, the budget guard,
+/// the client-side rate limit and the tags used for cost attribution
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Attribution {
+    pub tenant: Option<String>,
+    pub tags: BTreeMap<String, String>,
+    pub budget: Option<BudgetGuard>,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Attribution {
+    /// Fail if the tenant has used up its budget or the guard's ceiling is
+    /// reached, then wait for the rate limit to allow the call
+    pub(crate) fn check(&self) -> PhpResult<()> {
+        crate::throttle::check(self.tenant.as_deref())?;
+        if let Some(budget) = &self.budget {
+            budget.check()?;
+        }
+        match &self.rate_limit {
+            Some(rate_limit) => rate_limit.acquire(),
+            None => Ok(()),
+        }
+    }
+
+    /// Charge a finished call's usage to the tenant and the tags and pass it
+    /// to the usage hook. Tagged calls are also logged at 'info' level when a
+    /// logger is set.
+    pub(crate) fn record(&self, model: &str, usage: &TokenUsage) {
+        crate::throttle::record(self.tenant.as_deref(), usage);
+        if let Some(budget) = &self.budget {
+            budget.record(usage);
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.record(usage);
+        }
+        MODEL_TOTALS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(model.to_string())
+            .or_default()
+            .add(usage);
+        crate::usage_export::export(model, usage, self);
+        if self.tags.is_empty() {
+            return;
+        }
+        let mut totals = TOTALS.lock().unwrap_or_else(|e| e.into_inner());
+        add_to_totals(&mut totals, &self.tags, usage);
+        drop(totals);
+
+        let tags: Vec<String> = self.tags.iter().map(|(t, v)| format!("{t}={v}")).collect();
+        crate::logger::info(&format!(
+            "Usage: {} tokens ({} in, {} out), cost {:.6} [{}]",
+            usage.total_tokens,
+            usage.input_tokens,
+            usage.output_tokens,
+            usage.cost.unwrap_or(0.0),
+            tags.join(", ")
+        ));
+    }
+}
+
+/// Usage of tagged calls made by this process, as ['tag' => ['value' =>
+/// ['requests', 'input_tokens', 'output_tokens', 'total_tokens', 'cost']]].
+/// Pass true to reset the totals after reading them.
+#[php_function]
+pub fn llm_usage_by_tag(reset: Option<bool>) -> PhpResult<Zval> {
+    let totals = {
+        let mut totals = TOTALS.lock().unwrap_or_else(|e| e.into_inner());
+        if reset.unwrap_or(false) {
+            std::mem::take(&mut *totals)
+        } else {
+            totals.clone()
+        }
+    };
+
+    let mut by_tag: BTreeMap<String, PhpArray> = BTreeMap::new();
+    for ((tag, value), totals) in &totals {
+        by_tag
+            .entry(tag.clone())
+            .or_insert_with(PhpArray::new)
+            .insert(value.as_str(), totals.to_array()?)?;
+    }
+    let mut arr = PhpArray::new();
+    for (tag, values) in by_tag {
+        arr.insert(tag.as_str(), values)?;
+    }
+    Ok(arr.into_zval(false)?)
+}
+
+/// Sum of the per-model totals
+fn overall(totals: &BTreeMap<String, UsageTotals>) -> UsageTotals {
+    let mut sum = UsageTotals::default();
+    for model in totals.values() {
+        sum.merge(model);
+    }
+    sum
+}
+
+/// Usage of every call made by this process, see LLM::getGlobalUsage()
+pub(crate) fn global_usage(reset: bool) -> PhpResult<Zval> {
+    let totals = {
+        let mut totals = MODEL_TOTALS.lock().unwrap_or_else(|e| e.into_inner());
+        if reset {
+            std::mem::take(&mut *totals)
+        } else {
+            totals.clone()
+        }
+    };
+
+    let mut arr = overall(&totals).to_array()?;
+    let mut models = PhpArray::new();
+    for (model, totals) in &totals {
+        models.insert(model.as_str(), totals.to_array()?)?;
+    }
+    arr.insert("models", models)?;
+    Ok(arr.into_zval(false)?)
+}
+
+pub(crate) fn reset_global_usage() {
+    MODEL_TOTALS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(input: u32, output: u32, cost: Option<f64>) -> TokenUsage {
+        TokenUsage {
+            input_tokens: input as _,
+            output_tokens: output as _,
+            reasoning_tokens: 0,
+            total_tokens: (input + output) as _,
+            cache_read_tokens: 0,
+            cache_write_tokens: 0,
+            cost,
+            request_time_ms: None,
+        }
+    }
+
+    #[test]
+    fn test_totals_per_tag_value() {
+        let checkout: BTreeMap<String, String> = [
+            ("feature".to_string(), "checkout-bot".to_string()),
+            ("team".to_string(), "growth".to_string()),
+        ]
+        .into();
+        let search: BTreeMap<String, String> = [
+            ("feature".to_string(), "search".to_string()),
+            ("team".to_string(), "growth".to_string()),
+        ]
+        .into();
+
+        let mut totals = BTreeMap::new();
+        add_to_totals(&mut totals, &checkout, &usage(100, 20, Some(0.5)));
+        add_to_totals(&mut totals, &checkout, &usage(10, 5, None));
+        add_to_totals(&mut totals, &search, &usage(1, 1, Some(0.25)));
+
+        let key = |t: &str, v: &str| (t.to_string(), v.to_string());
+        let bot = &totals[&key("feature", "checkout-bot")];
+        assert_eq!((bot.requests, bot.total_tokens, bot.cost), (2, 135, 0.5));
+        let growth = &totals[&key("team", "growth")];
+        assert_eq!((growth.requests, growth.input_tokens), (3, 111));
+        assert_eq!(growth.cost, 0.75);
+        assert_eq!(totals.len(), 3);
+    }
+
+    #[test]
+    fn test_overall_sums_models() {
+        let mut totals: BTreeMap<String, UsageTotals> = BTreeMap::new();
+        totals
+            .entry("gpt-4o".to_string())
+            .or_default()
+            .add(&usage(100, 20, Some(0.5)));
+        totals
+            .entry("gpt-4o-mini".to_string())
+            .or_default()
+            .add(&usage(10, 5, Some(0.25)));
+        totals
+            .entry("gpt-4o-mini".to_string())
+            .or_default()
+            .add(&usage(1, 1, None));
+
+        let sum = overall(&totals);
+        assert_eq!(
+            (sum.requests, sum.input_tokens, sum.output_tokens),
+            (3, 111, 26)
+        );
+        assert_eq!((sum.total_tokens, sum.cost), (137, 0.75));
+        assert_eq!(overall(&BTreeMap::new()), UsageTotals::default());
+    }
+}
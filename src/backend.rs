@@ -0,0 +1,164 @@
+use anyhow::Result;
+use ext_php_rs::prelude::*;
+use octolib::llm::{
+    AiProvider, ChatCompletionParams, Message as OctoMessage, ProviderFactory, ProviderResponse,
+};
+use tokio::runtime::Runtime;
+
+use crate::config::ProviderConfig;
+use crate::error::IntoPhpException;
+use crate::params::{ParamPolicy, Penalties, ToolChoice};
+use crate::stream::{StreamParams, StreamTarget};
+
+/// Where the non-streaming requests of one call are sent.
+///
+/// octolib's ChatCompletionParams has no setter for some request parameters,
+/// and its providers send no custom headers. Requests that set such
+/// parameters, or come from an instance with its own organization, headers or
+/// User-Agent suffix, go to the provider's HTTP API directly, the way streams
+/// do. Everything else goes through octolib, with the instance's key and base
+/// URL passed through the environment (see `ProviderConfig::with_credentials`).
+pub(crate) struct Backend {
+    provider: Box<dyn AiProvider>,
+    /// Model name without the provider prefix
+    model: String,
+    direct: Option<StreamTarget>,
+}
+
+impl Backend {
+    /// Pick the backend for a request with `params`. Parameters neither
+    /// backend can send are handled by `policy` and cleared from `params`.
+    pub(crate) fn resolve(
+        rt: &Runtime,
+        full_model: &str,
+        config: &ProviderConfig,
+        policy: ParamPolicy,
+        params: &mut StreamParams,
+    ) -> PhpResult<Self> {
+        let (provider, model) = rt
+            .block_on(async { ProviderFactory::get_provider_for_model(full_model) })
+            .map_err(|e| e.into_php_exception())?;
+
+        // octolib takes the key and base URL from the provider's environment
+        // variables, which only the providers the extension can also reach
+        // directly are known to read; everything else it can't send
+        let options = config.direct_only_options();
+        let credentials = config.credential_options();
+        let needs_direct = !direct_only(params).is_empty() || !options.is_empty();
+        let target = if needs_direct || !credentials.is_empty() {
+            StreamTarget::for_model(full_model, config)
+        } else {
+            None
+        };
+        if target.is_none() && !(options.is_empty() && credentials.is_empty()) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Option(s) {} not supported for model '{full_model}', whose provider \
+                 only reads its connection settings from the environment",
+                [credentials, options].concat().join(", ")
+            )));
+        }
+        let direct = target.filter(|_| needs_direct);
+        match &direct {
+            Some(target) => {
+                if !target.supports_penalties() {
+                    let mut unsupported = params.penalties.set_names();
+                    if params.seed.is_some() {
+                        unsupported.push("seed");
+                    }
+                    policy.enforce(full_model, &unsupported)?;
+                    params.penalties = Penalties::default();
+                    params.seed = None;
+                }
+            }
+            None => {
+                policy.enforce(full_model, &direct_only(params))?;
+                clear_direct_only(params);
+            }
+        }
+
+        Ok(Self {
+            provider,
+            model,
+            direct,
+        })
+    }
+
+    /// Model name without the provider prefix
+    pub(crate) fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// Whether the request can ask for JSON natively
+    pub(crate) fn supports_structured_output(&self) -> bool {
+        match &self.direct {
+            Some(target) => {
+                target.supports_json_mode() && self.provider.supports_structured_output(&self.model)
+            }
+            None => self.provider.supports_structured_output(&self.model),
+        }
+    }
+
+    /// Send one attempt of the request. Calls through octolib are bounded by
+    /// the request timeout, direct ones by the HTTP client's.
+    pub(crate) async fn complete(
+        &self,
+        full_model: &str,
+        messages: &[OctoMessage],
+        params: &StreamParams,
+        top_k: u32,
+        config: &ProviderConfig,
+    ) -> Result<ProviderResponse> {
+        if let Some(target) = &self.direct {
+            let pricing = self.provider.get_model_pricing(&self.model);
+            return crate::stream::complete(target, messages, params, pricing).await;
+        }
+
+        // Retries are the caller's RetryPolicy; octolib's own would multiply
+        // them and run past the per-attempt timeout
+        let mut request = ChatCompletionParams::new(
+            messages,
+            &self.model,
+            params.temperature,
+            params.top_p,
+            top_k,
+            params.max_tokens,
+        )
+        .with_max_retries(0);
+        if !params.tools.is_empty() {
+            request = request.with_tools(params.tools.clone());
+        }
+        if let Some(structured) = &params.structured {
+            request = request.with_structured_output(structured.clone());
+        }
+        let call = config.with_credentials(full_model, self.provider.chat_completion(request));
+        config.with_timeout(full_model, call).await
+    }
+}
+
+/// Parameters that are set but only reach the provider over a direct request
+fn direct_only(params: &StreamParams) -> Vec<&'static str> {
+    let mut names = params.penalties.set_names();
+    if !params.stop.is_empty() {
+        names.push("stop");
+    }
+    if params.seed.is_some() {
+        names.push("seed");
+    }
+    if params.tool_choice != ToolChoice::Auto {
+        names.push("tool_choice");
+    }
+    if params.parallel_tool_calls.is_some() {
+        names.push("parallel_tool_calls");
+    }
+    names
+}
+
+fn clear_direct_only(params: &mut StreamParams) {
+    params.penalties = Penalties::default();
+    params.stop.clear();
+    params.seed = None;
+    params.tool_choice = ToolChoice::Auto;
+    params.parallel_tool_calls = None;
+}
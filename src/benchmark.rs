@@ -0,0 +1,92 @@
+use octolib::llm::TokenUsage;
+use serde_json::{json, Value};
+
+/// A successful request in LLM::benchmark()
+pub(crate) struct Run {
+    pub(crate) latency_ms: f64,
+    pub(crate) usage: Option<TokenUsage>,
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Median of sorted values
+fn median(sorted: &[f64]) -> Option<f64> {
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 0 => Some((sorted[mid - 1] + sorted[mid]) / 2.0),
+        _ => Some(sorted[mid]),
+    }
+}
+
+/// Comparison entry of one model: latency of the successful runs, tokens
+/// and cost averaged per run, and `quality` left null for the caller's own
+/// scoring. Cost is null when the provider reported none.
+pub(crate) fn summary(runs: &[Run], failures: usize) -> Value {
+    let mut latencies: Vec<f64> = runs.iter().map(|run| run.latency_ms).collect();
+    latencies.sort_by(f64::total_cmp);
+    let usages: Vec<&TokenUsage> = runs.iter().filter_map(|run| run.usage.as_ref()).collect();
+    let average = |field: fn(&TokenUsage) -> f64| {
+        mean(&usages.iter().map(|usage| field(usage)).collect::<Vec<_>>())
+    };
+    let costs: Vec<f64> = usages.iter().filter_map(|usage| usage.cost).collect();
+
+    json!({
+        "runs": runs.len(),
+        "failures": failures,
+        "latency_ms": {
+            "min": latencies.first(),
+            "avg": mean(&latencies),
+            "p50": median(&latencies),
+            "max": latencies.last(),
+        },
+        "tokens": {
+            "input": average(|usage| usage.input_tokens as f64),
+            "output": average(|usage| usage.output_tokens as f64),
+        },
+        "cost": mean(&costs),
+        "quality": null,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(latency_ms: f64, output_tokens: u64, cost: Option<f64>) -> Run {
+        let mut usage = crate::stream::token_usage(10, output_tokens);
+        usage.cost = cost;
+        Run {
+            latency_ms,
+            usage: Some(usage),
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let runs = [
+            run(300.0, 20, Some(0.002)),
+            run(100.0, 40, Some(0.004)),
+            run(200.0, 30, None),
+        ];
+        let summary = summary(&runs, 1);
+        assert_eq!(summary["runs"], 3);
+        assert_eq!(summary["failures"], 1);
+        assert_eq!(summary["latency_ms"]["min"], 100.0);
+        assert_eq!(summary["latency_ms"]["p50"], 200.0);
+        assert_eq!(summary["latency_ms"]["max"], 300.0);
+        assert_eq!(summary["tokens"]["output"], 30.0);
+        assert!((summary["cost"].as_f64().unwrap() - 0.003).abs() < 1e-9);
+        assert!(summary["quality"].is_null());
+    }
+
+    #[test]
+    fn test_summary_without_runs() {
+        let summary = summary(&[], 2);
+        assert_eq!(summary["runs"], 0);
+        assert!(summary["latency_ms"]["avg"].is_null());
+        assert!(summary["cost"].is_null());
+    }
+}
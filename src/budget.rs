@@ -0,0 +1,135 @@
+use ext_php_rs::prelude::*;
+use octolib::llm::TokenUsage;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Spending of keyed guards, shared by every request served by this process
+static SPENT: LazyLock<Mutex<HashMap<String, Arc<Mutex<f64>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn lock(spent: &Mutex<f64>) -> std::sync::MutexGuard<'_, f64> {
+    spent.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Cost ceiling for the calls of the LLM instances it is attached to.
+/// Guards created with the same key share their spending across the
+/// process; a guard without a key counts only its own calls.
+#[php_class]
+#[derive(Debug, Clone)]
+pub struct BudgetGuard {
+    max_cost: f64,
+    key: Option<String>,
+    spent: Arc<Mutex<f64>>,
+}
+
+// Internal methods - not exposed to PHP
+impl BudgetGuard {
+    /// Fail once the ceiling has been reached
+    pub(crate) fn check(&self) -> PhpResult<()> {
+        let spent = *lock(&self.spent);
+        if spent < self.max_cost {
+            return Ok(());
+        }
+        let scope = match &self.key {
+            Some(key) => format!("Budget '{key}'"),
+            None => "Budget".to_string(),
+        };
+        Err(PhpException::from_class::<
+            crate::error::LLMBudgetExceededException,
+        >(format!(
+            "{scope} exceeded: spent {spent:.4} of {:.4}",
+            self.max_cost
+        )))
+    }
+
+    /// Add a finished call's cost
+    pub(crate) fn record(&self, usage: &TokenUsage) {
+        if let Some(cost) = usage.cost {
+            *lock(&self.spent) += cost;
+        }
+    }
+}
+
+#[php_impl]
+impl BudgetGuard {
+    /// Guard with a cost ceiling in the provider's currency (USD for the
+    /// built-in pricing). With a key, spending is shared with every guard
+    /// of the same key in this PHP process and kept across requests.
+    #[php(constructor)]
+    pub fn __construct(max_cost: f64, key: Option<String>) -> PhpResult<Self> {
+        if !max_cost.is_finite() || max_cost <= 0.0 {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Maximum cost must be positive".to_string()));
+        }
+        let spent = match &key {
+            Some(key) => {
+                let mut spent = SPENT.lock().unwrap_or_else(|e| e.into_inner());
+                Arc::clone(spent.entry(key.clone()).or_default())
+            }
+            None => Arc::default(),
+        };
+        Ok(Self {
+            max_cost,
+            key,
+            spent,
+        })
+    }
+
+    pub fn get_max_cost(&self) -> f64 {
+        self.max_cost
+    }
+
+    pub fn get_key(&self) -> Option<String> {
+        self.key.clone()
+    }
+
+    /// Cost of the calls counted so far
+    pub fn get_spent(&self) -> f64 {
+        *lock(&self.spent)
+    }
+
+    /// Cost left before calls are rejected; negative after an overdraw
+    pub fn get_remaining(&self) -> f64 {
+        self.max_cost - self.get_spent()
+    }
+
+    /// Whether further calls are rejected
+    pub fn is_exceeded(&self) -> bool {
+        self.get_spent() >= self.max_cost
+    }
+
+    /// Start counting from zero, for every guard sharing the key
+    pub fn reset(&self) {
+        *lock(&self.spent) = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(cost: Option<f64>) -> TokenUsage {
+        let mut usage = crate::stream::token_usage(100, 20);
+        usage.cost = cost;
+        usage
+    }
+
+    #[test]
+    fn test_keyed_guards_share_spending() {
+        let a = BudgetGuard::__construct(1.0, Some("test-shared".to_string())).unwrap();
+        let b = BudgetGuard::__construct(2.0, Some("test-shared".to_string())).unwrap();
+        let own = BudgetGuard::__construct(1.0, None).unwrap();
+
+        a.record(&usage(Some(0.75)));
+        b.record(&usage(Some(0.5)));
+        b.record(&usage(None));
+        assert_eq!(a.get_spent(), 1.25);
+        assert!(a.is_exceeded());
+        assert!(!b.is_exceeded());
+        assert_eq!(own.get_spent(), 0.0);
+
+        a.reset();
+        assert_eq!(b.get_spent(), 0.0);
+    }
+}
@@ -0,0 +1,363 @@
+use ext_php_rs::convert::IntoZvalDyn;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::ZendCallable;
+use octolib::llm::{ImageData, Message as OctoMessage};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::CACHE_DIR_INI;
+
+/// Upper bound on entries per cache; expired entries are evicted first, then
+/// the ones closest to expiry
+const MAX_ENTRIES: usize = 1024;
+
+/// Puts to the file cache between sweeps of its directory
+const SWEEP_INTERVAL: usize = 64;
+
+/// Age after which a temporary entry file is taken as left by a writer that
+/// died before renaming it
+const STALE_TMP: Duration = Duration::from_secs(60);
+
+/// Cache key over everything that determines a response: the model, the
+/// request parameters (provider identity, schema, sampling settings, ...) and
+/// the final messages. A hex SHA-256 digest, as the file and APCu caches are
+/// shared between processes and must not confuse two requests.
+pub(crate) fn request_key(model: &str, params: &[&str], messages: &[OctoMessage]) -> String {
+    let mut hasher = Sha256::new();
+    // Each field is length-prefixed, so no two requests hash the same bytes
+    let mut field = |bytes: &[u8]| {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    };
+    field(model.as_bytes());
+    for param in params {
+        field(param.as_bytes());
+    }
+    for msg in messages {
+        field(msg.role.as_bytes());
+        field(msg.content.as_bytes());
+        field(msg.tool_call_id.as_deref().unwrap_or_default().as_bytes());
+        field(
+            msg.tool_calls
+                .as_ref()
+                .map(|c| c.to_string())
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        for image in msg.images.iter().flatten() {
+            match &image.data {
+                ImageData::Url(url) => field(url.as_bytes()),
+                ImageData::Base64(data) => field(data.as_bytes()),
+            }
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// In-process cache with per-entry TTL, shared by every request served by
+/// this process
+pub(crate) struct MemoryCache<T> {
+    entries: Mutex<HashMap<String, (Instant, T)>>,
+}
+
+impl<T: Clone> MemoryCache<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cached value for `key`, unless it has expired
+    pub(crate) fn get(&self, key: &str) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match entries.get(key) {
+            Some((expires, value)) if *expires > Instant::now() => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn put(&self, key: String, value: T, ttl: Duration) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+            entries.retain(|_, (expires, _)| *expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                if let Some(oldest) = entries
+                    .iter()
+                    .min_by_key(|(_, (expires, _))| *expires)
+                    .map(|(k, _)| k.clone())
+                {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        entries.insert(key, (now + ttl, value));
+    }
+}
+
+/// Serialized responses cached by `LLM::withCache('memory', ...)`
+static MEMORY: LazyLock<MemoryCache<String>> = LazyLock::new(MemoryCache::new);
+
+/// Where `LLM::withCache()` keeps responses
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Backend {
+    /// This process only
+    Memory,
+    /// Shared between the processes of a PHP-FPM pool
+    Apcu,
+    /// Shared between the processes of the same user, one file per entry
+    File(PathBuf),
+}
+
+/// Response cache with a fixed TTL. Entries are JSON strings; the cache is
+/// best effort, so backend failures read as misses and writes are dropped.
+#[derive(Debug, Clone)]
+pub(crate) struct ResponseCache {
+    backend: Backend,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(backend: &str, ttl: Duration) -> PhpResult<Self> {
+        let backend = match backend {
+            "memory" => Backend::Memory,
+            "file" => Backend::File(
+                crate::config::private_dir(CACHE_DIR_INI, "llm-php-ext-cache").map_err(|e| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                        "File cache unavailable: {e}"
+                    ))
+                })?,
+            ),
+            "apcu" if apcu_enabled() => Backend::Apcu,
+            "apcu" => {
+                return Err(PhpException::from_class::<
+                    crate::error::LLMValidationException,
+                >(
+                    "APCu cache needs the apcu extension with apc.enabled (and apc.enable_cli on the CLI)"
+                        .to_string(),
+                ))
+            }
+            other => {
+                return Err(PhpException::from_class::<
+                    crate::error::LLMValidationException,
+                >(format!(
+                    "Unknown cache backend '{other}', expected 'apcu', 'file' or 'memory'"
+                )))
+            }
+        };
+        Ok(Self { backend, ttl })
+    }
+
+    fn apcu_key(key: &str) -> String {
+        format!("llm-php-ext:{key}")
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        match &self.backend {
+            Backend::Memory => MEMORY.get(key),
+            Backend::Apcu => {
+                let key = Self::apcu_key(key);
+                // apcu_fetch() returns false on a miss
+                call_php("apcu_fetch", vec![&key])?.string()
+            }
+            Backend::File(dir) => file_get(&dir.join(format!("{key}.json"))),
+        }
+    }
+
+    pub(crate) fn put(&self, key: String, value: String) {
+        match &self.backend {
+            Backend::Memory => MEMORY.put(key, value, self.ttl),
+            Backend::Apcu => {
+                let key = Self::apcu_key(&key);
+                let ttl = self.ttl.as_secs() as i64;
+                call_php("apcu_store", vec![&key, &value, &ttl]);
+            }
+            Backend::File(dir) => {
+                let _ = file_put(dir, &format!("{key}.json"), &value, self.ttl);
+                if PUTS.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == 0 {
+                    file_sweep(dir, MAX_ENTRIES);
+                }
+            }
+        }
+    }
+}
+
+fn call_php(function: &str, args: Vec<&dyn IntoZvalDyn>) -> Option<ext_php_rs::types::Zval> {
+    ZendCallable::try_from_name(function)
+        .ok()?
+        .try_call(args)
+        .ok()
+}
+
+fn apcu_enabled() -> bool {
+    call_php("apcu_enabled", Vec::new()).and_then(|v| v.bool()) == Some(true)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Puts to the file cache by this process, counted to schedule sweeps
+static PUTS: AtomicUsize = AtomicUsize::new(1);
+
+/// Entry file: the expiry as Unix seconds on the first line, then the value
+fn file_get(path: &Path) -> Option<String> {
+    let data = std::fs::read_to_string(path).ok()?;
+    let (expires, value) = data.split_once('\n')?;
+    if expires.parse::<u64>().ok()? <= unix_now() {
+        let _ = std::fs::remove_file(path);
+        return None;
+    }
+    Some(value.to_string())
+}
+
+/// Write through a temporary file so readers never see a partial entry
+fn file_put(dir: &Path, name: &str, value: &str, ttl: Duration) -> std::io::Result<()> {
+    let tmp = dir.join(format!("{name}.{}.tmp", std::process::id()));
+    std::fs::write(&tmp, format!("{}\n{value}", unix_now() + ttl.as_secs()))?;
+    std::fs::rename(&tmp, dir.join(name))
+}
+
+/// Expiry on the first line of an entry file
+fn file_expiry(path: &Path) -> Option<u64> {
+    let mut line = String::new();
+    std::io::BufReader::new(std::fs::File::open(path).ok()?)
+        .read_line(&mut line)
+        .ok()?;
+    line.trim_end().parse().ok()
+}
+
+/// Delete expired and unreadable entries and stale temporary files, then the
+/// entries closest to expiry until at most `max_entries` are left. Entries
+/// are otherwise only deleted when read after expiring.
+fn file_sweep(dir: &Path, max_entries: usize) {
+    let Ok(files) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let now = unix_now();
+    let mut live = Vec::new();
+    for path in files.flatten().map(|entry| entry.path()) {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => match file_expiry(&path) {
+                Some(expires) if expires > now => live.push((expires, path)),
+                _ => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            },
+            Some("tmp") => {
+                let stale = std::fs::metadata(&path)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|t| t.elapsed().unwrap_or_default() > STALE_TMP);
+                if stale {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+            _ => {}
+        }
+    }
+    if live.len() > max_entries {
+        live.sort_unstable();
+        for (_, path) in &live[..live.len() - max_entries] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_respects_ttl() {
+        let cache = MemoryCache::new();
+        cache.put("a".to_string(), "fresh", Duration::from_secs(60));
+        cache.put("b".to_string(), "stale", Duration::ZERO);
+        assert_eq!(cache.get("a"), Some("fresh"));
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("c"), None);
+    }
+
+    #[test]
+    fn test_put_evicts_when_full() {
+        let cache = MemoryCache::new();
+        for key in 0..MAX_ENTRIES as u64 {
+            cache.put(key.to_string(), key, Duration::from_secs(60 + key));
+        }
+        cache.put("new".to_string(), 0, Duration::from_secs(3600));
+        assert_eq!(cache.get("0"), None);
+        assert_eq!(cache.get("1"), Some(1));
+        assert_eq!(cache.get("new"), Some(0));
+    }
+
+    #[test]
+    fn test_request_key_covers_params() {
+        let a = request_key("openai:gpt-4o", &["{\"type\":\"object\"}"], &[]);
+        let b = request_key("openai:gpt-4o", &["{\"type\":\"array\"}"], &[]);
+        assert_ne!(a, b);
+        assert_eq!(
+            a,
+            request_key("openai:gpt-4o", &["{\"type\":\"object\"}"], &[])
+        );
+        assert_eq!(a.len(), 64);
+        // Field boundaries are part of the key
+        assert_ne!(
+            request_key("openai:gpt-4o", &["ab", "c"], &[]),
+            request_key("openai:gpt-4o", &["a", "bc"], &[])
+        );
+    }
+
+    #[test]
+    fn test_file_entries_expire() {
+        let dir = std::env::temp_dir().join(format!("llm-cache-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        file_put(
+            &dir,
+            "a.json",
+            "{\"content\":\"hi\"}",
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        file_put(&dir, "b.json", "stale", Duration::ZERO).unwrap();
+        assert_eq!(
+            file_get(&dir.join("a.json")).as_deref(),
+            Some("{\"content\":\"hi\"}")
+        );
+        assert_eq!(file_get(&dir.join("b.json")), None);
+        assert!(!dir.join("b.json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_sweep_removes_expired_and_caps() {
+        let dir = std::env::temp_dir().join(format!("llm-sweep-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        file_put(&dir, "stale.json", "x", Duration::ZERO).unwrap();
+        for (name, ttl) in [("a.json", 60), ("b.json", 120), ("c.json", 180)] {
+            file_put(&dir, name, "x", Duration::from_secs(ttl)).unwrap();
+        }
+        std::fs::write(dir.join("broken.json"), "not an entry").unwrap();
+        file_sweep(&dir, 2);
+        let mut left: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["b.json", "c.json"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Plain-text document the model can cite
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Document {
+    pub text: String,
+    pub title: Option<String>,
+    /// Context about the document, passed to the model but never cited
+    pub context: Option<String>,
+}
+
+impl Document {
+    /// Documents from a list of strings or ['text' => ..., 'title' => ...,
+    /// 'context' => ...] arrays
+    pub(crate) fn list_from_array(documents: &PhpArray) -> PhpResult<Vec<Self>> {
+        let invalid = |i: usize| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Document {i} must be a string or an array with a 'text' string"
+            ))
+        };
+        let mut list = Vec::new();
+        for (i, (_, value)) in documents.iter().enumerate() {
+            let document = match (value.string(), value.array()) {
+                (Some(text), _) => Self {
+                    text,
+                    title: None,
+                    context: None,
+                },
+                (None, Some(arr)) => Self {
+                    text: arr
+                        .get("text")
+                        .and_then(|v| v.string())
+                        .ok_or_else(|| invalid(i))?,
+                    title: arr.get("title").and_then(|v| v.string()),
+                    context: arr.get("context").and_then(|v| v.string()),
+                },
+                (None, None) => return Err(invalid(i)),
+            };
+            list.push(document);
+        }
+        Ok(list)
+    }
+
+    /// Anthropic `document` content block with citations enabled
+    pub(crate) fn anthropic_block(&self) -> Value {
+        let mut block = json!({
+            "type": "document",
+            "source": { "type": "text", "media_type": "text/plain", "data": self.text },
+            "citations": { "enabled": true },
+        });
+        if let Some(title) = &self.title {
+            block["title"] = json!(title);
+        }
+        if let Some(context) = &self.context {
+            block["context"] = json!(context);
+        }
+        block
+    }
+}
+
+/// Put document blocks in front of the last user message of an Anthropic
+/// request, where the model reads them as part of the question
+pub(crate) fn attach_documents(messages: &mut [Value], documents: &[Document]) {
+    if documents.is_empty() {
+        return;
+    }
+    let Some(message) = messages.iter_mut().rev().find(|m| m["role"] == "user") else {
+        return;
+    };
+    let mut blocks: Vec<Value> = documents.iter().map(Document::anthropic_block).collect();
+    match message["content"].take() {
+        Value::Array(parts) => blocks.extend(parts),
+        Value::String(text) => blocks.push(json!({ "type": "text", "text": text })),
+        _ => {}
+    }
+    message["content"] = Value::Array(blocks);
+}
+
+/// Passage of a document supporting part of the answer. Document offsets
+/// come from the provider; answer offsets locate the supported text in the
+/// response content. All offsets count characters, end exclusive.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Citation {
+    pub document_index: u64,
+    pub document_title: Option<String>,
+    pub cited_text: String,
+    pub start: u64,
+    pub end: u64,
+    pub answer_start: u64,
+    pub answer_end: u64,
+}
+
+impl Citation {
+    /// Citation from an Anthropic `char_location` citation object; other
+    /// location types only occur with PDF or custom-content documents
+    pub(crate) fn from_anthropic(value: &Value, answer_start: u64) -> Option<Self> {
+        if value["type"] != "char_location" {
+            return None;
+        }
+        Some(Self {
+            document_index: value["document_index"].as_u64()?,
+            document_title: value["document_title"].as_str().map(str::to_string),
+            cited_text: value["cited_text"].as_str().unwrap_or_default().to_string(),
+            start: value["start_char_index"].as_u64()?,
+            end: value["end_char_index"].as_u64()?,
+            answer_start,
+            answer_end: answer_start,
+        })
+    }
+
+    fn to_php(&self) -> PhpResult<PhpArray> {
+        let mut arr = PhpArray::new();
+        arr.insert("document_index", self.document_index as i64)?;
+        arr.insert("document_title", self.document_title.clone())?;
+        arr.insert("cited_text", self.cited_text.clone())?;
+        arr.insert("start", self.start as i64)?;
+        arr.insert("end", self.end as i64)?;
+        arr.insert("answer_start", self.answer_start as i64)?;
+        arr.insert("answer_end", self.answer_end as i64)?;
+        Ok(arr)
+    }
+}
+
+/// Citations as a PHP list of arrays
+pub(crate) fn to_php(citations: &[Citation]) -> PhpResult<Zval> {
+    let mut arr = PhpArray::new();
+    for citation in citations {
+        arr.push(citation.to_php()?)?;
+    }
+    Ok(arr.into_zval(false)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_documents_go_before_last_user_text() {
+        let mut messages = vec![
+            json!({"role": "user", "content": "Hi"}),
+            json!({"role": "assistant", "content": "Hello"}),
+            json!({"role": "user", "content": "What color is the grass?"}),
+        ];
+        let documents = [Document {
+            text: "The grass is green.".to_string(),
+            title: Some("Facts".to_string()),
+            context: None,
+        }];
+        attach_documents(&mut messages, &documents);
+
+        assert_eq!(messages[0]["content"], "Hi");
+        let content = messages[2]["content"].as_array().unwrap();
+        assert_eq!(content[0]["type"], "document");
+        assert_eq!(content[0]["title"], "Facts");
+        assert_eq!(content[0]["citations"]["enabled"], true);
+        assert_eq!(content[1]["text"], "What color is the grass?");
+    }
+
+    #[test]
+    fn test_char_location_citation() {
+        let value = json!({
+            "type": "char_location",
+            "cited_text": "The grass is green.",
+            "document_index": 0,
+            "document_title": "Facts",
+            "start_char_index": 0,
+            "end_char_index": 19
+        });
+        let citation = Citation::from_anthropic(&value, 7).unwrap();
+        assert_eq!(citation.end, 19);
+        assert_eq!(citation.answer_start, 7);
+        assert!(Citation::from_anthropic(&json!({"type": "page_location"}), 0).is_none());
+    }
+}
@@ -0,0 +1,27 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::zend::ClassEntry;
+
+use crate::llm_class::Usage;
+
+/// What every completion result has, so code handling Response,
+/// StructuredResponse and ToolResponse alike can typehint one interface
+#[php_interface]
+#[php(name = "CompletionResultInterface")]
+pub trait CompletionResult {
+    fn get_content(&self) -> String;
+
+    fn get_usage(&self) -> Usage;
+
+    fn get_model(&self) -> String;
+
+    fn get_finish_reason(&self) -> String;
+
+    fn get_response_id(&self) -> Option<String>;
+}
+
+/// CompletionResultInterface, for the classes implementing it. Registered
+/// by the module before its classes.
+pub(crate) fn interface() -> &'static ClassEntry {
+    ClassEntry::try_find("CompletionResultInterface")
+        .expect("CompletionResultInterface is registered before the classes")
+}
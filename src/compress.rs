@@ -0,0 +1,225 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::ZendHashTable as PhpArray;
+use octolib::llm::Message as OctoMessage;
+use std::collections::HashMap;
+
+/// Messages shorter than this are sent as they are unless set otherwise
+pub(crate) const DEFAULT_MIN_CHARS: usize = 1000;
+
+/// Paragraphs shorter than this are kept verbatim: instructions, questions
+/// and headings carry too much per word to prune
+const MIN_BLOCK_CHARS: usize = 200;
+
+/// Lowercase words of a text
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Word counts over the text being compressed; a word's information is
+/// -ln(count / total), so words repeated throughout (function words, the
+/// topic itself) are worth little and rare ones a lot
+struct WordStats {
+    counts: HashMap<String, usize>,
+    total: f64,
+}
+
+impl WordStats {
+    fn new(text: &str) -> Self {
+        let mut counts = HashMap::new();
+        let mut total = 0;
+        for word in words(text) {
+            *counts.entry(word).or_insert(0) += 1;
+            total += 1;
+        }
+        Self {
+            counts,
+            total: total as f64,
+        }
+    }
+
+    /// Mean information of a sentence's words, with numbers counted double
+    /// since figures are rarely redundant
+    fn score(&self, sentence: &str) -> f64 {
+        let mut sum = 0.0;
+        let mut n = 0;
+        for word in words(sentence) {
+            let count = self.counts.get(&word).copied().unwrap_or(1) as f64;
+            let info = -(count / self.total).ln();
+            sum += if word.chars().any(|c| c.is_ascii_digit()) {
+                2.0 * info
+            } else {
+                info
+            };
+            n += 1;
+        }
+        if n == 0 {
+            0.0
+        } else {
+            sum / n as f64
+        }
+    }
+}
+
+/// Sentences of a paragraph: text up to a '.', '!' or '?' followed by
+/// whitespace, or up to a line break
+fn sentences(text: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, n)| n);
+        let end =
+            c == '\n' || (matches!(c, '.' | '!' | '?') && next.is_none_or(char::is_whitespace));
+        if end {
+            out.push(&text[start..i + c.len_utf8()]);
+            start = i + c.len_utf8();
+        }
+    }
+    out.push(&text[start..]);
+    out.into_iter()
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+/// Keep the most informative sentences of a paragraph, in their original
+/// order, within `ratio` of its length. The best sentence is always kept.
+fn compress_block(block: &str, ratio: f64, stats: &WordStats) -> String {
+    let body = block.trim_end();
+    let trail = &block[body.len()..];
+    let sentences = sentences(body);
+
+    let budget = (body.chars().count() as f64 * ratio).ceil() as usize;
+    let mut ranked: Vec<(usize, f64)> = sentences
+        .iter()
+        .enumerate()
+        .map(|(i, s)| (i, stats.score(s)))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut keep = vec![false; sentences.len()];
+    let mut used = 0;
+    for (i, _) in ranked {
+        // Kept sentences are joined with a space
+        let len = sentences[i].chars().count() + usize::from(used > 0);
+        if used > 0 && used + len > budget {
+            continue;
+        }
+        keep[i] = true;
+        used += len;
+    }
+
+    let kept: Vec<&str> = sentences
+        .iter()
+        .zip(keep)
+        .filter_map(|(s, k)| k.then_some(*s))
+        .collect();
+    format!("{}{trail}", kept.join(" "))
+}
+
+/// Compress the long paragraphs of a text to about `ratio` of their length,
+/// leaving short ones untouched
+pub(crate) fn compress_text(text: &str, ratio: f64) -> String {
+    let stats = WordStats::new(text);
+    text.split_inclusive("\n\n")
+        .map(|block| {
+            if block.trim().chars().count() < MIN_BLOCK_CHARS {
+                block.to_string()
+            } else {
+                compress_block(block, ratio, &stats)
+            }
+        })
+        .collect()
+}
+
+/// Heuristic prompt compression in the spirit of LLMLingua: long context
+/// blocks lose their least informative sentences before the request is sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Compression {
+    ratio: f64,
+    min_chars: usize,
+}
+
+impl Compression {
+    /// Compression to `ratio` (0 < ratio < 1) of messages of at least
+    /// 'min_chars' characters; None for a ratio of 1, which disables it
+    pub(crate) fn new(ratio: f64, options: Option<&PhpArray>) -> PhpResult<Option<Self>> {
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Compression ratio must be greater than 0 and at most 1".to_string(),
+            ));
+        }
+        let mut min_chars = DEFAULT_MIN_CHARS;
+        if let Some(value) = options.and_then(|o| o.get("min_chars")) {
+            min_chars = value.long().filter(|n| *n >= 0).ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(
+                    "Option 'min_chars' must be a non-negative integer".to_string(),
+                )
+            })? as usize;
+        }
+        Ok((ratio < 1.0).then_some(Self { ratio, min_chars }))
+    }
+
+    /// Compress long user and system messages in place. Assistant and tool
+    /// messages are left alone: the model reads its own turns and tool
+    /// results as given.
+    pub(crate) fn apply(&self, messages: &mut [OctoMessage]) {
+        for msg in messages
+            .iter_mut()
+            .filter(|m| m.role == "user" || m.role == "system")
+        {
+            if msg.content.chars().count() >= self.min_chars {
+                msg.content = compress_text(&msg.content, self.ratio);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILLER: &str = "It is what it is and that is that. ";
+
+    #[test]
+    fn test_keeps_informative_sentences_in_order() {
+        let block = format!(
+            "Manticore 6.3 added KNN vector search. {}The HNSW index uses 16 links per node.",
+            FILLER.repeat(4)
+        );
+        let out = compress_text(&block, 0.5);
+        assert!(out.chars().count() <= block.chars().count() / 2 + 1);
+        assert_eq!(
+            out,
+            "Manticore 6.3 added KNN vector search. The HNSW index uses 16 links per node."
+        );
+    }
+
+    #[test]
+    fn test_short_paragraphs_stay() {
+        let long = format!("{}Rare zebra fact.", FILLER.repeat(8));
+        let text = format!("Question: what is the zebra fact?\n\n{long}");
+        let out = compress_text(&text, 0.3);
+        assert!(out.starts_with("Question: what is the zebra fact?\n\n"));
+        assert!(out.ends_with("Rare zebra fact."));
+        assert!(out.len() < text.len());
+    }
+
+    #[test]
+    fn test_ratio_one_keeps_everything() {
+        let block = FILLER.repeat(10);
+        assert_eq!(compress_text(&block, 1.0), block);
+    }
+
+    #[test]
+    fn test_sentences_keep_decimals_together() {
+        assert_eq!(
+            sentences("Version 6.3 is out. Really?\nYes"),
+            vec!["Version 6.3 is out.", "Really?", "Yes"]
+        );
+    }
+}
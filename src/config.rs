@@ -0,0 +1,520 @@
+use anyhow::Result;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::ZendHashTable as PhpArray;
+use ext_php_rs::zend::ExecutorGlobals;
+use octolib::errors::ProviderError;
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::llm_class::get_env_prefix;
+
+/// INI directive appended to the User-Agent of direct requests
+pub(crate) const USER_AGENT_INI: &str = "llm.user_agent";
+/// INI directives sent as the `X-App-Name` and `X-App-Version` headers
+pub(crate) const APP_NAME_INI: &str = "llm.app_name";
+pub(crate) const APP_VERSION_INI: &str = "llm.app_version";
+/// INI directive naming the directory of `withCache('file')`
+pub(crate) const CACHE_DIR_INI: &str = "llm.cache_dir";
+/// INI directive naming the directory of the shared rate limit state
+pub(crate) const RATE_LIMIT_DIR_INI: &str = "llm.rate_limit_dir";
+/// INI directive limiting the tool loops running at once in a process
+/// (0 = no limit)
+pub(crate) const MAX_TOOL_LOOPS_INI: &str = "llm.max_tool_loops";
+
+/// Held while the credential variables are read or swapped, so an octolib
+/// call never sees another instance's key or URL
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// Non-empty value of an INI directive
+pub(crate) fn ini(name: &str) -> Option<String> {
+    ExecutorGlobals::get()
+        .ini_values()
+        .get(name)
+        .cloned()
+        .flatten()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Directory for files shared by this user's processes: the `ini_name`
+/// directive when set, otherwise `<name>-<uid>` in PHP's temp directory
+/// (`sys_temp_dir`). It must lie within open_basedir. A missing directory is
+/// created 0700; the one in the temp directory must also be owned by this
+/// user and closed to others, so nobody else can plant or read its files.
+pub(crate) fn private_dir(ini_name: &str, name: &str) -> std::io::Result<PathBuf> {
+    let (dir, shared_parent) = match ini(ini_name) {
+        Some(dir) => (PathBuf::from(dir), false),
+        None => {
+            let temp = ini("sys_temp_dir").map_or_else(std::env::temp_dir, PathBuf::from);
+            (temp.join(owned_name(name)), true)
+        }
+    };
+    let parent = dir.parent().unwrap_or(Path::new("/"));
+    let resolved = std::fs::canonicalize(parent)?.join(dir.file_name().unwrap_or_default());
+    if let Some(basedir) = ini("open_basedir") {
+        if !crate::message::within_open_basedir(&resolved, &basedir) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "'{}' is outside the allowed path(s) of open_basedir",
+                    resolved.display()
+                ),
+            ));
+        }
+    }
+    create_private_dir(&resolved, shared_parent)?;
+    Ok(resolved)
+}
+
+#[cfg(unix)]
+fn owned_name(name: &str) -> String {
+    // SAFETY: geteuid() has no preconditions and cannot fail
+    format!("{name}-{}", unsafe { libc::geteuid() })
+}
+
+#[cfg(not(unix))]
+fn owned_name(name: &str) -> String {
+    name.to_string()
+}
+
+#[cfg(unix)]
+fn create_private_dir(dir: &Path, check_owner: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new()
+        .recursive(!check_owner)
+        .mode(0o700)
+        .create(dir)
+    {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    if check_owner {
+        // Not following links: a planted symlink is refused like a planted directory
+        let meta = std::fs::symlink_metadata(dir)?;
+        // SAFETY: geteuid() has no preconditions and cannot fail
+        let uid = unsafe { libc::geteuid() };
+        if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o077 != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "'{}' is not a directory owned by this user and closed to others",
+                    dir.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path, _check_owner: bool) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)
+}
+
+/// The extension's User-Agent, followed by the deployment's suffix
+fn user_agent(suffix: Option<&str>) -> String {
+    let base = concat!("llm-php-ext/", env!("CARGO_PKG_VERSION"));
+    match suffix {
+        Some(suffix) => format!("{base} {suffix}"),
+        None => base.to_string(),
+    }
+}
+
+/// Provider connection settings passed as constructor options.
+///
+/// octolib reads credentials from `<PROVIDER>_API_KEY` / `<PROVIDER>_API_URL`
+/// only, so an instance's own key and base URL are set in the environment
+/// for the first poll of its octolib call, where the provider reads them,
+/// and restored right after (see `with_credentials`). octolib sends no
+/// custom headers, so requests of an instance with an organization, headers
+/// or a User-Agent suffix are sent directly over HTTP (see Backend), like
+/// streams and embeddings, which use the settings as-is.
+///
+/// octolib manages its own HTTP clients, so its calls are bounded by the
+/// request timeout as a whole and the connect timeout only reaches requests
+/// the extension sends itself. Each octolib call is a single attempt, with
+/// its built-in retries off, so the timeout applies per attempt on both
+/// paths.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProviderConfig {
+    api_key: Option<String>,
+    base_url: Option<String>,
+    organization: Option<String>,
+    headers: Vec<(String, String)>,
+    /// Appended to the User-Agent
+    user_agent: Option<String>,
+    /// `llm.user_agent` and the `llm.app_*` headers, where the options leave
+    /// them unset
+    ini_user_agent: Option<String>,
+    ini_headers: Vec<(String, String)>,
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+}
+
+impl ProviderConfig {
+    /// Settings from the constructor options, completed with the
+    /// deployment's `llm.user_agent`, `llm.app_name` and `llm.app_version`
+    /// INI directives where the options don't set them
+    pub(crate) fn new(options: Option<&PhpArray>) -> PhpResult<Self> {
+        let mut config = match options {
+            Some(options) => Self::from_options(options)?,
+            None => Self::default(),
+        };
+        if config.user_agent.is_none() {
+            config.ini_user_agent = ini(USER_AGENT_INI);
+        }
+        for (name, directive) in [
+            ("X-App-Name", APP_NAME_INI),
+            ("X-App-Version", APP_VERSION_INI),
+        ] {
+            let given = config
+                .headers
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case(name));
+            if let Some(value) = ini(directive).filter(|_| !given) {
+                config.ini_headers.push((name.to_string(), value));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Parse `api_key`, `base_url`, `organization`, `headers` and
+    /// `user_agent` options
+    fn from_options(options: &PhpArray) -> PhpResult<Self> {
+        let string_option = |key: &str| -> PhpResult<Option<String>> {
+            match options.get(key) {
+                None => Ok(None),
+                Some(v) if v.is_null() => Ok(None),
+                Some(v) => v.string().map(Some).ok_or_else(|| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                        "Option '{key}' must be a string"
+                    ))
+                }),
+            }
+        };
+
+        let mut headers = Vec::new();
+        if let Some(value) = options.get("headers") {
+            let arr = value.array().ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(
+                    "Option 'headers' must be an array of name => value".to_string(),
+                )
+            })?;
+            for (name, value) in arr.iter() {
+                let value = value.string().ok_or_else(|| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                        "Header '{name}' must be a string"
+                    ))
+                })?;
+                headers.push((name.to_string(), value));
+            }
+        }
+
+        Ok(Self {
+            api_key: string_option("api_key")?,
+            base_url: string_option("base_url")?,
+            organization: string_option("organization")?,
+            headers,
+            user_agent: string_option("user_agent")?,
+            ini_user_agent: None,
+            ini_headers: Vec::new(),
+            timeout: None,
+            connect_timeout: None,
+        })
+    }
+
+    /// Limit each attempt of a request to `seconds`; zero or less removes
+    /// the limit
+    pub(crate) fn set_timeout(&mut self, seconds: i64) {
+        self.timeout = (seconds > 0).then(|| Duration::from_secs(seconds as u64));
+    }
+
+    /// Limit connection setup to `seconds`; zero or less removes the limit
+    pub(crate) fn set_connect_timeout(&mut self, seconds: i64) {
+        self.connect_timeout = (seconds > 0).then(|| Duration::from_secs(seconds as u64));
+    }
+
+    /// Bound one attempt of an octolib call by the request timeout, failing
+    /// with `ProviderError::TimeoutError` so retry policies treat it as a
+    /// timeout. `call` must not retry by itself, or the limit would cover
+    /// all of its attempts together.
+    pub(crate) async fn with_timeout<T>(
+        &self,
+        model: &str,
+        call: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let Some(timeout) = self.timeout else {
+            return call.await;
+        };
+        tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or_else(|_| {
+                let provider = model.split_once(':').map_or(model, |(p, _)| p).trim();
+                Err(timeout_error(provider).into())
+            })
+    }
+
+    /// Run an octolib call with this instance's key and base URL in the
+    /// provider's environment variables. octolib reads them before its first
+    /// await, so they are only set, under the lock, while the call is first
+    /// polled; calls without their own settings hold the lock too, so they
+    /// don't pick up another instance's.
+    pub(crate) async fn with_credentials<T>(
+        &self,
+        model: &str,
+        call: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let prefix = get_env_prefix(model);
+        let provider = model.split_once(':').map_or(model, |(p, _)| p).trim();
+        let url = self
+            .base_url
+            .as_deref()
+            .map(|base_url| octolib_url(provider, base_url));
+        let overrides = [
+            (format!("{prefix}_API_KEY"), self.api_key.as_deref()),
+            (format!("{prefix}_API_URL"), url.as_deref()),
+        ];
+        let mut call = std::pin::pin!(call);
+        let mut polled = false;
+        std::future::poll_fn(|cx| {
+            if std::mem::replace(&mut polled, true) {
+                return call.as_mut().poll(cx);
+            }
+            let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let _overlay = EnvOverlay::set(&overrides);
+            call.as_mut().poll(cx)
+        })
+        .await
+    }
+
+    /// HTTP client for direct requests. For streams the timeout applies to
+    /// each gap between chunks, so long generations are not cut off. Fails
+    /// when the client can't be set up, e.g. without usable TLS roots.
+    pub(crate) fn http_client(&self, streaming: bool) -> Result<reqwest::Client> {
+        let suffix = self.user_agent.as_ref().or(self.ini_user_agent.as_ref());
+        let mut builder =
+            reqwest::Client::builder().user_agent(user_agent(suffix.map(String::as_str)));
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            builder = if streaming {
+                builder.read_timeout(timeout)
+            } else {
+                builder.timeout(timeout)
+            };
+        }
+        builder
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to create the HTTP client: {e}"))
+    }
+
+    /// Options that octolib cannot take, so requests must be sent directly
+    pub(crate) fn direct_only_options(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.organization.is_some() {
+            names.push("organization");
+        }
+        if !self.headers.is_empty() {
+            names.push("headers");
+        }
+        if self.user_agent.is_some() {
+            names.push("user_agent");
+        }
+        names
+    }
+
+    /// Credential options, which octolib takes from the environment
+    pub(crate) fn credential_options(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.api_key.is_some() {
+            names.push("api_key");
+        }
+        if self.base_url.is_some() {
+            names.push("base_url");
+        }
+        names
+    }
+
+    /// Who requests for `model` are sent as: the base URL and a digest of the
+    /// API key and organization. Part of response cache keys, so instances
+    /// with different accounts or endpoints never share responses.
+    pub(crate) fn identity(&self, model: &str) -> String {
+        let digest = Sha256::new()
+            .chain_update(self.api_key(model))
+            .chain_update(b"\0")
+            .chain_update(self.organization.as_deref().unwrap_or_default())
+            .finalize();
+        let hash: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{} {hash}", self.base_url(model).unwrap_or_default())
+    }
+
+    /// API key for direct HTTP requests, falling back to `<PREFIX>_API_KEY`
+    pub(crate) fn api_key(&self, model: &str) -> String {
+        self.api_key.clone().unwrap_or_else(|| {
+            env_var(&format!("{}_API_KEY", get_env_prefix(model))).unwrap_or_default()
+        })
+    }
+
+    /// Base URL for direct HTTP requests, falling back to `<PREFIX>_API_URL`
+    pub(crate) fn base_url(&self, model: &str) -> Option<String> {
+        self.base_url
+            .clone()
+            .or_else(|| env_var(&format!("{}_API_URL", get_env_prefix(model))))
+            .filter(|u| !u.is_empty())
+    }
+
+    /// Add the organization and custom headers to a direct HTTP request to
+    /// `provider`. The organization is OpenAI's and goes to OpenAI only.
+    pub(crate) fn apply_headers(
+        &self,
+        provider: &str,
+        mut request: reqwest::RequestBuilder,
+    ) -> reqwest::RequestBuilder {
+        if let Some(ref organization) = self.organization {
+            if provider.eq_ignore_ascii_case("openai") {
+                request = request.header("OpenAI-Organization", organization);
+            }
+        }
+        for (name, value) in self.headers.iter().chain(&self.ini_headers) {
+            request = request.header(name, value);
+        }
+        request
+    }
+}
+
+/// Environment variable, read under the lock so a credential swapped in for
+/// an octolib call is never taken as the default
+fn env_var(name: &str) -> Option<String> {
+    let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    std::env::var(name).ok()
+}
+
+/// octolib's `<PROVIDER>_API_URL` is the full endpoint, where the `base_url`
+/// option may stop at the API root: append the path octolib's provider posts
+/// to (OpenAI's Responses API, Anthropic's Messages API, Chat Completions
+/// elsewhere) unless it's already there
+fn octolib_url(provider: &str, base_url: &str) -> String {
+    let base_url = base_url.trim_end_matches('/');
+    let endpoint = match provider.to_lowercase().as_str() {
+        "openai" => "/responses",
+        "anthropic" | "minimax" => "/messages",
+        _ => "/chat/completions",
+    };
+    if ["/responses", "/messages", "/chat/completions"]
+        .iter()
+        .any(|path| base_url.ends_with(path))
+    {
+        base_url.to_string()
+    } else {
+        format!("{base_url}{endpoint}")
+    }
+}
+
+/// Environment variables set for the duration of a borrow and restored to
+/// their previous values on drop
+struct EnvOverlay<'a> {
+    previous: Vec<(&'a str, Option<OsString>)>,
+}
+
+impl<'a> EnvOverlay<'a> {
+    /// Set the variables that have a value; the caller holds `ENV_LOCK`
+    fn set(vars: &'a [(String, Option<&str>)]) -> Self {
+        let previous = vars
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = (*value)?;
+                let previous = std::env::var_os(name);
+                std::env::set_var(name, value);
+                Some((name.as_str(), previous))
+            })
+            .collect();
+        Self { previous }
+    }
+}
+
+impl Drop for EnvOverlay<'_> {
+    fn drop(&mut self) {
+        for (name, previous) in self.previous.drain(..) {
+            match previous {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+        }
+    }
+}
+
+fn timeout_error(provider: &str) -> ProviderError {
+    ProviderError::TimeoutError {
+        provider: provider.to_string(),
+    }
+}
+
+/// Map a failed direct HTTP request to the matching provider error
+pub(crate) fn request_error(provider: &str, err: reqwest::Error) -> ProviderError {
+    if err.is_timeout() {
+        timeout_error(provider)
+    } else {
+        ProviderError::NetworkError(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_octolib_url() {
+        assert_eq!(
+            octolib_url("openai", "https://gateway.example.com/v1/"),
+            "https://gateway.example.com/v1/responses"
+        );
+        assert_eq!(
+            octolib_url("anthropic", "https://gateway.example.com/v1"),
+            "https://gateway.example.com/v1/messages"
+        );
+        assert_eq!(
+            octolib_url("deepseek", "https://gateway.example.com/v1"),
+            "https://gateway.example.com/v1/chat/completions"
+        );
+        assert_eq!(
+            octolib_url("openai", "https://gateway.example.com/v1/chat/completions"),
+            "https://gateway.example.com/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn test_env_overlay_restores() {
+        let vars = [
+            ("LLM_TEST_OVERLAY_SET".to_string(), Some("instance")),
+            ("LLM_TEST_OVERLAY_UNSET".to_string(), Some("instance")),
+            ("LLM_TEST_OVERLAY_NONE".to_string(), None),
+        ];
+        std::env::set_var("LLM_TEST_OVERLAY_SET", "default");
+        std::env::set_var("LLM_TEST_OVERLAY_NONE", "default");
+        {
+            let _overlay = EnvOverlay::set(&vars);
+            assert_eq!(env_var("LLM_TEST_OVERLAY_SET").unwrap(), "instance");
+            assert_eq!(env_var("LLM_TEST_OVERLAY_UNSET").unwrap(), "instance");
+            assert_eq!(env_var("LLM_TEST_OVERLAY_NONE").unwrap(), "default");
+        }
+        assert_eq!(env_var("LLM_TEST_OVERLAY_SET").unwrap(), "default");
+        assert_eq!(env_var("LLM_TEST_OVERLAY_UNSET"), None);
+        assert_eq!(env_var("LLM_TEST_OVERLAY_NONE").unwrap(), "default");
+    }
+
+    #[test]
+    fn test_user_agent_suffix() {
+        let base = format!("llm-php-ext/{}", env!("CARGO_PKG_VERSION"));
+        assert_eq!(user_agent(None), base);
+        assert_eq!(
+            user_agent(Some("billing/1.4.2")),
+            format!("{base} billing/1.4.2")
+        );
+    }
+}
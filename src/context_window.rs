@@ -0,0 +1,162 @@
+use crate::tokenizer::count_tokens;
+use crate::trim;
+use ext_php_rs::prelude::*;
+use octolib::llm::Message as OctoMessage;
+
+/// Default tokens kept free by setMaxTokensAuto() for the tokenizer's
+/// estimation error on models without a published vocabulary
+const DEFAULT_MARGIN: u32 = 256;
+
+/// Context window and output limit of a model family, most specific prefix first
+const LIMITS: &[(&str, u32, u32)] = &[
+    ("gpt-5", 400_000, 128_000),
+    ("gpt-4.1", 1_047_576, 32_768),
+    ("gpt-4o", 128_000, 16_384),
+    ("chatgpt-4o", 128_000, 16_384),
+    ("gpt-4-turbo", 128_000, 4_096),
+    ("gpt-4", 8_192, 8_192),
+    ("gpt-3.5-turbo", 16_385, 4_096),
+    ("o1", 200_000, 100_000),
+    ("o3", 200_000, 100_000),
+    ("o4", 200_000, 100_000),
+    ("claude-opus-4", 200_000, 32_000),
+    ("claude-sonnet-4", 200_000, 64_000),
+    ("claude-3-7-sonnet", 200_000, 64_000),
+    ("claude", 200_000, 8_192),
+    ("gemini-2.5", 1_048_576, 65_536),
+    ("gemini", 1_048_576, 8_192),
+    ("deepseek-reasoner", 128_000, 65_536),
+    ("deepseek", 128_000, 8_192),
+];
+
+/// Context window and maximum output tokens of a "provider:model" string,
+/// None for models not in the table
+pub(crate) fn limits(model: &str) -> Option<(u32, u32)> {
+    let name = model.split_once(':').map_or(model, |(_, m)| m);
+    let name = name.rsplit('/').next().unwrap_or(name).to_lowercase();
+    LIMITS
+        .iter()
+        .find(|(prefix, _, _)| name.starts_with(prefix))
+        .map(|&(_, context, output)| (context, output))
+}
+
+/// Approximate prompt tokens of converted messages plus `extra` text sent
+/// alongside them, such as tool definitions or a schema
+pub(crate) fn prompt_tokens(model: &str, messages: &[OctoMessage], extra: &str) -> usize {
+    let messages: usize = messages
+        .iter()
+        .map(|msg| {
+            count_tokens(&msg.content, model)
+                + msg
+                    .tool_calls
+                    .as_ref()
+                    .map_or(0, |calls| count_tokens(&calls.to_string(), model))
+                + msg.images.as_ref().map_or(0, Vec::len) * trim::IMAGE_TOKENS
+                + trim::MESSAGE_OVERHEAD
+        })
+        .sum();
+    messages + count_tokens(extra, model)
+}
+
+/// max_tokens computed per request from the room the prompt leaves in the
+/// context window, see LLM::setMaxTokensAuto()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AutoMaxTokens {
+    pub margin: u32,
+    /// Window of models missing from the table
+    pub context_window: Option<u32>,
+}
+
+impl AutoMaxTokens {
+    pub(crate) fn new(margin: Option<i64>, context_window: Option<i64>) -> PhpResult<Self> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let margin = match margin {
+            Some(margin) => u32::try_from(margin)
+                .map_err(|_| invalid("Safety margin must not be negative".to_string()))?,
+            None => DEFAULT_MARGIN,
+        };
+        let context_window = match context_window {
+            Some(window) => Some(
+                u32::try_from(window)
+                    .ok()
+                    .filter(|window| *window > margin)
+                    .ok_or_else(|| {
+                        invalid("Context window must be larger than the safety margin".to_string())
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            margin,
+            context_window,
+        })
+    }
+
+    /// Context window minus prompt tokens minus the margin, capped by the
+    /// model's output limit when known
+    pub(crate) fn resolve(
+        &self,
+        model: &str,
+        messages: &[OctoMessage],
+        extra: &str,
+    ) -> PhpResult<u32> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let known = limits(model);
+        let Some(window) = self.context_window.or(known.map(|(context, _)| context)) else {
+            return Err(invalid(format!(
+                "Context window of model '{model}' is unknown, pass it to setMaxTokensAuto()"
+            )));
+        };
+        let prompt = prompt_tokens(model, messages, extra);
+        let available = (window as usize).saturating_sub(prompt + self.margin as usize);
+        if available == 0 {
+            return Err(invalid(format!(
+                "Prompt of about {prompt} tokens leaves no room for a reply in the \
+                 {window}-token context window of '{model}'"
+            )));
+        }
+        let cap = known.map_or(u32::MAX, |(_, output)| output);
+        Ok(u32::try_from(available).unwrap_or(u32::MAX).min(cap))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octolib::llm::MessageBuilder;
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(limits("openai:gpt-4o-mini"), Some((128_000, 16_384)));
+        assert_eq!(limits("openai:gpt-4"), Some((8_192, 8_192)));
+        assert_eq!(limits("openai:gpt-4.1-nano"), Some((1_047_576, 32_768)));
+        assert_eq!(
+            limits("openrouter:anthropic/claude-sonnet-4"),
+            Some((200_000, 64_000))
+        );
+        assert_eq!(limits("ollama:llama3"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let messages = vec![MessageBuilder::user("hello world").build().unwrap()];
+        let auto = AutoMaxTokens {
+            margin: 100,
+            context_window: Some(1000),
+        };
+        let prompt = prompt_tokens("ollama:llama3", &messages, "");
+        assert_eq!(
+            auto.resolve("ollama:llama3", &messages, "").ok(),
+            Some(900 - prompt as u32)
+        );
+        // Capped by the output limit of a known model
+        let auto = AutoMaxTokens {
+            margin: 100,
+            context_window: None,
+        };
+        assert_eq!(
+            auto.resolve("openai:gpt-4o", &messages, "").ok(),
+            Some(16_384)
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use ext_php_rs::convert::{FromZval, IntoZval};
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendCallable, ZendClassObject, Zval};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::llm_class::{Response, LLM};
+use crate::message::{Message, MessageCollection};
+use crate::trim::TrimStrategy;
+
+/// Version of the toJson() format
+const STATE_VERSION: u32 = 1;
+
+/// Saved form of a conversation. The LLM and trimming are configuration,
+/// set again when loading.
+#[derive(Serialize, Deserialize)]
+struct ConversationState {
+    version: u32,
+    system_prompt: Option<String>,
+    history: Value,
+}
+
+/// How the history is kept within bounds before each request
+enum Trimming {
+    None,
+    Tokens {
+        max_tokens: usize,
+        strategy: TrimStrategy,
+    },
+    /// `fn(MessageCollection $history): MessageCollection`
+    Callable(Zval),
+}
+
+/// Chat session owning its history: say() sends a user message with the
+/// history so far and records the reply
+#[php_class]
+pub struct Conversation {
+    llm: LLM,
+    system_prompt: Option<String>,
+    history: MessageCollection,
+    trimming: Trimming,
+}
+
+// Internal methods - not exposed to PHP
+impl Conversation {
+    fn fresh_history(system_prompt: Option<&str>) -> PhpResult<MessageCollection> {
+        let mut history = MessageCollection::__construct(None)?;
+        if let Some(prompt) = system_prompt {
+            history.push(Message::system(prompt.to_string())?);
+        }
+        Ok(history)
+    }
+
+    fn trim(&mut self) -> PhpResult<()> {
+        match &self.trimming {
+            Trimming::None => Ok(()),
+            Trimming::Tokens {
+                max_tokens,
+                strategy,
+            } => self
+                .history
+                .truncate(*max_tokens, *strategy, Some(&self.llm)),
+            Trimming::Callable(trimmer) => {
+                let trimmed = ZendCallable::new(trimmer)?.try_call(vec![&self.history])?;
+                let history = <&MessageCollection>::from_zval(&trimmed).ok_or_else(|| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(
+                        "Conversation trimmer must return a MessageCollection".to_string(),
+                    )
+                })?;
+                self.history = history.clone();
+                Ok(())
+            }
+        }
+    }
+
+    fn request(&mut self) -> PhpResult<Response> {
+        self.trim()?;
+        let messages = self.history.clone().into_zval(false)?;
+        self.llm.complete(&messages)
+    }
+}
+
+#[php_impl]
+impl Conversation {
+    /// Start a conversation with `llm`, optionally under a system prompt
+    #[php(constructor)]
+    pub fn __construct(llm: &LLM, system_prompt: Option<String>) -> PhpResult<Self> {
+        Ok(Self {
+            llm: llm.clone(),
+            history: Self::fresh_history(system_prompt.as_deref())?,
+            system_prompt,
+            trimming: Trimming::None,
+        })
+    }
+
+    /// Send a user message with the history so far and return the reply.
+    /// Both are appended to the history; on failure the history is left as
+    /// it was before the call.
+    pub fn say(&mut self, content: String) -> PhpResult<Response> {
+        let before = self.history.clone();
+        self.history.push(Message::user(content)?);
+        match self.request() {
+            Ok(response) => {
+                self.history
+                    .push(Message::assistant(response.get_content())?);
+                Ok(response)
+            }
+            Err(e) => {
+                self.history = before;
+                Err(e)
+            }
+        }
+    }
+
+    /// Forget the history, keeping the system prompt
+    pub fn reset(&mut self) -> PhpResult<()> {
+        self.history = Self::fresh_history(self.system_prompt.as_deref())?;
+        Ok(())
+    }
+
+    /// Copy of the history, including the system prompt
+    pub fn get_history(&self) -> MessageCollection {
+        self.history.clone()
+    }
+
+    /// Save the system prompt and history, tool calls and results
+    /// included, e.g. to a session between requests
+    pub fn to_json(&self) -> PhpResult<String> {
+        let state = ConversationState {
+            version: STATE_VERSION,
+            system_prompt: self.system_prompt.clone(),
+            history: self.history.json_value(),
+        };
+        serde_json::to_string(&state)
+            .map_err(|e| PhpException::default(format!("Failed to serialize to JSON: {e}")))
+    }
+
+    /// Continue a conversation saved with toJson() using `llm`. Token
+    /// limits and trimmers are not saved; set them again.
+    pub fn from_json(json: String, llm: &LLM) -> PhpResult<Self> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let state: ConversationState = serde_json::from_str(&json)
+            .map_err(|e| invalid(format!("Invalid conversation JSON: {e}")))?;
+        if state.version != STATE_VERSION {
+            return Err(invalid(format!(
+                "Unsupported conversation state version {}",
+                state.version
+            )));
+        }
+        Ok(Self {
+            llm: llm.clone(),
+            system_prompt: state.system_prompt,
+            history: MessageCollection::from_json_value(&state.history)?,
+            trimming: Trimming::None,
+        })
+    }
+
+    /// Trim the history to about `max_tokens` before each request (see
+    /// MessageCollection::truncateToTokens()). Strategy 'drop_oldest'
+    /// (default) or 'summarize', which uses the conversation's LLM.
+    pub fn with_token_limit(
+        self_: &mut ZendClassObject<Conversation>,
+        max_tokens: i64,
+        strategy: Option<String>,
+    ) -> PhpResult<&mut ZendClassObject<Conversation>> {
+        self_.trimming = Trimming::Tokens {
+            max_tokens: max_tokens.max(0) as usize,
+            strategy: TrimStrategy::parse(strategy.as_deref().unwrap_or("drop_oldest"))?,
+        };
+        Ok(self_)
+    }
+
+    /// Trim the history with a callable `fn(MessageCollection $history):
+    /// MessageCollection` before each request, instead of a token limit
+    pub fn with_trimmer<'a>(
+        self_: &'a mut ZendClassObject<Conversation>,
+        trimmer: &Zval,
+    ) -> PhpResult<&'a mut ZendClassObject<Conversation>> {
+        if !trimmer.is_callable() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Conversation trimmer must be callable".to_string()
+            ));
+        }
+        self_.trimming = Trimming::Callable(trimmer.shallow_clone());
+        Ok(self_)
+    }
+}
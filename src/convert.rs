@@ -1,17 +1,20 @@
 use ext_php_rs::convert::{FromZval, IntoZval};
 use ext_php_rs::prelude::*;
 use ext_php_rs::types::{ZendHashTable, Zval};
-use octolib::llm::Message as OctoMessage;
+use octolib::llm::{Message as OctoMessage, MessageBuilder};
 use serde_json::Value;
 
 use crate::message::Message;
 
-/// Convert PHP array or MessageCollection to Vec<octolib::Message>
+/// Convert PHP array, MessageCollection or MessageSnapshot to Vec<octolib::Message>
 pub fn php_to_messages(zval: &Zval) -> Result<Vec<OctoMessage>, PhpException> {
     // Try to get as MessageCollection first
     if let Some(collection) = <&crate::message::MessageCollection>::from_zval(zval) {
         return collection.to_octo();
     }
+    if let Some(snapshot) = <&crate::message::MessageSnapshot>::from_zval(zval) {
+        return snapshot.to_octo();
+    }
 
     // Fall back to array conversion
     if let Some(arr) = zval.array() {
@@ -39,6 +42,29 @@ pub fn php_to_messages(zval: &Zval) -> Result<Vec<OctoMessage>, PhpException> {
     }
 }
 
+/// Put a builder's system prompt in front of the conversation. A leading
+/// system message is merged into it, so providers with a separate system
+/// field (Anthropic) and those taking one system message see the same text.
+pub(crate) fn prepend_system(
+    messages: &mut Vec<OctoMessage>,
+    prompt: Option<&str>,
+) -> PhpResult<()> {
+    let Some(prompt) = prompt else {
+        return Ok(());
+    };
+    if let Some(first) = messages.first_mut().filter(|m| m.role == "system") {
+        first.content = format!("{prompt}\n\n{}", first.content);
+        return Ok(());
+    }
+    let system = MessageBuilder::system(prompt).build().map_err(|e| {
+        PhpException::from_class::<crate::error::LLMValidationException>(format!(
+            "Failed to build message: {e}"
+        ))
+    })?;
+    messages.insert(0, system);
+    Ok(())
+}
+
 /// Convert JSON Value to PHP array recursively
 pub fn json_value_to_php(value: &Value) -> PhpResult<Zval> {
     match value {
@@ -80,3 +106,53 @@ pub fn json_value_to_php(value: &Value) -> PhpResult<Zval> {
         }
     }
 }
+
+/// Recursively convert PHP Zval to serde_json::Value
+pub fn zval_to_json_value(zval: &Zval) -> serde_json::Value {
+    if let Some(s) = zval.string() {
+        serde_json::Value::String(s.to_string())
+    } else if let Some(i) = zval.long() {
+        serde_json::Value::Number(i.into())
+    } else if let Some(f) = zval.double() {
+        serde_json::Number::from_f64(f)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null)
+    } else if let Some(b) = zval.bool() {
+        serde_json::Value::Bool(b)
+    } else if let Some(arr) = zval.array() {
+        // Check if it's an associative array (object) or indexed array
+        let mut is_object = false;
+        for (k, _) in arr.iter() {
+            match k {
+                ext_php_rs::types::ArrayKey::Str(_) | ext_php_rs::types::ArrayKey::String(_) => {
+                    is_object = true;
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        if is_object {
+            // Convert to JSON object
+            let mut map = serde_json::Map::new();
+            for (k, v) in arr.iter() {
+                let key = match k {
+                    ext_php_rs::types::ArrayKey::Str(s) => s.to_string(),
+                    ext_php_rs::types::ArrayKey::String(s) => s,
+                    ext_php_rs::types::ArrayKey::Long(i) => i.to_string(),
+                };
+                map.insert(key, zval_to_json_value(v));
+            }
+            serde_json::Value::Object(map)
+        } else {
+            // Convert to JSON array
+            let mut vec = Vec::new();
+            for (_, v) in arr.iter() {
+                vec.push(zval_to_json_value(v));
+            }
+            serde_json::Value::Array(vec)
+        }
+    } else {
+        serde_json::Value::Null
+    }
+}
@@ -0,0 +1,168 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ArrayKey, ZendHashTable as PhpArray};
+use serde_json::{json, Value};
+
+/// Options for LLM::decide() and classify(), named by `method`: at least
+/// two distinct, non-empty strings
+pub(crate) fn check_options(method: &str, options: &[String]) -> PhpResult<()> {
+    let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+    if options.len() < 2 {
+        return Err(invalid(format!("{method}() needs at least two options")));
+    }
+    for (i, option) in options.iter().enumerate() {
+        if option.trim().is_empty() {
+            return Err(invalid(format!("Option {i} is empty")));
+        }
+        if options[..i].contains(option) {
+            return Err(invalid(format!("Option '{option}' is listed twice")));
+        }
+    }
+    Ok(())
+}
+
+/// Schema allowing exactly one of the options. Native JSON modes enforce the
+/// enum while decoding; prompted JSON is validated against it locally.
+pub(crate) fn schema(options: &[String]) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "choice": { "type": "string", "enum": options },
+        },
+        "required": ["choice"],
+        "additionalProperties": false,
+    })
+}
+
+/// System prompt listing the options
+pub(crate) fn instruction(options: &[String]) -> String {
+    let mut prompt = "Decide which one of the following options applies to the \
+        conversation and reply with it, exactly as written, in the \"choice\" field:"
+        .to_string();
+    for option in options {
+        prompt.push_str(&format!("\n- {option}"));
+    }
+    prompt
+}
+
+/// Labels for LLM::classify(), with their descriptions: a list of labels,
+/// or label => description
+pub(crate) fn labels(labels: &PhpArray) -> PhpResult<Vec<(String, Option<String>)>> {
+    let mut parsed = Vec::new();
+    for (key, value) in labels.iter() {
+        let value = value.string().ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(
+                "Labels and their descriptions must be strings".to_string(),
+            )
+        })?;
+        parsed.push(match key {
+            ArrayKey::Long(_) => (value, None),
+            ArrayKey::String(label) => (label, Some(value)),
+            ArrayKey::Str(label) => (label.to_string(), Some(value)),
+        });
+    }
+    let names: Vec<String> = parsed.iter().map(|(label, _)| label.clone()).collect();
+    check_options("classify", &names)?;
+    Ok(parsed)
+}
+
+/// schema() for classify(), also asking for a confidence from 0 to 1
+pub(crate) fn classify_schema(labels: &[String], confidence: bool) -> Value {
+    let mut schema = schema(labels);
+    if confidence {
+        schema["properties"]["confidence"] =
+            json!({ "type": "number", "minimum": 0, "maximum": 1 });
+        schema["required"] = json!(["choice", "confidence"]);
+    }
+    schema
+}
+
+/// System prompt listing the labels with their descriptions
+pub(crate) fn classify_instruction(
+    labels: &[(String, Option<String>)],
+    confidence: bool,
+) -> String {
+    let mut prompt = "Classify the text with exactly one of the following labels and \
+        reply with it, exactly as written, in the \"choice\" field:"
+        .to_string();
+    for (label, description) in labels {
+        match description {
+            Some(description) => prompt.push_str(&format!("\n- {label}: {description}")),
+            None => prompt.push_str(&format!("\n- {label}")),
+        }
+    }
+    if confidence {
+        prompt.push_str(
+            "\nIn the \"confidence\" field, give how sure you are of the label, \
+             from 0 to 1.",
+        );
+    }
+    prompt
+}
+
+/// Confidence reported with a classification, clamped to 0..1
+pub(crate) fn confidence(structured: &Value) -> f64 {
+    structured["confidence"]
+        .as_f64()
+        .unwrap_or(0.0)
+        .clamp(0.0, 1.0)
+}
+
+/// Index of the chosen option. Exact matches win; otherwise case and
+/// surrounding whitespace are ignored, for providers that don't enforce the
+/// enum strictly.
+pub(crate) fn choice_index(options: &[String], structured: &Value) -> Option<usize> {
+    let choice = structured["choice"].as_str()?;
+    options.iter().position(|o| o == choice).or_else(|| {
+        let choice = choice.trim().to_lowercase();
+        options
+            .iter()
+            .position(|o| o.trim().to_lowercase() == choice)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Vec<String> {
+        vec![
+            "refund".to_string(),
+            "exchange".to_string(),
+            "other".to_string(),
+        ]
+    }
+
+    #[test]
+    fn test_choice_index() {
+        let options = options();
+        assert_eq!(
+            choice_index(&options, &json!({"choice": "exchange"})),
+            Some(1)
+        );
+        assert_eq!(
+            choice_index(&options, &json!({"choice": " Other "})),
+            Some(2)
+        );
+        assert_eq!(choice_index(&options, &json!({"choice": "cancel"})), None);
+        assert_eq!(choice_index(&options, &json!({})), None);
+    }
+
+    #[test]
+    fn test_classify_schema_asks_for_confidence() {
+        let schema = classify_schema(&options(), true);
+        assert_eq!(schema["required"], json!(["choice", "confidence"]));
+        let reply = json!({"choice": "refund", "confidence": 1.4});
+        assert_eq!(confidence(&reply), 1.0);
+        assert!(classify_schema(&options(), false)["properties"]
+            .get("confidence")
+            .is_none());
+    }
+
+    #[test]
+    fn test_schema_enumerates_options() {
+        let schema = schema(&options());
+        assert_eq!(schema["properties"]["choice"]["enum"][2], "other");
+        let reply = crate::json_fallback::parse_reply("{\"choice\": \"cancel\"}", Some(&schema));
+        assert!(reply.is_err());
+    }
+}
@@ -0,0 +1,373 @@
+use ext_php_rs::convert::{FromZval, IntoZval};
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use serde_json::Value;
+
+use crate::convert::json_value_to_php;
+use crate::llm_class::Response;
+use crate::structured_builder::StructuredResponse;
+
+/// Word-level diff operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffOp {
+    Equal,
+    Insert,
+    Delete,
+}
+
+impl DiffOp {
+    fn as_str(self) -> &'static str {
+        match self {
+            DiffOp::Equal => "equal",
+            DiffOp::Insert => "insert",
+            DiffOp::Delete => "delete",
+        }
+    }
+}
+
+/// A change to one field of the structured output
+#[derive(Debug, Clone, PartialEq)]
+struct FieldChange {
+    path: String,
+    change: &'static str,
+    old: Option<Value>,
+    new: Option<Value>,
+}
+
+/// Diff tokens along a longest common subsequence, merging adjacent runs.
+/// Hirschberg's algorithm keeps memory linear in the input length.
+fn diff_tokens(old: &[&str], new: &[&str]) -> Vec<(DiffOp, String)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut edits = Vec::with_capacity(old.len() + new.len());
+    edits.extend(old[..prefix].iter().map(|token| (DiffOp::Equal, *token)));
+    align(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+        &mut edits,
+    );
+    edits.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|token| (DiffOp::Equal, *token)),
+    );
+
+    let mut ops: Vec<(DiffOp, String)> = Vec::new();
+    for (op, token) in edits {
+        match ops.last_mut() {
+            Some((last, text)) if *last == op => {
+                text.push(' ');
+                text.push_str(token);
+            }
+            _ => ops.push((op, token.to_string())),
+        }
+    }
+    ops
+}
+
+/// Append the edits turning `old` into `new`, deletions before insertions
+fn align<'a>(old: &[&'a str], new: &[&'a str], edits: &mut Vec<(DiffOp, &'a str)>) {
+    match old {
+        [] => edits.extend(new.iter().map(|token| (DiffOp::Insert, *token))),
+        [token] => match new.iter().position(|t| t == token) {
+            Some(j) => {
+                edits.extend(new[..j].iter().map(|t| (DiffOp::Insert, *t)));
+                edits.push((DiffOp::Equal, *token));
+                edits.extend(new[j + 1..].iter().map(|t| (DiffOp::Insert, *t)));
+            }
+            None => {
+                edits.push((DiffOp::Delete, *token));
+                edits.extend(new.iter().map(|t| (DiffOp::Insert, *t)));
+            }
+        },
+        _ if new.is_empty() => edits.extend(old.iter().map(|token| (DiffOp::Delete, *token))),
+        _ => {
+            // Split `new` where the LCS of the two halves of `old` meet
+            let mid = old.len() / 2;
+            let forward = lcs_lengths(old[..mid].iter(), new.iter());
+            let backward = lcs_lengths(old[mid..].iter().rev(), new.iter().rev());
+            let split = (0..=new.len())
+                .max_by_key(|&j| (forward[j] + backward[new.len() - j], std::cmp::Reverse(j)))
+                .unwrap_or(0);
+            align(&old[..mid], &new[..split], edits);
+            align(&old[mid..], &new[split..], edits);
+        }
+    }
+}
+
+/// LCS lengths of all of `old` against each prefix of `new`, in one row
+fn lcs_lengths<'a, 'b>(
+    old: impl Iterator<Item = &'a &'b str>,
+    new: impl Iterator<Item = &'a &'b str> + Clone,
+) -> Vec<u32>
+where
+    'b: 'a,
+{
+    let len = new.clone().count();
+    let mut row = vec![0u32; len + 1];
+    for a in old {
+        let mut diagonal = 0;
+        for (j, b) in new.clone().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if a == b {
+                diagonal + 1
+            } else {
+                above.max(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row
+}
+
+/// Recursively collect field-level changes between two JSON values
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<FieldChange>) {
+    let child = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+
+    match (old, new) {
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, old_val) in a {
+                match b.get(key) {
+                    Some(new_val) => diff_values(&child(key), old_val, new_val, changes),
+                    None => changes.push(FieldChange {
+                        path: child(key),
+                        change: "removed",
+                        old: Some(old_val.clone()),
+                        new: None,
+                    }),
+                }
+            }
+            for (key, new_val) in b {
+                if !a.contains_key(key) {
+                    changes.push(FieldChange {
+                        path: child(key),
+                        change: "added",
+                        old: None,
+                        new: Some(new_val.clone()),
+                    });
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for idx in 0..a.len().max(b.len()) {
+                let item_path = format!("{path}[{idx}]");
+                match (a.get(idx), b.get(idx)) {
+                    (Some(x), Some(y)) => diff_values(&item_path, x, y, changes),
+                    (Some(x), None) => changes.push(FieldChange {
+                        path: item_path,
+                        change: "removed",
+                        old: Some(x.clone()),
+                        new: None,
+                    }),
+                    (None, Some(y)) => changes.push(FieldChange {
+                        path: item_path,
+                        change: "added",
+                        old: None,
+                        new: Some(y.clone()),
+                    }),
+                    (None, None) => {}
+                }
+            }
+        }
+        _ if old != new => changes.push(FieldChange {
+            path: path.to_string(),
+            change: "changed",
+            old: Some(old.clone()),
+            new: Some(new.clone()),
+        }),
+        _ => {}
+    }
+}
+
+/// Content and structured output of either response type
+fn diff_input(zval: &Zval) -> PhpResult<(String, Option<Value>)> {
+    if let Some(response) = <&Response>::from_zval(zval) {
+        return Ok((response.get_content(), None));
+    }
+    if let Some(response) = <&StructuredResponse>::from_zval(zval) {
+        return Ok((response.get_content(), Some(response.structured_value())));
+    }
+    Err(PhpException::from_class::<
+        crate::error::LLMValidationException,
+    >(
+        "Expected a Response or StructuredResponse".to_string()
+    ))
+}
+
+/// Comparison of two responses, for evals and prompt regression review
+#[php_class]
+pub struct ResponseDiff {
+    content_ops: Vec<(DiffOp, String)>,
+    field_changes: Vec<FieldChange>,
+    similarity: f64,
+}
+
+#[php_impl]
+impl ResponseDiff {
+    /// Compare two Response or StructuredResponse objects
+    pub fn compare(old: &Zval, new: &Zval) -> PhpResult<Self> {
+        let (old_content, old_structured) = diff_input(old)?;
+        let (new_content, new_structured) = diff_input(new)?;
+
+        let old_tokens: Vec<&str> = old_content.split_whitespace().collect();
+        let new_tokens: Vec<&str> = new_content.split_whitespace().collect();
+        let content_ops = diff_tokens(&old_tokens, &new_tokens);
+
+        let equal: usize = content_ops
+            .iter()
+            .filter(|(op, _)| *op == DiffOp::Equal)
+            .map(|(_, text)| text.split(' ').count())
+            .sum();
+        let total = old_tokens.len() + new_tokens.len();
+        let similarity = if total == 0 {
+            1.0
+        } else {
+            (2 * equal) as f64 / total as f64
+        };
+
+        let mut field_changes = Vec::new();
+        diff_values(
+            "",
+            old_structured.as_ref().unwrap_or(&Value::Null),
+            new_structured.as_ref().unwrap_or(&Value::Null),
+            &mut field_changes,
+        );
+
+        Ok(Self {
+            content_ops,
+            field_changes,
+            similarity,
+        })
+    }
+
+    /// Whether content and structured output are identical
+    pub fn is_identical(&self) -> bool {
+        self.field_changes.is_empty() && self.content_ops.iter().all(|(op, _)| *op == DiffOp::Equal)
+    }
+
+    /// Word-level similarity of the content, from 0.0 to 1.0
+    pub fn get_similarity(&self) -> f64 {
+        self.similarity
+    }
+
+    /// Content diff as a list of ['op' => 'equal'|'insert'|'delete', 'text' => string]
+    pub fn get_content_diff(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        for (op, text) in &self.content_ops {
+            let mut entry = PhpArray::new();
+            entry.insert("op", op.as_str())?;
+            entry.insert("text", text.as_str())?;
+            arr.push(entry)?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
+    /// Structured field changes as a list of
+    /// ['path' => string, 'change' => 'added'|'removed'|'changed', 'old' => mixed, 'new' => mixed]
+    pub fn get_field_changes(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        for change in &self.field_changes {
+            let mut entry = PhpArray::new();
+            entry.insert("path", change.path.as_str())?;
+            entry.insert("change", change.change)?;
+            entry.insert(
+                "old",
+                json_value_to_php(change.old.as_ref().unwrap_or(&Value::Null))?,
+            )?;
+            entry.insert(
+                "new",
+                json_value_to_php(change.new.as_ref().unwrap_or(&Value::Null))?,
+            )?;
+            arr.push(entry)?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        arr.insert("identical", self.is_identical())?;
+        arr.insert("similarity", self.similarity)?;
+        arr.insert("content_diff", self.get_content_diff()?)?;
+        arr.insert("field_changes", self.get_field_changes()?)?;
+        Ok(arr.into_zval(false)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_diff_tokens() {
+        let old: Vec<&str> = "the quick brown fox".split_whitespace().collect();
+        let new: Vec<&str> = "the slow brown fox jumps".split_whitespace().collect();
+        assert_eq!(
+            diff_tokens(&old, &new),
+            vec![
+                (DiffOp::Equal, "the".to_string()),
+                (DiffOp::Delete, "quick".to_string()),
+                (DiffOp::Insert, "slow".to_string()),
+                (DiffOp::Equal, "brown fox".to_string()),
+                (DiffOp::Insert, "jumps".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_tokens_reorder() {
+        let old: Vec<&str> = "a b c d e f".split_whitespace().collect();
+        let new: Vec<&str> = "x b d c f y".split_whitespace().collect();
+        let ops = diff_tokens(&old, &new);
+
+        // Equal runs form a longest common subsequence (b c f or b d f)
+        let equal = ops
+            .iter()
+            .filter(|(op, _)| *op == DiffOp::Equal)
+            .map(|(_, text)| text.split(' ').count())
+            .sum::<usize>();
+        assert_eq!(equal, 3);
+
+        let replay = |keep: DiffOp| -> Vec<&str> {
+            ops.iter()
+                .filter(|(op, _)| *op == DiffOp::Equal || *op == keep)
+                .flat_map(|(_, text)| text.split(' '))
+                .collect()
+        };
+        assert_eq!(replay(DiffOp::Delete), old);
+        assert_eq!(replay(DiffOp::Insert), new);
+    }
+
+    #[test]
+    fn test_diff_values() {
+        let old = json!({"name": "Ann", "tags": ["a", "b"], "age": 30});
+        let new = json!({"name": "Ann", "tags": ["a"], "city": "Oslo", "age": 31});
+        let mut changes = Vec::new();
+        diff_values("", &old, &new, &mut changes);
+
+        let summary: Vec<(&str, &str)> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.change))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("age", "changed"),
+                ("tags[1]", "removed"),
+                ("city", "added")
+            ]
+        );
+    }
+}
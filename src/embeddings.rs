@@ -0,0 +1,652 @@
+use anyhow::Result;
+use base64::Engine;
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendClassObject, ZendHashTable as PhpArray, Zval};
+use octolib::errors::ProviderError;
+use octolib::llm::TokenUsage;
+use serde_json::{json, Value};
+
+use crate::config::{request_error, ProviderConfig};
+use crate::error::{IntoPhpException, RequestId};
+use crate::llm_class::{get_env_prefix, Usage};
+use crate::runtime::runtime;
+
+/// Whether the texts are search queries or documents being indexed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputType {
+    Query,
+    Document,
+}
+
+impl InputType {
+    fn parse(input_type: &str) -> PhpResult<Self> {
+        match input_type {
+            "query" => Ok(Self::Query),
+            "document" => Ok(Self::Document),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown input type '{other}', expected 'query' or 'document'"
+            ))),
+        }
+    }
+}
+
+/// How vectors are handed back to PHP
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum VectorFormat {
+    /// Arrays of floats
+    #[default]
+    Float,
+    /// Binary strings of little-endian float32, as produced by `pack('g*', ...)`
+    Float32,
+    /// Base64 of the float32 bytes
+    Base64,
+}
+
+impl VectorFormat {
+    fn parse(format: &str) -> PhpResult<Self> {
+        match format {
+            "float" => Ok(Self::Float),
+            "float32" => Ok(Self::Float32),
+            "base64" => Ok(Self::Base64),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown output format '{other}', expected 'float', 'float32' or 'base64'"
+            ))),
+        }
+    }
+
+    /// Convert one vector to its PHP value
+    fn to_zval(self, vector: &[f32]) -> PhpResult<Zval> {
+        match self {
+            Self::Float => Ok(vector
+                .iter()
+                .map(|x| *x as f64)
+                .collect::<Vec<f64>>()
+                .into_zval(false)?),
+            Self::Float32 => {
+                let mut zval = Zval::new();
+                zval.set_binary(pack_f32(vector));
+                Ok(zval)
+            }
+            Self::Base64 => Ok(base64::engine::general_purpose::STANDARD
+                .encode(pack_f32(vector))
+                .into_zval(false)?),
+        }
+    }
+}
+
+/// Little-endian float32 bytes of a vector
+fn pack_f32(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+/// Decode a base64 `encoding_format` embedding back into floats
+fn unpack_base64_f32(encoded: &str) -> Result<Vec<f32>> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    if bytes.len() % 4 != 0 {
+        anyhow::bail!("Base64 embedding is not a whole number of float32 values");
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+/// Request options applied on top of the model
+#[derive(Debug, Clone, Default)]
+struct EmbedOptions {
+    dimensions: Option<u32>,
+    input_type: Option<InputType>,
+    normalize: bool,
+    format: VectorFormat,
+}
+
+/// Resolved `/embeddings` endpoint for a "provider:model" string.
+///
+/// All supported providers speak the OpenAI embeddings wire format, so requests
+/// go straight to their HTTP API using the client's provider config, with the
+/// same `<PROVIDER>_API_KEY` and `<PROVIDER>_API_URL` fallbacks as completions.
+struct EmbeddingTarget {
+    url: String,
+    api_key: String,
+    model: String,
+    provider: String,
+    config: ProviderConfig,
+}
+
+impl EmbeddingTarget {
+    fn for_model(model: &str, config: &ProviderConfig) -> Result<Self, PhpException> {
+        let unsupported = || {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Embeddings not supported for model '{model}'"
+            ))
+        };
+
+        let (provider, model_name) = model.split_once(':').ok_or_else(unsupported)?;
+        let provider = provider.trim().to_lowercase();
+        let prefix = get_env_prefix(model);
+
+        let default_url = match provider.as_str() {
+            "openai" => "https://api.openai.com/v1",
+            "openrouter" => "https://openrouter.ai/api/v1",
+            "jina" => "https://api.jina.ai/v1",
+            "voyage" => "https://api.voyageai.com/v1",
+            "mistral" => "https://api.mistral.ai/v1",
+            "ollama" => "http://localhost:11434/v1",
+            "local" => "",
+            _ => return Err(unsupported()),
+        };
+
+        let base_url = config
+            .base_url(model)
+            .unwrap_or_else(|| default_url.to_string());
+        if base_url.is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "{prefix}_API_URL or the 'base_url' option must be set for provider '{provider}'"
+            )));
+        }
+
+        let base_url = base_url.trim_end_matches('/');
+        let url = if base_url.ends_with("/embeddings") {
+            base_url.to_string()
+        } else {
+            format!("{base_url}/embeddings")
+        };
+
+        Ok(Self {
+            url,
+            api_key: config.api_key(model),
+            model: model_name.trim().to_string(),
+            provider,
+            config: config.clone(),
+        })
+    }
+
+    /// Request body with the provider's spelling of dimensions and input type.
+    /// Providers without an input type parameter simply don't get one.
+    fn request_body(&self, texts: &[String], options: &EmbedOptions) -> Value {
+        let mut body = json!({
+            "model": self.model,
+            "input": texts,
+        });
+
+        if let Some(dimensions) = options.dimensions {
+            let key = match self.provider.as_str() {
+                "voyage" | "mistral" => "output_dimension",
+                _ => "dimensions",
+            };
+            body[key] = json!(dimensions);
+        }
+
+        if let Some(input_type) = options.input_type {
+            match self.provider.as_str() {
+                "voyage" => {
+                    body["input_type"] = json!(match input_type {
+                        InputType::Query => "query",
+                        InputType::Document => "document",
+                    });
+                }
+                "jina" => {
+                    body["task"] = json!(match input_type {
+                        InputType::Query => "retrieval.query",
+                        InputType::Document => "retrieval.passage",
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // OpenAI can send vectors as base64 float32, a fraction of the JSON size
+        if options.format != VectorFormat::Float && self.provider == "openai" {
+            body["encoding_format"] = json!("base64");
+        }
+
+        body
+    }
+
+    async fn embed(
+        &self,
+        texts: &[String],
+        options: &EmbedOptions,
+    ) -> Result<(Vec<Vec<f32>>, u64)> {
+        let body = self.request_body(texts, options);
+
+        let request = self
+            .config
+            .http_client(false)?
+            .post(&self.url)
+            .bearer_auth(&self.api_key)
+            .json(&body);
+        let response = self
+            .config
+            .apply_headers(&self.provider, request)
+            .send()
+            .await
+            .map_err(|e| request_error(&self.provider, e))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let request_id = RequestId::from_headers(response.headers());
+            let message = response.text().await.unwrap_or_default();
+            let err = anyhow::Error::from(ProviderError::ApiError {
+                provider: self.provider.clone(),
+                status: status.as_u16(),
+                message,
+            });
+            return Err(match request_id {
+                Some(id) => err.context(id),
+                None => err,
+            });
+        }
+
+        let value: Value = response
+            .json()
+            .await
+            .map_err(|e| request_error(&self.provider, e))?;
+        parse_embeddings_response(&value)
+    }
+}
+
+/// Truncate vectors longer than the requested dimensions (Matryoshka models
+/// whose provider ignores the parameter), then L2-normalize if asked to.
+/// Truncated vectors are always re-normalized, since truncation breaks unit length.
+fn postprocess(vectors: &mut [Vec<f32>], options: &EmbedOptions) {
+    for vector in vectors.iter_mut() {
+        let mut normalize = options.normalize;
+        if let Some(dimensions) = options.dimensions {
+            if vector.len() > dimensions as usize {
+                vector.truncate(dimensions as usize);
+                normalize = true;
+            }
+        }
+
+        if normalize {
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                vector.iter_mut().for_each(|x| *x /= norm);
+            }
+        }
+    }
+}
+
+/// Default embedding model for the provider of a "provider:model" string
+pub(crate) fn default_model_for(model: &str) -> Option<String> {
+    let provider = model.split_once(':').map_or(model, |(p, _)| p).trim();
+    let name = match provider.to_lowercase().as_str() {
+        "openai" => "text-embedding-3-small",
+        "jina" => "jina-embeddings-v3",
+        "voyage" => "voyage-3",
+        "mistral" => "mistral-embed",
+        _ => return None,
+    };
+    Some(format!("{provider}:{name}"))
+}
+
+/// Fail unless embeddings are supported for a "provider:model" string
+pub(crate) fn check_model(model: &str, config: &ProviderConfig) -> PhpResult<()> {
+    EmbeddingTarget::for_model(model, config).map(|_| ())
+}
+
+/// Embed one text as a unit vector, for callers inside the extension
+pub(crate) fn embed_text(model: &str, config: &ProviderConfig, text: &str) -> PhpResult<Vec<f32>> {
+    embed_texts(model, config, &[text.to_string()])?
+        .pop()
+        .ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMException>(
+                "Provider returned no embedding".to_string(),
+            )
+        })
+}
+
+/// Embed texts as unit vectors in one request, for callers inside the
+/// extension
+pub(crate) fn embed_texts(
+    model: &str,
+    config: &ProviderConfig,
+    texts: &[String],
+) -> PhpResult<Vec<Vec<f32>>> {
+    let target = EmbeddingTarget::for_model(model, config)?;
+    let options = EmbedOptions {
+        normalize: true,
+        ..EmbedOptions::default()
+    };
+    let (mut vectors, _) = runtime()?
+        .block_on(target.embed(texts, &options))
+        .map_err(|e| e.into_php_exception())?;
+    postprocess(&mut vectors, &options);
+    if vectors.len() != texts.len() {
+        return Err(PhpException::from_class::<crate::error::LLMException>(
+            format!(
+                "Provider returned {} embeddings for {} texts",
+                vectors.len(),
+                texts.len()
+            ),
+        ));
+    }
+    Ok(vectors)
+}
+
+/// Extract vectors (ordered by `index`) and prompt token count
+fn parse_embeddings_response(value: &Value) -> Result<(Vec<Vec<f32>>, u64)> {
+    let data = value["data"]
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("Embeddings response has no 'data' array"))?;
+
+    let mut indexed: Vec<(u64, Vec<f32>)> = data
+        .iter()
+        .enumerate()
+        .map(|(pos, item)| {
+            let index = item["index"].as_u64().unwrap_or(pos as u64);
+            let vector = match &item["embedding"] {
+                Value::String(encoded) => unpack_base64_f32(encoded)?,
+                Value::Array(v) => v
+                    .iter()
+                    .filter_map(|x| x.as_f64())
+                    .map(|x| x as f32)
+                    .collect(),
+                _ => Vec::new(),
+            };
+            Ok((index, vector))
+        })
+        .collect::<Result<_>>()?;
+    indexed.sort_by_key(|(index, _)| *index);
+
+    let tokens = value["usage"]["prompt_tokens"]
+        .as_u64()
+        .or_else(|| value["usage"]["total_tokens"].as_u64())
+        .unwrap_or(0);
+
+    Ok((indexed.into_iter().map(|(_, v)| v).collect(), tokens))
+}
+
+/// Embeddings client
+#[php_class]
+#[derive(Clone)]
+pub struct Embeddings {
+    model: String,
+    config: ProviderConfig,
+    options: EmbedOptions,
+}
+
+// Internal constructor - not exposed to PHP
+impl Embeddings {
+    pub(crate) fn new(model: String, config: ProviderConfig) -> Self {
+        Self {
+            model,
+            config,
+            options: EmbedOptions::default(),
+        }
+    }
+
+    /// Vectors for `texts`, hinting `input_type` unless the client set one,
+    /// for callers inside the extension
+    pub(crate) fn vectors(
+        &self,
+        texts: &[String],
+        input_type: InputType,
+    ) -> PhpResult<Vec<Vec<f32>>> {
+        let options = EmbedOptions {
+            input_type: self.options.input_type.or(Some(input_type)),
+            ..self.options.clone()
+        };
+        self.request(texts, &options).map(|(vectors, _, _)| vectors)
+    }
+
+    /// Embed `texts` and check the provider returned one vector per text.
+    /// Returns the vectors, prompt tokens and the model name.
+    fn request(
+        &self,
+        texts: &[String],
+        options: &EmbedOptions,
+    ) -> PhpResult<(Vec<Vec<f32>>, u64, String)> {
+        let target = EmbeddingTarget::for_model(&self.model, &self.config)?;
+
+        let (mut vectors, tokens) = runtime()?
+            .block_on(target.embed(texts, options))
+            .map_err(|e| e.into_php_exception())?;
+
+        if vectors.len() != texts.len() {
+            return Err(PhpException::from_class::<crate::error::LLMException>(
+                format!(
+                    "Expected {} embeddings, provider returned {}",
+                    texts.len(),
+                    vectors.len()
+                ),
+            ));
+        }
+
+        postprocess(&mut vectors, options);
+        Ok((vectors, tokens, target.model))
+    }
+}
+
+#[php_impl]
+impl Embeddings {
+    /// Create an embeddings client for a "provider:model" string
+    #[php(constructor)]
+    pub fn __construct(model: String, options: Option<&PhpArray>) -> PhpResult<Self> {
+        let config = ProviderConfig::new(options)?;
+
+        Ok(Self::new(model, config))
+    }
+
+    /// Embed a single text
+    pub fn embed(&self, text: String) -> PhpResult<EmbeddingResponse> {
+        self.embed_batch(vec![text])
+    }
+
+    /// Embed several texts in one request
+    pub fn embed_batch(&self, texts: Vec<String>) -> PhpResult<EmbeddingResponse> {
+        let (vectors, tokens, model) = self.request(&texts, &self.options)?;
+
+        Ok(EmbeddingResponse {
+            embeddings: vectors,
+            usage: Usage::from_octo(TokenUsage {
+                input_tokens: tokens as _,
+                output_tokens: 0,
+                reasoning_tokens: 0,
+                total_tokens: tokens as _,
+                cache_read_tokens: 0,
+                cache_write_tokens: 0,
+                cost: None,
+                request_time_ms: None,
+            }),
+            model,
+            format: self.options.format,
+        })
+    }
+
+    pub fn get_model(&self) -> String {
+        self.model.clone()
+    }
+
+    /// Request vectors of this many dimensions. Vectors the provider returns
+    /// longer are truncated and re-normalized. Pass null to use the model default.
+    pub fn set_dimensions(
+        self_: &mut ZendClassObject<Embeddings>,
+        dimensions: Option<i64>,
+    ) -> PhpResult<&mut ZendClassObject<Embeddings>> {
+        if dimensions.is_some_and(|d| d <= 0) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Dimensions must be positive".to_string()));
+        }
+        self_.options.dimensions = dimensions.map(|d| d as u32);
+        Ok(self_)
+    }
+
+    /// Hint whether texts are search queries or documents ('query' or
+    /// 'document'), for providers with asymmetric embeddings (Voyage, Jina).
+    /// Pass null to remove the hint.
+    pub fn set_input_type(
+        self_: &mut ZendClassObject<Embeddings>,
+        input_type: Option<String>,
+    ) -> PhpResult<&mut ZendClassObject<Embeddings>> {
+        self_.options.input_type = input_type.as_deref().map(InputType::parse).transpose()?;
+        Ok(self_)
+    }
+
+    /// L2-normalize returned vectors to unit length
+    pub fn set_normalize(
+        self_: &mut ZendClassObject<Embeddings>,
+        normalize: bool,
+    ) -> &mut ZendClassObject<Embeddings> {
+        self_.options.normalize = normalize;
+        self_
+    }
+
+    /// Return vectors as 'float' arrays (default), 'float32' packed binary
+    /// strings (little-endian, same as `pack('g*', ...)`) or 'base64' of those
+    /// bytes. Packed formats skip building PHP arrays entirely.
+    pub fn set_output_format(
+        self_: &mut ZendClassObject<Embeddings>,
+        format: String,
+    ) -> PhpResult<&mut ZendClassObject<Embeddings>> {
+        self_.options.format = VectorFormat::parse(&format)?;
+        Ok(self_)
+    }
+}
+
+/// Embedding vectors with token usage
+#[php_class]
+pub struct EmbeddingResponse {
+    embeddings: Vec<Vec<f32>>,
+    usage: Usage,
+    model: String,
+    format: VectorFormat,
+}
+
+#[php_impl]
+impl EmbeddingResponse {
+    /// First embedding vector, in the client's output format
+    pub fn get_embedding(&self) -> PhpResult<Zval> {
+        let first = self.embeddings.first().map_or(&[][..], |v| v.as_slice());
+        self.format.to_zval(first)
+    }
+
+    /// All embedding vectors in input order, in the client's output format
+    pub fn get_embeddings(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        for vector in &self.embeddings {
+            arr.push(self.format.to_zval(vector)?)?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
+    /// Name of the output format: 'float', 'float32' or 'base64'
+    pub fn get_format(&self) -> String {
+        match self.format {
+            VectorFormat::Float => "float",
+            VectorFormat::Float32 => "float32",
+            VectorFormat::Base64 => "base64",
+        }
+        .to_string()
+    }
+
+    pub fn get_dimensions(&self) -> i64 {
+        self.embeddings.first().map_or(0, |v| v.len() as i64)
+    }
+
+    pub fn get_usage(&self) -> Usage {
+        self.usage.clone()
+    }
+
+    pub fn get_model(&self) -> String {
+        self.model.clone()
+    }
+
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        arr.insert("embeddings", self.get_embeddings()?)?;
+        arr.insert("usage", self.usage.to_array()?)?;
+        arr.insert("model", self.model.clone())?;
+        Ok(arr.into_zval(false)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn target(provider: &str) -> EmbeddingTarget {
+        EmbeddingTarget {
+            url: String::new(),
+            api_key: String::new(),
+            model: "m".to_string(),
+            provider: provider.to_string(),
+            config: ProviderConfig::default(),
+        }
+    }
+
+    #[test]
+    fn test_request_body_per_provider() {
+        let options = EmbedOptions {
+            dimensions: Some(256),
+            input_type: Some(InputType::Query),
+            normalize: false,
+            format: VectorFormat::Base64,
+        };
+        let texts = vec!["hi".to_string()];
+
+        let body = target("openai").request_body(&texts, &options);
+        assert_eq!(body["dimensions"], 256);
+        assert_eq!(body["encoding_format"], "base64");
+        assert!(body.get("input_type").is_none());
+
+        let body = target("voyage").request_body(&texts, &options);
+        assert_eq!(body["output_dimension"], 256);
+        assert_eq!(body["input_type"], "query");
+        assert!(body.get("encoding_format").is_none());
+
+        let body = target("jina").request_body(&texts, &options);
+        assert_eq!(body["task"], "retrieval.query");
+    }
+
+    #[test]
+    fn test_postprocess_truncates_and_normalizes() {
+        let mut vectors = vec![vec![3.0_f32, 4.0, 12.0]];
+        let options = EmbedOptions {
+            dimensions: Some(2),
+            ..EmbedOptions::default()
+        };
+        postprocess(&mut vectors, &options);
+        assert_eq!(vectors, vec![vec![0.6_f32, 0.8]]);
+    }
+
+    #[test]
+    fn test_parse_embeddings_orders_by_index() {
+        let value = json!({
+            "data": [
+                {"index": 1, "embedding": [0.3, 0.4]},
+                {"index": 0, "embedding": [0.1, 0.2]}
+            ],
+            "usage": {"prompt_tokens": 7, "total_tokens": 7}
+        });
+        let (vectors, tokens) = parse_embeddings_response(&value).unwrap();
+        assert_eq!(vectors, vec![vec![0.1_f32, 0.2], vec![0.3, 0.4]]);
+        assert_eq!(tokens, 7);
+    }
+
+    #[test]
+    fn test_parse_embeddings_decodes_base64() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(pack_f32(&[0.5, -1.0]));
+        let value = json!({"data": [{"index": 0, "embedding": encoded}]});
+        let (vectors, _) = parse_embeddings_response(&value).unwrap();
+        assert_eq!(vectors, vec![vec![0.5_f32, -1.0]]);
+
+        let value = json!({"data": [{"index": 0, "embedding": "AAA="}]});
+        assert!(parse_embeddings_response(&value).is_err());
+    }
+
+    #[test]
+    fn test_parse_embeddings_requires_data() {
+        assert!(parse_embeddings_response(&json!({"error": "bad"})).is_err());
+    }
+}
@@ -1,111 +1,629 @@
+use ext_php_rs::class::RegisteredClass;
+use ext_php_rs::convert::IntoZval;
 use ext_php_rs::exception::PhpException;
 use ext_php_rs::prelude::*;
+use ext_php_rs::types::Zval;
+use ext_php_rs::zend::ClassEntry;
 use octolib::errors::{ProviderError, StructuredOutputError, ToolCallError};
+use serde_json::Value;
+use std::time::Duration;
+
+use crate::redact::{sanitize_error, sanitize_raw_body};
+use crate::retry::{retry_hint, DeadlineExceeded};
 
 /// Convert octolib errors to PHP exceptions
 pub trait IntoPhpException {
     fn into_php_exception(self) -> PhpException;
 }
 
-// Implement for references to avoid clone issues
-impl IntoPhpException for &ProviderError {
-    fn into_php_exception(self) -> PhpException {
+/// Exception class an error maps to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorClass {
+    Generic,
+    Connection,
+    Validation,
+    StructuredOutput,
+    ToolCall,
+}
+
+impl ErrorClass {
+    fn ce(self) -> &'static ClassEntry {
         match self {
-            ProviderError::NetworkError(msg) => {
-                PhpException::from_class::<crate::error::LLMConnectionException>(msg.to_string())
-            }
+            ErrorClass::Generic => LLMException::get_metadata().ce(),
+            ErrorClass::Connection => LLMConnectionException::get_metadata().ce(),
+            ErrorClass::Validation => LLMValidationException::get_metadata().ce(),
+            ErrorClass::StructuredOutput => LLMStructuredOutputException::get_metadata().ce(),
+            ErrorClass::ToolCall => LLMToolCallException::get_metadata().ce(),
+        }
+    }
+}
+
+/// Provider response behind an error, returned by LLMException::getProvider()
+/// and the other getters. Empty for errors that did not come from a provider.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ErrorDetails {
+    provider: Option<String>,
+    http_status: Option<u16>,
+    request_id: Option<String>,
+    error_type: Option<String>,
+    raw_body: Option<String>,
+    /// Delay the provider asked for before retrying
+    retry_after: Option<Duration>,
+    /// Ways structured output did not match its schema
+    violations: Vec<String>,
+    /// Limit a timeout hit: 'attempt' or 'deadline'
+    timeout_limit: Option<&'static str>,
+}
+
+impl ErrorDetails {
+    fn of_provider(err: &ProviderError) -> Self {
+        match err {
             ProviderError::ApiError {
                 provider,
                 status,
                 message,
-            } => PhpException::from_class::<crate::error::LLMConnectionException>(format!(
-                "API Error [{provider}] ({status}): {message}"
-            )),
-            ProviderError::ModelNotSupported { model, provider } => {
-                PhpException::from_class::<crate::error::LLMValidationException>(format!(
-                    "Model '{model}' not supported by provider '{provider}'"
-                ))
-            }
-            ProviderError::TimeoutError { provider } => {
-                PhpException::from_class::<crate::error::LLMConnectionException>(format!(
-                    "Request timeout for provider: {provider}"
-                ))
+            } => Self {
+                provider: Some(provider.clone()),
+                http_status: Some(*status),
+                request_id: None,
+                error_type: error_type(message),
+                // Longer than the message, and complete in verbose mode
+                raw_body: Some(sanitize_raw_body(message)),
+                ..Self::default()
+            },
+            ProviderError::ModelNotSupported { provider, .. } => Self {
+                provider: Some(provider.clone()),
+                ..Self::default()
+            },
+            ProviderError::TimeoutError { provider } => Self {
+                provider: Some(provider.clone()),
+                timeout_limit: Some("attempt"),
+                ..Self::default()
+            },
+            _ => Self::default(),
+        }
+    }
+}
+
+/// Error type from a provider's JSON error body: `error.type` (OpenAI,
+/// Anthropic), `error.status` (Gemini) or a string `error.code`
+fn error_type(body: &str) -> Option<String> {
+    let value: Value = serde_json::from_str(body).ok()?;
+    let error = value.get("error")?;
+    ["type", "status", "code"]
+        .iter()
+        .find_map(|key| error.get(key)?.as_str())
+        .map(str::to_string)
+}
+
+/// Provider request id (`x-request-id` or `request-id` header), attached as
+/// context to errors from requests the extension sends itself
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub String);
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "request id {}", self.0)
+    }
+}
+
+impl RequestId {
+    pub(crate) fn from_headers(headers: &reqwest::header::HeaderMap) -> Option<Self> {
+        ["x-request-id", "request-id"]
+            .iter()
+            .find_map(|name| headers.get(*name)?.to_str().ok())
+            .map(|id| Self(id.to_string()))
+    }
+}
+
+/// An error mapped to the exception it is thrown as
+struct Classified {
+    class: ErrorClass,
+    /// One of the LLMException::ERR_* codes; only provider errors have one
+    code: i32,
+    message: String,
+    details: ErrorDetails,
+}
+
+impl Classified {
+    fn new(class: ErrorClass, message: String) -> Self {
+        Self {
+            class,
+            code: LLMException::ERR_UNKNOWN,
+            message,
+            details: ErrorDetails::default(),
+        }
+    }
+
+    fn provider(err: &ProviderError) -> Self {
+        let (class, message) = classify_provider(err);
+        Self {
+            class,
+            code: provider_code(err),
+            message,
+            details: ErrorDetails::of_provider(err),
+        }
+    }
+
+    /// Exception to throw, as the object carrying the details when it can
+    /// be created
+    fn exception(self) -> PhpException {
+        let mut exception = PhpException::new(self.message.clone(), self.code, self.class.ce());
+        if let Ok(object) = self.object() {
+            exception.set_object(Some(object));
+        }
+        exception
+    }
+
+    /// Instantiate the exception without throwing it, e.g. to return it in
+    /// an array of per-item results
+    fn object(self) -> PhpResult<Zval> {
+        let details = self.details;
+        let exception = match self.class {
+            ErrorClass::Generic => LLMException { details }.into_zval(false)?,
+            ErrorClass::Connection => LLMConnectionException { details }.into_zval(false)?,
+            ErrorClass::Validation => LLMValidationException { details }.into_zval(false)?,
+            ErrorClass::StructuredOutput => {
+                LLMStructuredOutputException { details }.into_zval(false)?
             }
-            _ => PhpException::from_class::<crate::error::LLMException>(format!(
-                "Provider error: {self:?}"
-            )),
+            ErrorClass::ToolCall => LLMToolCallException { details }.into_zval(false)?,
+        };
+        // \Exception::__construct() sets message and code as a thrown one has them
+        exception
+            .object()
+            .ok_or_else(|| PhpException::default("Failed to create exception".to_string()))?
+            .try_call_method("__construct", vec![&self.message, &self.code])?;
+        Ok(exception)
+    }
+}
+
+/// Stable code of a provider error, see the LLMException::ERR_* constants
+fn provider_code(err: &ProviderError) -> i32 {
+    match err {
+        ProviderError::NetworkError(_) => LLMException::ERR_NETWORK,
+        ProviderError::TimeoutError { .. } => LLMException::ERR_TIMEOUT,
+        ProviderError::ApiError { status, .. } => match status {
+            401 | 403 => LLMException::ERR_AUTHENTICATION,
+            429 => LLMException::ERR_RATE_LIMIT,
+            500..=599 => LLMException::ERR_SERVER,
+            _ => LLMException::ERR_API,
+        },
+        ProviderError::ModelNotSupported { .. } => LLMException::ERR_MODEL_NOT_SUPPORTED,
+        _ => LLMException::ERR_PROVIDER,
+    }
+}
+
+fn classify_provider(err: &ProviderError) -> (ErrorClass, String) {
+    match err {
+        ProviderError::NetworkError(msg) => (ErrorClass::Connection, sanitize_error(msg)),
+        ProviderError::ApiError {
+            provider,
+            status,
+            message,
+        } => (
+            ErrorClass::Connection,
+            format!(
+                "API Error [{provider}] ({status}): {}",
+                sanitize_error(message)
+            ),
+        ),
+        ProviderError::ModelNotSupported { model, provider } => (
+            ErrorClass::Validation,
+            format!("Model '{model}' not supported by provider '{provider}'"),
+        ),
+        ProviderError::TimeoutError { provider } => (
+            ErrorClass::Connection,
+            format!("Request timeout for provider: {provider}"),
+        ),
+        _ => (
+            ErrorClass::Generic,
+            format!("Provider error: {}", sanitize_error(&format!("{err:?}"))),
+        ),
+    }
+}
+
+/// Reply that could not be turned into structured output locally, e.g. no
+/// JSON in a prompted reply or a value that does not match the schema
+#[derive(Debug)]
+pub(crate) struct InvalidStructuredOutput(pub String);
+
+impl std::fmt::Display for InvalidStructuredOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidStructuredOutput {}
+
+/// Structured output that does not match the schema, with the violations
+/// found by the validator
+#[derive(Debug)]
+pub(crate) struct SchemaViolations(pub Vec<String>);
+
+impl std::fmt::Display for SchemaViolations {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Response does not match schema: {}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for SchemaViolations {}
+
+impl SchemaViolations {
+    fn classified(&self) -> Classified {
+        let mut classified = Classified::new(ErrorClass::StructuredOutput, self.to_string());
+        classified.details.violations = self.0.clone();
+        classified
+    }
+}
+
+fn classify(err: &anyhow::Error) -> Classified {
+    // Try to downcast to known error types
+    if let Some(provider_err) = err.downcast_ref::<ProviderError>() {
+        let mut classified = Classified::provider(provider_err);
+        if let Some(RequestId(id)) = err.downcast_ref::<RequestId>() {
+            classified.details.request_id = Some(id.clone());
         }
+        classified.details.retry_after = retry_hint(err);
+        return classified;
+    }
+    if let Some(err) = err.downcast_ref::<SchemaViolations>() {
+        return err.classified();
+    }
+    if let Some(err) = err.downcast_ref::<DeadlineExceeded>() {
+        return Classified {
+            class: ErrorClass::Connection,
+            code: LLMException::ERR_TIMEOUT,
+            message: err.to_string(),
+            details: ErrorDetails {
+                timeout_limit: Some("deadline"),
+                ..ErrorDetails::default()
+            },
+        };
+    }
+    if let Some(err) = err.downcast_ref::<InvalidStructuredOutput>() {
+        return Classified::new(ErrorClass::StructuredOutput, err.0.clone());
+    }
+    // Use a catch-all pattern for these since the enum structure may vary
+    if let Some(err) = err.downcast_ref::<StructuredOutputError>() {
+        return Classified::new(
+            ErrorClass::StructuredOutput,
+            format!(
+                "Structured output error: {}",
+                sanitize_error(&format!("{err:?}"))
+            ),
+        );
+    }
+    if let Some(err) = err.downcast_ref::<ToolCallError>() {
+        return Classified::new(
+            ErrorClass::ToolCall,
+            format!("Tool call error: {}", sanitize_error(&format!("{err:?}"))),
+        );
+    }
+
+    // Fallback to generic exception
+    Classified::new(ErrorClass::Generic, sanitize_error(&err.to_string()))
+}
+
+/// LLMConnectionException for a request held back by the client-side rate
+/// limit, coded and carrying a retry delay like a provider's 429
+pub(crate) fn rate_limited(message: String, retry_after: Duration) -> PhpException {
+    Classified {
+        class: ErrorClass::Connection,
+        code: LLMException::ERR_RATE_LIMIT,
+        message,
+        details: ErrorDetails {
+            retry_after: Some(retry_after),
+            ..ErrorDetails::default()
+        },
+    }
+    .exception()
+}
+
+/// Build (without throwing) the exception object an error maps to
+pub(crate) fn exception_object(err: &anyhow::Error) -> PhpResult<Zval> {
+    classify(err).object()
+}
+
+// Implement for references to avoid clone issues
+impl IntoPhpException for &ProviderError {
+    fn into_php_exception(self) -> PhpException {
+        Classified::provider(self).exception()
     }
 }
 
 impl IntoPhpException for &StructuredOutputError {
     fn into_php_exception(self) -> PhpException {
-        // Use a catch-all pattern since the enum structure may vary
-        PhpException::from_class::<crate::error::LLMStructuredOutputException>(format!(
-            "Structured output error: {self:?}"
-        ))
+        Classified::new(
+            ErrorClass::StructuredOutput,
+            format!(
+                "Structured output error: {}",
+                sanitize_error(&format!("{self:?}"))
+            ),
+        )
+        .exception()
+    }
+}
+
+impl IntoPhpException for &SchemaViolations {
+    fn into_php_exception(self) -> PhpException {
+        self.classified().exception()
     }
 }
 
 impl IntoPhpException for &ToolCallError {
     fn into_php_exception(self) -> PhpException {
-        // Use a catch-all pattern since the enum structure may vary
-        PhpException::from_class::<crate::error::LLMToolCallException>(format!(
-            "Tool call error: {self:?}"
-        ))
+        Classified::new(
+            ErrorClass::ToolCall,
+            format!("Tool call error: {}", sanitize_error(&format!("{self:?}"))),
+        )
+        .exception()
     }
 }
 
 impl IntoPhpException for anyhow::Error {
     fn into_php_exception(self) -> PhpException {
-        // Try to downcast to known error types
-        if let Some(err) = self.downcast_ref::<ProviderError>() {
-            return err.into_php_exception();
-        }
-        if let Some(err) = self.downcast_ref::<StructuredOutputError>() {
-            return err.into_php_exception();
-        }
-        if let Some(err) = self.downcast_ref::<ToolCallError>() {
-            return err.into_php_exception();
-        }
-
-        // Fallback to generic exception
-        PhpException::from_class::<crate::error::LLMException>(self.to_string())
+        classify(&self).exception()
     }
 }
 
-// Exception classes for PHP — all extend \Exception so they implement Throwable
-// and can be caught with catch (\Throwable $e) in userland.
-//
-// We store message/code as #[php(prop)] fields so that Exception::getMessage()
-// and Exception::getCode() work correctly. The __construct populates them;
-// ext-php-rs requires a #[php_impl] block to allow PHP-side instantiation.
+// Exception classes for PHP. LLMException extends \Exception and the others
+// extend LLMException, so catch (LLMException $e) covers every error of the
+// extension. Message, code and previous are the standard \Exception
+// properties, set by the inherited constructor; the Rust side only holds the
+// provider details of errors the extension throws.
+
+/// Class entry of \Exception, the parent of LLMException
+fn php_exception() -> &'static ClassEntry {
+    ext_php_rs::zend::ce::exception()
+}
+
+/// Class entry of LLMException, the parent of the other exception classes
+fn llm_exception() -> &'static ClassEntry {
+    LLMException::get_metadata().ce()
+}
 
 macro_rules! php_exception_class {
-    ($rust_name:ident, $php_name:literal) => {
+    ($rust_name:ident, $php_name:literal, $parent:ident, $parent_stub:literal $(, { $($members:tt)* })?) => {
         #[php_class]
-        #[php(name = $php_name, extends(ce = ext_php_rs::zend::ce::exception, stub = "\\Exception"))]
+        #[php(name = $php_name, extends(ce = $parent, stub = $parent_stub))]
+        #[derive(Default)]
         pub struct $rust_name {
-            #[php(prop, flags = ext_php_rs::flags::PropertyFlags::Protected)]
-            message: String,
-            #[php(prop, flags = ext_php_rs::flags::PropertyFlags::Protected)]
-            code: i64,
+            details: ErrorDetails,
         }
 
         #[php_impl]
         impl $rust_name {
-            pub fn __construct(message: Option<String>, code: Option<i64>) -> Self {
-                Self {
-                    message: message.unwrap_or_default(),
-                    code: code.unwrap_or(0),
-                }
+            $($($members)*)?
+
+            /// Provider that returned the error, e.g. 'openai'
+            pub fn get_provider(&self) -> Option<String> {
+                self.details.provider.clone()
+            }
+
+            /// HTTP status of the provider's error response
+            pub fn get_http_status(&self) -> Option<i64> {
+                self.details.http_status.map(i64::from)
+            }
+
+            /// Provider's id of the failed request, for support tickets
+            pub fn get_request_id(&self) -> Option<String> {
+                self.details.request_id.clone()
+            }
+
+            /// Provider's error type, e.g. 'rate_limit_error' or
+            /// 'insufficient_quota'
+            pub fn get_error_type(&self) -> Option<String> {
+                self.details.error_type.clone()
+            }
+
+            /// Provider's error response body, with API keys masked and cut
+            /// to 2000 characters unless verbose errors are on
+            pub fn get_raw_body(&self) -> Option<String> {
+                self.details.raw_body.clone()
             }
         }
     };
 }
 
-php_exception_class!(LLMException, "LLMException");
-php_exception_class!(LLMConnectionException, "LLMConnectionException");
-php_exception_class!(LLMValidationException, "LLMValidationException");
-php_exception_class!(LLMStructuredOutputException, "LLMStructuredOutputException");
-php_exception_class!(LLMToolCallException, "LLMToolCallException");
+php_exception_class!(
+    LLMException,
+    "LLMException",
+    php_exception,
+    "\\Exception",
+    {
+        /// Not a provider error
+        pub const ERR_UNKNOWN: i32 = 0;
+        /// Connection failed or was interrupted
+        pub const ERR_NETWORK: i32 = 1;
+        /// Provider did not answer in time
+        pub const ERR_TIMEOUT: i32 = 2;
+        /// API error status not covered by a more specific code
+        pub const ERR_API: i32 = 3;
+        /// API key missing, invalid or not allowed (HTTP 401 or 403)
+        pub const ERR_AUTHENTICATION: i32 = 4;
+        /// Rate limit or quota exceeded (HTTP 429)
+        pub const ERR_RATE_LIMIT: i32 = 5;
+        /// Provider-side failure (HTTP 5xx)
+        pub const ERR_SERVER: i32 = 6;
+        /// Model unknown to the provider
+        pub const ERR_MODEL_NOT_SUPPORTED: i32 = 7;
+        /// Other provider error
+        pub const ERR_PROVIDER: i32 = 8;
+    }
+);
+php_exception_class!(
+    LLMConnectionException,
+    "LLMConnectionException",
+    llm_exception,
+    "\\LLMException",
+    {
+        /// Seconds the provider asked to wait before retrying, from the
+        /// `Retry-After` header or a "try again in 1.5s" message of a 429
+        pub fn get_retry_after(&self) -> Option<f64> {
+            self.details.retry_after.map(|delay| delay.as_secs_f64())
+        }
+
+        /// Limit a timeout hit: 'attempt' when a single request ran past
+        /// setTimeout(), 'deadline' when setDeadline() ran out across the
+        /// retries; null for other errors
+        pub fn get_timeout_limit(&self) -> Option<String> {
+            self.details.timeout_limit.map(str::to_string)
+        }
+    }
+);
+php_exception_class!(
+    LLMValidationException,
+    "LLMValidationException",
+    llm_exception,
+    "\\LLMException"
+);
+php_exception_class!(
+    LLMStructuredOutputException,
+    "LLMStructuredOutputException",
+    llm_exception,
+    "\\LLMException",
+    {
+        /// Ways the output did not match the schema, as "/path: message"
+        /// with the path a JSON pointer into the output; empty for other
+        /// structured output errors
+        pub fn get_violations(&self) -> Vec<String> {
+            self.details.violations.clone()
+        }
+    }
+);
+php_exception_class!(
+    LLMToolCallException,
+    "LLMToolCallException",
+    llm_exception,
+    "\\LLMException"
+);
+php_exception_class!(
+    LLMBudgetExceededException,
+    "LLMBudgetExceededException",
+    llm_exception,
+    "\\LLMException"
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(status: u16) -> ProviderError {
+        ProviderError::ApiError {
+            provider: "openai".to_string(),
+            status,
+            message: "failed".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_provider_code() {
+        assert_eq!(
+            provider_code(&api_error(401)),
+            LLMException::ERR_AUTHENTICATION
+        );
+        assert_eq!(provider_code(&api_error(429)), LLMException::ERR_RATE_LIMIT);
+        assert_eq!(provider_code(&api_error(503)), LLMException::ERR_SERVER);
+        assert_eq!(provider_code(&api_error(400)), LLMException::ERR_API);
+        assert_eq!(
+            provider_code(&ProviderError::NetworkError("reset".to_string())),
+            LLMException::ERR_NETWORK
+        );
+        assert_eq!(
+            provider_code(&ProviderError::TimeoutError {
+                provider: "openai".to_string()
+            }),
+            LLMException::ERR_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_error_type() {
+        assert_eq!(
+            error_type(r#"{"type":"error","error":{"type":"rate_limit_error","message":"slow"}}"#),
+            Some("rate_limit_error".to_string())
+        );
+        assert_eq!(
+            error_type(r#"{"error":{"code":429,"status":"RESOURCE_EXHAUSTED"}}"#),
+            Some("RESOURCE_EXHAUSTED".to_string())
+        );
+        assert_eq!(
+            error_type(r#"{"error":{"message":"no","code":"invalid_api_key"}}"#),
+            Some("invalid_api_key".to_string())
+        );
+        assert_eq!(error_type("Bad Gateway"), None);
+    }
+
+    #[test]
+    fn test_details_of_api_error() {
+        let details = ErrorDetails::of_provider(&api_error(429));
+        assert_eq!(details.provider.as_deref(), Some("openai"));
+        assert_eq!(details.http_status, Some(429));
+        assert_eq!(details.raw_body.as_deref(), Some("failed"));
+        assert_eq!(
+            ErrorDetails::of_provider(&ProviderError::NetworkError("reset".to_string())),
+            ErrorDetails::default()
+        );
+    }
+
+    #[test]
+    fn test_raw_body_truncated_unless_verbose() {
+        let long = ProviderError::ApiError {
+            provider: "openai".to_string(),
+            status: 400,
+            message: "x".repeat(10_000),
+        };
+        let raw_body = ErrorDetails::of_provider(&long).raw_body.unwrap();
+        assert!(raw_body.ends_with("[truncated]"));
+        assert!(raw_body.chars().count() < 2100);
+
+        crate::redact::set_verbose(true);
+        let raw_body = ErrorDetails::of_provider(&long).raw_body.unwrap();
+        crate::redact::set_verbose(false);
+        assert_eq!(raw_body.len(), 10_000);
+    }
+
+    #[test]
+    fn test_retry_after_from_context() {
+        let err = anyhow::Error::from(api_error(429))
+            .context(crate::retry::RetryAfter(Duration::from_millis(2500)));
+        assert_eq!(
+            classify(&err).details.retry_after,
+            Some(Duration::from_millis(2500))
+        );
+        let err = anyhow::Error::from(api_error(503));
+        assert_eq!(classify(&err).details.retry_after, None);
+    }
+
+    #[test]
+    fn test_schema_violations_are_kept() {
+        let violations = vec!["/: \"name\" is a required property".to_string()];
+        let classified = classify(&anyhow::Error::from(SchemaViolations(violations.clone())));
+        assert_eq!(classified.class, ErrorClass::StructuredOutput);
+        assert_eq!(classified.details.violations, violations);
+        assert_eq!(
+            classified.message,
+            "Response does not match schema: /: \"name\" is a required property"
+        );
+    }
+
+    #[test]
+    fn test_timeout_limit() {
+        let err = anyhow::Error::from(ProviderError::TimeoutError {
+            provider: "openai".to_string(),
+        });
+        assert_eq!(classify(&err).details.timeout_limit, Some("attempt"));
+
+        let err = anyhow::Error::from(DeadlineExceeded {
+            deadline: Duration::from_secs(30),
+            attempts: 3,
+        });
+        let classified = classify(&err);
+        assert_eq!(classified.code, LLMException::ERR_TIMEOUT);
+        assert_eq!(classified.details.timeout_limit, Some("deadline"));
+        assert_eq!(
+            classified.message,
+            "Deadline of 30s exceeded after 3 attempts"
+        );
+    }
+}
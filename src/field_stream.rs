@@ -0,0 +1,199 @@
+use serde_json::Value;
+
+/// Where the parser is within the top-level JSON object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Skipping prose or a code fence before the opening brace
+    BeforeObject,
+    BeforeKey,
+    InKey,
+    BeforeColon,
+    BeforeValue,
+    InValue,
+    /// A string, array or object value was reported at its closing character
+    AfterValue,
+    Done,
+}
+
+/// Incremental parser reporting each top-level field of a streamed JSON
+/// object as soon as its value is complete. Strings, arrays and objects
+/// are complete at their closing character; numbers, booleans and null at
+/// the comma or brace after them.
+pub(crate) struct FieldStream {
+    buffer: String,
+    /// Byte offset of the next character to scan
+    pos: usize,
+    state: State,
+    /// Nesting depth inside the current value
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    key: String,
+    /// Start of the current key or value in `buffer`
+    start: usize,
+}
+
+impl FieldStream {
+    pub(crate) fn new() -> Self {
+        Self {
+            buffer: String::new(),
+            pos: 0,
+            state: State::BeforeObject,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            key: String::new(),
+            start: 0,
+        }
+    }
+
+    /// Add a chunk of the reply; returns the fields completed by it
+    pub(crate) fn feed(&mut self, chunk: &str) -> Vec<(String, Value)> {
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.push_str(chunk);
+        let mut fields = Vec::new();
+        let scanned = self.pos;
+        for (offset, c) in buffer[scanned..].char_indices() {
+            let i = scanned + offset;
+            let end = match self.state {
+                State::Done => break,
+                State::InValue => self.scan_value(c, i),
+                _ => {
+                    self.scan_structure(&buffer, c, i);
+                    None
+                }
+            };
+            if let Some(end) = end {
+                if let Ok(value) = serde_json::from_str(buffer[self.start..end].trim()) {
+                    fields.push((std::mem::take(&mut self.key), value));
+                }
+            }
+        }
+        self.pos = buffer.len();
+        self.buffer = buffer;
+        fields
+    }
+
+    /// Characters outside values: braces, keys, colons and commas
+    fn scan_structure(&mut self, buffer: &str, c: char, i: usize) {
+        match (self.state, c) {
+            (State::BeforeObject, '{') => self.state = State::BeforeKey,
+            (State::BeforeKey, '"') => {
+                self.state = State::InKey;
+                self.start = i;
+                self.escaped = false;
+            }
+            (State::BeforeKey | State::AfterValue, '}') => self.state = State::Done,
+            (State::AfterValue, ',') => self.state = State::BeforeKey,
+            (State::InKey, _) if self.escaped => self.escaped = false,
+            (State::InKey, '\\') => self.escaped = true,
+            (State::InKey, '"') => {
+                self.key = serde_json::from_str(&buffer[self.start..=i]).unwrap_or_default();
+                self.state = State::BeforeColon;
+            }
+            (State::BeforeColon, ':') => self.state = State::BeforeValue,
+            (State::BeforeValue, c) if !c.is_whitespace() => {
+                self.state = State::InValue;
+                self.start = i;
+                self.depth = 0;
+                self.in_string = false;
+                self.escaped = false;
+                // The first character of the value
+                let _ = self.scan_value(c, i);
+            }
+            _ => {}
+        }
+    }
+
+    /// One character of a value; returns the value's end offset once it is
+    /// complete
+    fn scan_value(&mut self, c: char, i: usize) -> Option<usize> {
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = false;
+                if self.depth == 0 {
+                    self.state = State::AfterValue;
+                    return Some(i + 1);
+                }
+            }
+            return None;
+        }
+        match c {
+            '"' => self.in_string = true,
+            '{' | '[' => self.depth += 1,
+            '}' | ']' if self.depth == 0 => {
+                // A scalar ended by the closing brace of the object
+                self.state = State::Done;
+                return Some(i);
+            }
+            '}' | ']' => {
+                self.depth -= 1;
+                if self.depth == 0 {
+                    self.state = State::AfterValue;
+                    return Some(i + 1);
+                }
+            }
+            ',' if self.depth == 0 => {
+                self.state = State::BeforeKey;
+                return Some(i);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields_in_chunks(text: &str, size: usize) -> Vec<(String, Value)> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut stream = FieldStream::new();
+        chars
+            .chunks(size)
+            .flat_map(|chunk| stream.feed(&chunk.iter().collect::<String>()))
+            .collect()
+    }
+
+    #[test]
+    fn test_fields_in_order() {
+        let text = r#"{"name": "Ada \"L\"", "age": 36, "tags": ["a", "b]"], "address": {"city": "X, Y"}, "ok": true}"#;
+        let expected = vec![
+            ("name".to_string(), json!("Ada \"L\"")),
+            ("age".to_string(), json!(36)),
+            ("tags".to_string(), json!(["a", "b]"])),
+            ("address".to_string(), json!({"city": "X, Y"})),
+            ("ok".to_string(), json!(true)),
+        ];
+        for size in [1, 3, 7, text.len()] {
+            assert_eq!(fields_in_chunks(text, size), expected, "chunk size {size}");
+        }
+    }
+
+    #[test]
+    fn test_field_reported_at_closing_character() {
+        let mut stream = FieldStream::new();
+        assert!(stream.feed(r#"{"title": "Hel"#).is_empty());
+        assert_eq!(
+            stream.feed(r#"lo", "n"#),
+            vec![("title".to_string(), json!("Hello"))]
+        );
+        assert!(stream.feed(": 4").is_empty());
+        assert_eq!(stream.feed("2}"), vec![("n".to_string(), json!(42))]);
+    }
+
+    #[test]
+    fn test_skips_code_fence() {
+        let text = "```json\n{\"a\": 1, \"b\": null}\n```";
+        assert_eq!(
+            fields_in_chunks(text, 4),
+            vec![("a".to_string(), json!(1)), ("b".to_string(), Value::Null)]
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::Result;
+use ext_php_rs::prelude::*;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+use crate::attribution::Attribution;
+use crate::error::IntoPhpException;
+use crate::llm_class::{Completion, Response};
+use crate::runtime::runtime;
+
+/// Handle to a completion running in the background on the shared runtime
+#[php_class]
+#[php(name = "LLMFuture")]
+pub struct LLMFuture {
+    handle: Option<JoinHandle<Result<Completion>>>,
+    model: String,
+    attribution: Attribution,
+    response: Option<Response>,
+    cancelled: bool,
+}
+
+// Internal constructor - not exposed to PHP
+impl LLMFuture {
+    pub(crate) fn new(
+        handle: JoinHandle<Result<Completion>>,
+        model: String,
+        attribution: Attribution,
+    ) -> Self {
+        Self {
+            handle: Some(handle),
+            model,
+            attribution,
+            response: None,
+            cancelled: false,
+        }
+    }
+
+    fn cancelled_error() -> PhpException {
+        PhpException::from_class::<crate::error::LLMException>(
+            "Completion was cancelled".to_string(),
+        )
+    }
+}
+
+#[php_impl]
+impl LLMFuture {
+    /// Whether the completion has finished (successfully or not)
+    pub fn is_ready(&self) -> bool {
+        self.response.is_some() || self.handle.as_ref().is_some_and(|h| h.is_finished())
+    }
+
+    /// Block until the completion finishes and return its Response. With a
+    /// timeout, returns null if it has not finished in time; the completion
+    /// keeps running and wait() can be called again.
+    pub fn wait(&mut self, timeout_ms: Option<i64>) -> PhpResult<Option<Response>> {
+        if let Some(ref response) = self.response {
+            return Ok(Some(response.clone()));
+        }
+        if self.cancelled {
+            return Err(Self::cancelled_error());
+        }
+        let Some(handle) = self.handle.as_mut() else {
+            return Err(PhpException::from_class::<crate::error::LLMException>(
+                "Completion already failed".to_string(),
+            ));
+        };
+
+        let rt = runtime()?;
+        let joined = match timeout_ms {
+            Some(ms) => {
+                let timeout = Duration::from_millis(ms.max(0) as u64);
+                match rt.block_on(tokio::time::timeout(timeout, handle)) {
+                    Ok(joined) => joined,
+                    Err(_) => return Ok(None),
+                }
+            }
+            None => rt.block_on(handle),
+        };
+        self.handle = None;
+
+        let completion = match joined {
+            Ok(result) => result.map_err(|e| e.into_php_exception())?,
+            Err(e) if e.is_cancelled() => return Err(Self::cancelled_error()),
+            Err(e) => {
+                return Err(PhpException::from_class::<crate::error::LLMException>(
+                    format!("Completion task failed: {e}"),
+                ))
+            }
+        };
+
+        let response = Response::from_completion(completion, self.model.clone(), &self.attribution);
+        self.response = Some(response.clone());
+        Ok(Some(response))
+    }
+
+    /// Abort the request if it is still running. Returns false when it had
+    /// already finished or been cancelled.
+    pub fn cancel(&mut self) -> bool {
+        match self.handle.take() {
+            Some(handle) if !handle.is_finished() => {
+                handle.abort();
+                self.cancelled = true;
+                true
+            }
+            Some(handle) => {
+                // Finished: keep the result available to wait()
+                self.handle = Some(handle);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for LLMFuture {
+    /// Dropping an unawaited future aborts its request
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::ZendHashTable as PhpArray;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+/// JPEG quality used when a JPEG is downscaled without a quality given
+const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+/// How attached images are shrunk before they are base64-encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ImageOptions {
+    /// Longest side in pixels; larger images are scaled down to it
+    max_dimension: Option<u32>,
+    /// Re-encode as JPEG with this quality (1-100)
+    quality: Option<u8>,
+}
+
+impl ImageOptions {
+    /// Options 'max_dimension' and 'quality'; None when neither is set
+    pub(crate) fn parse(options: Option<&PhpArray>) -> PhpResult<Option<Self>> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let get = |key: &str| options.and_then(|o| o.get(key));
+        let max_dimension = match get("max_dimension").map(|v| v.long()) {
+            None => None,
+            Some(Some(n)) if n > 0 => Some(n.min(i64::from(u32::MAX)) as u32),
+            Some(_) => {
+                return Err(invalid(
+                    "Option 'max_dimension' must be a positive integer".to_string(),
+                ))
+            }
+        };
+        let quality = match get("quality").map(|v| v.long()) {
+            None => None,
+            Some(Some(q)) if (1..=100).contains(&q) => Some(q as u8),
+            Some(_) => {
+                return Err(invalid(
+                    "Option 'quality' must be an integer from 1 to 100".to_string(),
+                ))
+            }
+        };
+        Ok(
+            (max_dimension.is_some() || quality.is_some()).then_some(Self {
+                max_dimension,
+                quality,
+            }),
+        )
+    }
+}
+
+/// Blend a color channel over a white background
+fn over_white(channel: u8, alpha: u8) -> u8 {
+    let (c, a) = (u32::from(channel), u32::from(alpha));
+    ((c * a + 255 * (255 - a) + 127) / 255) as u8
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> image::ImageResult<Vec<u8>> {
+    let rgba = image.to_rgba8();
+    let rgb = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        Rgb([over_white(r, a), over_white(g, a), over_white(b, a)])
+    });
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, quality).encode_image(&rgb)?;
+    Ok(out)
+}
+
+/// Downscale and re-encode image data. JPEGs stay JPEG and other formats
+/// become PNG, unless a quality asks for JPEG, with transparency flattened
+/// onto white. Images that need no change, or would only grow, are kept
+/// as they are. Returns the data and its media type.
+pub(crate) fn process(
+    bytes: Vec<u8>,
+    media_type: String,
+    options: &ImageOptions,
+) -> PhpResult<(Vec<u8>, String)> {
+    let failed = |e: image::ImageError| {
+        PhpException::from_class::<crate::error::LLMValidationException>(format!(
+            "Failed to process image: {e}"
+        ))
+    };
+    let image = image::load_from_memory(&bytes).map_err(failed)?;
+    let format = image::guess_format(&bytes).ok();
+
+    let resized = options
+        .max_dimension
+        .filter(|max| image.width().max(image.height()) > *max)
+        .map(|max| image.resize(max, max, FilterType::Lanczos3));
+    if resized.is_none() && options.quality.is_none() {
+        return Ok((bytes, media_type));
+    }
+    let image = resized.as_ref().unwrap_or(&image);
+
+    let (out, out_type) = match (options.quality, format) {
+        (Some(quality), _) => (encode_jpeg(image, quality), "image/jpeg"),
+        (None, Some(ImageFormat::Jpeg)) => (encode_jpeg(image, DEFAULT_JPEG_QUALITY), "image/jpeg"),
+        (None, _) => {
+            let mut out = Vec::new();
+            let result = image.write_to(&mut Cursor::new(&mut out), ImageFormat::Png);
+            (result.map(|()| out), "image/png")
+        }
+    };
+    let out = out.map_err(failed)?;
+    if resized.is_none() && out.len() >= bytes.len() {
+        return Ok((bytes, media_type));
+    }
+    Ok((out, out_type.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            Rgba([(x % 256) as u8, (y % 256) as u8, 128, 255])
+        });
+        let mut out = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut Cursor::new(&mut out), ImageFormat::Png)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_over_white() {
+        assert_eq!(over_white(0, 0), 255);
+        assert_eq!(over_white(0, 255), 0);
+        assert_eq!(over_white(100, 128), 177);
+    }
+
+    #[test]
+    fn test_downscale_keeps_aspect_and_format() {
+        let options = ImageOptions {
+            max_dimension: Some(100),
+            quality: None,
+        };
+        let (out, media_type) = process(png(400, 200), "image/png".to_string(), &options).unwrap();
+        assert_eq!(media_type, "image/png");
+        assert_eq!(crate::vision::image_size(&out), Some((100, 50)));
+    }
+
+    #[test]
+    fn test_small_image_is_kept() {
+        let original = png(50, 40);
+        let options = ImageOptions {
+            max_dimension: Some(100),
+            quality: None,
+        };
+        let (out, _) = process(original.clone(), "image/png".to_string(), &options).unwrap();
+        assert_eq!(out, original);
+    }
+
+    #[test]
+    fn test_quality_converts_to_jpeg() {
+        let options = ImageOptions {
+            max_dimension: Some(64),
+            quality: Some(70),
+        };
+        let (out, media_type) = process(png(256, 128), "image/png".to_string(), &options).unwrap();
+        assert_eq!(media_type, "image/jpeg");
+        assert_eq!(crate::vision::image_size(&out), Some((64, 32)));
+    }
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Strings shorter than this are not worth a pool lookup
+const MIN_LEN: usize = 256;
+
+/// Pool size below which unused strings are not swept
+const MIN_SWEEP: usize = 1024;
+
+/// Large strings held by messages and tools, shared by every request served
+/// by this process so that repeated system prompts, schemas and context
+/// blocks are stored once
+struct Pool {
+    strings: HashSet<Arc<str>>,
+    /// Size at which strings no longer referenced outside the pool are swept
+    sweep_at: usize,
+}
+
+static POOL: LazyLock<Mutex<Pool>> = LazyLock::new(|| {
+    Mutex::new(Pool {
+        strings: HashSet::new(),
+        sweep_at: MIN_SWEEP,
+    })
+});
+
+/// Shared copy of `text`: large strings equal to one already held are
+/// returned from the pool instead of being stored again
+pub(crate) fn intern(text: impl Into<String> + AsRef<str>) -> Arc<str> {
+    if text.as_ref().len() < MIN_LEN {
+        return Arc::from(text.into());
+    }
+    let mut pool = POOL.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(shared) = pool.strings.get(text.as_ref()) {
+        return Arc::clone(shared);
+    }
+    let shared: Arc<str> = Arc::from(text.into());
+    pool.strings.insert(Arc::clone(&shared));
+    if pool.strings.len() >= pool.sweep_at {
+        pool.strings.retain(|s| Arc::strong_count(s) > 1);
+        pool.sweep_at = (pool.strings.len() * 2).max(MIN_SWEEP);
+    }
+    shared
+}
+
+/// Deserialize a string into the pool, for `#[serde(deserialize_with)]`
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Arc<str>, D::Error> {
+    String::deserialize(deserializer).map(intern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_strings_are_shared() {
+        let prompt = "You are a helpful assistant. ".repeat(20);
+        let a = intern(prompt.clone());
+        let b = intern(prompt.as_str());
+        assert!(Arc::ptr_eq(&a, &b));
+
+        let short = intern("Hello");
+        assert!(!Arc::ptr_eq(&short, &intern("Hello")));
+    }
+}
@@ -0,0 +1,145 @@
+use ext_php_rs::prelude::*;
+use octolib::llm::{Message as OctoMessage, MessageBuilder};
+use serde_json::Value;
+
+/// What StructuredBuilder does when the model has no native JSON mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum FallbackStrategy {
+    /// Ask for JSON in the prompt, then parse and validate the reply locally
+    #[default]
+    Prompt,
+    /// Throw LLMStructuredOutputException
+    Error,
+}
+
+impl FallbackStrategy {
+    pub(crate) fn parse(strategy: &str) -> PhpResult<Self> {
+        match strategy {
+            "prompt" => Ok(Self::Prompt),
+            "error" => Ok(Self::Error),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown fallback strategy '{other}', expected 'prompt' or 'error'"
+            ))),
+        }
+    }
+}
+
+fn instruction(schema: Option<&Value>) -> String {
+    let base = "Respond with a single JSON value only, without any other text or code fences.";
+    match schema.and_then(|s| serde_json::to_string_pretty(s).ok()) {
+        Some(schema) => format!("{base} It must conform to this JSON schema:\n{schema}"),
+        None => base.to_string(),
+    }
+}
+
+/// Ask for JSON output in the system prompt, appending to an existing
+/// leading system message or inserting a new one
+pub(crate) fn add_instruction(
+    messages: &mut Vec<OctoMessage>,
+    schema: Option<&Value>,
+) -> PhpResult<()> {
+    let text = instruction(schema);
+    if let Some(first) = messages.first_mut().filter(|m| m.role == "system") {
+        first.content = format!("{}\n\n{text}", first.content);
+        return Ok(());
+    }
+    let system = MessageBuilder::system(&text).build().map_err(|e| {
+        PhpException::from_class::<crate::error::LLMValidationException>(format!(
+            "Failed to build message: {e}"
+        ))
+    })?;
+    messages.insert(0, system);
+    Ok(())
+}
+
+/// JSON value in a prompted reply: the whole reply, a fenced code block, or
+/// the outermost object or array within surrounding prose
+fn extract_json(content: &str) -> Option<Value> {
+    let trimmed = content.trim();
+    if let Ok(value) = serde_json::from_str(trimmed) {
+        return Some(value);
+    }
+
+    if let Some(start) = trimmed.find("```") {
+        let fenced = &trimmed[start + 3..];
+        // Skip the language tag
+        let body = fenced.split_once('\n').map_or(fenced, |(_, rest)| rest);
+        if let Some(end) = body.find("```") {
+            if let Ok(value) = serde_json::from_str(body[..end].trim()) {
+                return Some(value);
+            }
+        }
+    }
+
+    let start = trimmed.find(['{', '['])?;
+    let close = if trimmed[start..].starts_with('{') {
+        '}'
+    } else {
+        ']'
+    };
+    let end = trimmed.rfind(close).filter(|end| *end > start)?;
+    serde_json::from_str(&trimmed[start..=end]).ok()
+}
+
+/// Parse a reply to a JSON-prompted request and validate it against the schema
+pub(crate) fn parse_reply(content: &str, schema: Option<&Value>) -> Result<Value, String> {
+    let value = extract_json(content).ok_or("Model did not return valid JSON")?;
+    if let Some(schema) = schema {
+        let violations = crate::schema_validation::violations(&value, schema);
+        if !violations.is_empty() {
+            return Err(format!(
+                "Response does not match schema: {}",
+                violations.join("; ")
+            ));
+        }
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_extract_json_from_prose_and_fences() {
+        assert_eq!(extract_json(" {\"a\": 1} "), Some(json!({"a": 1})));
+        assert_eq!(
+            extract_json("Here you go:\n```json\n{\"a\": 1}\n```"),
+            Some(json!({"a": 1}))
+        );
+        assert_eq!(
+            extract_json("The list is [1, 2] as requested."),
+            Some(json!([1, 2]))
+        );
+        assert_eq!(extract_json("no json here"), None);
+    }
+
+    #[test]
+    fn test_parse_reply_checks_schema() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"name": {"type": "string"}},
+            "required": ["name"]
+        });
+        assert_eq!(
+            parse_reply("Sure: {\"name\": \"x\"}", Some(&schema)).unwrap(),
+            json!({"name": "x"})
+        );
+        assert!(parse_reply("{\"name\": 1}", Some(&schema))
+            .unwrap_err()
+            .starts_with("Response does not match schema: /name: "));
+    }
+
+    #[test]
+    fn test_add_instruction_extends_system_prompt() {
+        let mut messages = vec![MessageBuilder::system("Be brief.").build().unwrap()];
+        add_instruction(&mut messages, None).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0]
+            .content
+            .starts_with("Be brief.\n\nRespond with a single JSON value"));
+    }
+}
@@ -0,0 +1,194 @@
+use serde_json::Value;
+
+/// Body of the first markdown code fence, or the text itself
+fn strip_fence(text: &str) -> &str {
+    let Some(start) = text.find("```") else {
+        return text;
+    };
+    let fenced = &text[start + 3..];
+    // Skip the language tag
+    let body = fenced.split_once('\n').map_or(fenced, |(_, rest)| rest);
+    match body.find("```") {
+        Some(end) => &body[..end],
+        None => body,
+    }
+}
+
+/// Rewrite almost-valid JSON: quote unquoted keys, turn single-quoted
+/// strings into double-quoted ones, drop trailing commas, map Python's
+/// True, False and None, and close strings and brackets left open by a
+/// truncated reply
+fn repair(text: &str) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<char> = Vec::new();
+    // Quote character of the string being copied
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+
+    let next_significant = |from: usize| chars[from..].iter().find(|c| !c.is_whitespace());
+    while i < chars.len() {
+        let c = chars[i];
+        if let Some(q) = quote {
+            match c {
+                '\\' if q == '\'' && chars.get(i + 1) == Some(&'\'') => {
+                    out.push('\'');
+                    i += 1;
+                }
+                '\\' => {
+                    out.push('\\');
+                    if let Some(&escaped) = chars.get(i + 1) {
+                        out.push(escaped);
+                        i += 1;
+                    }
+                }
+                '"' if q == '\'' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                c if c == q => {
+                    out.push('"');
+                    quote = None;
+                }
+                c => out.push(c),
+            }
+            i += 1;
+            continue;
+        }
+
+        match c {
+            '"' | '\'' => {
+                out.push('"');
+                quote = Some(c);
+            }
+            '{' | '[' => {
+                out.push(c);
+                open.push(if c == '{' { '}' } else { ']' });
+            }
+            '}' | ']' => {
+                out.push(c);
+                open.pop();
+            }
+            ',' if matches!(next_significant(i + 1), None | Some('}' | ']')) => {}
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || matches!(chars[i], '_' | '$' | '-'))
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                let in_object = open.last() == Some(&'}');
+                if in_object && next_significant(i) == Some(&':') {
+                    out.push('"');
+                    out.push_str(&word);
+                    out.push('"');
+                } else {
+                    out.push_str(match word.as_str() {
+                        "True" => "true",
+                        "False" => "false",
+                        "None" => "null",
+                        word => word,
+                    });
+                }
+                continue;
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+
+    if quote.is_some() {
+        out.push('"');
+    }
+    let trimmed = out.trim_end().trim_end_matches(',').len();
+    out.truncate(trimmed);
+    while let Some(close) = open.pop() {
+        out.push(close);
+    }
+    out
+}
+
+/// Parse JSON a model got almost right: the body of a code fence, or the
+/// object or array after any leading prose, repaired as needed. Text after
+/// the value is ignored.
+pub(crate) fn parse_lenient(text: &str) -> Option<Value> {
+    let text = strip_fence(text).trim();
+    if let Ok(value) = serde_json::from_str(text) {
+        return Some(value);
+    }
+    let start = text.find(['{', '['])?;
+    let repaired = repair(&text[start..]);
+    serde_json::Deserializer::from_str(&repaired)
+        .into_iter::<Value>()
+        .next()?
+        .ok()
+}
+
+/// Object or array a streamed reply has produced so far: the text is
+/// closed where it stops, and a member cut off before its value can be
+/// parsed is left out
+pub(crate) fn parse_partial(text: &str) -> Option<Value> {
+    let mut text = text;
+    loop {
+        if let Some(value) = parse_lenient(text).filter(|v| v.is_object() || v.is_array()) {
+            return Some(value);
+        }
+        // Drop the last member, e.g. `"key": tr` or `"ke`
+        let cut = text.rfind([',', '{', '['])?;
+        text = if text[cut..].starts_with(',') {
+            &text[..cut]
+        } else if cut + 1 < text.len() {
+            &text[..=cut]
+        } else {
+            return None;
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_repairs_common_mistakes() {
+        assert_eq!(
+            parse_lenient("{name: 'Ada', tags: ['a', 'b',],}"),
+            Some(json!({"name": "Ada", "tags": ["a", "b"]}))
+        );
+        assert_eq!(
+            parse_lenient("Here it is:\n```json\n{\"ok\": True, \"err\": None}\n```"),
+            Some(json!({"ok": true, "err": null}))
+        );
+        assert_eq!(
+            parse_lenient("{'quote': 'say \"hi\"', 'it\\'s': 1}"),
+            Some(json!({"quote": "say \"hi\"", "it's": 1}))
+        );
+    }
+
+    #[test]
+    fn test_closes_truncated_output() {
+        assert_eq!(
+            parse_lenient("{\"items\": [{\"id\": 1}, {\"id\": 2, \"name\": \"Bo"),
+            Some(json!({"items": [{"id": 1}, {"id": 2, "name": "Bo"}]}))
+        );
+        assert_eq!(parse_lenient("no json here"), None);
+    }
+
+    #[test]
+    fn test_parses_partial_objects() {
+        assert_eq!(parse_partial("Sure: {\"na"), Some(json!({})));
+        assert_eq!(
+            parse_partial("{\"name\": \"Ad"),
+            Some(json!({"name": "Ad"}))
+        );
+        assert_eq!(
+            parse_partial("{\"name\": \"Ada\", \"tags\": [\"a\", tr"),
+            Some(json!({"name": "Ada", "tags": ["a"]}))
+        );
+        assert_eq!(
+            parse_partial("{\"items\": [{\"id\": 1}, {\"id\":"),
+            Some(json!({"items": [{"id": 1}, {}]}))
+        );
+        assert_eq!(parse_partial("Thinking"), None);
+    }
+}
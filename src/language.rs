@@ -0,0 +1,505 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use octolib::llm::{Message as OctoMessage, MessageBuilder};
+
+/// Local detections below this confidence are handed to the model in 'auto' mode
+pub(crate) const AUTO_THRESHOLD: f64 = 0.6;
+
+/// System prompt for model-based detection
+pub(crate) const PROMPT: &str = "Identify the language of the text below. Reply with JSON \
+only: {\"language\": \"<ISO 639-1 code>\", \"confidence\": <number from 0 to 1>}";
+
+/// Where detectLanguage() may look
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DetectMode {
+    /// Local heuristic, asking the model when it is unsure
+    Auto,
+    Local,
+    Model,
+}
+
+impl DetectMode {
+    pub(crate) fn parse(mode: &str) -> PhpResult<Self> {
+        match mode {
+            "auto" => Ok(Self::Auto),
+            "local" => Ok(Self::Local),
+            "model" => Ok(Self::Model),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown detection mode '{other}', expected 'auto', 'local' or 'model'"
+            ))),
+        }
+    }
+}
+
+/// English names of common languages, for instructions to the model
+const NAMES: &[(&str, &str)] = &[
+    ("ar", "Arabic"),
+    ("cs", "Czech"),
+    ("da", "Danish"),
+    ("de", "German"),
+    ("el", "Greek"),
+    ("en", "English"),
+    ("es", "Spanish"),
+    ("fi", "Finnish"),
+    ("fr", "French"),
+    ("he", "Hebrew"),
+    ("hi", "Hindi"),
+    ("hu", "Hungarian"),
+    ("hy", "Armenian"),
+    ("id", "Indonesian"),
+    ("it", "Italian"),
+    ("ja", "Japanese"),
+    ("ka", "Georgian"),
+    ("ko", "Korean"),
+    ("nl", "Dutch"),
+    ("no", "Norwegian"),
+    ("pl", "Polish"),
+    ("pt", "Portuguese"),
+    ("ro", "Romanian"),
+    ("ru", "Russian"),
+    ("sv", "Swedish"),
+    ("th", "Thai"),
+    ("tr", "Turkish"),
+    ("uk", "Ukrainian"),
+    ("vi", "Vietnamese"),
+    ("zh", "Chinese"),
+];
+
+/// Languages detect() recognizes by their script alone
+const SCRIPT_LANGUAGES: &[&str] = &[
+    "ar", "el", "he", "hi", "hy", "ja", "ka", "ko", "ru", "th", "uk", "zh",
+];
+
+/// Frequent function words of Latin-script languages
+const STOPWORDS: &[(&str, &[&str])] = &[
+    (
+        "en",
+        &[
+            "the", "and", "is", "are", "of", "to", "that", "it", "you", "was", "for", "with",
+            "this", "have", "not", "be",
+        ],
+    ),
+    (
+        "es",
+        &[
+            "el", "los", "las", "que", "y", "es", "por", "una", "para", "con", "no", "está", "del",
+            "pero", "como", "muy",
+        ],
+    ),
+    (
+        "fr",
+        &[
+            "le", "les", "des", "et", "est", "que", "une", "pour", "pas", "dans", "avec", "vous",
+            "je", "il", "sur", "du",
+        ],
+    ),
+    (
+        "de",
+        &[
+            "der", "die", "das", "und", "ist", "nicht", "ich", "ein", "eine", "zu", "mit", "den",
+            "sie", "auf", "für", "auch",
+        ],
+    ),
+    (
+        "it",
+        &[
+            "il", "lo", "gli", "che", "e", "è", "di", "per", "non", "sono", "della", "questo",
+            "ho", "un", "anche", "molto",
+        ],
+    ),
+    (
+        "pt",
+        &[
+            "o", "os", "que", "e", "é", "não", "um", "uma", "para", "com", "do", "da", "em",
+            "você", "mas", "muito",
+        ],
+    ),
+    (
+        "nl",
+        &[
+            "de", "het", "een", "en", "is", "niet", "van", "dat", "ik", "je", "op", "te", "zijn",
+            "met", "voor", "ook",
+        ],
+    ),
+    (
+        "sv",
+        &[
+            "och", "är", "att", "det", "som", "en", "på", "inte", "jag", "med", "för", "har", "av",
+            "den", "till", "om",
+        ],
+    ),
+    (
+        "pl",
+        &[
+            "i", "w", "nie", "się", "na", "że", "jest", "to", "z", "do", "jak", "co", "ale", "tak",
+            "jestem", "czy",
+        ],
+    ),
+    (
+        "tr",
+        &[
+            "ve", "bir", "bu", "da", "de", "için", "ile", "ne", "çok", "değil", "ben", "mi",
+            "olarak", "ama", "gibi", "var",
+        ],
+    ),
+];
+
+/// Writing systems that identify a language (or decide how to tell them apart)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Cyrillic,
+    Greek,
+    Arabic,
+    Hebrew,
+    Devanagari,
+    Thai,
+    Georgian,
+    Armenian,
+    Hangul,
+    Kana,
+    Han,
+}
+
+fn script_of(c: char) -> Option<Script> {
+    let script = match c as u32 {
+        0x0041..=0x024F if c.is_alphabetic() => Script::Latin,
+        0x0370..=0x03FF => Script::Greek,
+        0x0400..=0x04FF => Script::Cyrillic,
+        0x0530..=0x058F => Script::Armenian,
+        0x0590..=0x05FF => Script::Hebrew,
+        0x0600..=0x06FF => Script::Arabic,
+        0x0900..=0x097F => Script::Devanagari,
+        0x0E00..=0x0E7F => Script::Thai,
+        0x10A0..=0x10FF => Script::Georgian,
+        0x1100..=0x11FF | 0xAC00..=0xD7AF => Script::Hangul,
+        0x3040..=0x30FF => Script::Kana,
+        0x4E00..=0x9FFF => Script::Han,
+        _ => return None,
+    };
+    Some(script)
+}
+
+/// Language guessed by the local heuristic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Guess {
+    pub code: &'static str,
+    pub confidence: f64,
+}
+
+fn round(confidence: f64) -> f64 {
+    (confidence.clamp(0.0, 1.0) * 100.0).round() / 100.0
+}
+
+/// Detect the language from its script, and for Latin script from function
+/// words. None when the text gives nothing to go on.
+pub(crate) fn detect(text: &str) -> Option<Guess> {
+    let mut counts: Vec<(Script, usize)> = Vec::new();
+    for script in text.chars().filter_map(script_of) {
+        match counts.iter_mut().find(|(s, _)| *s == script) {
+            Some((_, n)) => *n += 1,
+            None => counts.push((script, 1)),
+        }
+    }
+    let letters: usize = counts.iter().map(|(_, n)| n).sum();
+    let (script, count) = counts.iter().copied().max_by_key(|(_, n)| *n)?;
+    let share = count as f64 / letters as f64;
+
+    let code = match script {
+        Script::Latin => {
+            return detect_latin(text).map(|g| Guess {
+                code: g.code,
+                confidence: round(g.confidence * share),
+            })
+        }
+        Script::Cyrillic if text.chars().any(|c| "іїєґІЇЄҐ".contains(c)) => "uk",
+        Script::Cyrillic => "ru",
+        Script::Greek => "el",
+        Script::Arabic => "ar",
+        Script::Hebrew => "he",
+        Script::Devanagari => "hi",
+        Script::Thai => "th",
+        Script::Georgian => "ka",
+        Script::Armenian => "hy",
+        Script::Hangul => "ko",
+        // Japanese mixes kana with kanji; Han alone is Chinese
+        Script::Kana => "ja",
+        Script::Han if counts.iter().any(|(s, _)| *s == Script::Kana) => "ja",
+        Script::Han => "zh",
+    };
+    Some(Guess {
+        code,
+        confidence: round(share),
+    })
+}
+
+fn detect_latin(text: &str) -> Option<Guess> {
+    let lower = text.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    let mut scores: Vec<(&'static str, usize)> = STOPWORDS
+        .iter()
+        .map(|(code, list)| (*code, words.iter().filter(|w| list.contains(*w)).count()))
+        .collect();
+    scores.sort_by(|a, b| b.1.cmp(&a.1));
+    let (code, best) = scores[0];
+    if best == 0 {
+        return None;
+    }
+    let second = scores[1].1;
+
+    // How clearly the best language wins, scaled down for little evidence
+    let margin = best as f64 / (best + second) as f64;
+    let support = (best as f64 / 4.0).min(1.0);
+    Some(Guess {
+        code,
+        confidence: margin * support,
+    })
+}
+
+/// Language reported by the model; None when the reply has no usable code
+pub(crate) fn parse_model_reply(content: &str) -> Option<(String, f64)> {
+    let value = crate::json_fallback::parse_reply(content, None).ok()?;
+    let code = value["language"].as_str()?.trim().to_lowercase();
+    if code.len() != 2 || !code.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
+    }
+    let confidence = value["confidence"].as_f64().unwrap_or(1.0);
+    Some((code, round(confidence)))
+}
+
+/// Language replies must be in (see LLM::setOutputLanguage())
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OutputLanguage {
+    /// Language tag as given, e.g. 'de' or 'pt-BR'
+    tag: String,
+    /// Whether complete() checks the reply and retries once on a mismatch
+    validate: bool,
+}
+
+impl OutputLanguage {
+    pub(crate) fn new(tag: &str, validate: bool) -> PhpResult<Self> {
+        let tag = tag.trim().replace('_', "-");
+        let (primary, region) = tag.split_once('-').unwrap_or((&tag, ""));
+        let valid = primary.len() == 2
+            && primary.chars().all(|c| c.is_ascii_alphabetic())
+            && region.chars().all(|c| c.is_ascii_alphanumeric());
+        if !valid {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Invalid language code '{tag}', expected an ISO 639-1 code such as 'de' or 'pt-BR'"
+            )));
+        }
+        let tag = match region {
+            "" => primary.to_lowercase(),
+            region => format!("{}-{}", primary.to_lowercase(), region.to_uppercase()),
+        };
+        Ok(Self { tag, validate })
+    }
+
+    fn code(&self) -> &str {
+        self.tag.split('-').next().unwrap_or(&self.tag)
+    }
+
+    /// How the language is named to the model
+    fn describe(&self) -> String {
+        match NAMES.iter().find(|(code, _)| *code == self.code()) {
+            Some((_, name)) => format!("{name} ({})", self.tag),
+            None => format!("the language with code '{}'", self.tag),
+        }
+    }
+
+    fn instruction(&self) -> String {
+        format!(
+            "Always reply in {}, whatever the language of the messages, unless explicitly \
+             asked to translate into another language.",
+            self.describe()
+        )
+    }
+
+    /// Add the instruction to the first system message, or as a system
+    /// message of its own
+    pub(crate) fn instruct(&self, messages: &mut Vec<OctoMessage>) -> PhpResult<()> {
+        let instruction = self.instruction();
+        if let Some(first) = messages.first_mut().filter(|m| m.role == "system") {
+            first.content = format!("{}\n\n{instruction}", first.content);
+            return Ok(());
+        }
+        let system = MessageBuilder::system(&instruction).build().map_err(|e| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Failed to build message: {e}"
+            ))
+        })?;
+        messages.insert(0, system);
+        Ok(())
+    }
+
+    /// Language a reply was confidently detected in, if validation is on and
+    /// it is not the requested one. Languages the local heuristic can't
+    /// recognize are never reported.
+    pub(crate) fn mismatch(&self, reply: &str) -> Option<&'static str> {
+        let code = self.code();
+        let detectable =
+            SCRIPT_LANGUAGES.contains(&code) || STOPWORDS.iter().any(|(c, _)| *c == code);
+        if !self.validate || !detectable {
+            return None;
+        }
+        detect(reply)
+            .filter(|g| g.confidence >= AUTO_THRESHOLD && g.code != code)
+            .map(|g| g.code)
+    }
+
+    /// Follow-up asking for the reply again in the right language
+    pub(crate) fn correction(&self) -> String {
+        format!(
+            "Your previous reply was not in {}. Give the same reply again, in {} only.",
+            self.describe(),
+            self.describe()
+        )
+    }
+}
+
+/// Result of LLM::detectLanguage()
+#[php_class]
+#[derive(Clone)]
+pub struct LanguageDetection {
+    language: String,
+    confidence: f64,
+    source: &'static str,
+}
+
+// Internal constructor - not exposed to PHP
+impl LanguageDetection {
+    pub(crate) fn local(guess: Guess) -> Self {
+        Self {
+            language: guess.code.to_string(),
+            confidence: guess.confidence,
+            source: "local",
+        }
+    }
+
+    pub(crate) fn model(language: String, confidence: f64) -> Self {
+        Self {
+            language,
+            confidence,
+            source: "model",
+        }
+    }
+}
+
+#[php_impl]
+impl LanguageDetection {
+    /// ISO 639-1 code, e.g. 'en'
+    pub fn get_language(&self) -> String {
+        self.language.clone()
+    }
+
+    /// Confidence from 0 to 1
+    pub fn get_confidence(&self) -> f64 {
+        self.confidence
+    }
+
+    /// 'local' or 'model'
+    pub fn get_source(&self) -> String {
+        self.source.to_string()
+    }
+
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        arr.insert("language", self.language.clone())?;
+        arr.insert("confidence", self.confidence)?;
+        arr.insert("source", self.source)?;
+        Ok(arr.into_zval(false)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(text: &str) -> Option<&'static str> {
+        detect(text).map(|g| g.code)
+    }
+
+    #[test]
+    fn test_detects_latin_languages_by_function_words() {
+        assert_eq!(code("The weather is nice and the sun is out"), Some("en"));
+        assert_eq!(
+            code("Der Hund ist nicht auf dem Sofa und die Katze auch"),
+            Some("de")
+        );
+        assert_eq!(
+            code("Je pense que les enfants sont dans le jardin avec vous"),
+            Some("fr")
+        );
+        assert_eq!(
+            code("El perro está en la casa con los niños y no quiere salir"),
+            Some("es")
+        );
+        assert_eq!(code("xyzzy plugh"), None);
+    }
+
+    #[test]
+    fn test_detects_by_script() {
+        assert_eq!(code("Привет, как дела?"), Some("ru"));
+        assert_eq!(code("Привіт, як справи? Її немає"), Some("uk"));
+        assert_eq!(code("こんにちは、元気ですか"), Some("ja"));
+        assert_eq!(code("你好，你今天怎么样"), Some("zh"));
+        assert_eq!(code("안녕하세요"), Some("ko"));
+        assert_eq!(detect("Καλημέρα").unwrap().confidence, 1.0);
+    }
+
+    #[test]
+    fn test_short_text_has_low_confidence() {
+        let guess = detect("the").unwrap();
+        assert_eq!(guess.code, "en");
+        assert!(guess.confidence < AUTO_THRESHOLD);
+    }
+
+    #[test]
+    fn test_parse_model_reply() {
+        assert_eq!(
+            parse_model_reply("{\"language\": \"PT\", \"confidence\": 0.91}"),
+            Some(("pt".to_string(), 0.91))
+        );
+        assert_eq!(parse_model_reply("{\"language\": \"Portuguese\"}"), None);
+    }
+
+    #[test]
+    fn test_output_language_tags() {
+        assert_eq!(OutputLanguage::new(" pt_br", false).unwrap().tag, "pt-BR");
+        let german = OutputLanguage::new("DE", true).unwrap();
+        assert!(german.instruction().contains("German (de)"));
+        assert!(OutputLanguage::new("xx", false)
+            .unwrap()
+            .instruction()
+            .contains("code 'xx'"));
+    }
+
+    #[test]
+    fn test_output_language_mismatch() {
+        let reply = "The order was shipped and it is on the way to you.";
+        let german = OutputLanguage::new("de", true).unwrap();
+        assert_eq!(german.mismatch(reply), Some("en"));
+        assert_eq!(
+            german.mismatch("Die Bestellung ist mit der Post auf dem Weg und wird bald da sein."),
+            None
+        );
+        assert_eq!(
+            OutputLanguage::new("de", false).unwrap().mismatch(reply),
+            None
+        );
+        // The heuristic can't recognize Finnish, so nothing is reported
+        assert_eq!(
+            OutputLanguage::new("fi", true).unwrap().mismatch(reply),
+            None
+        );
+    }
+}
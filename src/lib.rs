@@ -1,32 +1,126 @@
 #![cfg_attr(windows, feature(abi_vectorcall))]
 
+mod attribution;
+mod backend;
+mod benchmark;
+mod budget;
+mod cache;
+mod citations;
+mod completion_result;
+mod compress;
+mod config;
+mod context_window;
+mod conversation;
 mod convert;
+mod decide;
+mod diff;
+mod embeddings;
 mod error;
+mod field_stream;
+mod future;
+mod image_resize;
+mod intern;
+mod json_fallback;
+mod json_repair;
+mod language;
 mod llm_class;
+mod logger;
+mod manticore;
 mod message;
+mod message_repair;
+mod params;
+mod rag;
+mod rate_limit;
+mod reasoning;
+mod redact;
+mod reflection;
+mod refusal;
+mod retry;
+mod runtime;
+mod schema;
+mod schema_limits;
+mod schema_validation;
+mod semantic_cache;
+mod serialization;
+mod speculative;
+mod split;
+mod stream;
 mod structured_builder;
+mod templates;
+mod throttle;
+mod title;
+mod tokenizer;
+mod tool_binding;
 mod tool_builder;
+mod tool_registry;
+mod tool_select;
+mod transcript;
+mod translate;
+mod trim;
+mod usage_export;
+mod vision;
+mod webhook;
 
 use ext_php_rs::prelude::*;
 
+/// Module startup: register INI directives
+extern "C" fn startup(_type: i32, module_number: i32) -> i32 {
+    runtime::register_ini(module_number);
+    0
+}
+
+/// Request shutdown: drop per-request state holding PHP values
+extern "C" fn request_shutdown(_type: i32, _module_number: i32) -> i32 {
+    redact::reset();
+    logger::reset();
+    tool_binding::reset();
+    tool_registry::reset();
+    usage_export::reset();
+    0
+}
+
 /// Module entry point
 #[php_module]
 pub fn get_module(module: ModuleBuilder) -> ModuleBuilder {
     module
+        .startup_function(startup)
+        .request_shutdown_function(request_shutdown)
+        .function(wrap_function!(attribution::llm_usage_by_tag))
+        .interface::<completion_result::PhpInterfaceCompletionResult>()
         .class::<llm_class::LLM>()
         .class::<llm_class::Response>()
         .class::<llm_class::Usage>()
+        .class::<schema::Schema>()
         .class::<structured_builder::StructuredBuilder>()
         .class::<structured_builder::StructuredResponse>()
         .class::<tool_builder::ToolBuilder>()
         .class::<tool_builder::Tool>()
         .class::<tool_builder::ToolCall>()
         .class::<tool_builder::ToolResponse>()
+        .class::<tool_registry::ToolRegistry>()
         .class::<message::Message>()
         .class::<message::MessageCollection>()
+        .class::<message::MessageSnapshot>()
+        .class::<message::MessageIterator>()
+        .class::<conversation::Conversation>()
+        .class::<stream::StreamIterator>()
+        .class::<future::LLMFuture>()
+        .class::<speculative::SpeculativeResponse>()
+        .class::<language::LanguageDetection>()
+        .class::<translate::Translation>()
+        .class::<embeddings::Embeddings>()
+        .class::<embeddings::EmbeddingResponse>()
+        .class::<manticore::ManticoreVectorStore>()
+        .class::<rag::RAG>()
+        .class::<rag::RAGAnswer>()
+        .class::<tokenizer::Tokenizer>()
+        .class::<diff::ResponseDiff>()
+        .class::<webhook::Webhook>()
+        .class::<budget::BudgetGuard>()
         .class::<error::LLMException>()
         .class::<error::LLMConnectionException>()
         .class::<error::LLMValidationException>()
         .class::<error::LLMStructuredOutputException>()
         .class::<error::LLMToolCallException>()
+        .class::<error::LLMBudgetExceededException>()
 }
@@ -1,13 +1,44 @@
+use ext_php_rs::boxed::ZBox;
 use ext_php_rs::convert::IntoZval;
 use ext_php_rs::prelude::*;
-use ext_php_rs::types::{ZendClassObject, ZendHashTable as PhpArray, Zval};
-use octolib::llm::{ChatCompletionParams, ProviderFactory, TokenUsage};
+use ext_php_rs::types::{ZendCallable, ZendClassObject, ZendHashTable as PhpArray, Zval};
+use octolib::llm::{Message as OctoMessage, MessageBuilder, ProviderExchange, TokenUsage};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 
+use crate::attribution::{parse_tags, Attribution};
+use crate::backend::Backend;
+use crate::budget::BudgetGuard;
+use crate::cache::ResponseCache;
+use crate::citations::{Citation, Document};
+use crate::compress::Compression;
+use crate::config::ProviderConfig;
+use crate::context_window::AutoMaxTokens;
 use crate::convert::php_to_messages;
+use crate::embeddings::Embeddings;
 use crate::error::IntoPhpException;
+use crate::future::LLMFuture;
+use crate::language::{DetectMode, LanguageDetection, OutputLanguage};
+use crate::message::{ImagePart, Message, MessageCollection};
+use crate::params::{parse_stop, remap_system_role, ParamPolicy, Penalties, ToolChoice};
+use crate::rate_limit::RateLimitInfo;
+use crate::reasoning::extract_reasoning;
+use crate::refusal::detect_refusal;
+use crate::retry::RetryPolicy;
+use crate::runtime::runtime;
+use crate::semantic_cache::{split_prompt, SemanticCache};
+use crate::speculative::{SpeculativeResponse, Winner};
+use crate::split::MapReduceOptions;
+use crate::stream::{StreamIterator, StreamParams, StreamTarget};
+use crate::templates::{PromptTemplates, TemplateKind};
+use crate::throttle::{limiter_key, RateLimit};
 use crate::tool_builder::Tool;
+use crate::translate::{TranslateOptions, Translation};
+use crate::vision::{AnnotateOptions, Coordinates};
 
 /// Get the environment variable prefix for a provider from a model string.
 /// Maps "provider:model" → "PROVIDER" with special cases for aliases.
@@ -24,17 +55,101 @@ pub(crate) fn get_env_prefix(model: &str) -> String {
     }
 }
 
+/// Per-instance settings inherited by the builders created from an LLM
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CallSettings {
+    pub templates: PromptTemplates,
+    pub attribution: Attribution,
+    pub config: ProviderConfig,
+    pub param_policy: ParamPolicy,
+    pub retry: RetryPolicy,
+    pub compression: Option<Compression>,
+    /// Whether schemas are simplified before sending
+    pub simplify_schemas: bool,
+    pub output_language: Option<OutputLanguage>,
+    /// Whether responses keep the raw provider exchange, see setDebug()
+    pub debug: bool,
+    /// Whether invalid role sequences are fixed, see repairMessages()
+    pub repair_messages: bool,
+    /// Replaces the fixed max_tokens, see setMaxTokensAuto()
+    pub max_tokens_auto: Option<AutoMaxTokens>,
+}
+
+impl CallSettings {
+    /// Repair invalid role sequences, compress long messages, add the output
+    /// language instruction, apply the prompt templates, then give system
+    /// messages the role the model accepts
+    pub(crate) fn shape_messages(
+        &self,
+        model: &str,
+        messages: &mut Vec<OctoMessage>,
+    ) -> PhpResult<()> {
+        if self.repair_messages {
+            let dropped = crate::message_repair::repair(messages);
+            if dropped > 0 {
+                crate::logger::warn(&format!(
+                    "Dropped {dropped} tool result(s) without a matching tool call"
+                ));
+            }
+        }
+        if let Some(compression) = &self.compression {
+            compression.apply(messages);
+        }
+        if let Some(language) = &self.output_language {
+            language.instruct(messages)?;
+        }
+        self.templates.apply(model, messages);
+        remap_system_role(model, messages);
+        Ok(())
+    }
+
+    /// max_tokens of a request: `configured`, or the room left in the
+    /// context window after the prompt and `extra` when setMaxTokensAuto()
+    /// is on
+    pub(crate) fn max_tokens_for(
+        &self,
+        model: &str,
+        messages: &[OctoMessage],
+        extra: &str,
+        configured: u32,
+    ) -> PhpResult<u32> {
+        match &self.max_tokens_auto {
+            Some(auto) => auto.resolve(model, messages, extra),
+            None => Ok(configured),
+        }
+    }
+}
+
+impl CallSettings {
+    /// Where non-streaming requests with `params` go, see Backend
+    pub(crate) fn backend(
+        &self,
+        rt: &Runtime,
+        model: &str,
+        params: &mut StreamParams,
+    ) -> PhpResult<Backend> {
+        Backend::resolve(rt, model, &self.config, self.param_policy, params)
+    }
+}
+
 /// Main LLM class for interacting with language models
 #[php_class]
+#[derive(Clone)]
 #[allow(clippy::upper_case_acronyms)]
 pub struct LLM {
     model: String,
     temperature: f32,
     max_tokens: u32,
     top_p: f32,
+    top_k: u32,
     frequency_penalty: f32,
     presence_penalty: f32,
-    runtime: Arc<Runtime>,
+    stop: Vec<String>,
+    title_model: Option<String>,
+    seed: Option<i64>,
+    cache: Option<ResponseCache>,
+    semantic_cache: Option<SemanticCache>,
+    settings: CallSettings,
 }
 
 #[php_impl]
@@ -42,92 +157,687 @@ impl LLM {
     /// Create a new LLM instance
     #[php(constructor)]
     pub fn __construct(model: String, options: Option<&PhpArray>) -> PhpResult<Self> {
-        // Set provider env vars from options before anything touches octolib
-        if let Some(opts) = options {
-            let prefix = get_env_prefix(&model);
-            if let Some(api_key) = opts.get("api_key").and_then(|v| v.string()) {
-                unsafe {
-                    std::env::set_var(format!("{prefix}_API_KEY"), &api_key);
-                }
-            }
-            if let Some(base_url) = opts.get("base_url").and_then(|v| v.string()) {
-                unsafe {
-                    std::env::set_var(format!("{prefix}_API_URL"), &base_url);
-                }
-            }
-        }
-
-        let runtime = Arc::new(Runtime::new().map_err(|e| {
-            PhpException::from_class::<crate::error::LLMException>(format!(
-                "Failed to create runtime: {e}"
-            ))
-        })?);
+        let config = ProviderConfig::new(options)?;
 
         Ok(Self {
             model,
             temperature: 0.7,
             max_tokens: 1000,
             top_p: 1.0,
+            top_k: 50,
             frequency_penalty: 0.0,
             presence_penalty: 0.0,
-            runtime,
+            stop: Vec::new(),
+            title_model: None,
+            seed: None,
+            cache: None,
+            semantic_cache: None,
+            settings: CallSettings {
+                config,
+                ..CallSettings::default()
+            },
         })
     }
 
     /// Complete a conversation
     pub fn complete(&self, messages: &Zval) -> PhpResult<Response> {
-        let rt = self.runtime.clone();
+        let messages_vec = self.prepare_completion(messages)?;
+        let response = self.complete_prepared(&messages_vec)?;
+        let Some(language) = &self.settings.output_language else {
+            return Ok(response);
+        };
+        if language.mismatch(&response.content).is_none() {
+            return Ok(response);
+        }
 
-        let messages_vec = php_to_messages(messages)?;
+        // One retry, showing the model its reply in the wrong language
+        let build_err = |e: octolib::errors::MessageError| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Failed to build message: {e}"
+            ))
+        };
+        let mut retry_messages = messages_vec;
+        retry_messages.push(
+            MessageBuilder::assistant(&response.content)
+                .build()
+                .map_err(build_err)?,
+        );
+        retry_messages.push(
+            MessageBuilder::user(&language.correction())
+                .build()
+                .map_err(build_err)?,
+        );
+        // A second request: the budget and rate limit apply to it as well
+        self.settings.attribution.check()?;
+        let mut retried = self.complete_prepared(&retry_messages)?;
+        let mut usage = response.usage;
+        usage.add(&retried.usage);
+        retried.usage = usage;
+        Ok(retried)
+    }
 
-        let (provider, model) = rt
-            .block_on(async { ProviderFactory::get_provider_for_model(&self.model) })
-            .map_err(|e| e.into_php_exception())?;
+    /// Start a completion in the background and return immediately.
+    /// Several futures can run concurrently; collect them with wait().
+    pub fn complete_async(&self, messages: &Zval) -> PhpResult<LLMFuture> {
+        let messages_vec = self.prepare_completion(messages)?;
+        let (handle, model) = self.spawn_completion(runtime()?, messages_vec, None)?;
+        Ok(LLMFuture::new(
+            handle,
+            model,
+            self.settings.attribution.clone(),
+        ))
+    }
 
-        let params = ChatCompletionParams::new(
-            &messages_vec,
-            &model,
-            self.temperature,
-            self.top_p,
-            50, // top_k
-            self.max_tokens,
-        );
+    /// Send the same conversation to each of `models`, `iterations` times
+    /// (default 1), all requests at once, and compare them. Returns
+    /// ['model' => ['runs', 'failures', 'latency_ms' => ['min', 'avg', 'p50',
+    /// 'max'], 'tokens' => ['input', 'output'], 'cost', 'quality',
+    /// 'responses']], with tokens and cost averaged per run, 'quality' left
+    /// null for your own scoring and a Response or the exception for each
+    /// run. This instance's settings apply to every model.
+    pub fn benchmark(
+        &self,
+        models: Vec<String>,
+        messages: &Zval,
+        iterations: Option<i64>,
+    ) -> PhpResult<Zval> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let iterations = iterations.unwrap_or(1);
+        if iterations < 1 {
+            return Err(invalid("Iterations must be at least 1".to_string()));
+        }
+        if models.is_empty() {
+            return Err(invalid("benchmark() needs at least one model".to_string()));
+        }
+        for (i, model) in models.iter().enumerate() {
+            if models[..i].contains(model) {
+                return Err(invalid(format!("Model '{model}' is listed twice")));
+            }
+        }
 
-        let response = rt
-            .block_on(async { provider.chat_completion(params).await })
-            .map_err(|e| e.into_php_exception())?;
+        // Invalid models and parameters throw here, before any request is sent
+        let mut prepared = Vec::new();
+        for model in &models {
+            let llm = Self {
+                model: model.clone(),
+                ..self.clone()
+            };
+            let messages_vec = llm.prepare_completion(messages)?;
+            prepared.push((llm, messages_vec));
+        }
 
-        let usage = response.exchange.usage.unwrap_or(TokenUsage {
-            input_tokens: 0,
-            output_tokens: 0,
-            reasoning_tokens: 0,
-            total_tokens: 0,
-            cache_read_tokens: 0,
-            cache_write_tokens: 0,
-            cost: None,
-            request_time_ms: None,
-        });
+        let rt = runtime()?;
+        let mut tasks = Vec::new();
+        for (llm, messages_vec) in &prepared {
+            let mut runs = Vec::new();
+            for _ in 0..iterations {
+                let start = Instant::now();
+                let (handle, model) = llm.spawn_completion(rt, messages_vec.clone(), None)?;
+                let timed = rt.spawn(async move {
+                    let result = handle.await;
+                    (result, start.elapsed())
+                });
+                runs.push((timed, model));
+            }
+            tasks.push((llm.model.clone(), runs));
+        }
 
-        Ok(Response::new(
-            response.content,
-            usage,
+        let mut results = PhpArray::new();
+        for (model_name, runs) in tasks {
+            let mut measured = Vec::new();
+            let mut failures = 0;
+            let mut responses = PhpArray::new();
+            for (timed, model) in runs {
+                let (result, elapsed) = rt
+                    .block_on(timed)
+                    .map_err(|e| PhpException::default(format!("Benchmark task failed: {e}")))?;
+                let result =
+                    result.unwrap_or_else(|e| Err(anyhow::anyhow!("Completion task failed: {e}")));
+                match result {
+                    Ok(completion) => {
+                        measured.push(crate::benchmark::Run {
+                            latency_ms: elapsed.as_secs_f64() * 1000.0,
+                            usage: completion.usage.clone(),
+                        });
+                        responses.push(
+                            Response::from_completion(
+                                completion,
+                                model,
+                                &self.settings.attribution,
+                            )
+                            .into_zval(false)?,
+                        )?;
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        responses.push(crate::error::exception_object(&e)?)?;
+                    }
+                }
+            }
+
+            let mut entry = PhpArray::new();
+            let summary = crate::benchmark::summary(&measured, failures);
+            for (key, value) in summary.as_object().into_iter().flatten() {
+                entry.insert(key.as_str(), crate::convert::json_value_to_php(value)?)?;
+            }
+            entry.insert("responses", responses)?;
+            results.insert(model_name.as_str(), entry)?;
+        }
+        Ok(results.into_zval(false)?)
+    }
+
+    /// Run several conversations concurrently, at most `concurrency` at a
+    /// time. Returns a list with, in input order, a Response or the exception
+    /// for each conversation that failed.
+    pub fn complete_many(
+        &self,
+        conversations: &PhpArray,
+        concurrency: Option<i64>,
+    ) -> PhpResult<Zval> {
+        let rt = runtime()?;
+        let limit = Arc::new(Semaphore::new(concurrency.unwrap_or(4).max(1) as usize));
+
+        // Invalid conversations throw here, before any request is sent
+        let mut prepared = Vec::new();
+        for (_, conversation) in conversations.iter() {
+            prepared.push(self.prepare_completion(conversation)?);
+        }
+
+        let mut tasks = Vec::new();
+        for messages_vec in prepared {
+            tasks.push(self.spawn_completion(rt, messages_vec, Some(limit.clone()))?);
+        }
+
+        let mut results = PhpArray::new();
+        for (handle, model) in tasks {
+            let result = match rt.block_on(handle) {
+                Ok(result) => result,
+                Err(e) => Err(anyhow::anyhow!("Completion task failed: {e}")),
+            };
+            match result {
+                Ok(completion) => results.push(
+                    Response::from_completion(completion, model, &self.settings.attribution)
+                        .into_zval(false)?,
+                )?,
+                Err(e) => results.push(crate::error::exception_object(&e)?)?,
+            }
+        }
+        Ok(results.into_zval(false)?)
+    }
+
+    /// Generate a short title for a conversation, using the model set with
+    /// setTitleModel() or this instance's model. Long histories are cut down
+    /// to their opening messages.
+    pub fn title(&self, messages: &Zval) -> PhpResult<String> {
+        let transcript = crate::title::transcript(&php_to_messages(messages)?);
+        if transcript.is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Cannot title a conversation without user or assistant messages".to_string(),
+            ));
+        }
+
+        let model = self
+            .title_model
+            .clone()
+            .unwrap_or_else(|| self.model.clone());
+        let response = self.helper_completion(
+            model,
+            crate::title::PROMPT,
+            &transcript,
+            crate::title::MAX_TOKENS,
+        )?;
+        Ok(crate::title::clean(&response.get_content()))
+    }
+
+    /// Detect the language of a text, returning its ISO 639-1 code and a
+    /// confidence. Mode 'auto' (default) tries a local heuristic first and
+    /// asks the model only when it is unsure; 'local' never sends a request,
+    /// 'model' always does.
+    pub fn detect_language(
+        &self,
+        text: String,
+        mode: Option<String>,
+    ) -> PhpResult<LanguageDetection> {
+        let mode = DetectMode::parse(mode.as_deref().unwrap_or("auto"))?;
+        let local = match mode {
+            DetectMode::Model => None,
+            _ => crate::language::detect(&text),
+        };
+        match (mode, local) {
+            (DetectMode::Local, Some(guess)) => return Ok(LanguageDetection::local(guess)),
+            (DetectMode::Local, None) => {
+                return Err(PhpException::from_class::<
+                    crate::error::LLMValidationException,
+                >(
+                    "Cannot detect the language of this text locally".to_string(),
+                ))
+            }
+            (DetectMode::Auto, Some(guess))
+                if guess.confidence >= crate::language::AUTO_THRESHOLD =>
+            {
+                return Ok(LanguageDetection::local(guess))
+            }
+            _ => {}
+        }
+
+        let response =
+            self.helper_completion(self.model.clone(), crate::language::PROMPT, &text, 32)?;
+        match crate::language::parse_model_reply(&response.get_content()) {
+            Some((language, confidence)) => Ok(LanguageDetection::model(language, confidence)),
+            // An unsure local guess beats no answer
+            None => local.map(LanguageDetection::local).ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMException>(
+                    "Model did not return a language code".to_string(),
+                )
+            }),
+        }
+    }
+
+    /// Translate a text into `targetLang`. Long texts are translated in
+    /// chunks split at paragraph or sentence boundaries and reassembled.
+    /// Options: 'glossary' (term => required translation), 'source_lang'
+    /// and 'chunk_size' (characters, default 2000).
+    pub fn translate(
+        &self,
+        text: String,
+        target_lang: String,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<Translation> {
+        let options = TranslateOptions::parse(options)?;
+        if text.trim().is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Cannot translate an empty text".to_string()));
+        }
+
+        let chunks = crate::translate::chunks(&text, options.chunk_chars);
+        let mut translation = String::with_capacity(text.len());
+        let mut usage: Option<Usage> = None;
+        let mut model = self.model.clone();
+        let mut missing_terms = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            if chunk.trim().is_empty() {
+                translation.push_str(chunk);
+                continue;
+            }
+            let terms = crate::translate::relevant_terms(&options.glossary, chunk);
+
+            // One corrective retry for an unparseable reply or left-out glossary terms
+            let mut missed = Vec::new();
+            let mut retried = false;
+            let translated = loop {
+                let system = crate::translate::prompt(
+                    &target_lang,
+                    options.source_lang.as_deref(),
+                    &terms,
+                    &missed,
+                );
+                let response = self.helper_completion(
+                    self.model.clone(),
+                    &system,
+                    chunk.trim(),
+                    self.max_tokens,
+                )?;
+                match &mut usage {
+                    Some(total) => total.add(&response.usage),
+                    None => usage = Some(response.usage.clone()),
+                }
+                model = response.model.clone();
+
+                match crate::translate::parse_reply(&response.content) {
+                    Ok(translated) => {
+                        let left_out = crate::translate::missing_terms(&terms, &translated);
+                        if left_out.is_empty() || retried {
+                            missing_terms.extend(left_out.iter().map(|(term, _)| term.clone()));
+                            break translated;
+                        }
+                        missed = left_out;
+                    }
+                    Err(e) if retried => {
+                        return Err(PhpException::from_class::<
+                            crate::error::LLMStructuredOutputException,
+                        >(format!(
+                            "Translation of chunk {} of {} failed: {e}",
+                            i + 1,
+                            chunks.len()
+                        )))
+                    }
+                    Err(_) => {}
+                }
+                retried = true;
+            };
+            translation.push_str(&crate::translate::reassemble(chunk, &translated));
+        }
+
+        missing_terms.sort();
+        missing_terms.dedup();
+        Ok(Translation::new(
+            translation,
+            usage.expect("at least one chunk is translated"),
             model,
-            response.finish_reason.unwrap_or_else(|| "stop".to_string()),
+            chunks.len(),
+            missing_terms,
         ))
     }
 
-    /// Create a builder for structured output
-    pub fn structured(&self, schema: Option<String>) -> PhpResult<StructuredBuilder> {
-        Ok(StructuredBuilder::new(
-            self.model.clone(),
-            self.temperature,
-            self.max_tokens,
-            self.top_p,
-            schema,
-            self.runtime.clone(),
+    /// Apply `instruction` to a text too long for one request: the text is
+    /// split into chunks of about 'chunk_tokens' tokens (default 4000), each
+    /// chunk is processed on its own (map), and the results are combined
+    /// into one answer (reduce), in groups first when they are too long
+    /// together. Option 'reduce_instruction' replaces the combining prompt.
+    /// A text that fits in one chunk takes a single request. The response
+    /// is the final one, with the usage of every request.
+    pub fn map_reduce(
+        &self,
+        text: String,
+        instruction: String,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<Response> {
+        let options = MapReduceOptions::parse(options)?;
+        if text.trim().is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Cannot process an empty text".to_string()));
+        }
+
+        let chunks = crate::split::token_chunks(&text, options.chunk_tokens, &self.model);
+        if chunks.len() <= 1 {
+            return self.instructed(&instruction, &text);
+        }
+        let mut usage: Option<Usage> = None;
+        let mut run = |system: &str, user: &str| -> PhpResult<Response> {
+            let response = self.instructed(system, user)?;
+            match &mut usage {
+                Some(total) => total.add(&response.usage),
+                None => usage = Some(response.usage.clone()),
+            }
+            Ok(response)
+        };
+
+        let mut results = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let system = crate::split::map_prompt(&instruction, i + 1, chunks.len());
+            results.push(run(&system, chunk.trim())?.content);
+        }
+
+        let reduce = options
+            .reduce_instruction
+            .unwrap_or_else(|| crate::split::reduce_prompt(&instruction));
+        for _ in 0..crate::split::MAX_REDUCE_ROUNDS {
+            let combined = crate::split::combine(&results);
+            let groups = crate::split::token_chunks(&combined, options.chunk_tokens, &self.model);
+            if groups.len() <= 1 {
+                break;
+            }
+            results = groups
+                .iter()
+                .map(|group| Ok(run(&reduce, group.trim())?.content))
+                .collect::<PhpResult<_>>()?;
+        }
+        let mut response = run(&reduce, &crate::split::combine(&results))?;
+        response.usage = usage.expect("at least one chunk is processed");
+        Ok(response)
+    }
+
+    /// Send the conversation to this instance's model and to `fastModel` at
+    /// once. The instance model's answer is used if it arrives within
+    /// `deadlineMs`; after that, the fast model's.
+    pub fn complete_speculative(
+        &self,
+        messages: &Zval,
+        fast_model: String,
+        deadline_ms: i64,
+    ) -> PhpResult<SpeculativeResponse> {
+        let rt = runtime()?;
+        let fast = Self {
+            model: fast_model,
+            ..self.clone()
+        };
+        let strong_messages = self.prepare_completion(messages)?;
+        let fast_messages = fast.prepare_completion(messages)?;
+        // Charged for whichever request is aborted
+        let strong_prompt = crate::speculative::prompt_usage(rt, &self.model, &strong_messages);
+        let fast_prompt = crate::speculative::prompt_usage(rt, &fast.model, &fast_messages);
+
+        let (strong_handle, strong_model) = self.spawn_completion(rt, strong_messages, None)?;
+        let (fast_handle, fast_model) = match fast.spawn_completion(rt, fast_messages, None) {
+            Ok(spawned) => spawned,
+            Err(e) => {
+                strong_handle.abort();
+                return Err(e);
+            }
+        };
+
+        let deadline = Duration::from_millis(deadline_ms.max(0) as u64);
+        let outcome = rt
+            .block_on(crate::speculative::race(
+                strong_handle,
+                fast_handle,
+                deadline,
+            ))
+            .map_err(|e| e.into_php_exception())?;
+
+        let aborted = match outcome.winner {
+            Winner::Strong => fast_prompt,
+            Winner::Fast => strong_prompt,
+        };
+        Ok(SpeculativeResponse::new(
+            outcome,
+            strong_model,
+            fast_model,
+            aborted,
+            &self.settings.attribution,
         ))
     }
 
+    /// Have the model pick exactly one of `options` for the conversation and
+    /// return its index. The choice is constrained with a JSON schema enum,
+    /// enforced by the provider's JSON mode where it has one and validated
+    /// locally otherwise.
+    pub fn decide(&self, messages: &Zval, options: Vec<String>) -> PhpResult<i64> {
+        crate::decide::check_options("decide", &options)?;
+        let schema = crate::decide::schema(&options).to_string();
+        let response = self
+            .structured_json(Some(schema))
+            .with_instruction(crate::decide::instruction(&options))
+            .complete(messages)?;
+        let structured = response.structured_value();
+        crate::decide::choice_index(&options, &structured)
+            .map(|i| i as i64)
+            .ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMStructuredOutputException>(format!(
+                    "Model chose {}, which is not one of the options",
+                    structured["choice"]
+                ))
+            })
+    }
+
+    /// Classify a text with one of `labels`, a list of labels or label =>
+    /// description, and return the label. With `withConfidence`, returns
+    /// ['label' => string, 'confidence' => float] with the model's own
+    /// confidence from 0 to 1. Constrained like decide().
+    pub fn classify(
+        &self,
+        text: String,
+        labels: &PhpArray,
+        with_confidence: Option<bool>,
+    ) -> PhpResult<Zval> {
+        let with_confidence = with_confidence.unwrap_or(false);
+        let labels = crate::decide::labels(labels)?;
+        let names: Vec<String> = labels.iter().map(|(label, _)| label.clone()).collect();
+        let schema = crate::decide::classify_schema(&names, with_confidence).to_string();
+
+        let mut messages = MessageCollection::__construct(None)?;
+        messages.push(Message::user(text)?);
+        let response = self
+            .structured_json(Some(schema))
+            .with_instruction(crate::decide::classify_instruction(
+                &labels,
+                with_confidence,
+            ))
+            .complete(&messages.into_zval(false)?)?;
+        let structured = response.structured_value();
+        let label = crate::decide::choice_index(&names, &structured)
+            .map(|i| names[i].clone())
+            .ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMStructuredOutputException>(format!(
+                    "Model chose {}, which is not one of the labels",
+                    structured["choice"]
+                ))
+            })?;
+        if !with_confidence {
+            return Ok(label.into_zval(false)?);
+        }
+        let mut result = PhpArray::new();
+        result.insert("label", label)?;
+        result.insert("confidence", crate::decide::confidence(&structured))?;
+        Ok(result.into_zval(false)?)
+    }
+
+    /// Extract the fields described by `schema` (JSON string, array or
+    /// Schema) from a text, returning them as an array matching the schema
+    pub fn extract(&self, text: String, schema: &Zval) -> PhpResult<Zval> {
+        if text.trim().is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Cannot extract from an empty text".to_string()
+            ));
+        }
+        let schema = crate::schema::schema_json(schema)?;
+
+        let mut messages = MessageCollection::__construct(None)?;
+        messages.push(Message::user(text)?);
+        self.structured_json(Some(schema))
+            .with_instruction(crate::schema::EXTRACT_PROMPT.to_string())
+            .complete(&messages.into_zval(false)?)?
+            .get_structured()
+    }
+
+    /// Detect objects in an image (http(s) URL, data: URL or local file)
+    /// with a vision model, returning a list of ['label', 'confidence',
+    /// 'box' => ['x', 'y', 'width', 'height']] with the box's top-left
+    /// corner. `labels` restricts the objects to those labels. Options:
+    /// 'coordinates' ('normalized' fractions of the image size, the default;
+    /// 'per_mille'; or 'pixels'), 'width' and 'height' (the image size, for
+    /// pixels when the image is given by URL) and 'max_objects' (default 50).
+    pub fn annotate_image(
+        &self,
+        image: String,
+        labels: Option<Vec<String>>,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<Zval> {
+        let options = AnnotateOptions::parse(options)?;
+        let labels = labels.unwrap_or_default();
+        let part = ImagePart::from_path_or_url(&image)?;
+        let size = options
+            .size
+            .or_else(|| part.bytes().and_then(|b| crate::vision::image_size(&b)));
+        if options.coordinates == Coordinates::Pixels && size.is_none() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Pixel coordinates need the image size: pass 'width' and 'height' for \
+                 images given by URL"
+                    .to_string(),
+            ));
+        }
+
+        let mut messages = MessageCollection::__construct(None)?;
+        messages.push(Message::user_with_part(
+            "Annotate this image.".to_string(),
+            part,
+        )?);
+        let schema = crate::vision::schema(&labels).to_string();
+        let response = self
+            .structured_json(Some(schema))
+            .with_instruction(crate::vision::instruction(&labels, options.max_objects))
+            .complete(&messages.into_zval(false)?)?;
+        let annotations = crate::vision::parse(&response.structured_value(), options.max_objects);
+        crate::vision::to_php(&annotations, options.coordinates, size)
+    }
+
+    /// Stream a completion, invoking the callback with every content delta.
+    /// Returns the assembled Response once the stream has finished.
+    pub fn stream(&self, messages: &Zval, on_delta: ZendCallable) -> PhpResult<Response> {
+        let mut iter = self.stream_iterator(messages)?;
+        iter.rewind()?;
+        while let Some(delta) = iter.current() {
+            on_delta.try_call(vec![&delta])?;
+            iter.next()?;
+        }
+        iter.get_response().ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMConnectionException>(
+                "Stream ended unexpectedly".to_string(),
+            )
+        })
+    }
+
+    /// Stream a completion as an Iterator of content deltas, for use with foreach
+    pub fn stream_iterator(&self, messages: &Zval) -> PhpResult<StreamIterator> {
+        match StreamTarget::for_model(&self.model, &self.settings.config) {
+            Some(target) => self.stream_to(target, messages, Vec::new()),
+            // No SSE support for this provider: yield the full completion as one delta
+            None => Ok(StreamIterator::from_response(self.complete(messages)?)),
+        }
+    }
+
+    /// Complete with documents the model can cite (Anthropic models).
+    /// Documents are strings or ['text' => ..., 'title' => ..., 'context' =>
+    /// ...] arrays; getCitations() on the response maps the answer back to
+    /// the supporting passages.
+    pub fn complete_with_documents(
+        &self,
+        messages: &Zval,
+        documents: &PhpArray,
+    ) -> PhpResult<Response> {
+        let documents = Document::list_from_array(documents)?;
+        let target = StreamTarget::for_model(&self.model, &self.settings.config)
+            .filter(StreamTarget::supports_documents)
+            .ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "Document citations need an Anthropic model, not '{}'",
+                    self.model
+                ))
+            })?;
+
+        let mut iter = self.stream_to(target, messages, documents)?;
+        iter.rewind()?;
+        while iter.valid() {
+            iter.next()?;
+        }
+        iter.get_response().ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMConnectionException>(
+                "Stream ended unexpectedly".to_string(),
+            )
+        })
+    }
+
+    /// Create an embeddings client for this instance's provider.
+    /// Defaults to the provider's standard embedding model.
+    pub fn embeddings(&self, model: Option<String>) -> PhpResult<Embeddings> {
+        let model = model
+            .or_else(|| crate::embeddings::default_model_for(&self.model))
+            .ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "No default embedding model for '{}', pass one explicitly",
+                    self.model
+                ))
+            })?;
+        Ok(Embeddings::new(model, self.settings.config.clone()))
+    }
+
+    /// Create a builder for structured output
+    pub fn structured(&self, schema: Option<&Zval>) -> PhpResult<StructuredBuilder> {
+        let schema = schema
+            .filter(|schema| !schema.is_null())
+            .map(crate::schema::schema_json)
+            .transpose()?;
+        Ok(self.structured_json(schema))
+    }
+
     /// Create a builder for tool calling
     pub fn with_tools(&self, tools: Option<&PhpArray>) -> PhpResult<ToolBuilder> {
         let tools_vec = if let Some(tools_arr) = tools {
@@ -149,11 +859,382 @@ impl LLM {
             self.temperature,
             self.max_tokens,
             self.top_p,
+            self.top_k,
+            self.penalties(),
+            self.stop.clone(),
+            self.seed,
             tools_vec,
-            self.runtime.clone(),
+            self.settings.clone(),
         ))
     }
 
+    /// Include full provider error details in exception messages for the
+    /// current request. API keys are masked either way.
+    pub fn set_verbose_errors(enabled: bool) {
+        crate::redact::set_verbose(enabled);
+    }
+
+    /// Set a callable `fn(string $text): string` used to redact sensitive
+    /// content in exception messages, logs and transcripts for the current
+    /// request. Pass null to remove it.
+    pub fn set_redactor(redactor: Option<&Zval>) -> PhpResult<()> {
+        match redactor {
+            Some(callable) if callable.is_callable() => {
+                crate::redact::set_redactor(Some(callable.shallow_clone()));
+                Ok(())
+            }
+            Some(_) => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Redactor must be callable".to_string())),
+            None => {
+                crate::redact::set_redactor(None);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set the per-minute token and cost budget for a tenant key, shared by
+    /// all requests served by this PHP process. Pass nulls to remove it.
+    pub fn set_tenant_budget(
+        key: String,
+        tokens_per_minute: Option<i64>,
+        cost_per_minute: Option<f64>,
+    ) -> PhpResult<()> {
+        if tokens_per_minute.is_some_and(|t| t <= 0) || cost_per_minute.is_some_and(|c| c <= 0.0) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Tenant budgets must be positive".to_string()));
+        }
+        crate::throttle::set_budget(&key, tokens_per_minute.map(|t| t as u64), cost_per_minute);
+        Ok(())
+    }
+
+    /// Usage of every call made by this PHP process since it started or was
+    /// last reset, as ['requests', 'input_tokens', 'output_tokens',
+    /// 'total_tokens', 'cost', 'models' => ['model' => [...]]]. Pass true to
+    /// reset the totals after reading them.
+    pub fn get_global_usage(reset: Option<bool>) -> PhpResult<Zval> {
+        crate::attribution::global_usage(reset.unwrap_or(false))
+    }
+
+    /// Start counting the global usage from zero
+    pub fn reset_global_usage() {
+        crate::attribution::reset_global_usage();
+    }
+
+    /// Remaining budget of a tenant as ['tokens' => ?float, 'cost' => ?float],
+    /// or null when the tenant has no budget
+    pub fn get_tenant_budget(key: String) -> PhpResult<Option<Zval>> {
+        crate::throttle::remaining(&key)
+    }
+
+    /// Charge this instance's calls against a tenant budget. Calls fail with
+    /// LLMBudgetExceededException once the budget is used up.
+    pub fn set_tenant_key(
+        self_: &mut ZendClassObject<LLM>,
+        key: Option<String>,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.attribution.tenant = key;
+        self_
+    }
+
+    /// Reject this instance's calls with LLMBudgetExceededException once the
+    /// guard's cost ceiling is reached. Builders created afterwards inherit
+    /// it; pass null to remove it.
+    pub fn with_budget_guard<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        guard: Option<&BudgetGuard>,
+    ) -> &'a mut ZendClassObject<LLM> {
+        self_.settings.attribution.budget = guard.cloned();
+        self_
+    }
+
+    /// Limit this instance's calls to `requestsPerMinute` requests and
+    /// `tokensPerMinute` tokens, counted across the user's processes on the host
+    /// for the provider and API key, so calls are held back before the provider
+    /// rejects them. A call waits until the limit allows it, or for at most
+    /// `maxWait` seconds before throwing LLMConnectionException with code
+    /// LLMException::ERR_RATE_LIMIT. 0 leaves a dimension unlimited; two
+    /// zeros remove the limit. Builders created afterwards inherit it.
+    pub fn with_rate_limit(
+        self_: &mut ZendClassObject<LLM>,
+        requests_per_minute: i64,
+        tokens_per_minute: i64,
+        max_wait: Option<f64>,
+    ) -> PhpResult<&mut ZendClassObject<LLM>> {
+        let invalid = |msg: &str| {
+            PhpException::from_class::<crate::error::LLMValidationException>(msg.to_string())
+        };
+        if requests_per_minute < 0 || tokens_per_minute < 0 {
+            return Err(invalid("Rate limits must not be negative"));
+        }
+        let max_wait = match max_wait {
+            Some(secs) if secs.is_finite() && secs >= 0.0 => Some(Duration::from_secs_f64(secs)),
+            Some(_) => return Err(invalid("Maximum wait must be a non-negative number")),
+            None => None,
+        };
+        let limit = |n: i64| (n > 0).then_some(n as u64);
+        let (requests, tokens) = (limit(requests_per_minute), limit(tokens_per_minute));
+
+        let rate_limit = if requests.is_some() || tokens.is_some() {
+            let key = limiter_key(
+                &get_env_prefix(&self_.model),
+                &self_.settings.config.api_key(&self_.model),
+            );
+            Some(RateLimit::new(key, requests, tokens, max_wait))
+        } else {
+            None
+        };
+        self_.settings.attribution.rate_limit = rate_limit;
+        Ok(self_)
+    }
+
+    /// Tag this instance's calls for cost attribution, e.g. ['feature' =>
+    /// 'checkout-bot', 'team' => 'growth']. Usage is summed per tag value
+    /// (see llm_usage_by_tag()) and tagged calls are logged at 'info' level.
+    /// Builders created afterwards inherit the tags; an empty array removes
+    /// them.
+    pub fn with_tags<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        tags: &PhpArray,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        self_.settings.attribution.tags = parse_tags(tags)?;
+        Ok(self_)
+    }
+
+    /// Shorten long user and system messages to about `ratio` of their
+    /// length (e.g. 0.5) before sending, dropping their least informative
+    /// sentences. Aimed at large retrieved contexts; short paragraphs such
+    /// as the question itself are kept. Options: 'min_chars', the length
+    /// from which a message is compressed (default 1000). Builders created
+    /// afterwards inherit it; pass 1 to disable.
+    pub fn with_compression<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        ratio: f64,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        self_.settings.compression = Compression::new(ratio, options)?;
+        Ok(self_)
+    }
+
+    /// Send structured-output and tool schemas without descriptions and
+    /// titles, with local $refs inlined. Cuts prompt tokens and helps large
+    /// schemas fit provider limits; schemas over a provider's published
+    /// limits (OpenAI: 10 levels of nesting, 5000 properties, 1000 enum
+    /// values) are rejected before sending either way. Builders created
+    /// afterwards inherit it.
+    pub fn simplify_schemas(
+        self_: &mut ZendClassObject<LLM>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.simplify_schemas = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// Fix message sequences providers reject before sending: consecutive
+    /// messages of the same role are merged, and tool results that do not
+    /// answer a tool call of the preceding assistant message are dropped
+    /// with a warning. Builders created afterwards inherit it.
+    pub fn repair_messages(
+        self_: &mut ZendClassObject<LLM>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.repair_messages = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// Keep the raw request and response of each completion on the Response
+    /// (see Response::getExchange()), for debugging what was actually sent to
+    /// and returned by the provider. Builders created afterwards inherit it.
+    pub fn set_debug(
+        self_: &mut ZendClassObject<LLM>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.debug = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// Instruct the model to always reply in `language`, an ISO 639-1 code
+    /// such as 'de' or 'pt-BR'; null removes the instruction. With
+    /// `validate`, complete() detects the reply's language locally and
+    /// retries once when it is confidently another one; languages the local
+    /// detector doesn't know are not checked. Builders created afterwards
+    /// inherit the instruction.
+    pub fn set_output_language(
+        self_: &mut ZendClassObject<LLM>,
+        language: Option<String>,
+        validate: Option<bool>,
+    ) -> PhpResult<&mut ZendClassObject<LLM>> {
+        self_.settings.output_language = language
+            .map(|tag| OutputLanguage::new(&tag, validate.unwrap_or(false)))
+            .transpose()?;
+        Ok(self_)
+    }
+
+    /// The messages as they would be sent after compression (see
+    /// withCompression()), as a list of ['role', 'content'] arrays
+    pub fn compress(&self, messages: &Zval) -> PhpResult<Zval> {
+        let mut messages_vec = php_to_messages(messages)?;
+        if let Some(compression) = &self.settings.compression {
+            compression.apply(&mut messages_vec);
+        }
+        let mut arr = PhpArray::new();
+        for msg in messages_vec {
+            let mut entry = PhpArray::new();
+            entry.insert("role", msg.role)?;
+            entry.insert("content", msg.content)?;
+            arr.push(entry)?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
+    /// Set a callable `fn(string $level, string $message): void` receiving the
+    /// extension's warnings, and the usage of tagged calls at 'info' level,
+    /// for the current request. Without one, warnings are raised as
+    /// E_USER_WARNING. Pass null to remove it.
+    pub fn set_logger(logger: Option<&Zval>) -> PhpResult<()> {
+        match logger {
+            Some(callable) if callable.is_callable() => {
+                crate::logger::set_logger(Some(callable.shallow_clone()));
+                Ok(())
+            }
+            Some(_) => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Logger must be callable".to_string())),
+            None => {
+                crate::logger::set_logger(None);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set a callable `fn(array $record): void` called after every completed
+    /// provider request of the current PHP request, with ['model',
+    /// 'input_tokens', 'output_tokens', 'reasoning_tokens', 'cached_tokens',
+    /// 'total_tokens', 'cost', 'request_time_ms', 'tenant', 'tags',
+    /// 'timestamp'], e.g. to write usage to a billing table. Cached responses
+    /// are not reported, nor are requests made by the hook itself. Pass null
+    /// to remove it.
+    pub fn set_usage_hook(hook: Option<&Zval>) -> PhpResult<()> {
+        match hook {
+            Some(callable) if callable.is_callable() => {
+                crate::usage_export::set_hook(Some(callable.shallow_clone()));
+                Ok(())
+            }
+            Some(_) => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Usage hook must be callable".to_string())),
+            None => {
+                crate::usage_export::set_hook(None);
+                Ok(())
+            }
+        }
+    }
+
+    /// Set what happens when a parameter is not supported by the provider:
+    /// 'strict' throws, 'drop' (default) leaves it out, 'warn' leaves it out
+    /// and logs a warning
+    pub fn set_parameter_policy(
+        self_: &mut ZendClassObject<LLM>,
+        policy: String,
+    ) -> PhpResult<&mut ZendClassObject<LLM>> {
+        self_.settings.param_policy = ParamPolicy::parse(&policy)?;
+        Ok(self_)
+    }
+
+    /// Retry failed requests with exponential backoff. Options: max_attempts
+    /// (default 1), backoff_ms (500), max_backoff_ms (30000) and retry_on
+    /// (['timeout', '429', '5xx']; also 'network', '4xx' or any status code).
+    /// A 429's Retry-After delay takes precedence over the backoff.
+    pub fn with_retry<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        options: &PhpArray,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        self_.settings.retry =
+            RetryPolicy::from_options(options)?.with_deadline_of(&self_.settings.retry);
+        Ok(self_)
+    }
+
+    /// Cache complete() responses for `ttl` seconds, keyed on the model,
+    /// sampling parameters and messages. Backends: 'memory' (this process),
+    /// 'apcu' (shared by a PHP-FPM pool) or 'file' (the system temp
+    /// directory). Pass a ttl of 0 to disable.
+    pub fn with_cache<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        backend: String,
+        ttl: i64,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        let cache = ResponseCache::new(&backend, Duration::from_secs(ttl.max(0) as u64))?;
+        self_.cache = (ttl > 0).then_some(cache);
+        Ok(self_)
+    }
+
+    /// Also answer complete() from the cache when the final user message is
+    /// similar enough to one answered before: its embedding's cosine
+    /// similarity must reach `threshold` (e.g. 0.95), and the model,
+    /// parameters and earlier messages must match exactly. Entries live in
+    /// this process for `ttl` seconds. The embedding model defaults to the
+    /// provider's (see embeddings()). Pass a ttl of 0 to disable.
+    pub fn with_semantic_cache<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        threshold: f64,
+        ttl: i64,
+        embedding_model: Option<String>,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        if ttl <= 0 {
+            self_.semantic_cache = None;
+            return Ok(self_);
+        }
+        let embedding_model = match embedding_model {
+            Some(model) => model,
+            None => crate::embeddings::default_model_for(&self_.model).ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "No default embedding model for '{}', pass one explicitly",
+                    self_.model
+                ))
+            })?,
+        };
+        self_.semantic_cache = Some(SemanticCache::new(
+            threshold,
+            Duration::from_secs(ttl as u64),
+            embedding_model,
+            &self_.settings.config,
+        )?);
+        Ok(self_)
+    }
+
+    /// Limit each attempt of a request to this many seconds (0 for no
+    /// limit). Streams apply it to the gap between chunks instead of the
+    /// whole response.
+    pub fn set_timeout(
+        self_: &mut ZendClassObject<LLM>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.config.set_timeout(seconds);
+        self_
+    }
+
+    /// Limit a request to this many seconds over all of its retry attempts
+    /// and the waits between them (0 for no limit). Streams are limited
+    /// until they start.
+    pub fn set_deadline(
+        self_: &mut ZendClassObject<LLM>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.retry.set_deadline(seconds);
+        self_
+    }
+
+    /// Limit connection setup to this many seconds (0 for no limit).
+    /// Applies to streaming and embeddings requests.
+    pub fn set_connect_timeout(
+        self_: &mut ZendClassObject<LLM>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.settings.config.set_connect_timeout(seconds);
+        self_
+    }
+
     /// Set configuration options
     pub fn with_options<'a>(
         self_: &'a mut ZendClassObject<LLM>,
@@ -165,10 +1246,14 @@ impl LLM {
         }
         if let Some(tokens) = options.get("max_tokens").and_then(|v| v.long()) {
             s.max_tokens = tokens as u32;
+            s.settings.max_tokens_auto = None;
         }
         if let Some(top_p) = options.get("top_p").and_then(|v| v.double()) {
             s.top_p = top_p as f32;
         }
+        if let Some(top_k) = options.get("top_k").and_then(|v| v.long()) {
+            s.top_k = top_k as u32;
+        }
         if let Some(fp) = options.get("frequency_penalty").and_then(|v| v.double()) {
             s.frequency_penalty = fp as f32;
         }
@@ -178,6 +1263,23 @@ impl LLM {
         self_
     }
 
+    /// Override how a message kind ('system' or 'tool_result') is rendered,
+    /// for one provider or for all providers when none is given.
+    /// Placeholders: {content}, {tool_call_id}, {provider}, {model}
+    pub fn set_prompt_template<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        kind: String,
+        template: String,
+        provider: Option<String>,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        let kind = TemplateKind::parse(&kind)?;
+        self_
+            .settings
+            .templates
+            .set(kind, provider.as_deref(), template);
+        Ok(self_)
+    }
+
     /// Set temperature
     pub fn set_temperature(
         self_: &mut ZendClassObject<LLM>,
@@ -193,15 +1295,36 @@ impl LLM {
         max_tokens: i64,
     ) -> &mut ZendClassObject<LLM> {
         self_.max_tokens = max_tokens as u32;
+        self_.settings.max_tokens_auto = None;
         self_
     }
 
+    /// Compute max_tokens per request as the model's context window minus
+    /// the prompt tokens counted by the local tokenizer minus a safety
+    /// margin, capped by the model's output limit. `context_window` is
+    /// needed for models the extension has no limits for. setMaxTokens()
+    /// turns it off.
+    pub fn set_max_tokens_auto(
+        self_: &mut ZendClassObject<LLM>,
+        safety_margin: Option<i64>,
+        context_window: Option<i64>,
+    ) -> PhpResult<&mut ZendClassObject<LLM>> {
+        self_.settings.max_tokens_auto = Some(AutoMaxTokens::new(safety_margin, context_window)?);
+        Ok(self_)
+    }
+
     /// Set top_p
     pub fn set_top_p(self_: &mut ZendClassObject<LLM>, top_p: f64) -> &mut ZendClassObject<LLM> {
         self_.top_p = top_p as f32;
         self_
     }
 
+    /// Set top_k
+    pub fn set_top_k(self_: &mut ZendClassObject<LLM>, top_k: i64) -> &mut ZendClassObject<LLM> {
+        self_.top_k = top_k as u32;
+        self_
+    }
+
     /// Set frequency penalty
     pub fn set_frequency_penalty(
         self_: &mut ZendClassObject<LLM>,
@@ -219,15 +1342,118 @@ impl LLM {
         self_.presence_penalty = penalty as f32;
         self_
     }
+
+    /// Sample with a fixed seed, so repeated requests with the same input
+    /// return the same output where the provider supports it
+    pub fn set_seed(self_: &mut ZendClassObject<LLM>, seed: i64) -> &mut ZendClassObject<LLM> {
+        self_.seed = Some(seed);
+        self_
+    }
+
+    /// Make completions as reproducible as the provider allows: temperature
+    /// 0 and a fixed seed (default 0). Compare getSystemFingerprint() across
+    /// responses to tell backend changes apart.
+    pub fn deterministic(
+        self_: &mut ZendClassObject<LLM>,
+        seed: Option<i64>,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.temperature = 0.0;
+        self_.seed = Some(seed.unwrap_or(0));
+        self_
+    }
+
+    /// Model used by title(), typically a cheap one ("provider:model")
+    pub fn set_title_model(
+        self_: &mut ZendClassObject<LLM>,
+        model: String,
+    ) -> &mut ZendClassObject<LLM> {
+        self_.title_model = Some(model);
+        self_
+    }
+
+    /// Stop generating at any of these sequences (a string or an array of
+    /// strings; an empty array clears them)
+    pub fn set_stop<'a>(
+        self_: &'a mut ZendClassObject<LLM>,
+        stop: &Zval,
+    ) -> PhpResult<&'a mut ZendClassObject<LLM>> {
+        self_.stop = parse_stop(stop)?;
+        Ok(self_)
+    }
+}
+
+/// Provider response fields needed to build a Response
+pub(crate) struct Completion {
+    pub content: String,
+    pub usage: Option<TokenUsage>,
+    pub finish_reason: Option<String>,
+    pub id: Option<String>,
+    pub raw: serde_json::Value,
+    /// Kept only in debug mode
+    pub exchange: Option<Exchange>,
+}
+
+/// Raw request and response of a completion, kept on responses by
+/// LLM::setDebug()
+#[derive(Debug, Clone)]
+pub(crate) struct Exchange {
+    provider: String,
+    request: serde_json::Value,
+    response: serde_json::Value,
+    /// Unix time the response arrived, in seconds
+    timestamp: u64,
+    request_time_ms: Option<u64>,
+}
+
+impl Exchange {
+    /// Copy of octolib's exchange when `debug` is on
+    pub(crate) fn capture(debug: bool, exchange: &ProviderExchange) -> Option<Self> {
+        debug.then(|| Self {
+            provider: exchange.provider.clone(),
+            request: exchange.request.clone(),
+            response: exchange.response.clone(),
+            timestamp: exchange.timestamp,
+            request_time_ms: exchange.usage.as_ref().and_then(|u| u.request_time_ms),
+        })
+    }
+
+    fn to_php(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        arr.insert("provider", self.provider.as_str())?;
+        arr.insert("request", crate::convert::json_value_to_php(&self.request)?)?;
+        arr.insert(
+            "response",
+            crate::convert::json_value_to_php(&self.response)?,
+        )?;
+        arr.insert("timestamp", self.timestamp as i64)?;
+        arr.insert("request_time_ms", self.request_time_ms.map(|ms| ms as i64))?;
+        Ok(arr.into_zval(false)?)
+    }
 }
 
 /// Response from LLM completion
 #[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[php(implements(ce = crate::completion_result::interface, stub = "\\CompletionResultInterface"))]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Response {
     content: String,
     usage: Usage,
     model: String,
     finish_reason: String,
+    #[serde(default)]
+    response_id: Option<String>,
+    refusal: Option<String>,
+    reasoning: Option<String>,
+    system_fingerprint: Option<String>,
+    #[serde(default)]
+    citations: Vec<Citation>,
+    #[serde(skip)]
+    cached: bool,
+    #[serde(skip)]
+    rate_limit: Option<RateLimitInfo>,
+    #[serde(skip)]
+    exchange: Option<Exchange>,
 }
 
 // Internal constructor - not exposed to PHP
@@ -243,8 +1469,73 @@ impl Response {
             usage: Usage::from_octo(usage),
             model,
             finish_reason,
+            response_id: None,
+            refusal: None,
+            reasoning: None,
+            system_fingerprint: None,
+            citations: Vec::new(),
+            cached: false,
+            rate_limit: None,
+            exchange: None,
         }
     }
+
+    /// Build from a finished completion, charging its usage to the tenant
+    /// and the tags
+    pub(crate) fn from_completion(
+        completion: Completion,
+        model: String,
+        attribution: &Attribution,
+    ) -> Self {
+        let usage = completion.usage.unwrap_or(TokenUsage {
+            input_tokens: 0,
+            output_tokens: 0,
+            reasoning_tokens: 0,
+            total_tokens: 0,
+            cache_read_tokens: 0,
+            cache_write_tokens: 0,
+            cost: None,
+            request_time_ms: None,
+        });
+        attribution.record(&model, &usage);
+
+        let finish_reason = completion
+            .finish_reason
+            .unwrap_or_else(|| "stop".to_string());
+        let refusal = detect_refusal(&finish_reason, &completion.raw);
+        let reasoning = extract_reasoning(&completion.raw);
+        let system_fingerprint = completion.raw["system_fingerprint"]
+            .as_str()
+            .map(str::to_string);
+
+        let mut response = Self::new(completion.content, usage, model, finish_reason)
+            .with_refusal(refusal)
+            .with_reasoning(reasoning);
+        response.system_fingerprint = system_fingerprint;
+        response.response_id = completion.id;
+        response.exchange = completion.exchange;
+        response
+    }
+
+    pub(crate) fn with_refusal(mut self, refusal: Option<String>) -> Self {
+        self.refusal = refusal;
+        self
+    }
+
+    pub(crate) fn with_reasoning(mut self, reasoning: Option<String>) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    pub(crate) fn with_citations(mut self, citations: Vec<Citation>) -> Self {
+        self.citations = citations;
+        self
+    }
+
+    pub(crate) fn with_rate_limit(mut self, rate_limit: Option<RateLimitInfo>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
 }
 
 #[php_impl]
@@ -265,25 +1556,114 @@ impl Response {
         self.finish_reason.clone()
     }
 
+    /// Provider's id of the response, e.g. 'chatcmpl-…' or 'msg_…'; null
+    /// when the provider sent none or the response was streamed
+    pub fn get_response_id(&self) -> Option<String> {
+        self.response_id.clone()
+    }
+
+    /// Whether the provider refused or content-filtered the completion
+    pub fn is_refusal(&self) -> bool {
+        self.refusal.is_some()
+    }
+
+    /// Refusal message or filtered categories, if the completion was refused
+    pub fn get_refusal_reason(&self) -> Option<String> {
+        self.refusal.clone()
+    }
+
+    /// Reasoning ("thinking") text, for models and providers that return it
+    pub fn get_reasoning(&self) -> Option<String> {
+        self.reasoning.clone()
+    }
+
+    /// Tokens spent on reasoning (shortcut for getUsage()->getReasoningTokens())
+    pub fn get_reasoning_tokens(&self) -> i64 {
+        self.usage.reasoning_tokens
+    }
+
+    /// Backend configuration the provider served the request with, for
+    /// checking that seeded requests are reproducible
+    pub fn get_system_fingerprint(&self) -> Option<String> {
+        self.system_fingerprint.clone()
+    }
+
+    /// Passages of the documents given to completeWithDocuments() that
+    /// support the answer, as a list of ['document_index', 'document_title',
+    /// 'cited_text', 'start', 'end', 'answer_start', 'answer_end']. Offsets
+    /// count characters (use mb_substr()); end offsets are exclusive.
+    pub fn get_citations(&self) -> PhpResult<Zval> {
+        crate::citations::to_php(&self.citations)
+    }
+
+    /// Whether this response was served by withCache() or withSemanticCache().
+    /// Usage is that of the original request; a cached response costs nothing.
+    pub fn is_cached(&self) -> bool {
+        self.cached
+    }
+
+    /// Rate-limit state the provider sent with the response, as
+    /// ['limit_requests', 'remaining_requests', 'reset_requests',
+    /// 'limit_tokens', 'remaining_tokens', 'reset_tokens', 'retry_after'],
+    /// with only the reported keys and resets in seconds. Null when the
+    /// headers are not available, which is the case for responses that were
+    /// not streamed.
+    pub fn get_rate_limit_info(&self) -> PhpResult<Option<Zval>> {
+        self.rate_limit
+            .as_ref()
+            .map(RateLimitInfo::to_php)
+            .transpose()
+    }
+
+    /// Raw provider exchange as ['provider', 'request', 'response',
+    /// 'timestamp', 'request_time_ms'], with the request and response bodies
+    /// decoded to arrays. Only kept with LLM::setDebug(); null otherwise and
+    /// for streamed or cached responses.
+    pub fn get_exchange(&self) -> PhpResult<Option<Zval>> {
+        self.exchange.as_ref().map(Exchange::to_php).transpose()
+    }
+
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("content", self.content.clone())?;
         arr.insert("usage", self.usage.to_array()?)?;
         arr.insert("model", self.model.clone())?;
         arr.insert("finish_reason", self.finish_reason.clone())?;
+        if let Some(ref response_id) = self.response_id {
+            arr.insert("response_id", &**response_id)?;
+        }
+        if let Some(ref refusal) = self.refusal {
+            arr.insert("refusal", &**refusal)?;
+        }
+        if let Some(ref reasoning) = self.reasoning {
+            arr.insert("reasoning", &**reasoning)?;
+        }
+        if let Some(ref fingerprint) = self.system_fingerprint {
+            arr.insert("system_fingerprint", &**fingerprint)?;
+        }
+        if !self.citations.is_empty() {
+            arr.insert("citations", self.get_citations()?)?;
+        }
+        if let Some(ref rate_limit) = self.rate_limit {
+            arr.insert("rate_limit", rate_limit.to_php()?)?;
+        }
+        if let Some(ref exchange) = self.exchange {
+            arr.insert("exchange", exchange.to_php()?)?;
+        }
         Ok(arr.into_zval(false)?)
     }
 
     pub fn to_json(&self) -> PhpResult<String> {
         match serde_json::to_string(&serde_json::json!({
             "content": self.content,
-            "usage": {
-                "prompt_tokens": self.usage.get_prompt_tokens(),
-                "output_tokens": self.usage.get_output_tokens(),
-                "total_tokens": self.usage.get_total_tokens(),
-            },
+            "usage": self.usage.json_value(),
             "model": self.model,
             "finish_reason": self.finish_reason,
+            "response_id": self.response_id,
+            "refusal": self.refusal,
+            "reasoning": self.reasoning,
+            "system_fingerprint": self.system_fingerprint,
+            "citations": self.citations,
         })) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
@@ -291,15 +1671,35 @@ impl Response {
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+
+    /// State for serialize(), e.g. to keep the object in a session or queue
+    pub fn __serialize(&self) -> PhpResult<ZBox<PhpArray>> {
+        crate::serialization::save(self)
+    }
+
+    pub fn __unserialize(self_: &mut ZendClassObject<Response>, data: &PhpArray) -> PhpResult<()> {
+        self_.initialize(crate::serialization::restore(data, "Response")?);
+        Ok(())
+    }
 }
 
 /// Token usage information
 #[php_class]
-#[derive(Clone)]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Usage {
     prompt_tokens: i64,
     output_tokens: i64,
+    reasoning_tokens: i64,
     total_tokens: i64,
+    cached_tokens: i64,
+    cost: Option<f64>,
+    request_time_ms: Option<i64>,
 }
 
 // Internal constructor - not exposed to PHP
@@ -308,9 +1708,43 @@ impl Usage {
         Self {
             prompt_tokens: usage.input_tokens as i64,
             output_tokens: usage.output_tokens as i64,
+            reasoning_tokens: usage.reasoning_tokens as i64,
             total_tokens: usage.total_tokens as i64,
+            cached_tokens: usage.cache_read_tokens as i64,
+            cost: usage.cost,
+            request_time_ms: usage.request_time_ms.map(|ms| ms as i64),
         }
     }
+
+    /// JSON shape shared by `toJson()` here and on the response classes
+    pub(crate) fn json_value(&self) -> serde_json::Value {
+        serde_json::json!({
+            "prompt_tokens": self.prompt_tokens,
+            "output_tokens": self.output_tokens,
+            "reasoning_tokens": self.reasoning_tokens,
+            "total_tokens": self.total_tokens,
+            "cached_tokens": self.cached_tokens,
+            "cost": self.cost,
+            "request_time_ms": self.request_time_ms,
+        })
+    }
+
+    /// Add the usage of another request
+    pub(crate) fn add(&mut self, other: &Usage) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.output_tokens += other.output_tokens;
+        self.reasoning_tokens += other.reasoning_tokens;
+        self.total_tokens += other.total_tokens;
+        self.cached_tokens += other.cached_tokens;
+        self.cost = match (self.cost, other.cost) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+        };
+        self.request_time_ms = match (self.request_time_ms, other.request_time_ms) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or_default() + b.unwrap_or_default()),
+        };
+    }
 }
 
 #[php_impl]
@@ -323,30 +1757,400 @@ impl Usage {
         self.output_tokens
     }
 
+    /// Tokens spent on reasoning; already included in the output tokens
+    pub fn get_reasoning_tokens(&self) -> i64 {
+        self.reasoning_tokens
+    }
+
     pub fn get_total_tokens(&self) -> i64 {
         self.total_tokens
     }
 
+    /// Prompt tokens served from the provider's prompt cache
+    pub fn get_cached_tokens(&self) -> i64 {
+        self.cached_tokens
+    }
+
+    /// Cost in USD, when reported for the provider and model
+    pub fn get_cost(&self) -> Option<f64> {
+        self.cost
+    }
+
+    /// Provider request time in milliseconds, when measured
+    pub fn get_request_time_ms(&self) -> Option<i64> {
+        self.request_time_ms
+    }
+
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("prompt_tokens", self.prompt_tokens)?;
         arr.insert("output_tokens", self.output_tokens)?;
+        arr.insert("reasoning_tokens", self.reasoning_tokens)?;
         arr.insert("total_tokens", self.total_tokens)?;
+        arr.insert("cached_tokens", self.cached_tokens)?;
+        arr.insert("cost", self.cost)?;
+        arr.insert("request_time_ms", self.request_time_ms)?;
         Ok(arr.into_zval(false)?)
     }
 
     pub fn to_json(&self) -> PhpResult<String> {
-        match serde_json::to_string(&serde_json::json!({
-            "prompt_tokens": self.prompt_tokens,
-            "output_tokens": self.output_tokens,
-            "total_tokens": self.total_tokens,
-        })) {
+        match serde_json::to_string(&self.json_value()) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
                 "Failed to serialize to JSON: {e}"
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+
+    /// State for serialize(), e.g. to keep the object in a session or queue
+    pub fn __serialize(&self) -> PhpResult<ZBox<PhpArray>> {
+        crate::serialization::save(self)
+    }
+
+    pub fn __unserialize(self_: &mut ZendClassObject<Usage>, data: &PhpArray) -> PhpResult<()> {
+        self_.initialize(crate::serialization::restore(data, "Usage")?);
+        Ok(())
+    }
+}
+
+// Internal methods - not exposed to PHP
+impl LLM {
+    /// Checks and message conversion shared by complete() and complete_async()
+    fn prepare_completion(&self, messages: &Zval) -> PhpResult<Vec<OctoMessage>> {
+        self.settings.attribution.check()?;
+
+        let mut messages_vec = php_to_messages(messages)?;
+        self.settings
+            .shape_messages(&self.model, &mut messages_vec)?;
+        Ok(messages_vec)
+    }
+
+    /// complete() for converted messages, through the response caches
+    fn complete_prepared(&self, messages_vec: &[OctoMessage]) -> PhpResult<Response> {
+        let cache_key = self.cache.as_ref().map(|_| self.cache_key(messages_vec));
+        if let Some(mut cached) = self
+            .cache
+            .as_ref()
+            .zip(cache_key.as_deref())
+            .and_then(|(cache, key)| cache.get(key))
+            .and_then(|json| serde_json::from_str::<Response>(&json).ok())
+        {
+            cached.cached = true;
+            return Ok(cached);
+        }
+        // Prompts close enough to one answered in the same context
+        let semantic = self.semantic_cache.as_ref().and_then(|cache| {
+            let (context, prompt) = split_prompt(messages_vec)?;
+            let vector = cache.embed(prompt, &self.settings.config)?;
+            Some((cache, cache.context(&self.cache_key(context)), vector))
+        });
+        if let Some(mut cached) = semantic
+            .as_ref()
+            .and_then(|(cache, context, vector)| cache.get(*context, vector))
+            .and_then(|json| serde_json::from_str::<Response>(&json).ok())
+        {
+            cached.cached = true;
+            return Ok(cached);
+        }
+        let max_tokens =
+            self.settings
+                .max_tokens_for(&self.model, messages_vec, "", self.max_tokens)?;
+        let rt = runtime()?;
+
+        let mut params = self.request_params(max_tokens);
+        let backend = self.settings.backend(rt, &self.model, &mut params)?;
+        let model = backend.model().to_string();
+
+        let response = rt
+            .block_on(self.settings.retry.run(|| {
+                backend.complete(
+                    &self.model,
+                    messages_vec,
+                    &params,
+                    self.top_k,
+                    &self.settings.config,
+                )
+            }))
+            .map_err(|e| e.into_php_exception())?;
+
+        let completion = Completion {
+            exchange: Exchange::capture(self.settings.debug, &response.exchange),
+            content: response.content,
+            usage: response.exchange.usage,
+            finish_reason: response.finish_reason,
+            id: response.id,
+            raw: response.exchange.response,
+        };
+        let response = Response::from_completion(completion, model, &self.settings.attribution);
+        if self.cache.is_some() || semantic.is_some() {
+            if let Ok(json) = serde_json::to_string(&response) {
+                if let (Some(cache), Some(key)) = (&self.cache, cache_key) {
+                    cache.put(key, json.clone());
+                }
+                if let Some((cache, context, vector)) = semantic {
+                    cache.put(context, vector, json);
+                }
+            }
+        }
+        Ok(response)
+    }
+
+    /// complete() with a system prompt and one user message
+    fn instructed(&self, system: &str, user: &str) -> PhpResult<Response> {
+        let mut messages = MessageCollection::__construct(None)?;
+        messages.push(Message::system(system.to_string())?);
+        messages.push(Message::user(user.to_string())?);
+        self.complete(&messages.into_zval(false)?)
+    }
+
+    /// Summary of a conversation transcript, for
+    /// MessageCollection::truncateToTokens()
+    pub(crate) fn summarize(&self, transcript: &str) -> PhpResult<String> {
+        let response = self.helper_completion(
+            self.model.clone(),
+            crate::trim::SUMMARY_PROMPT,
+            transcript,
+            crate::trim::SUMMARY_MAX_TOKENS,
+        )?;
+        Ok(response.get_content().trim().to_string())
+    }
+
+    /// Start summarizing a long tool result, for
+    /// ToolBuilder::summarizeResults()
+    pub(crate) fn spawn_tool_summary(
+        &self,
+        text: &str,
+        max_tokens: u32,
+    ) -> PhpResult<JoinHandle<anyhow::Result<Completion>>> {
+        let (handle, _) = self.spawn_helper(
+            self.model.clone(),
+            crate::tool_builder::RESULT_SUMMARY_PROMPT,
+            text,
+            max_tokens,
+        )?;
+        Ok(handle)
+    }
+
+    /// structured() for a schema already encoded as JSON
+    fn structured_json(&self, schema: Option<String>) -> StructuredBuilder {
+        StructuredBuilder::new(
+            self.model.clone(),
+            self.temperature,
+            self.max_tokens,
+            self.top_p,
+            self.top_k,
+            self.penalties(),
+            self.stop.clone(),
+            self.seed,
+            schema,
+            self.settings.clone(),
+        )
+    }
+
+    /// One-off completion with a fixed system prompt, for helpers such as
+    /// title(). Sampling is reset to a low temperature with no penalties or
+    /// stop sequences; the seed is kept, so seeded helpers stay reproducible.
+    fn helper_completion(
+        &self,
+        model: String,
+        system: &str,
+        user: &str,
+        max_tokens: u32,
+    ) -> PhpResult<Response> {
+        let (handle, model) = self.spawn_helper(model, system, user, max_tokens)?;
+        let completion = runtime()?
+            .block_on(handle)
+            .unwrap_or_else(|e| Err(anyhow::anyhow!("Completion task failed: {e}")))
+            .map_err(|e| e.into_php_exception())?;
+
+        Ok(Response::from_completion(
+            completion,
+            model,
+            &self.settings.attribution,
+        ))
+    }
+
+    /// helper_completion() started on the runtime without waiting for it
+    fn spawn_helper(
+        &self,
+        model: String,
+        system: &str,
+        user: &str,
+        max_tokens: u32,
+    ) -> PhpResult<(JoinHandle<anyhow::Result<Completion>>, String)> {
+        let mut helper = Self {
+            model,
+            temperature: 0.2,
+            max_tokens,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            stop: Vec::new(),
+            ..self.clone()
+        };
+        helper.settings.max_tokens_auto = None;
+        let build_err = |e: octolib::errors::MessageError| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Failed to build message: {e}"
+            ))
+        };
+        let mut prompt = vec![
+            MessageBuilder::system(system).build().map_err(build_err)?,
+            MessageBuilder::user(user).build().map_err(build_err)?,
+        ];
+        remap_system_role(&helper.model, &mut prompt);
+
+        self.settings.attribution.check()?;
+        helper.spawn_completion(runtime()?, prompt, None)
+    }
+
+    /// Spawn a completion on the runtime, optionally limited by a semaphore.
+    /// Returns the task handle and the resolved model name.
+    fn spawn_completion(
+        &self,
+        rt: &Runtime,
+        messages_vec: Vec<OctoMessage>,
+        limit: Option<Arc<Semaphore>>,
+    ) -> PhpResult<(JoinHandle<anyhow::Result<Completion>>, String)> {
+        let max_tokens =
+            self.settings
+                .max_tokens_for(&self.model, &messages_vec, "", self.max_tokens)?;
+        let mut params = self.request_params(max_tokens);
+        let backend = self.settings.backend(rt, &self.model, &mut params)?;
+        let model = backend.model().to_string();
+
+        let top_k = self.top_k;
+        let retry = self.settings.retry.clone();
+        let config = self.settings.config.clone();
+        let debug = self.settings.debug;
+        let full_model = self.model.clone();
+        let handle = rt.spawn(async move {
+            let _permit = match limit {
+                Some(limit) => Some(limit.acquire_owned().await?),
+                None => None,
+            };
+            let response = retry
+                .run(|| backend.complete(&full_model, &messages_vec, &params, top_k, &config))
+                .await?;
+            Ok::<_, anyhow::Error>(Completion {
+                exchange: Exchange::capture(debug, &response.exchange),
+                content: response.content,
+                usage: response.exchange.usage,
+                finish_reason: response.finish_reason,
+                id: response.id,
+                raw: response.exchange.response,
+            })
+        });
+
+        Ok((handle, model))
+    }
+
+    /// Start streaming to a resolved endpoint
+    fn stream_to(
+        &self,
+        target: StreamTarget,
+        messages: &Zval,
+        documents: Vec<Document>,
+    ) -> PhpResult<StreamIterator> {
+        let mut penalties = self.penalties();
+        let mut seed = self.seed;
+        if !target.supports_penalties() {
+            let mut unsupported = penalties.set_names();
+            if seed.is_some() {
+                unsupported.push("seed");
+            }
+            self.settings
+                .param_policy
+                .enforce(&self.model, &unsupported)?;
+            penalties = Penalties::default();
+            seed = None;
+        }
+        self.settings.attribution.check()?;
+
+        let mut messages_vec = php_to_messages(messages)?;
+        self.settings
+            .shape_messages(&self.model, &mut messages_vec)?;
+        let params = StreamParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.settings.max_tokens_for(
+                &self.model,
+                &messages_vec,
+                "",
+                self.max_tokens,
+            )?,
+            penalties,
+            stop: self.stop.clone(),
+            seed,
+            tools: Vec::new(),
+            tool_choice: ToolChoice::Auto,
+            parallel_tool_calls: None,
+            documents,
+            structured: None,
+        };
+        let model = target.model_name().to_string();
+        let rx = crate::stream::spawn(
+            runtime()?,
+            target,
+            &messages_vec,
+            &params,
+            self.settings.retry.clone(),
+        );
+
+        Ok(StreamIterator::new(
+            rx,
+            model,
+            self.settings.attribution.clone(),
+        ))
+    }
+
+    /// Cache key over the model, provider identity, sampling parameters and
+    /// messages
+    fn cache_key(&self, messages: &[OctoMessage]) -> String {
+        crate::cache::request_key(
+            &self.model,
+            &[
+                &self.settings.config.identity(&self.model),
+                &self.temperature.to_string(),
+                &self.top_p.to_string(),
+                &self.top_k.to_string(),
+                &self.max_tokens.to_string(),
+                &self.frequency_penalty.to_string(),
+                &self.presence_penalty.to_string(),
+                &self.stop.join("\u{0}"),
+                &format!("{:?}", self.seed),
+            ],
+            messages,
+        )
+    }
+
+    fn penalties(&self) -> Penalties {
+        Penalties {
+            frequency: self.frequency_penalty,
+            presence: self.presence_penalty,
+        }
+    }
+
+    /// Sampling parameters of a completion request
+    fn request_params(&self, max_tokens: u32) -> StreamParams {
+        StreamParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens,
+            penalties: self.penalties(),
+            stop: self.stop.clone(),
+            seed: self.seed,
+            tools: Vec::new(),
+            tool_choice: ToolChoice::Auto,
+            parallel_tool_calls: None,
+            documents: Vec::new(),
+            structured: None,
+        }
+    }
 }
 
 // Forward declarations for builders
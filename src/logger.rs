@@ -0,0 +1,51 @@
+use ext_php_rs::types::{ZendCallable, Zval};
+use std::cell::RefCell;
+
+/// PHP's E_USER_WARNING, used when no logger is configured
+const E_USER_WARNING: i64 = 512;
+
+// Per-request logger callable, reset from the module's request shutdown hook
+thread_local! {
+    static LOGGER: RefCell<Option<Zval>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn set_logger(logger: Option<Zval>) {
+    LOGGER.with(|l| *l.borrow_mut() = logger);
+}
+
+/// Reset the per-request logger
+pub(crate) fn reset() {
+    set_logger(None);
+}
+
+/// Pass a message to the user logger; false when there is none
+fn log(level: &str, message: &str) -> bool {
+    LOGGER.with(|l| {
+        let logger = l.borrow();
+        let Some(callable) = logger.as_ref() else {
+            return false;
+        };
+        ZendCallable::new(callable)
+            .and_then(|f| f.try_call(vec![&level, &message]))
+            .is_ok()
+    })
+}
+
+/// Log a warning through the user logger, or raise an E_USER_WARNING when
+/// none is configured. Messages pass through the redactor first.
+pub(crate) fn warn(message: &str) {
+    let message = crate::redact::redact_content(message);
+    let logged = log("warning", &message);
+
+    if !logged {
+        let _ = ZendCallable::try_from_name("trigger_error")
+            .and_then(|f| f.try_call(vec![&message.as_str(), &E_USER_WARNING]));
+    }
+}
+
+/// Log an informational message through the user logger; without one it is
+/// dropped. Runtime worker threads never have a logger, so this is a no-op
+/// there.
+pub(crate) fn info(message: &str) {
+    log("info", &crate::redact::redact_content(message));
+}
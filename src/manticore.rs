@@ -0,0 +1,276 @@
+use anyhow::Result;
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use octolib::errors::ProviderError;
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::request_error;
+use crate::embeddings::{Embeddings, InputType};
+use crate::error::IntoPhpException;
+use crate::runtime::runtime;
+
+/// Manticore's HTTP listener on a default install
+const DEFAULT_URL: &str = "http://127.0.0.1:9308";
+
+/// Column holding the stored text
+const TEXT_FIELD: &str = "text";
+/// Column holding the embedding
+const VECTOR_FIELD: &str = "embedding";
+
+/// Manticore identifiers are not quoted in SQL, so only plain names are allowed
+fn valid_table_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Statement creating a table for vectors of `dims` dimensions, indexed with
+/// HNSW for cosine similarity
+fn create_table_sql(table: &str, dims: usize) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {table} ({TEXT_FIELD} text stored, {VECTOR_FIELD} \
+        float_vector knn_type='hnsw' knn_dims='{dims}' hnsw_similarity='cosine')"
+    )
+}
+
+/// Id, text and distance of each KNN hit, nearest first
+fn parse_hits(value: &Value) -> Vec<(u64, String, f64)> {
+    value["hits"]["hits"]
+        .as_array()
+        .map(|hits| {
+            hits.iter()
+                .filter_map(|hit| {
+                    // Older versions send the id as a string
+                    let id = hit["_id"]
+                        .as_u64()
+                        .or_else(|| hit["_id"].as_str()?.parse().ok())?;
+                    let text = hit["_source"][TEXT_FIELD]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string();
+                    Some((id, text, hit["_knn_dist"].as_f64().unwrap_or(0.0)))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Error reported by Manticore in a response body, if any
+fn body_error(value: &Value) -> Option<String> {
+    // /sql?mode=raw answers with a list of result sets
+    let value = value.as_array().and_then(|v| v.first()).unwrap_or(value);
+    match &value["error"] {
+        Value::String(msg) if !msg.is_empty() => Some(msg.clone()),
+        Value::Object(err) => Some(
+            err.get("reason")
+                .and_then(Value::as_str)
+                .map_or_else(|| Value::Object(err.clone()).to_string(), str::to_string),
+        ),
+        _ => None,
+    }
+}
+
+/// Vector store in a Manticore Search table, filled and queried over its
+/// HTTP JSON API
+#[php_class]
+pub struct ManticoreVectorStore {
+    table: String,
+    url: String,
+    embeddings: Embeddings,
+    client: reqwest::Client,
+    /// Whether the table is known to exist
+    ready: AtomicBool,
+}
+
+// Internal methods - not exposed to PHP
+impl ManticoreVectorStore {
+    async fn post(&self, path: &str, body: reqwest::Body) -> Result<Value> {
+        let response = self
+            .client
+            .post(format!("{}{path}", self.url))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| request_error("manticore", e))?;
+        let status = response.status();
+        let text = response
+            .text()
+            .await
+            .map_err(|e| request_error("manticore", e))?;
+        let value: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+        if let Some(message) = body_error(&value).or_else(|| (!status.is_success()).then_some(text))
+        {
+            return Err(ProviderError::ApiError {
+                provider: "manticore".to_string(),
+                status: status.as_u16(),
+                message,
+            }
+            .into());
+        }
+        Ok(value)
+    }
+
+    async fn post_json(&self, path: &str, body: &Value) -> Result<Value> {
+        self.post(path, body.to_string().into()).await
+    }
+
+    /// Create the table on first write, sized to the embedding model
+    async fn ensure_table(&self, dims: usize) -> Result<()> {
+        if self.ready.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.post("/sql?mode=raw", create_table_sql(&self.table, dims).into())
+            .await?;
+        self.ready.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn embed(&self, text: String, input_type: InputType) -> PhpResult<Vec<f32>> {
+        let mut vectors = self.embeddings.vectors(&[text], input_type)?;
+        Ok(vectors.pop().unwrap_or_default())
+    }
+}
+
+#[php_impl]
+impl ManticoreVectorStore {
+    /// Store for `table`, embedding texts with `embeddings`. Options: 'url'
+    /// of the Manticore HTTP listener (default http://127.0.0.1:9308). The
+    /// table is created on the first store() if it doesn't exist.
+    #[php(constructor)]
+    pub fn __construct(
+        table: String,
+        embeddings: &Embeddings,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<Self> {
+        if !valid_table_name(&table) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Invalid Manticore table name '{table}'"
+            )));
+        }
+        let url = match options.and_then(|o| o.get("url")) {
+            Some(url) => url.string().ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(
+                    "Option 'url' must be a string".to_string(),
+                )
+            })?,
+            None => DEFAULT_URL.to_string(),
+        };
+        Ok(Self {
+            table,
+            url: url.trim_end_matches('/').to_string(),
+            embeddings: embeddings.clone(),
+            client: reqwest::Client::new(),
+            ready: AtomicBool::new(false),
+        })
+    }
+
+    /// Embed `text` and store it under `id`, replacing any previous document
+    /// with that id
+    pub fn store(&self, id: i64, text: String) -> PhpResult<()> {
+        if id <= 0 {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Document id must be positive".to_string()));
+        }
+        let vector = self.embed(text.clone(), InputType::Document)?;
+        let body = json!({
+            "table": self.table,
+            "id": id,
+            "doc": { TEXT_FIELD: text, VECTOR_FIELD: vector },
+        });
+        runtime()?
+            .block_on(async {
+                self.ensure_table(vector.len()).await?;
+                self.post_json("/replace", &body).await
+            })
+            .map_err(|e| e.into_php_exception())?;
+        Ok(())
+    }
+
+    /// The `k` stored texts nearest to `query`, as a list of ['id', 'text',
+    /// 'distance'] arrays, nearest first
+    pub fn search(&self, query: String, k: i64) -> PhpResult<Zval> {
+        if k <= 0 {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("k must be positive".to_string()));
+        }
+        let vector = self.embed(query, InputType::Query)?;
+        let body = json!({
+            "table": self.table,
+            "knn": { "field": VECTOR_FIELD, "query": vector, "k": k },
+            "_source": [TEXT_FIELD],
+            "limit": k,
+        });
+        let value = runtime()?
+            .block_on(self.post_json("/search", &body))
+            .map_err(|e| e.into_php_exception())?;
+
+        let mut arr = PhpArray::new();
+        for (id, text, distance) in parse_hits(&value) {
+            let mut hit = PhpArray::new();
+            hit.insert("id", id as i64)?;
+            hit.insert("text", text)?;
+            hit.insert("distance", distance)?;
+            arr.push(hit)?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
+    pub fn get_table(&self) -> String {
+        self.table.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_names() {
+        assert!(valid_table_name("docs"));
+        assert!(valid_table_name("_rag_chunks2"));
+        assert!(!valid_table_name("2docs"));
+        assert!(!valid_table_name("docs; DROP TABLE x"));
+        assert!(!valid_table_name(""));
+        assert!(create_table_sql("docs", 1536).contains("knn_dims='1536'"));
+    }
+
+    #[test]
+    fn test_parse_hits() {
+        let value = json!({
+            "hits": {"total": 2, "hits": [
+                {"_id": 7, "_score": 1, "_knn_dist": 0.12, "_source": {"text": "Manticore is fast"}},
+                {"_id": "9", "_score": 1, "_knn_dist": 0.4, "_source": {"text": "Cats"}}
+            ]}
+        });
+        assert_eq!(
+            parse_hits(&value),
+            vec![
+                (7, "Manticore is fast".to_string(), 0.12),
+                (9, "Cats".to_string(), 0.4)
+            ]
+        );
+        assert!(parse_hits(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_body_error() {
+        assert_eq!(
+            body_error(&json!([{"total": 0, "error": "table exists", "warning": ""}])),
+            Some("table exists".to_string())
+        );
+        assert_eq!(
+            body_error(&json!({"error": {"type": "x", "reason": "no such table"}})),
+            Some("no such table".to_string())
+        );
+        assert_eq!(body_error(&json!([{"total": 0, "error": ""}])), None);
+        assert_eq!(body_error(&json!({"_id": 1, "result": "created"})), None);
+    }
+}
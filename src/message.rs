@@ -1,18 +1,217 @@
+use crate::image_resize::{self, ImageOptions};
+use crate::intern::intern;
+use crate::llm_class::LLM;
+use crate::tokenizer::count_tokens;
 use crate::tool_builder::ToolResponse;
-use ext_php_rs::convert::IntoZval;
+use crate::transcript::{self, Turn};
+use crate::trim::{self, TrimStrategy};
+use base64::Engine;
+use ext_php_rs::boxed::ZBox;
+use ext_php_rs::convert::{FromZval, IntoZval};
 use ext_php_rs::prelude::*;
-use ext_php_rs::types::{ZendClassObject, ZendHashTable as PhpArray, Zval};
-use octolib::llm::{Message as OctoMessage, MessageBuilder};
+use ext_php_rs::types::{ZendCallable, ZendClassObject, ZendHashTable as PhpArray, Zval};
+use ext_php_rs::zend::ce;
+use octolib::llm::{
+    ImageAttachment, ImageData, Message as OctoMessage, MessageBuilder, SourceType,
+};
+use serde_json::{json, Value};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::sync::Arc;
+
+/// Read a local image file, honouring PHP's open_basedir restriction
+fn read_image_file(source: &str) -> PhpResult<Vec<u8>> {
+    let read_error = |e: std::io::Error| {
+        PhpException::from_class::<crate::error::LLMValidationException>(format!(
+            "Failed to read image '{source}': {e}"
+        ))
+    };
+    // Symlinks and '..' are resolved first, so the file read is the one checked
+    let path = std::fs::canonicalize(source).map_err(read_error)?;
+    if let Some(basedir) = crate::config::ini("open_basedir") {
+        if !within_open_basedir(&path, &basedir) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Image '{source}' is outside the allowed path(s) of open_basedir"
+            )));
+        }
+    }
+    std::fs::read(&path).map_err(read_error)
+}
+
+/// Whether canonical `path` lies under one of the open_basedir entries. As in
+/// PHP, an entry is a path prefix, and one ending in a separator a directory.
+pub(crate) fn within_open_basedir(path: &Path, basedir: &str) -> bool {
+    let list_separator = if cfg!(windows) { ';' } else { ':' };
+    let path = path.to_string_lossy();
+    basedir
+        .split(list_separator)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            let Ok(resolved) = std::fs::canonicalize(entry) else {
+                return false;
+            };
+            let mut prefix = resolved.to_string_lossy().into_owned();
+            if entry.ends_with(MAIN_SEPARATOR) && !prefix.ends_with(MAIN_SEPARATOR) {
+                prefix.push(MAIN_SEPARATOR);
+            }
+            path.starts_with(&prefix)
+        })
+}
+
+/// Image content part attached to a message
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ImagePart {
+    Url(String),
+    Base64 { data: String, media_type: String },
+}
+
+impl ImagePart {
+    /// Build from an http(s) URL, a data: URL, or a local file path
+    pub(crate) fn from_path_or_url(source: &str) -> PhpResult<Self> {
+        if source.starts_with("http://") || source.starts_with("https://") {
+            return Ok(Self::Url(source.to_string()));
+        }
+        if let Some(part) = Self::from_data_url(source) {
+            return Ok(part);
+        }
+
+        let bytes = read_image_file(source)?;
+        let media_type = media_type_for_path(source).ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Unsupported image type for '{source}', expected png, jpeg, gif or webp"
+            ))
+        })?;
+
+        Ok(Self::Base64 {
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+            media_type: media_type.to_string(),
+        })
+    }
+
+    /// Parse `data:<media type>;base64,<data>`
+    fn from_data_url(url: &str) -> Option<Self> {
+        let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+        let media_type = header.strip_suffix(";base64")?;
+        Some(Self::Base64 {
+            data: data.to_string(),
+            media_type: media_type.to_string(),
+        })
+    }
+
+    /// Parse ['url' => ...] or ['data' => ..., 'media_type' => ...]
+    fn from_array(arr: &PhpArray) -> Option<Self> {
+        if let Some(url) = arr.get("url").and_then(|v| v.string()) {
+            return Some(Self::Url(url));
+        }
+        Some(Self::Base64 {
+            data: arr.get("data").and_then(|v| v.string())?,
+            media_type: arr.get("media_type").and_then(|v| v.string())?,
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        if let Some(url) = value["url"].as_str() {
+            return Some(Self::Url(url.to_string()));
+        }
+        Some(Self::Base64 {
+            data: value["data"].as_str()?.to_string(),
+            media_type: value["media_type"].as_str()?.to_string(),
+        })
+    }
+
+    fn to_json(&self) -> Value {
+        match self {
+            Self::Url(url) => json!({ "url": url }),
+            Self::Base64 { data, media_type } => json!({ "data": data, "media_type": media_type }),
+        }
+    }
+
+    fn to_php(&self) -> PhpResult<PhpArray> {
+        let mut arr = PhpArray::new();
+        match self {
+            Self::Url(url) => arr.insert("url", url.as_str())?,
+            Self::Base64 { data, media_type } => {
+                arr.insert("data", data.as_str())?;
+                arr.insert("media_type", media_type.as_str())?;
+            }
+        }
+        Ok(arr)
+    }
+
+    /// Downscale or re-encode the image data; URL images are sent as-is
+    fn processed(self, options: Option<ImageOptions>) -> PhpResult<Self> {
+        let (Some(options), Self::Base64 { data, media_type }) = (options, &self) else {
+            return Ok(self);
+        };
+        let bytes = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map_err(|e| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "Invalid base64 image data: {e}"
+                ))
+            })?;
+        let (bytes, media_type) = image_resize::process(bytes, media_type.clone(), &options)?;
+        Ok(Self::Base64 {
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+            media_type,
+        })
+    }
+
+    /// Image data, unless the image is only referenced by URL
+    pub(crate) fn bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::Url(_) => None,
+            Self::Base64 { data, .. } => {
+                base64::engine::general_purpose::STANDARD.decode(data).ok()
+            }
+        }
+    }
+
+    fn to_octo(&self) -> ImageAttachment {
+        match self {
+            Self::Url(url) => ImageAttachment {
+                data: ImageData::Url(url.clone()),
+                media_type: media_type_for_path(url).unwrap_or("image/jpeg").to_string(),
+                source_type: SourceType::Url,
+                dimensions: None,
+                size_bytes: None,
+            },
+            Self::Base64 { data, media_type } => ImageAttachment {
+                data: ImageData::Base64(data.clone()),
+                media_type: media_type.clone(),
+                source_type: SourceType::Clipboard,
+                dimensions: None,
+                size_bytes: None,
+            },
+        }
+    }
+}
+
+fn media_type_for_path(path: &str) -> Option<&'static str> {
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    let ext = path.rsplit_once('.')?.1.to_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
 
 /// Message in conversation
 #[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
 #[derive(Clone)]
 pub struct Message {
     role: String,
-    content: String,
+    /// Interned, so repeated system prompts and context blocks are shared
+    content: Arc<str>,
     tool_call_id: Option<String>,
     id: Option<String>,
     tool_calls: Option<String>,
+    images: Vec<ImagePart>,
 }
 
 #[php_impl]
@@ -21,10 +220,11 @@ impl Message {
     pub fn user(content: String) -> PhpResult<Self> {
         Ok(Self {
             role: "user".to_string(),
-            content,
+            content: intern(content),
             tool_call_id: None,
             id: None,
             tool_calls: None,
+            images: Vec::new(),
         })
     }
 
@@ -32,10 +232,11 @@ impl Message {
     pub fn assistant(content: String) -> PhpResult<Self> {
         Ok(Self {
             role: "assistant".to_string(),
-            content,
+            content: intern(content),
             tool_call_id: None,
             id: None,
             tool_calls: None,
+            images: Vec::new(),
         })
     }
 
@@ -43,10 +244,11 @@ impl Message {
     pub fn system(content: String) -> PhpResult<Self> {
         Ok(Self {
             role: "system".to_string(),
-            content,
+            content: intern(content),
             tool_call_id: None,
             id: None,
             tool_calls: None,
+            images: Vec::new(),
         })
     }
 
@@ -54,13 +256,86 @@ impl Message {
     pub fn tool(tool_call_id: String, result: String) -> PhpResult<Self> {
         Ok(Self {
             role: "tool".to_string(),
-            content: result,
+            content: intern(result),
             tool_call_id: Some(tool_call_id),
             id: None,
             tool_calls: None,
+            images: Vec::new(),
+        })
+    }
+
+    /// Split a text too long for one message into user messages of at most
+    /// `maxTokens` tokens, marked "[Part 2 of 5]" and, except the last,
+    /// "[Continued in the next message]". A text that fits gives a single
+    /// unmarked message. Tokens are counted for `model`, by default with
+    /// o200k_base.
+    pub fn split(
+        text: String,
+        max_tokens: i64,
+        model: Option<String>,
+    ) -> PhpResult<MessageCollection> {
+        let max_tokens = crate::split::chunk_tokens(Some(max_tokens)).ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "maxTokens must be at least {}",
+                crate::split::MIN_CHUNK_TOKENS
+            ))
+        })?;
+        if text.trim().is_empty() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Cannot split an empty text".to_string()));
+        }
+        let model = model.as_deref().unwrap_or("o200k_base");
+        let messages = crate::split::parts(&text, max_tokens, model)
+            .into_iter()
+            .map(Self::user)
+            .collect::<PhpResult<_>>()?;
+        Ok(MessageCollection {
+            messages: Arc::new(messages),
+            prompt_caching: false,
         })
     }
 
+    /// Create a user message with an image from an http(s) URL, a data: URL
+    /// or a local file path, which must be within open_basedir. Options
+    /// 'max_dimension' and 'quality' shrink the image data before it is
+    /// encoded.
+    pub fn user_with_image(
+        text: String,
+        image_path_or_url: String,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<Self> {
+        let options = ImageOptions::parse(options)?;
+        let mut msg = Self::user(text)?;
+        msg.images
+            .push(ImagePart::from_path_or_url(&image_path_or_url)?.processed(options)?);
+        Ok(msg)
+    }
+
+    /// Attach an image by URL
+    pub fn add_image_url(
+        self_: &mut ZendClassObject<Message>,
+        url: String,
+    ) -> &mut ZendClassObject<Message> {
+        let part = ImagePart::from_data_url(&url).unwrap_or(ImagePart::Url(url));
+        self_.images.push(part);
+        self_
+    }
+
+    /// Attach base64-encoded image data, e.g. media type 'image/png', with
+    /// the same options as userWithImage()
+    pub fn add_image_base64(
+        self_: &mut ZendClassObject<Message>,
+        data: String,
+        media_type: String,
+        options: Option<&PhpArray>,
+    ) -> PhpResult<&mut ZendClassObject<Message>> {
+        let part =
+            ImagePart::Base64 { data, media_type }.processed(ImageOptions::parse(options)?)?;
+        self_.images.push(part);
+        Ok(self_)
+    }
+
     /// Create from ToolResponse
     pub fn from_response(response: &ToolResponse) -> PhpResult<Self> {
         // Serialize tool_calls to JSON if present
@@ -71,7 +346,7 @@ impl Message {
                 .map(|call| {
                     // Parse the arguments_json back to Value for proper serialization
                     let args_value: serde_json::Value =
-                        serde_json::from_str(call.get_arguments_json())
+                        serde_json::from_str(&call.get_arguments_json())
                             .unwrap_or(serde_json::Value::Null);
                     serde_json::json!({
                         "id": call.get_id(),
@@ -89,10 +364,11 @@ impl Message {
 
         Ok(Self {
             role: "assistant".to_string(),
-            content: response.get_content(),
+            content: intern(response.get_content()),
             tool_call_id: None,
             id: response.get_id(),
             tool_calls: tool_calls_json,
+            images: Vec::new(),
         })
     }
 
@@ -130,12 +406,23 @@ impl Message {
             .and_then(|v| v.str())
             .map(|s| s.to_string());
 
+        let images = data
+            .get("images")
+            .and_then(|v| v.array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|(_, v)| v.array().and_then(ImagePart::from_array))
+                    .collect()
+            })
+            .unwrap_or_default();
+
         Ok(Self {
             role,
             content,
             tool_call_id,
             id,
             tool_calls,
+            images,
         })
     }
 
@@ -144,7 +431,7 @@ impl Message {
     }
 
     pub fn get_content(&self) -> String {
-        self.content.clone()
+        self.content.to_string()
     }
 
     pub fn get_tool_calls(&self) -> Option<String> {
@@ -159,10 +446,19 @@ impl Message {
         self.tool_call_id.clone()
     }
 
+    /// Attached images as ['url' => ...] or ['data' => ..., 'media_type' => ...]
+    pub fn get_images(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        for image in &self.images {
+            arr.push(image.to_php()?)?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("role", self.role.clone())?;
-        arr.insert("content", self.content.clone())?;
+        arr.insert("content", &*self.content)?;
         if let Some(ref tool_id) = self.tool_call_id {
             arr.insert("tool_call_id", &**tool_id)?;
         }
@@ -172,27 +468,117 @@ impl Message {
         if let Some(ref calls) = self.tool_calls {
             arr.insert("tool_calls", &**calls)?;
         }
+        if !self.images.is_empty() {
+            arr.insert("images", self.get_images()?)?;
+        }
         Ok(arr.into_zval(false)?)
     }
     pub fn to_json(&self) -> PhpResult<String> {
-        match serde_json::to_string(&serde_json::json!({
-            "role": self.role,
-            "content": self.content,
-            "tool_call_id": self.tool_call_id,
-            "id": self.id,
-            "tool_calls": self.tool_calls,
-        })) {
+        match serde_json::to_string(&self.json_value()) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
                 "Failed to serialize to JSON: {e}"
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+
+    /// State for serialize(), e.g. to keep the object in a session or queue
+    pub fn __serialize(&self) -> PhpResult<ZBox<PhpArray>> {
+        crate::serialization::payload(&self.json_value())
+    }
+
+    pub fn __unserialize(self_: &mut ZendClassObject<Message>, data: &PhpArray) -> PhpResult<()> {
+        let state = crate::serialization::state(data, "Message")?;
+        self_.initialize(Self::from_json_value(&state)?);
+        Ok(())
+    }
 }
 
 // Internal methods - not exposed to PHP
 impl Message {
+    /// User message with one image attached
+    pub(crate) fn user_with_part(text: String, image: ImagePart) -> PhpResult<Self> {
+        let mut msg = Self::user(text)?;
+        msg.images.push(image);
+        Ok(msg)
+    }
+
+    /// Inverse of json_value()
+    fn from_json_value(value: &Value) -> PhpResult<Self> {
+        let field = |name: &str| value[name].as_str().map(str::to_string);
+        let required = |name: &str| {
+            field(name).ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "Message must have '{name}' field"
+                ))
+            })
+        };
+        Ok(Self {
+            role: required("role")?,
+            content: intern(required("content")?),
+            tool_call_id: field("tool_call_id"),
+            id: field("id"),
+            tool_calls: field("tool_calls"),
+            images: value["images"]
+                .as_array()
+                .map(|images| images.iter().filter_map(ImagePart::from_json).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    fn json_value(&self) -> Value {
+        let mut value = json!({
+            "role": self.role,
+            "content": &*self.content,
+            "tool_call_id": self.tool_call_id,
+            "id": self.id,
+            "tool_calls": self.tool_calls,
+        });
+        if !self.images.is_empty() {
+            value["images"] = self.images.iter().map(ImagePart::to_json).collect();
+        }
+        value
+    }
+
+    /// Approximate prompt tokens of the message for a model
+    fn tokens(&self, model: &str) -> usize {
+        let text = count_tokens(&self.content, model)
+            + self
+                .tool_calls
+                .as_deref()
+                .map_or(0, |calls| count_tokens(calls, model));
+        text + self.images.len() * trim::IMAGE_TOKENS + trim::MESSAGE_OVERHEAD
+    }
+
+    /// Whether this is a summary left by truncateToTokens()
+    fn is_summary(&self) -> bool {
+        self.role == "system" && self.content.starts_with(trim::SUMMARY_PREFIX)
+    }
+
+    fn turn(&self) -> Turn<'_> {
+        Turn {
+            role: &self.role,
+            content: &self.content,
+            tool_call_id: self.tool_call_id.as_deref(),
+            tool_calls: self.tool_calls.as_deref(),
+            images: self.images.len(),
+        }
+    }
+
     pub(crate) fn to_octo(&self) -> Result<OctoMessage, PhpException> {
+        let mut msg = self.to_octo_text()?;
+        if !self.images.is_empty() {
+            msg.images = Some(self.images.iter().map(ImagePart::to_octo).collect());
+        }
+        Ok(msg)
+    }
+
+    fn to_octo_text(&self) -> Result<OctoMessage, PhpException> {
         let map_build_err = |e: octolib::errors::MessageError| {
             PhpException::from_class::<crate::error::LLMValidationException>(format!(
                 "Failed to build message: {e}"
@@ -200,11 +586,11 @@ impl Message {
         };
 
         match self.role.as_str() {
-            "user" => Ok(MessageBuilder::user(&self.content)
+            "user" => Ok(MessageBuilder::user(&*self.content)
                 .build()
                 .map_err(map_build_err)?),
             "assistant" => {
-                let mut builder = MessageBuilder::assistant(&self.content);
+                let mut builder = MessageBuilder::assistant(&*self.content);
                 if let Some(ref msg_id) = self.id {
                     builder = builder.id(msg_id);
                 }
@@ -217,12 +603,12 @@ impl Message {
                 }
                 Ok(msg)
             }
-            "system" => Ok(MessageBuilder::system(&self.content)
+            "system" => Ok(MessageBuilder::system(&*self.content)
                 .build()
                 .map_err(map_build_err)?),
             "tool" => {
                 if let Some(ref id) = self.tool_call_id {
-                    Ok(MessageBuilder::tool(&self.content, id, &String::new())
+                    Ok(MessageBuilder::tool(&*self.content, id, &String::new())
                         .build()
                         .map_err(map_build_err)?)
                 } else {
@@ -243,10 +629,78 @@ impl Message {
     }
 }
 
-/// Collection of messages
+/// List index from an ArrayAccess offset: an int or a numeric string
+fn index_of(offset: &Zval) -> Option<usize> {
+    let index = offset
+        .long()
+        .or_else(|| offset.str().and_then(|s| s.parse().ok()))?;
+    usize::try_from(index).ok()
+}
+
+/// Message from a Message object or a ['role', 'content'] array
+fn message_from_zval(value: &Zval, what: &str) -> PhpResult<Message> {
+    match (<&Message>::from_zval(value), value.array()) {
+        (Some(message), _) => Ok(message.clone()),
+        (None, Some(arr)) => Message::from_array(arr),
+        (None, None) => Err(PhpException::from_class::<
+            crate::error::LLMValidationException,
+        >(format!(
+            "{what} must be Message objects or message arrays"
+        ))),
+    }
+}
+
+/// Whether PHP would treat `value` as true, as array_filter() does
+fn truthy(value: &Zval) -> bool {
+    if let Some(b) = value.bool() {
+        return b;
+    }
+    if let Some(n) = value.long() {
+        return n != 0;
+    }
+    if let Some(f) = value.double() {
+        return f != 0.0;
+    }
+    if let Some(s) = value.str() {
+        return !s.is_empty() && s != "0";
+    }
+    if let Some(arr) = value.array() {
+        return !arr.is_empty();
+    }
+    value.is_object()
+}
+
+/// Indices taken by slice(), with array_slice() semantics: a negative
+/// offset counts from the end, and a negative length stops that many
+/// messages before the end
+fn slice_range(count: usize, offset: i64, length: Option<i64>) -> std::ops::Range<usize> {
+    let count = count as i64;
+    let start = if offset < 0 {
+        (count + offset).max(0)
+    } else {
+        offset.min(count)
+    };
+    let end = match length {
+        None => count,
+        Some(len) if len < 0 => (count + len).max(start),
+        Some(len) => start.saturating_add(len).min(count),
+    };
+    start as usize..end as usize
+}
+
+/// Collection of messages. Works as a list: count($collection),
+/// $collection[2] and foreach.
 #[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[php(implements(ce = ce::countable, stub = "\\Countable"))]
+#[php(implements(ce = ce::arrayaccess, stub = "\\ArrayAccess"))]
+#[php(implements(ce = ce::aggregate, stub = "\\IteratorAggregate"))]
+#[derive(Clone)]
 pub struct MessageCollection {
-    messages: Vec<Message>,
+    /// Shared with snapshots and iterators, copied on the first change
+    messages: Arc<Vec<Message>>,
+    /// Whether to mark prompt cache breakpoints (see withPromptCaching())
+    prompt_caching: bool,
 }
 
 #[php_impl]
@@ -265,7 +719,10 @@ impl MessageCollection {
             }
         }
 
-        Ok(Self { messages: msgs })
+        Ok(Self {
+            messages: Arc::new(msgs),
+            prompt_caching: false,
+        })
     }
 
     /// Create from array
@@ -278,7 +735,7 @@ impl MessageCollection {
         self_: &'a mut ZendClassObject<MessageCollection>,
         message: &mut Message,
     ) -> &'a mut ZendClassObject<MessageCollection> {
-        self_.messages.push(message.clone());
+        self_.messages_mut().push(message.clone());
         self_
     }
 
@@ -288,7 +745,7 @@ impl MessageCollection {
         content: String,
     ) -> &mut ZendClassObject<MessageCollection> {
         if let Ok(msg) = Message::user(content) {
-            self_.messages.push(msg);
+            self_.messages_mut().push(msg);
         }
         self_
     }
@@ -299,7 +756,7 @@ impl MessageCollection {
         content: String,
     ) -> &mut ZendClassObject<MessageCollection> {
         if let Ok(msg) = Message::assistant(content) {
-            self_.messages.push(msg);
+            self_.messages_mut().push(msg);
         }
         self_
     }
@@ -310,7 +767,7 @@ impl MessageCollection {
         content: String,
     ) -> &mut ZendClassObject<MessageCollection> {
         if let Ok(msg) = Message::system(content) {
-            self_.messages.push(msg);
+            self_.messages_mut().push(msg);
         }
         self_
     }
@@ -322,11 +779,43 @@ impl MessageCollection {
         result: String,
     ) -> &mut ZendClassObject<MessageCollection> {
         if let Ok(msg) = Message::tool(tool_call_id, result) {
-            self_.messages.push(msg);
+            self_.messages_mut().push(msg);
         }
         self_
     }
 
+    /// Mark prompt cache breakpoints at the stable prefixes of the
+    /// conversation on each request: the system prompt, which also covers
+    /// the tools sent before it, and the last two user or tool turns, so
+    /// each request reads the history the previous one cached. Providers
+    /// with explicit caching (Anthropic) bill the cached prefix at the
+    /// cache-read rate; the others ignore the markers.
+    pub fn with_prompt_caching(
+        self_: &mut ZendClassObject<MessageCollection>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<MessageCollection> {
+        self_.prompt_caching = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// Trim the conversation to about `max_tokens`, oldest messages first.
+    /// System messages and the latest message are always kept, and tool
+    /// results go with the turn that requested them. Strategy 'drop_oldest'
+    /// (default) discards the messages; 'summarize' replaces them with a
+    /// summary written by `llm`, kept as a system message after the others.
+    /// Tokens are counted for the LLM's model when one is passed, otherwise
+    /// with o200k_base.
+    pub fn truncate_to_tokens<'a>(
+        self_: &'a mut ZendClassObject<MessageCollection>,
+        max_tokens: i64,
+        strategy: Option<String>,
+        llm: Option<&LLM>,
+    ) -> PhpResult<&'a mut ZendClassObject<MessageCollection>> {
+        let strategy = TrimStrategy::parse(strategy.as_deref().unwrap_or("drop_oldest"))?;
+        self_.truncate(max_tokens.max(0) as usize, strategy, llm)?;
+        Ok(self_)
+    }
+
     /// Get message at index
     pub fn get(&self, index: i64) -> Option<Message> {
         if index >= 0 && (index as usize) < self.messages.len() {
@@ -338,7 +827,7 @@ impl MessageCollection {
 
     /// Get all messages
     pub fn all(&self) -> Vec<Message> {
-        self.messages.clone()
+        self.messages.to_vec()
     }
 
     /// Get message count
@@ -346,10 +835,155 @@ impl MessageCollection {
         self.messages.len() as i64
     }
 
+    /// Whether there is a message at `offset`
+    pub fn offset_exists(&self, offset: &Zval) -> bool {
+        index_of(offset).is_some_and(|i| i < self.messages.len())
+    }
+
+    /// Message at `offset`, or null
+    pub fn offset_get(&self, offset: &Zval) -> Option<Message> {
+        index_of(offset).and_then(|i| self.messages.get(i).cloned())
+    }
+
+    /// Replace the message at `offset`, or append with `$collection[] =`
+    /// or an offset one past the end. Takes a Message or a ['role',
+    /// 'content'] array.
+    pub fn offset_set(&mut self, offset: &Zval, value: &Zval) -> PhpResult<()> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let message = message_from_zval(value, "MessageCollection items")?;
+        let len = self.messages.len();
+        if offset.is_null() {
+            self.messages_mut().push(message);
+            return Ok(());
+        }
+        match index_of(offset) {
+            Some(i) if i < len => self.messages_mut()[i] = message,
+            Some(i) if i == len => self.messages_mut().push(message),
+            Some(i) => {
+                return Err(invalid(format!(
+                    "Cannot set message at offset {i} of a collection of {len}"
+                )))
+            }
+            None => {
+                return Err(invalid(
+                    "MessageCollection offsets must be non-negative integers".to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the message at `offset`. Later messages move up, so offsets
+    /// stay 0 to count() - 1.
+    pub fn offset_unset(&mut self, offset: &Zval) {
+        if let Some(i) = index_of(offset).filter(|i| *i < self.messages.len()) {
+            self.messages_mut().remove(i);
+        }
+    }
+
+    /// Remove the message at `index`; later messages move up
+    pub fn remove(
+        self_: &mut ZendClassObject<MessageCollection>,
+        index: i64,
+    ) -> PhpResult<&mut ZendClassObject<MessageCollection>> {
+        let len = self_.messages.len();
+        match usize::try_from(index).ok().filter(|i| *i < len) {
+            Some(i) => {
+                self_.messages_mut().remove(i);
+                Ok(self_)
+            }
+            None => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Cannot remove message {index} of a collection of {len}"
+            ))),
+        }
+    }
+
+    /// Insert a message before `index`; count() appends
+    pub fn insert<'a>(
+        self_: &'a mut ZendClassObject<MessageCollection>,
+        index: i64,
+        message: &Message,
+    ) -> PhpResult<&'a mut ZendClassObject<MessageCollection>> {
+        let len = self_.messages.len();
+        match usize::try_from(index).ok().filter(|i| *i <= len) {
+            Some(i) => {
+                self_.messages_mut().insert(i, message.clone());
+                Ok(self_)
+            }
+            None => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Cannot insert a message at {index} of a collection of {len}"
+            ))),
+        }
+    }
+
+    /// New collection with `length` messages from `offset`, or up to the
+    /// end without a length. Offsets and lengths work as in array_slice().
+    pub fn slice(&self, offset: i64, length: Option<i64>) -> Self {
+        let range = slice_range(self.messages.len(), offset, length);
+        self.with_messages(self.messages[range].to_vec())
+    }
+
+    /// New collection with the messages for which `fn(Message $m): bool`
+    /// returns true
+    pub fn filter(&self, callback: ZendCallable) -> PhpResult<Self> {
+        let mut kept = Vec::new();
+        for message in self.messages.iter() {
+            if truthy(&callback.try_call(vec![message])?) {
+                kept.push(message.clone());
+            }
+        }
+        Ok(self.with_messages(kept))
+    }
+
+    /// New collection with each message replaced by what
+    /// `fn(Message $m): Message|array` returns
+    pub fn map(&self, callback: ZendCallable) -> PhpResult<Self> {
+        let messages = self
+            .messages
+            .iter()
+            .map(|message| {
+                let result = callback.try_call(vec![message])?;
+                message_from_zval(&result, "MessageCollection::map() results")
+            })
+            .collect::<PhpResult<_>>()?;
+        Ok(self.with_messages(messages))
+    }
+
+    /// Append the messages of another collection
+    pub fn merge<'a>(
+        self_: &'a mut ZendClassObject<MessageCollection>,
+        other: &MessageCollection,
+    ) -> &'a mut ZendClassObject<MessageCollection> {
+        self_.messages_mut().extend(other.messages.iter().cloned());
+        self_
+    }
+
+    /// Iterator over a snapshot of the messages, for foreach
+    pub fn get_iterator(&self) -> MessageIterator {
+        MessageIterator {
+            messages: Arc::clone(&self.messages),
+            position: 0,
+        }
+    }
+
+    /// Read-only view of the messages as they are now, sharing them with
+    /// this collection instead of copying: later changes to the collection
+    /// copy its messages first and leave the snapshot as it was
+    pub fn snapshot(&self) -> MessageSnapshot {
+        MessageSnapshot {
+            messages: Arc::clone(&self.messages),
+            prompt_caching: self.prompt_caching,
+        }
+    }
+
     /// Convert to array
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
-        for msg in &self.messages {
+        for msg in self.messages.iter() {
             arr.push(msg.clone().into_zval(false)?)?;
         }
         Ok(arr.into_zval(false)?)
@@ -357,32 +991,454 @@ impl MessageCollection {
 
     /// Convert to JSON
     pub fn to_json(&self) -> PhpResult<String> {
-        let messages: Vec<serde_json::Value> = self
-            .messages
-            .iter()
-            .map(|m| {
-                serde_json::json!({
-                    "role": m.role,
-                    "content": m.content,
-                    "tool_call_id": m.tool_call_id,
-                    "id": m.id,
-                    "tool_calls": m.tool_calls,
-                })
-            })
-            .collect();
-        match serde_json::to_string(&messages) {
+        match serde_json::to_string(&self.json_value()) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
                 "Failed to serialize to JSON: {e}"
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+
+    /// Restore a collection saved with toJson(), tool calls and tool
+    /// results included
+    pub fn from_json(json: String) -> PhpResult<Self> {
+        let value: Value = serde_json::from_str(&json).map_err(|e| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Invalid message JSON: {e}"
+            ))
+        })?;
+        Self::from_json_value(&value)
+    }
+
+    /// State for serialize(), e.g. to keep the object in a session or queue
+    pub fn __serialize(&self) -> PhpResult<ZBox<PhpArray>> {
+        crate::serialization::payload(&json!({
+            "messages": self.json_value(),
+            "prompt_caching": self.prompt_caching,
+        }))
+    }
+
+    pub fn __unserialize(
+        self_: &mut ZendClassObject<MessageCollection>,
+        data: &PhpArray,
+    ) -> PhpResult<()> {
+        let state = crate::serialization::state(data, "MessageCollection")?;
+        let mut collection = Self::from_json_value(&state["messages"])?;
+        collection.prompt_caching = state["prompt_caching"].as_bool().unwrap_or(false);
+        self_.initialize(collection);
+        Ok(())
+    }
+
+    /// Render as a Markdown transcript, including tool calls and results
+    pub fn to_markdown(&self) -> String {
+        let turns: Vec<Turn> = self.messages.iter().map(Message::turn).collect();
+        transcript::to_markdown(&turns)
+    }
+
+    /// Render as an HTML transcript fragment. Elements carry classes such as
+    /// `message-user` and `tool-call` for styling.
+    pub fn to_html(&self) -> String {
+        let turns: Vec<Turn> = self.messages.iter().map(Message::turn).collect();
+        transcript::to_html(&turns)
+    }
 }
 
 // Internal methods - not exposed to PHP
 impl MessageCollection {
     /// Convert to octolib messages
     pub(crate) fn to_octo(&self) -> Result<Vec<OctoMessage>, PhpException> {
-        self.messages.iter().map(|m| m.to_octo()).collect()
+        octo_messages(&self.messages, self.prompt_caching)
+    }
+
+    fn messages_mut(&mut self) -> &mut Vec<Message> {
+        Arc::make_mut(&mut self.messages)
+    }
+
+    /// Messages as a JSON list, the format of toJson()
+    pub(crate) fn json_value(&self) -> Value {
+        Value::Array(self.messages.iter().map(Message::json_value).collect())
+    }
+
+    /// Inverse of json_value()
+    pub(crate) fn from_json_value(value: &Value) -> PhpResult<Self> {
+        let list = value.as_array().ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(
+                "Message JSON must be a list of messages".to_string(),
+            )
+        })?;
+        Ok(Self {
+            messages: Arc::new(
+                list.iter()
+                    .map(Message::from_json_value)
+                    .collect::<PhpResult<_>>()?,
+            ),
+            prompt_caching: false,
+        })
+    }
+
+    /// Collection with the same settings holding `messages`
+    fn with_messages(&self, messages: Vec<Message>) -> Self {
+        Self {
+            messages: Arc::new(messages),
+            prompt_caching: self.prompt_caching,
+        }
+    }
+
+    pub(crate) fn push(&mut self, message: Message) {
+        self.messages_mut().push(message);
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Message> {
+        self.messages_mut().pop()
+    }
+
+    /// Trim to about `max_tokens`, see truncateToTokens()
+    pub(crate) fn truncate(
+        &mut self,
+        max_tokens: usize,
+        strategy: TrimStrategy,
+        llm: Option<&LLM>,
+    ) -> PhpResult<()> {
+        let summarizer = match strategy {
+            TrimStrategy::DropOldest => None,
+            TrimStrategy::Summarize => Some(llm.ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(
+                    "The 'summarize' strategy needs an LLM".to_string(),
+                )
+            })?),
+        };
+        let model = llm.map_or_else(|| "o200k_base".to_string(), LLM::get_model);
+        let mut max = max_tokens;
+        if summarizer.is_some() {
+            max = max.saturating_sub(trim::SUMMARY_MAX_TOKENS as usize);
+        }
+
+        let drop = {
+            // A previous summary is folded into the new one
+            let roles: Vec<&str> = self
+                .messages
+                .iter()
+                .map(|m| match summarizer {
+                    Some(_) if m.is_summary() => "summary",
+                    _ => m.role.as_str(),
+                })
+                .collect();
+            let tokens: Vec<usize> = self.messages.iter().map(|m| m.tokens(&model)).collect();
+            trim::plan_drop(&roles, &tokens, max)
+        };
+        if !drop.contains(&true) {
+            return Ok(());
+        }
+
+        let summary = match summarizer {
+            Some(llm) => {
+                let transcript = trim::transcript(
+                    self.messages
+                        .iter()
+                        .zip(&drop)
+                        .filter(|(_, dropped)| **dropped)
+                        .map(|(m, _)| (m.role.as_str(), &*m.content)),
+                );
+                Some(llm.summarize(&transcript)?)
+            }
+            None => None,
+        };
+
+        let mut dropped = drop.into_iter();
+        self.messages_mut()
+            .retain(|_| !dropped.next().unwrap_or(false));
+        if let Some(summary) = summary {
+            let at = self
+                .messages
+                .iter()
+                .take_while(|m| m.role == "system")
+                .count();
+            let message = Message::system(format!("{}\n{summary}", trim::SUMMARY_PREFIX))?;
+            self.messages_mut().insert(at, message);
+        }
+        Ok(())
+    }
+}
+
+/// Convert to octolib messages, marking the prompt cache breakpoints when
+/// `prompt_caching`
+fn octo_messages(
+    messages: &[Message],
+    prompt_caching: bool,
+) -> Result<Vec<OctoMessage>, PhpException> {
+    let mut messages = messages
+        .iter()
+        .map(|m| m.to_octo())
+        .collect::<Result<Vec<_>, _>>()?;
+    if prompt_caching {
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        for i in cache_breakpoints(&roles) {
+            messages[i].cached = true;
+        }
+    }
+    Ok(messages)
+}
+
+/// Messages ending a cacheable prefix: the last system message and the last
+/// two user or tool turns. Anthropic allows four breakpoints per request, so
+/// one is left for the caller.
+fn cache_breakpoints(roles: &[&str]) -> Vec<usize> {
+    let mut points: Vec<usize> = roles
+        .iter()
+        .rposition(|r| *r == "system")
+        .into_iter()
+        .collect();
+    points.extend(
+        roles
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, r)| matches!(**r, "user" | "tool"))
+            .map(|(i, _)| i)
+            .take(2),
+    );
+    points.sort_unstable();
+    points
+}
+
+/// Read-only messages returned by MessageCollection::snapshot(). Shares
+/// the messages with the collection, so it is cheap to take of a long
+/// history and to pass to concurrent async completions. Works as a list
+/// like the collection; changing it throws.
+#[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[php(implements(ce = ce::countable, stub = "\\Countable"))]
+#[php(implements(ce = ce::arrayaccess, stub = "\\ArrayAccess"))]
+#[php(implements(ce = ce::aggregate, stub = "\\IteratorAggregate"))]
+#[derive(Clone)]
+pub struct MessageSnapshot {
+    messages: Arc<Vec<Message>>,
+    prompt_caching: bool,
+}
+
+fn read_only() -> PhpException {
+    PhpException::from_class::<crate::error::LLMValidationException>(
+        "MessageSnapshot is read-only; call toCollection() for a copy to change".to_string(),
+    )
+}
+
+#[php_impl]
+impl MessageSnapshot {
+    /// Get message at index
+    pub fn get(&self, index: i64) -> Option<Message> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| self.messages.get(i).cloned())
+    }
+
+    /// Get all messages
+    pub fn all(&self) -> Vec<Message> {
+        self.messages.to_vec()
+    }
+
+    pub fn count(&self) -> i64 {
+        self.messages.len() as i64
+    }
+
+    pub fn offset_exists(&self, offset: &Zval) -> bool {
+        index_of(offset).is_some_and(|i| i < self.messages.len())
+    }
+
+    pub fn offset_get(&self, offset: &Zval) -> Option<Message> {
+        index_of(offset).and_then(|i| self.messages.get(i).cloned())
+    }
+
+    pub fn offset_set(&self, _offset: &Zval, _value: &Zval) -> PhpResult<()> {
+        Err(read_only())
+    }
+
+    pub fn offset_unset(&self, _offset: &Zval) -> PhpResult<()> {
+        Err(read_only())
+    }
+
+    pub fn get_iterator(&self) -> MessageIterator {
+        MessageIterator {
+            messages: Arc::clone(&self.messages),
+            position: 0,
+        }
+    }
+
+    /// Collection holding these messages, to change; they are copied on its
+    /// first change
+    pub fn to_collection(&self) -> MessageCollection {
+        MessageCollection {
+            messages: Arc::clone(&self.messages),
+            prompt_caching: self.prompt_caching,
+        }
+    }
+
+    /// Convert to array
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        self.to_collection().to_array()
+    }
+
+    /// Convert to JSON, in the format of MessageCollection::toJson()
+    pub fn to_json(&self) -> PhpResult<String> {
+        self.to_collection().to_json()
+    }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+}
+
+// Internal methods - not exposed to PHP
+impl MessageSnapshot {
+    /// Convert to octolib messages
+    pub(crate) fn to_octo(&self) -> Result<Vec<OctoMessage>, PhpException> {
+        octo_messages(&self.messages, self.prompt_caching)
+    }
+}
+
+/// Iterator returned by MessageCollection::getIterator()
+#[php_class]
+#[php(implements(ce = ce::iterator, stub = "\\Iterator"))]
+pub struct MessageIterator {
+    messages: Arc<Vec<Message>>,
+    position: usize,
+}
+
+#[php_impl]
+impl MessageIterator {
+    pub fn current(&self) -> Option<Message> {
+        self.messages.get(self.position).cloned()
+    }
+
+    pub fn key(&self) -> i64 {
+        self.position as i64
+    }
+
+    pub fn next(&mut self) {
+        self.position += 1;
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0;
+    }
+
+    pub fn valid(&self) -> bool {
+        self.position < self.messages.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cache_breakpoints, media_type_for_path, slice_range, within_open_basedir, ImagePart,
+        Message, MessageCollection,
+    };
+    use std::sync::Arc;
+
+    #[test]
+    fn test_media_type_for_path() {
+        assert_eq!(media_type_for_path("/tmp/cat.PNG"), Some("image/png"));
+        assert_eq!(
+            media_type_for_path("https://example.com/a.jpg?size=large"),
+            Some("image/jpeg")
+        );
+        assert_eq!(media_type_for_path("notes.txt"), None);
+    }
+
+    #[test]
+    fn test_data_url() {
+        assert_eq!(
+            ImagePart::from_data_url("data:image/png;base64,iVBORw0KGgo="),
+            Some(ImagePart::Base64 {
+                data: "iVBORw0KGgo=".to_string(),
+                media_type: "image/png".to_string(),
+            })
+        );
+        assert_eq!(ImagePart::from_data_url("data:text/plain,hello"), None);
+    }
+
+    #[test]
+    fn test_within_open_basedir() {
+        let dir = std::env::temp_dir().join(format!("llm-basedir-test-{}", std::process::id()));
+        let allowed = dir.join("allowed");
+        std::fs::create_dir_all(&allowed).unwrap();
+        let file = allowed.join("cat.png");
+        std::fs::write(&file, b"png").unwrap();
+        let file = std::fs::canonicalize(&file).unwrap();
+        let allowed = allowed.to_string_lossy().into_owned();
+
+        assert!(within_open_basedir(&file, &allowed));
+        assert!(within_open_basedir(
+            &file,
+            &format!("/nonexistent:{allowed}/")
+        ));
+        assert!(!within_open_basedir(&file, &format!("{allowed}/sub/")));
+        assert!(!within_open_basedir(&file, "/nonexistent"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_message_json_round_trip() {
+        let message = Message {
+            role: "assistant".to_string(),
+            content: "".into(),
+            tool_call_id: None,
+            id: Some("msg_1".to_string()),
+            tool_calls: Some(r#"[{"id":"call_1","name":"weather"}]"#.to_string()),
+            images: vec![ImagePart::Url("https://example.com/a.png".to_string())],
+        };
+        let value = message.json_value();
+        let restored = Message::from_json_value(&value).map(|m| m.json_value());
+        assert_eq!(restored.ok(), Some(value));
+    }
+
+    #[test]
+    fn test_snapshot_is_unaffected_by_changes() {
+        let message = |content: &str| Message {
+            role: "user".to_string(),
+            content: content.into(),
+            tool_call_id: None,
+            id: None,
+            tool_calls: None,
+            images: Vec::new(),
+        };
+        let mut collection = MessageCollection::from_json_value(&serde_json::json!([])).unwrap();
+        collection.push(message("first"));
+        let snapshot = collection.snapshot();
+        assert!(Arc::ptr_eq(&snapshot.messages, &collection.messages));
+
+        collection.push(message("second"));
+        assert_eq!((snapshot.count(), collection.count()), (1, 2));
+        assert_eq!(&*snapshot.messages[0].content, "first");
+    }
+
+    #[test]
+    fn test_cache_breakpoints() {
+        let roles = ["system", "user", "assistant", "user", "assistant", "user"];
+        assert_eq!(cache_breakpoints(&roles), vec![0, 3, 5]);
+        assert_eq!(
+            cache_breakpoints(&["user", "assistant", "tool"]),
+            vec![0, 2]
+        );
+        assert_eq!(cache_breakpoints(&["system"]), vec![0]);
+        assert!(cache_breakpoints(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_slice_range() {
+        assert_eq!(slice_range(5, 1, Some(2)), 1..3);
+        assert_eq!(slice_range(5, 3, None), 3..5);
+        assert_eq!(slice_range(5, -2, None), 3..5);
+        assert_eq!(slice_range(5, 1, Some(-1)), 1..4);
+        assert_eq!(slice_range(5, 4, Some(10)), 4..5);
+        assert_eq!(slice_range(5, 9, Some(1)), 5..5);
+        assert_eq!(slice_range(5, -9, Some(2)), 0..2);
+        assert_eq!(slice_range(5, 3, Some(-4)), 3..3);
     }
 }
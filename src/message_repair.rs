@@ -0,0 +1,105 @@
+use octolib::llm::Message as OctoMessage;
+use std::collections::HashSet;
+
+/// Ids of the tool calls requested by an assistant message
+fn call_ids(message: &OctoMessage) -> impl Iterator<Item = &str> {
+    message
+        .tool_calls
+        .as_ref()
+        .and_then(|calls| calls.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|call| call.get("id").and_then(|id| id.as_str()))
+}
+
+/// Whether `next` can be folded into `previous`: same role, not tool
+/// results (each answers its own call), and `previous` does not request
+/// tool calls, which must be followed by their results
+fn mergeable(previous: &OctoMessage, next: &OctoMessage) -> bool {
+    previous.role == next.role && previous.role != "tool" && previous.tool_calls.is_none()
+}
+
+/// Fix sequences providers reject, see LLM::repairMessages(): consecutive
+/// messages of the same role are merged, and tool results that do not
+/// answer a preceding tool call are dropped. Returns the number of dropped
+/// tool results.
+pub(crate) fn repair(messages: &mut Vec<OctoMessage>) -> usize {
+    let mut repaired: Vec<OctoMessage> = Vec::with_capacity(messages.len());
+    let mut pending: HashSet<String> = HashSet::new();
+    let mut dropped = 0;
+
+    for message in messages.drain(..) {
+        if message.role == "tool" {
+            let answered = message
+                .tool_call_id
+                .as_ref()
+                .is_some_and(|id| pending.remove(id));
+            if !answered {
+                dropped += 1;
+                continue;
+            }
+        } else {
+            pending = call_ids(&message).map(str::to_string).collect();
+        }
+
+        match repaired.last_mut() {
+            Some(previous) if mergeable(previous, &message) => {
+                if !message.content.is_empty() {
+                    if !previous.content.is_empty() {
+                        previous.content.push_str("\n\n");
+                    }
+                    previous.content.push_str(&message.content);
+                }
+                if let Some(images) = message.images {
+                    previous.images.get_or_insert_with(Vec::new).extend(images);
+                }
+                previous.tool_calls = message.tool_calls;
+            }
+            _ => repaired.push(message),
+        }
+    }
+
+    *messages = repaired;
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octolib::llm::MessageBuilder;
+    use serde_json::json;
+
+    fn roles(messages: &[OctoMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    #[test]
+    fn test_merges_consecutive_roles() {
+        let mut messages = vec![
+            MessageBuilder::system("Be brief.").build().unwrap(),
+            MessageBuilder::user("Hello").build().unwrap(),
+            MessageBuilder::user("Are you there?").build().unwrap(),
+            MessageBuilder::assistant("Yes.").build().unwrap(),
+        ];
+        assert_eq!(repair(&mut messages), 0);
+        assert_eq!(roles(&messages), ["system", "user", "assistant"]);
+        assert_eq!(messages[1].content, "Hello\n\nAre you there?");
+    }
+
+    #[test]
+    fn test_drops_orphaned_tool_results() {
+        let mut call = MessageBuilder::assistant("").build().unwrap();
+        call.tool_calls = Some(json!([{"id": "call_1", "name": "weather", "arguments": {}}]));
+        let mut messages = vec![
+            MessageBuilder::user("Weather?").build().unwrap(),
+            MessageBuilder::tool("stale", "call_0", "").build().unwrap(),
+            call,
+            MessageBuilder::tool("sunny", "call_1", "").build().unwrap(),
+            MessageBuilder::tool("again", "call_1", "").build().unwrap(),
+            MessageBuilder::assistant("It is sunny.").build().unwrap(),
+        ];
+        assert_eq!(repair(&mut messages), 2);
+        assert_eq!(roles(&messages), ["user", "assistant", "tool", "assistant"]);
+        assert_eq!(messages[2].content, "sunny");
+    }
+}
@@ -0,0 +1,305 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::Zval;
+use octolib::llm::Message as OctoMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// What to do when a parameter set on the instance is not supported by the
+/// target provider
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ParamPolicy {
+    /// Throw LLMValidationException
+    Strict,
+    /// Silently leave the parameter out of the request
+    #[default]
+    Drop,
+    /// Leave it out and log a warning
+    Warn,
+}
+
+impl ParamPolicy {
+    pub(crate) fn parse(policy: &str) -> PhpResult<Self> {
+        match policy {
+            "strict" => Ok(Self::Strict),
+            "drop" => Ok(Self::Drop),
+            "warn" => Ok(Self::Warn),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown parameter policy '{other}', expected 'strict', 'drop' or 'warn'"
+            ))),
+        }
+    }
+
+    /// Apply the policy to parameters that will not be sent
+    pub(crate) fn enforce(self, model: &str, unsupported: &[&str]) -> PhpResult<()> {
+        if unsupported.is_empty() {
+            return Ok(());
+        }
+
+        let message = format!(
+            "Parameter(s) {} not supported for model '{model}'",
+            unsupported.join(", ")
+        );
+        match self {
+            Self::Strict => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(message)),
+            Self::Drop => Ok(()),
+            Self::Warn => {
+                crate::logger::warn(&format!("{message}, dropping"));
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Sampling penalties set on an LLM instance; zero means unset
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub(crate) struct Penalties {
+    pub frequency: f32,
+    pub presence: f32,
+}
+
+impl Penalties {
+    /// Names of the penalties that are set
+    pub(crate) fn set_names(&self) -> Vec<&'static str> {
+        let mut names = Vec::new();
+        if self.frequency != 0.0 {
+            names.push("frequency_penalty");
+        }
+        if self.presence != 0.0 {
+            names.push("presence_penalty");
+        }
+        names
+    }
+}
+
+/// Whether and which tools the model must call
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum ToolChoice {
+    /// The model decides
+    #[default]
+    Auto,
+    None,
+    /// At least one tool call
+    Required,
+    /// A call to this tool
+    Function(String),
+}
+
+impl ToolChoice {
+    pub(crate) fn parse(mode: &str, tool_name: Option<String>) -> PhpResult<Self> {
+        let invalid =
+            |msg: String| PhpException::from_class::<crate::error::LLMValidationException>(msg);
+        match (mode, tool_name) {
+            ("function", Some(name)) if !name.is_empty() => Ok(Self::Function(name)),
+            ("function", _) => Err(invalid(
+                "Tool choice 'function' needs the name of the tool to call".to_string(),
+            )),
+            (_, Some(_)) => Err(invalid(format!(
+                "A tool name is only accepted with tool choice 'function', not '{mode}'"
+            ))),
+            ("auto", None) => Ok(Self::Auto),
+            ("none", None) => Ok(Self::None),
+            ("required", None) => Ok(Self::Required),
+            (other, None) => Err(invalid(format!(
+                "Unknown tool choice '{other}', expected 'auto', 'none', 'required' or 'function'"
+            ))),
+        }
+    }
+
+    /// Choice for the request following tool results: forcing a call again
+    /// would keep the model from ever answering
+    pub(crate) fn after_tool_results(&self) -> Self {
+        match self {
+            Self::Required | Self::Function(_) => Self::Auto,
+            other => other.clone(),
+        }
+    }
+
+    /// `tool_choice` in the OpenAI wire format
+    pub(crate) fn openai_value(&self) -> Option<Value> {
+        match self {
+            Self::Auto => None,
+            Self::None => Some(json!("none")),
+            Self::Required => Some(json!("required")),
+            Self::Function(name) => Some(json!({
+                "type": "function",
+                "function": { "name": name },
+            })),
+        }
+    }
+
+    /// `tool_choice` in the Anthropic wire format
+    pub(crate) fn anthropic_value(&self) -> Option<Value> {
+        match self {
+            Self::Auto => None,
+            Self::None => Some(json!({ "type": "none" })),
+            Self::Required => Some(json!({ "type": "any" })),
+            Self::Function(name) => Some(json!({ "type": "tool", "name": name })),
+        }
+    }
+}
+
+/// Stop sequences from a string or an array of strings
+pub(crate) fn parse_stop(stop: &Zval) -> PhpResult<Vec<String>> {
+    let invalid = || {
+        PhpException::from_class::<crate::error::LLMValidationException>(
+            "Stop must be a string or an array of strings".to_string(),
+        )
+    };
+    let sequences = if let Some(sequence) = stop.string() {
+        vec![sequence]
+    } else if let Some(arr) = stop.array() {
+        arr.iter()
+            .map(|(_, v)| v.string().ok_or_else(invalid))
+            .collect::<PhpResult<Vec<_>>>()?
+    } else {
+        return Err(invalid());
+    };
+    Ok(sequences.into_iter().filter(|s| !s.is_empty()).collect())
+}
+
+fn provider_of(model: &str) -> String {
+    let provider = model.split_once(':').map_or(model, |(p, _)| p);
+    provider.trim().to_lowercase()
+}
+
+/// Role a model accepts for system instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SystemRole {
+    System,
+    /// OpenAI reasoning models (o1, o3, o4) take instructions as 'developer'
+    Developer,
+    /// No system prompt at all (o1-mini, o1-preview, Gemma): instructions go
+    /// into the next user message
+    User,
+}
+
+fn system_role(model: &str) -> SystemRole {
+    let provider = provider_of(model);
+    let name = model.split_once(':').map_or(model, |(_, m)| m).trim();
+    // OpenRouter style "openai/o1-mini" names
+    let name = name.rsplit('/').next().unwrap_or(name).to_lowercase();
+
+    if ["o1-mini", "o1-preview", "gemma"]
+        .iter()
+        .any(|p| name.starts_with(p))
+    {
+        SystemRole::User
+    } else if matches!(provider.as_str(), "openai" | "azure")
+        && ["o1", "o3", "o4"].iter().any(|p| name.starts_with(p))
+    {
+        SystemRole::Developer
+    } else {
+        SystemRole::System
+    }
+}
+
+/// Give system messages the role the model accepts, so requests are not
+/// rejected with a 400. Without a system role the instructions are put in
+/// front of the next user message, or sent as a user message when none
+/// follows.
+pub(crate) fn remap_system_role(model: &str, messages: &mut Vec<OctoMessage>) {
+    match system_role(model) {
+        SystemRole::System => {}
+        SystemRole::Developer => {
+            for message in messages.iter_mut().filter(|m| m.role == "system") {
+                message.role = "developer".to_string();
+            }
+        }
+        SystemRole::User => {
+            let mut i = 0;
+            while i < messages.len() {
+                if messages[i].role != "system" {
+                    i += 1;
+                    continue;
+                }
+                match (i + 1..messages.len()).find(|&j| messages[j].role == "user") {
+                    Some(j) => {
+                        let system = messages.remove(i);
+                        let user = &mut messages[j - 1];
+                        user.content = format!("{}\n\n{}", system.content, user.content);
+                    }
+                    None => {
+                        messages[i].role = "user".to_string();
+                        i += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_names() {
+        assert!(Penalties::default().set_names().is_empty());
+        let penalties = Penalties {
+            frequency: 0.5,
+            presence: 0.0,
+        };
+        assert_eq!(penalties.set_names(), vec!["frequency_penalty"]);
+    }
+
+    #[test]
+    fn test_tool_choice_relaxes_after_results() {
+        let forced = ToolChoice::Function("get_weather".to_string());
+        assert_eq!(forced.after_tool_results(), ToolChoice::Auto);
+        assert_eq!(ToolChoice::None.after_tool_results(), ToolChoice::None);
+        assert_eq!(
+            forced.anthropic_value(),
+            Some(json!({"type": "tool", "name": "get_weather"}))
+        );
+        assert_eq!(ToolChoice::Auto.openai_value(), None);
+    }
+
+    #[test]
+    fn test_system_role_by_model() {
+        assert_eq!(system_role("openai:gpt-4o"), SystemRole::System);
+        assert_eq!(system_role("openai:o3-mini"), SystemRole::Developer);
+        assert_eq!(system_role("openai:o1-mini"), SystemRole::User);
+        assert_eq!(
+            system_role("openrouter:google/gemma-2-9b-it"),
+            SystemRole::User
+        );
+        // Other providers with an "o1" name keep their own rules
+        assert_eq!(system_role("openrouter:openai/o3"), SystemRole::System);
+    }
+
+    #[test]
+    fn test_remap_system_into_next_user_message() {
+        let message = |role: &str, content: &str| {
+            let mut message = octolib::llm::MessageBuilder::user(content).build().unwrap();
+            message.role = role.to_string();
+            message
+        };
+        let mut messages = vec![
+            message("system", "Be brief."),
+            message("user", "Hi"),
+            message("assistant", "Hello"),
+            message("system", "Answer in French."),
+        ];
+        remap_system_role("openai:o1-mini", &mut messages);
+        let roles: Vec<(&str, &str)> = messages
+            .iter()
+            .map(|m| (m.role.as_str(), m.content.as_str()))
+            .collect();
+        assert_eq!(
+            roles,
+            vec![
+                ("user", "Be brief.\n\nHi"),
+                ("assistant", "Hello"),
+                ("user", "Answer in French."),
+            ]
+        );
+
+        let mut messages = vec![message("system", "Be brief."), message("user", "Hi")];
+        remap_system_role("openai:o1", &mut messages);
+        assert_eq!(messages[0].role, "developer");
+    }
+}
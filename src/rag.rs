@@ -0,0 +1,245 @@
+use ext_php_rs::convert::{FromZval, IntoZval};
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendCallable, ZendClassObject, ZendHashTable as PhpArray, Zval};
+
+use crate::embeddings::{Embeddings, InputType};
+use crate::llm_class::{Response, LLM};
+use crate::manticore::ManticoreVectorStore;
+
+/// Chunks retrieved per question unless set otherwise
+const DEFAULT_K: usize = 4;
+
+/// Prompt sent to the model, with `{context}` and `{question}` placeholders
+const DEFAULT_TEMPLATE: &str = "Answer the question using only the context below. \
+If the context does not contain the answer, say that you don't know.\n\n\
+Context:\n{context}\n\nQuestion: {question}";
+
+/// Retrieved chunks as numbered passages
+fn format_context(texts: &[String]) -> String {
+    texts
+        .iter()
+        .enumerate()
+        .map(|(i, text)| format!("[{}] {}", i + 1, text.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// Fill the template's placeholders in one pass, so braces in the retrieved
+/// text or the question are never taken for placeholders
+fn render(template: &str, context: &str, question: &str) -> String {
+    template
+        .split("{context}")
+        .map(|part| part.replace("{question}", question))
+        .collect::<Vec<_>>()
+        .join(context)
+}
+
+/// Text of a retrieved chunk: a string, or an array with a 'text' string
+fn chunk_text(chunk: &Zval) -> Option<String> {
+    chunk
+        .string()
+        .or_else(|| chunk.array()?.get("text")?.string())
+}
+
+/// Retrieval-augmented generation: fetch the chunks nearest to a question
+/// from a store, put them in the prompt and ask the model
+#[php_class]
+#[allow(clippy::upper_case_acronyms)]
+pub struct RAG {
+    llm: LLM,
+    /// ManticoreVectorStore or a callable
+    store: Zval,
+    embeddings: Option<Embeddings>,
+    k: usize,
+    template: String,
+}
+
+// Internal methods - not exposed to PHP
+impl RAG {
+    /// The `k` chunks nearest to the question, as returned by the store
+    fn retrieve(&self, question: &str) -> PhpResult<Vec<Zval>> {
+        let found = match <&ManticoreVectorStore>::from_zval(&self.store) {
+            Some(store) => store.search(question.to_string(), self.k as i64)?,
+            None => {
+                let embeddings = match &self.embeddings {
+                    Some(embeddings) => embeddings.clone(),
+                    None => self.llm.embeddings(None)?,
+                };
+                let vector: Vec<f64> = embeddings
+                    .vectors(&[question.to_string()], InputType::Query)?
+                    .pop()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(f64::from)
+                    .collect();
+                let k = self.k as i64;
+                ZendCallable::new(&self.store)?.try_call(vec![&question, &vector, &k])?
+            }
+        };
+
+        let chunks = found.array().ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(
+                "RAG store must return an array of chunks".to_string(),
+            )
+        })?;
+        Ok(chunks
+            .iter()
+            .take(self.k)
+            .map(|(_, chunk)| chunk.shallow_clone())
+            .collect())
+    }
+}
+
+#[php_impl]
+impl RAG {
+    /// Answer questions with `llm` from chunks in `store`: a
+    /// ManticoreVectorStore, or a callable `fn(string $question, array
+    /// $embedding, int $k): array` returning chunks as strings or arrays with
+    /// a 'text' key
+    #[php(constructor)]
+    pub fn __construct(llm: &LLM, store: &Zval) -> PhpResult<Self> {
+        if <&ManticoreVectorStore>::from_zval(store).is_none() && !store.is_callable() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "RAG store must be a ManticoreVectorStore or a callable".to_string(),
+            ));
+        }
+        Ok(Self {
+            llm: llm.clone(),
+            store: store.shallow_clone(),
+            embeddings: None,
+            k: DEFAULT_K,
+            template: DEFAULT_TEMPLATE.to_string(),
+        })
+    }
+
+    /// Retrieve the chunks for `question`, ask the model with them in the
+    /// prompt and return the answer with the chunks used
+    pub fn ask(&self, question: String) -> PhpResult<RAGAnswer> {
+        let sources = self.retrieve(&question)?;
+        let texts = sources
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                chunk_text(chunk).ok_or_else(|| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                        "Chunk {i} must be a string or an array with a 'text' string"
+                    ))
+                })
+            })
+            .collect::<PhpResult<Vec<String>>>()?;
+
+        let mut message = PhpArray::new();
+        message.insert("role", "user")?;
+        message.insert(
+            "content",
+            render(&self.template, &format_context(&texts), &question),
+        )?;
+        let mut messages = PhpArray::new();
+        messages.push(message)?;
+        let response = self.llm.complete(&messages.into_zval(false)?)?;
+
+        Ok(RAGAnswer { response, sources })
+    }
+
+    /// Number of chunks to retrieve per question (default 4)
+    pub fn set_k(self_: &mut ZendClassObject<RAG>, k: i64) -> PhpResult<&mut ZendClassObject<RAG>> {
+        if k <= 0 {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("k must be positive".to_string()));
+        }
+        self_.k = k as usize;
+        Ok(self_)
+    }
+
+    /// Prompt template with `{context}` (the numbered chunks) and
+    /// `{question}` placeholders
+    pub fn with_template(
+        self_: &mut ZendClassObject<RAG>,
+        template: String,
+    ) -> PhpResult<&mut ZendClassObject<RAG>> {
+        if !template.contains("{context}") || !template.contains("{question}") {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "RAG template must contain {context} and {question}".to_string(),
+            ));
+        }
+        self_.template = template;
+        Ok(self_)
+    }
+
+    /// Embeddings client for questions sent to a callable store. Defaults to
+    /// the LLM's provider default (see LLM::embeddings()).
+    pub fn with_embeddings<'a>(
+        self_: &'a mut ZendClassObject<RAG>,
+        embeddings: &Embeddings,
+    ) -> &'a mut ZendClassObject<RAG> {
+        self_.embeddings = Some(embeddings.clone());
+        self_
+    }
+}
+
+/// Result of RAG::ask()
+#[php_class]
+pub struct RAGAnswer {
+    response: Response,
+    sources: Vec<Zval>,
+}
+
+#[php_impl]
+impl RAGAnswer {
+    pub fn get_answer(&self) -> String {
+        self.response.get_content()
+    }
+
+    pub fn get_response(&self) -> Response {
+        self.response.clone()
+    }
+
+    /// Chunks put in the prompt, as returned by the store, in prompt order
+    pub fn get_sources(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        for source in &self.sources {
+            arr.push(source.shallow_clone())?;
+        }
+        Ok(arr.into_zval(false)?)
+    }
+
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        arr.insert("answer", self.get_answer())?;
+        arr.insert("sources", self.get_sources()?)?;
+        arr.insert("usage", self.response.get_usage().to_array()?)?;
+        Ok(arr.into_zval(false)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_numbers_chunks() {
+        let texts = vec![
+            " Manticore is fast.\n".to_string(),
+            "It has KNN.".to_string(),
+        ];
+        let prompt = render(DEFAULT_TEMPLATE, &format_context(&texts), "Is it fast?");
+        assert!(prompt.contains(
+            "Context:\n[1] Manticore is fast.\n\n[2] It has KNN.\n\nQuestion: Is it fast?"
+        ));
+    }
+
+    #[test]
+    fn test_placeholders_in_values_stay_literal() {
+        let prompt = render(
+            "{context} | {question}",
+            &format_context(&["see {question}".to_string()]),
+            "what is {context}?",
+        );
+        assert_eq!(prompt, "[1] see {question} | what is {context}?");
+    }
+}
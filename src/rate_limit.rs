@@ -0,0 +1,206 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use reqwest::header::HeaderMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::retry::RetryAfter;
+
+/// Rate-limit state a provider reported with a response, returned by
+/// Response::getRateLimitInfo()
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RateLimitInfo {
+    limit_requests: Option<i64>,
+    remaining_requests: Option<i64>,
+    /// Seconds until the request limit is replenished
+    reset_requests: Option<f64>,
+    limit_tokens: Option<i64>,
+    remaining_tokens: Option<i64>,
+    /// Seconds until the token limit is replenished
+    reset_tokens: Option<f64>,
+    retry_after: Option<f64>,
+}
+
+impl RateLimitInfo {
+    /// Read the OpenAI style `x-ratelimit-*` headers, Anthropic's
+    /// `anthropic-ratelimit-*` ones and `Retry-After`. None when the
+    /// response has none of them.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let get = |names: [&str; 2]| {
+            names
+                .iter()
+                .find_map(|name| headers.get(*name)?.to_str().ok())
+                .map(str::trim)
+        };
+        let count = |names| get(names)?.parse::<i64>().ok();
+        let reset = |names| reset_seconds(get(names)?, SystemTime::now());
+
+        let info = Self {
+            limit_requests: count([
+                "x-ratelimit-limit-requests",
+                "anthropic-ratelimit-requests-limit",
+            ]),
+            remaining_requests: count([
+                "x-ratelimit-remaining-requests",
+                "anthropic-ratelimit-requests-remaining",
+            ]),
+            reset_requests: reset([
+                "x-ratelimit-reset-requests",
+                "anthropic-ratelimit-requests-reset",
+            ]),
+            limit_tokens: count([
+                "x-ratelimit-limit-tokens",
+                "anthropic-ratelimit-tokens-limit",
+            ]),
+            remaining_tokens: count([
+                "x-ratelimit-remaining-tokens",
+                "anthropic-ratelimit-tokens-remaining",
+            ]),
+            reset_tokens: reset([
+                "x-ratelimit-reset-tokens",
+                "anthropic-ratelimit-tokens-reset",
+            ]),
+            retry_after: RetryAfter::from_header(headers.get("retry-after"))
+                .map(|RetryAfter(delay)| delay.as_secs_f64()),
+        };
+        (info != Self::default()).then_some(info)
+    }
+
+    /// PHP array with the keys that were reported
+    pub(crate) fn to_php(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        let counts = [
+            ("limit_requests", self.limit_requests),
+            ("remaining_requests", self.remaining_requests),
+            ("limit_tokens", self.limit_tokens),
+            ("remaining_tokens", self.remaining_tokens),
+        ];
+        for (key, value) in counts {
+            if let Some(value) = value {
+                arr.insert(key, value)?;
+            }
+        }
+        let seconds = [
+            ("reset_requests", self.reset_requests),
+            ("reset_tokens", self.reset_tokens),
+            ("retry_after", self.retry_after),
+        ];
+        for (key, value) in seconds {
+            if let Some(value) = value {
+                arr.insert(key, value)?;
+            }
+        }
+        Ok(arr.into_zval(false)?)
+    }
+}
+
+/// Seconds until a reset: a duration such as "6m0s", "1.5s" or "20ms"
+/// (OpenAI), plain seconds, or an RFC 3339 UTC timestamp (Anthropic)
+fn reset_seconds(value: &str, now: SystemTime) -> Option<f64> {
+    if let Ok(secs) = value.parse::<f64>() {
+        return (secs >= 0.0).then_some(secs);
+    }
+    if let Some(at) = parse_timestamp(value) {
+        let now = now.duration_since(UNIX_EPOCH).ok()?;
+        return Some(at.saturating_sub(now).as_secs_f64());
+    }
+    parse_duration(value)
+}
+
+/// Go style duration: numbers with the units h, m, s or ms
+fn parse_duration(value: &str) -> Option<f64> {
+    let mut total = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let number: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        total += number
+            * match &rest[..unit_len] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 0.001,
+                _ => return None,
+            };
+        rest = &rest[unit_len..];
+    }
+    (!value.is_empty()).then_some(total)
+}
+
+/// `YYYY-MM-DDTHH:MM:SS[.fff]Z` as time since the Unix epoch
+fn parse_timestamp(value: &str) -> Option<Duration> {
+    let value = value.strip_suffix('Z')?;
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':');
+    let hour: i64 = time.next()?.parse().ok()?;
+    let minute: i64 = time.next()?.parse().ok()?;
+    let second: f64 = time.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days since the epoch of a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let secs = (days * 86_400 + hour * 3600 + minute * 60) as f64 + second;
+    (secs >= 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("6m0s"), Some(360.0));
+        assert_eq!(parse_duration("1.5s"), Some(1.5));
+        assert_eq!(parse_duration("20ms"), Some(0.02));
+        assert_eq!(parse_duration("1h2m"), Some(3720.0));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn test_reset_from_timestamp() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        // 2023-11-14T22:13:20Z is 1_700_000_000
+        assert_eq!(reset_seconds("2023-11-14T22:13:50Z", now), Some(30.0));
+        assert_eq!(reset_seconds("2023-11-14T22:13:00Z", now), Some(0.0));
+        assert_eq!(reset_seconds("12", now), Some(12.0));
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(RateLimitInfo::from_headers(&headers), None);
+
+        headers.insert(
+            "x-ratelimit-limit-requests",
+            HeaderValue::from_static("500"),
+        );
+        headers.insert(
+            "x-ratelimit-remaining-requests",
+            HeaderValue::from_static("499"),
+        );
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("6m0s"));
+        headers.insert("retry-after", HeaderValue::from_static("2"));
+        let info = RateLimitInfo::from_headers(&headers).unwrap();
+        assert_eq!(info.limit_requests, Some(500));
+        assert_eq!(info.remaining_requests, Some(499));
+        assert_eq!(info.reset_tokens, Some(360.0));
+        assert_eq!(info.retry_after, Some(2.0));
+        assert_eq!(info.remaining_tokens, None);
+    }
+}
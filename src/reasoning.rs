@@ -0,0 +1,62 @@
+use serde_json::Value;
+
+/// Extract reasoning ("thinking") text from a raw provider response.
+///
+/// Covers OpenAI-compatible `reasoning_content` / `reasoning` message fields
+/// (DeepSeek, OpenRouter, Moonshot, Z.AI) and Anthropic `thinking` content
+/// blocks. Returns None when the model produced no reasoning text.
+pub(crate) fn extract_reasoning(raw_response: &Value) -> Option<String> {
+    let message = &raw_response["choices"][0]["message"];
+    for field in ["reasoning_content", "reasoning"] {
+        if let Some(text) = message[field].as_str().filter(|t| !t.is_empty()) {
+            return Some(text.to_string());
+        }
+    }
+
+    let thinking: Vec<&str> = raw_response["content"]
+        .as_array()?
+        .iter()
+        .filter(|block| block["type"] == "thinking")
+        .filter_map(|block| block["thinking"].as_str())
+        .collect();
+    if thinking.is_empty() {
+        None
+    } else {
+        Some(thinking.join("\n\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::extract_reasoning;
+    use serde_json::json;
+
+    #[test]
+    fn test_openai_compatible_reasoning() {
+        let raw = json!({
+            "choices": [{"message": {"content": "4", "reasoning_content": "2 + 2 = 4"}}]
+        });
+        assert_eq!(extract_reasoning(&raw).as_deref(), Some("2 + 2 = 4"));
+    }
+
+    #[test]
+    fn test_anthropic_thinking_blocks() {
+        let raw = json!({
+            "content": [
+                {"type": "thinking", "thinking": "First step"},
+                {"type": "thinking", "thinking": "Second step"},
+                {"type": "text", "text": "Answer"}
+            ]
+        });
+        assert_eq!(
+            extract_reasoning(&raw).as_deref(),
+            Some("First step\n\nSecond step")
+        );
+    }
+
+    #[test]
+    fn test_no_reasoning() {
+        let raw = json!({"choices": [{"message": {"content": "hi", "reasoning": ""}}]});
+        assert_eq!(extract_reasoning(&raw), None);
+    }
+}
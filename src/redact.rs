@@ -0,0 +1,179 @@
+use ext_php_rs::types::{ZendCallable, Zval};
+use std::cell::{Cell, RefCell};
+
+/// Placeholder substituted for anything redacted
+const REDACTED: &str = "[REDACTED]";
+
+/// Provider error details longer than this are truncated unless verbose mode is
+/// on, since providers often echo (parts of) the request prompt back.
+const MAX_ERROR_DETAIL_CHARS: usize = 500;
+
+/// Raw provider error bodies are kept to this many characters unless verbose
+/// mode is on, as an exception may be logged or rendered in full
+const MAX_RAW_BODY_CHARS: usize = 2000;
+
+/// Well-known API key prefixes masked wherever they appear
+const KEY_PREFIXES: &[&str] = &["sk-", "sk_", "gsk_", "xai-", "AIza"];
+
+/// Shortest token after a key prefix that is treated as a secret
+const MIN_KEY_LEN: usize = 16;
+
+// Per-request settings. PHP callables cannot outlive the request that created
+// them, so both are reset from the module's request shutdown hook.
+thread_local! {
+    static VERBOSE: Cell<bool> = const { Cell::new(false) };
+    static REDACTOR: RefCell<Option<Zval>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn set_verbose(verbose: bool) {
+    VERBOSE.with(|v| v.set(verbose));
+}
+
+pub(crate) fn set_redactor(redactor: Option<Zval>) {
+    REDACTOR.with(|r| *r.borrow_mut() = redactor);
+}
+
+/// Reset per-request redaction settings
+pub(crate) fn reset() {
+    set_verbose(false);
+    set_redactor(None);
+}
+
+/// Pass message content through the user redactor, if one is configured.
+/// Used for anything that leaves the process: logs, transcripts, exceptions.
+pub(crate) fn redact_content(text: &str) -> String {
+    REDACTOR.with(|r| {
+        let redactor = r.borrow();
+        let Some(callable) = redactor.as_ref() else {
+            return text.to_string();
+        };
+        ZendCallable::new(callable)
+            .and_then(|f| f.try_call(vec![&text]))
+            .ok()
+            .and_then(|result| result.string())
+            .unwrap_or_else(|| REDACTED.to_string())
+    })
+}
+
+/// Prepare provider error details for an exception message.
+///
+/// API keys are always masked. Unless verbose mode is enabled, the detail is
+/// also passed through the redactor and truncated.
+pub(crate) fn sanitize_error(text: &str) -> String {
+    let masked = redact_secrets(text);
+    if VERBOSE.with(|v| v.get()) {
+        return masked;
+    }
+    truncate(&redact_content(&masked), MAX_ERROR_DETAIL_CHARS)
+}
+
+/// Prepare a provider's error body for LLMException::getRawBody(). API keys
+/// are masked and the redactor is applied; unless verbose mode is enabled,
+/// the body is also truncated.
+pub(crate) fn sanitize_raw_body(text: &str) -> String {
+    let redacted = redact_content(&redact_secrets(text));
+    if VERBOSE.with(|v| v.get()) {
+        return redacted;
+    }
+    truncate(&redacted, MAX_RAW_BODY_CHARS)
+}
+
+/// Mask API keys: values of `*_API_KEY` environment variables, well-known key
+/// prefixes, and bearer tokens.
+pub(crate) fn redact_secrets(text: &str) -> String {
+    let mut result = text.to_string();
+
+    for (name, value) in std::env::vars() {
+        if name.ends_with("_API_KEY") && value.len() >= 8 {
+            result = result.replace(&value, REDACTED);
+        }
+    }
+
+    mask_tokens(&result)
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.'
+}
+
+fn mask_tokens(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    let mut prev: Option<char> = None;
+
+    while let Some(c) = rest.chars().next() {
+        let at_boundary = prev.is_none_or(|p| !is_token_char(p));
+
+        if at_boundary {
+            if let Some(after) = rest.strip_prefix("Bearer ") {
+                let len = after.find(|c| !is_token_char(c)).unwrap_or(after.len());
+                if len >= 8 {
+                    out.push_str("Bearer ");
+                    out.push_str(REDACTED);
+                    rest = &after[len..];
+                    prev = Some('x');
+                    continue;
+                }
+            }
+
+            if KEY_PREFIXES.iter().any(|p| rest.starts_with(p)) {
+                let len = rest.find(|c| !is_token_char(c)).unwrap_or(rest.len());
+                if len >= MIN_KEY_LEN {
+                    out.push_str(REDACTED);
+                    rest = &rest[len..];
+                    prev = Some('x');
+                    continue;
+                }
+            }
+        }
+
+        out.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+
+    out
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}… [truncated]", &text[..idx]),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{mask_tokens, truncate};
+
+    #[test]
+    fn test_masks_prefixed_keys() {
+        assert_eq!(
+            mask_tokens("Incorrect API key provided: sk-proj-abcdefghijklmnop1234."),
+            "Incorrect API key provided: [REDACTED]"
+        );
+        assert_eq!(
+            mask_tokens("task-abcdefghijklmnopqrst"),
+            "task-abcdefghijklmnopqrst"
+        );
+    }
+
+    #[test]
+    fn test_masks_bearer_tokens() {
+        assert_eq!(
+            mask_tokens("Authorization: Bearer abc123def456"),
+            "Authorization: Bearer [REDACTED]"
+        );
+    }
+
+    #[test]
+    fn test_short_prefixes_untouched() {
+        assert_eq!(mask_tokens("use sk-1 here"), "use sk-1 here");
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("hello", 10), "hello");
+        assert_eq!(truncate("hello world", 5), "hello… [truncated]");
+    }
+}
@@ -0,0 +1,335 @@
+use ext_php_rs::convert::{IntoZval, IntoZvalDyn};
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ArrayKey, ZendCallable, ZendHashTable as PhpArray, ZendObject, Zval};
+use ext_php_rs::zend::ClassEntry;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+
+use crate::convert::zval_to_json_value;
+use crate::schema::{backed_enum_schema, PHP_CLASS_KEY};
+
+/// ReflectionProperty::IS_PUBLIC
+const IS_PUBLIC: i64 = 1;
+
+fn invalid(message: String) -> PhpException {
+    PhpException::from_class::<crate::error::LLMValidationException>(message)
+}
+
+fn call(object: &Zval, method: &str, args: Vec<&dyn IntoZvalDyn>) -> PhpResult<Zval> {
+    let object = object
+        .object()
+        .ok_or_else(|| PhpException::default(format!("Cannot call {method}() on a non-object")))?;
+    Ok(object.try_call_method(method, args)?)
+}
+
+fn call_string(object: &Zval, method: &str) -> PhpResult<String> {
+    Ok(call(object, method, Vec::new())?
+        .string()
+        .unwrap_or_default())
+}
+
+fn call_bool(object: &Zval, method: &str) -> PhpResult<bool> {
+    Ok(call(object, method, Vec::new())?.bool() == Some(true))
+}
+
+/// Items of an array returned by a reflection method
+fn call_list(object: &Zval, method: &str, args: Vec<&dyn IntoZvalDyn>) -> PhpResult<Vec<Zval>> {
+    let list = call(object, method, args)?;
+    Ok(list
+        .array()
+        .map(|list| list.iter().map(|(_, item)| item.shallow_clone()).collect())
+        .unwrap_or_default())
+}
+
+/// Reflection object of class `reflector`, e.g. ReflectionClass
+fn new_reflection(reflector: &str, args: Vec<&dyn IntoZvalDyn>) -> PhpResult<Zval> {
+    let reflection_class = ClassEntry::try_find(reflector)
+        .ok_or_else(|| PhpException::default("Reflection is not available".to_string()))?;
+    let reflection = ZendObject::new(reflection_class);
+    reflection.try_call_method("__construct", args)?;
+    Ok(reflection.into_zval(false)?)
+}
+
+/// ReflectionClass for an existing class
+fn reflect(class: &str) -> PhpResult<Zval> {
+    if ClassEntry::try_find(class).is_none() {
+        return Err(invalid(format!("Class '{class}' does not exist")));
+    }
+    new_reflection("ReflectionClass", vec![&class.to_string()])
+}
+
+/// Arguments of the attributes of a class or property, by attribute name
+/// without its namespace
+fn attributes(reflector: &Zval) -> PhpResult<Vec<(String, Vec<Value>)>> {
+    call_list(reflector, "getAttributes", Vec::new())?
+        .iter()
+        .map(|attribute| {
+            let name = call_string(attribute, "getName")?;
+            let short = name.rsplit('\\').next().unwrap_or_default().to_string();
+            let args = call_list(attribute, "getArguments", Vec::new())?
+                .iter()
+                .map(zval_to_json_value)
+                .collect();
+            Ok((short, args))
+        })
+        .collect()
+}
+
+/// Apply #[Description('...')] and #[Enum([...])] to a schema node
+fn apply_attributes(reflector: &Zval, node: &mut Value) -> PhpResult<()> {
+    for (name, mut args) in attributes(reflector)? {
+        match (name.as_str(), args.first_mut()) {
+            ("Description", Some(Value::String(description))) => {
+                node["description"] = Value::String(std::mem::take(description));
+            }
+            ("Enum", Some(values @ Value::Array(_))) => node["enum"] = values.take(),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Schema of a property or parameter type and whether it allows null.
+/// `subject` names the property or parameter in errors.
+fn type_schema(
+    reflection_type: &Zval,
+    subject: &str,
+    seen: &mut HashSet<String>,
+) -> PhpResult<(Value, bool)> {
+    if reflection_type.is_null() {
+        return Ok((json!({}), true));
+    }
+    let kind = reflection_type
+        .object()
+        .map(|t| t.get_class_name())
+        .transpose()?
+        .unwrap_or_default();
+    if kind != "ReflectionNamedType" {
+        return Err(invalid(format!(
+            "{subject} has a union or intersection type, which has no schema"
+        )));
+    }
+    let name = call_string(reflection_type, "getName")?;
+    let nullable = call_bool(reflection_type, "allowsNull")?;
+    let scalar = match name.as_str() {
+        "int" => "integer",
+        "float" => "number",
+        "string" => "string",
+        "bool" => "boolean",
+        "array" => "array",
+        "mixed" => return Ok((json!({}), true)),
+        _ if call_bool(reflection_type, "isBuiltin")? => {
+            return Err(invalid(format!(
+                "{subject} has type '{name}', which has no schema"
+            )))
+        }
+        class => {
+            let schema = match backed_enum_schema(class) {
+                Ok(schema) => schema,
+                Err(_) => class_schema_seen(class, seen)?,
+            };
+            return Ok((schema, nullable));
+        }
+    };
+    let schema = if nullable {
+        json!({ "type": [scalar, "null"] })
+    } else {
+        json!({ "type": scalar })
+    };
+    Ok((schema, nullable))
+}
+
+fn class_schema_seen(class: &str, seen: &mut HashSet<String>) -> PhpResult<Value> {
+    if !seen.insert(class.to_lowercase()) {
+        return Err(invalid(format!(
+            "Class '{class}' refers to itself, which has no schema"
+        )));
+    }
+    let reflection = reflect(class)?;
+
+    // Promoted constructor parameters keep their default on the parameter
+    let mut optional = HashSet::new();
+    let constructor = call(&reflection, "getConstructor", Vec::new())?;
+    if !constructor.is_null() {
+        for param in call_list(&constructor, "getParameters", Vec::new())? {
+            if call_bool(&param, "isDefaultValueAvailable")? {
+                optional.insert(call_string(&param, "getName")?);
+            }
+        }
+    }
+
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for property in call_list(&reflection, "getProperties", vec![&IS_PUBLIC])? {
+        if call_bool(&property, "isStatic")? {
+            continue;
+        }
+        let name = call_string(&property, "getName")?;
+        let reflection_type = call(&property, "getType", Vec::new())?;
+        let subject = format!("Property '{name}'");
+        let (mut node, nullable) = type_schema(&reflection_type, &subject, seen)?;
+        apply_attributes(&property, &mut node)?;
+        if !nullable && !call_bool(&property, "hasDefaultValue")? && !optional.contains(&name) {
+            required.push(name.clone());
+        }
+        properties.insert(name, node);
+    }
+
+    let mut schema = json!({
+        "type": "object",
+        "properties": properties,
+        PHP_CLASS_KEY: class,
+    });
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    apply_attributes(&reflection, &mut schema)?;
+    seen.remove(&class.to_lowercase());
+    Ok(schema)
+}
+
+/// JSON schema for the public properties of a class: scalar and array
+/// types, backed enums and nested classes, described by #[Description] and
+/// limited by #[Enum] attributes. Properties without a default that do not
+/// allow null are required. Objects are marked with the class, for
+/// instantiate().
+pub(crate) fn class_schema(class: &str) -> PhpResult<Value> {
+    class_schema_seen(class, &mut HashSet::new())
+}
+
+/// A callable as a tool: its closure, name and description, and the schema
+/// of its parameters, marked for hydrate()
+pub(crate) struct CallableTool {
+    pub(crate) closure: Zval,
+    /// None for anonymous functions
+    pub(crate) name: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) schema: Value,
+}
+
+/// Summary of a doc comment: its text up to the first blank line or tag
+fn doc_summary(doc: &str) -> Option<String> {
+    let mut summary = Vec::new();
+    for line in doc.lines() {
+        let line = line
+            .trim()
+            .trim_start_matches("/**")
+            .trim_end_matches("*/")
+            .trim_start_matches('*')
+            .trim();
+        if line.starts_with('@') || (line.is_empty() && !summary.is_empty()) {
+            break;
+        }
+        if !line.is_empty() {
+            summary.push(line);
+        }
+    }
+    (!summary.is_empty()).then(|| summary.join(" "))
+}
+
+/// Tool definition of a callable. Parameters are typed like the properties
+/// of class_schema(), with #[Description] and #[Enum] attributes; those
+/// without a default are required and variadic ones are left out. The
+/// description comes from a #[Description] attribute or the doc comment.
+pub(crate) fn callable_tool(callable: &Zval) -> PhpResult<CallableTool> {
+    let closure = ZendCallable::try_from_name("Closure::fromCallable")?.try_call(vec![callable])?;
+    let reflection = new_reflection("ReflectionFunction", vec![&closure])?;
+
+    let name = call_string(&reflection, "getName")?;
+    let name = (!name.contains('{')).then_some(name);
+    let description = match attributes(&reflection)?
+        .into_iter()
+        .find(|(attribute, _)| attribute == "Description")
+        .and_then(|(_, args)| args.into_iter().next())
+    {
+        Some(Value::String(description)) => Some(description),
+        _ => call(&reflection, "getDocComment", Vec::new())?
+            .string()
+            .and_then(|doc| doc_summary(&doc)),
+    };
+
+    let mut seen = HashSet::new();
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for param in call_list(&reflection, "getParameters", Vec::new())? {
+        if call_bool(&param, "isVariadic")? {
+            continue;
+        }
+        let param_name = call_string(&param, "getName")?;
+        let reflection_type = call(&param, "getType", Vec::new())?;
+        let subject = format!("Parameter '${param_name}'");
+        let (mut node, _) = type_schema(&reflection_type, &subject, &mut seen)?;
+        apply_attributes(&param, &mut node)?;
+        if !call_bool(&param, "isOptional")? {
+            required.push(param_name.clone());
+        }
+        properties.insert(param_name, node);
+    }
+
+    let mut schema = json!({ "type": "object", "properties": properties });
+    if !required.is_empty() {
+        schema["required"] = json!(required);
+    }
+    Ok(CallableTool {
+        closure,
+        name,
+        description,
+        schema,
+    })
+}
+
+/// Instance of `class` from hydrated property values: constructor
+/// parameters are passed by name, other public properties are assigned
+pub(crate) fn instantiate(class: &str, mut values: PhpArray) -> PhpResult<Zval> {
+    let reflection = reflect(class)?;
+    let constructor = call(&reflection, "getConstructor", Vec::new())?;
+    let mut object = if constructor.is_null() {
+        call(&reflection, "newInstanceWithoutConstructor", Vec::new())?
+    } else {
+        let mut args = PhpArray::new();
+        for param in call_list(&constructor, "getParameters", Vec::new())? {
+            let name = call_string(&param, "getName")?;
+            if let Some(value) = values.get(name.as_str()).map(Zval::shallow_clone) {
+                values.remove(name.as_str());
+                args.insert(name.as_str(), value)?;
+            }
+        }
+        call(
+            &reflection,
+            "newInstanceArgs",
+            vec![&args.into_zval(false)?],
+        )?
+    };
+
+    let target = object
+        .object_mut()
+        .ok_or_else(|| PhpException::default(format!("Failed to instantiate '{class}'")))?;
+    for (key, value) in values.iter() {
+        let key = match key {
+            ArrayKey::Long(i) => i.to_string(),
+            ArrayKey::String(s) => s,
+            ArrayKey::Str(s) => s.to_string(),
+        };
+        target.set_property(&key, value.shallow_clone())?;
+    }
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_doc_summary() {
+        let doc = "/**\n * Look up the weather\n * for a city.\n *\n * Uses the cache.\n * @param string $city\n */";
+        assert_eq!(
+            doc_summary(doc),
+            Some("Look up the weather for a city.".to_string())
+        );
+        assert_eq!(doc_summary("/** @return int */"), None);
+        assert_eq!(
+            doc_summary("/** Add two numbers */"),
+            Some("Add two numbers".to_string())
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use serde_json::Value;
+
+/// Detect a provider refusal or content-filter outcome.
+///
+/// Returns the refusal reason when the completion was refused rather than
+/// answered: OpenAI's `refusal` message field, a `content_filter` finish reason
+/// (OpenAI/Azure, with the filtered Azure categories when present), or
+/// Anthropic's `refusal` stop reason.
+pub(crate) fn detect_refusal(finish_reason: &str, raw_response: &Value) -> Option<String> {
+    let choice = &raw_response["choices"][0];
+
+    if let Some(refusal) = choice["message"]["refusal"].as_str() {
+        if !refusal.is_empty() {
+            return Some(refusal.to_string());
+        }
+    }
+
+    match finish_reason {
+        "content_filter" => {
+            let categories = filtered_categories(&choice["content_filter_results"]);
+            if categories.is_empty() {
+                Some("Content filtered by provider".to_string())
+            } else {
+                Some(format!(
+                    "Content filtered by provider: {}",
+                    categories.join(", ")
+                ))
+            }
+        }
+        "refusal" => Some("Model refused to respond".to_string()),
+        _ => None,
+    }
+}
+
+/// Collect Azure content filter categories flagged as `filtered: true`
+fn filtered_categories(results: &Value) -> Vec<String> {
+    results
+        .as_object()
+        .map(|obj| {
+            obj.iter()
+                .filter(|(_, v)| v["filtered"].as_bool() == Some(true))
+                .map(|(k, _)| k.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::detect_refusal;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_openai_refusal_field() {
+        let raw = json!({"choices": [{"message": {"content": null, "refusal": "I can't help with that."}}]});
+        assert_eq!(
+            detect_refusal("stop", &raw).as_deref(),
+            Some("I can't help with that.")
+        );
+    }
+
+    #[test]
+    fn test_azure_content_filter() {
+        let raw = json!({"choices": [{"content_filter_results": {
+            "hate": {"filtered": false, "severity": "safe"},
+            "violence": {"filtered": true, "severity": "high"}
+        }}]});
+        assert_eq!(
+            detect_refusal("content_filter", &raw).as_deref(),
+            Some("Content filtered by provider: violence")
+        );
+    }
+
+    #[test]
+    fn test_anthropic_refusal_stop_reason() {
+        assert!(detect_refusal("refusal", &Value::Null).is_some());
+    }
+
+    #[test]
+    fn test_normal_completion() {
+        let raw = json!({"choices": [{"message": {"content": "Hello", "refusal": null}}]});
+        assert!(detect_refusal("stop", &raw).is_none());
+    }
+}
@@ -0,0 +1,312 @@
+use anyhow::Result;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::ZendHashTable as PhpArray;
+use octolib::errors::ProviderError;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Longest delay honoured from a provider's retry hint
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+
+/// Retry delay requested by the provider (429 `Retry-After` header), attached
+/// as context to errors from requests the extension sends itself
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryAfter(pub Duration);
+
+impl std::fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "retry after {}ms", self.0.as_millis())
+    }
+}
+
+impl RetryAfter {
+    /// Parse a `Retry-After` header given in seconds
+    pub(crate) fn from_header(value: Option<&reqwest::header::HeaderValue>) -> Option<Self> {
+        let secs: f64 = value?.to_str().ok()?.trim().parse().ok()?;
+        (secs >= 0.0).then(|| Self(Duration::from_secs_f64(secs)))
+    }
+}
+
+/// A request's overall deadline ran out, across all of its attempts and
+/// the backoff between them
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeadlineExceeded {
+    pub deadline: Duration,
+    pub attempts: u32,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Deadline of {}s exceeded after {} attempt{}",
+            self.deadline.as_secs_f64(),
+            self.attempts,
+            if self.attempts == 1 { "" } else { "s" }
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Retry policy shared by all completion paths. It is the only retry layer:
+/// octolib's built-in retries are turned off for every request.
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    max_attempts: u32,
+    backoff_ms: u64,
+    max_backoff_ms: u64,
+    retry_on: Vec<String>,
+    /// Limit on all attempts together, see LLM::setDeadline()
+    deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// A single attempt: retries are opt-in
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            retry_on: vec!["timeout".to_string(), "429".to_string(), "5xx".to_string()],
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Parse `max_attempts`, `backoff_ms`, `max_backoff_ms` and `retry_on`.
+    /// `retry_on` entries are 'timeout', 'network', '5xx', '4xx' or a status code.
+    pub(crate) fn from_options(options: &PhpArray) -> PhpResult<Self> {
+        let invalid = |msg: &str| {
+            PhpException::from_class::<crate::error::LLMValidationException>(msg.to_string())
+        };
+        let mut policy = Self::default();
+
+        if let Some(v) = options.get("max_attempts") {
+            let attempts = v
+                .long()
+                .filter(|n| *n >= 1)
+                .ok_or_else(|| invalid("Retry option 'max_attempts' must be a positive integer"))?;
+            policy.max_attempts = attempts as u32;
+        }
+        if let Some(v) = options.get("backoff_ms") {
+            let ms = v.long().filter(|n| *n >= 0).ok_or_else(|| {
+                invalid("Retry option 'backoff_ms' must be a non-negative integer")
+            })?;
+            policy.backoff_ms = ms as u64;
+        }
+        if let Some(v) = options.get("max_backoff_ms") {
+            let ms = v.long().filter(|n| *n >= 0).ok_or_else(|| {
+                invalid("Retry option 'max_backoff_ms' must be a non-negative integer")
+            })?;
+            policy.max_backoff_ms = ms as u64;
+        }
+        if let Some(v) = options.get("retry_on") {
+            let arr = v
+                .array()
+                .ok_or_else(|| invalid("Retry option 'retry_on' must be an array"))?;
+            policy.retry_on = arr
+                .iter()
+                .filter_map(|(_, v)| v.string().or_else(|| v.long().map(|n| n.to_string())))
+                .map(|s| s.to_lowercase())
+                .collect();
+        }
+
+        Ok(policy)
+    }
+
+    /// Limit all attempts together to `seconds`; zero or less removes the
+    /// limit
+    pub(crate) fn set_deadline(&mut self, seconds: i64) {
+        self.deadline = (seconds > 0).then(|| Duration::from_secs(seconds as u64));
+    }
+
+    /// This policy with the deadline of `other`, which is set separately
+    /// from the retry options
+    pub(crate) fn with_deadline_of(mut self, other: &Self) -> Self {
+        self.deadline = other.deadline;
+        self
+    }
+
+    /// Run `call` until it succeeds, fails with a non-retryable error, or the
+    /// attempts are used up. Past the deadline, fails with DeadlineExceeded.
+    pub(crate) async fn run<T, F, Fut>(&self, mut call: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let attempts = AtomicU32::new(0);
+        let attempt_all = async {
+            loop {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
+                match call().await {
+                    Err(e) if attempt < self.max_attempts && self.should_retry(&e) => {
+                        tokio::time::sleep(self.delay(attempt, &e)).await;
+                    }
+                    result => return result,
+                }
+            }
+        };
+        let Some(deadline) = self.deadline else {
+            return attempt_all.await;
+        };
+        tokio::time::timeout(deadline, attempt_all)
+            .await
+            .unwrap_or_else(|_| {
+                Err(DeadlineExceeded {
+                    deadline,
+                    attempts: attempts.load(Ordering::Relaxed),
+                }
+                .into())
+            })
+    }
+
+    fn should_retry(&self, err: &anyhow::Error) -> bool {
+        let Some(err) = err.downcast_ref::<ProviderError>() else {
+            return false;
+        };
+        let allows = |kind: &str| self.retry_on.iter().any(|k| k == kind);
+
+        match err {
+            ProviderError::TimeoutError { .. } => allows("timeout"),
+            ProviderError::NetworkError(_) => allows("network"),
+            ProviderError::ApiError { status, .. } => {
+                let class = match status {
+                    400..=499 => "4xx",
+                    500..=599 => "5xx",
+                    _ => "",
+                };
+                allows(&status.to_string()) || allows(class)
+            }
+            _ => false,
+        }
+    }
+
+    /// Exponential backoff, unless the provider asked for a specific delay
+    fn delay(&self, attempt: u32, err: &anyhow::Error) -> Duration {
+        if let Some(hint) = retry_hint(err) {
+            return hint.min(MAX_RETRY_AFTER);
+        }
+        let factor = 2u64.saturating_pow(attempt - 1);
+        Duration::from_millis(
+            self.backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+/// Provider retry hint: a `Retry-After` header when the request was sent by
+/// the extension, otherwise "try again in 1.5s" style text in a 429 message
+pub(crate) fn retry_hint(err: &anyhow::Error) -> Option<Duration> {
+    if let Some(RetryAfter(delay)) = err.downcast_ref::<RetryAfter>() {
+        return Some(*delay);
+    }
+    match err.downcast_ref::<ProviderError>()? {
+        ProviderError::ApiError {
+            status: 429,
+            message,
+            ..
+        } => retry_after_from_message(message),
+        _ => None,
+    }
+}
+
+fn retry_after_from_message(message: &str) -> Option<Duration> {
+    let lower = message.to_lowercase();
+    let rest = ["try again in ", "retry after "]
+        .iter()
+        .find_map(|marker| lower.find(marker).map(|i| &lower[i + marker.len()..]))?;
+
+    let number_len = rest
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rest.len());
+    let value: f64 = rest[..number_len].parse().ok()?;
+    let unit = rest[number_len..].trim_start();
+
+    if unit.starts_with("ms") {
+        Some(Duration::from_secs_f64(value / 1000.0))
+    } else if unit.starts_with('s') || unit.is_empty() {
+        Some(Duration::from_secs_f64(value))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_error(status: u16, message: &str) -> anyhow::Error {
+        ProviderError::ApiError {
+            provider: "openai".to_string(),
+            status,
+            message: message.to_string(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_retry_after_from_message() {
+        assert_eq!(
+            retry_after_from_message("Rate limit reached. Please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            retry_after_from_message("Please try again in 250ms"),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(retry_after_from_message("Rate limit reached"), None);
+    }
+
+    #[test]
+    fn test_should_retry_matches_classes() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(&api_error(429, "slow down")));
+        assert!(policy.should_retry(&api_error(503, "unavailable")));
+        assert!(!policy.should_retry(&api_error(400, "bad request")));
+        assert!(!policy.should_retry(&anyhow::anyhow!("not a provider error")));
+    }
+
+    #[test]
+    fn test_delay_backoff_and_hint() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            backoff_ms: 500,
+            max_backoff_ms: 1500,
+            retry_on: Vec::new(),
+            deadline: None,
+        };
+        let err = api_error(503, "unavailable");
+        assert_eq!(policy.delay(1, &err), Duration::from_millis(500));
+        assert_eq!(policy.delay(2, &err), Duration::from_millis(1000));
+        assert_eq!(policy.delay(3, &err), Duration::from_millis(1500));
+
+        let err = api_error(429, "slow down").context(RetryAfter(Duration::from_secs(3)));
+        assert_eq!(policy.delay(1, &err), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_deadline_covers_all_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            backoff_ms: 20,
+            max_backoff_ms: 20,
+            retry_on: vec!["5xx".to_string()],
+            deadline: Some(Duration::from_millis(50)),
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let result: Result<()> = rt.block_on(policy.run(|| async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Err(api_error(503, "unavailable"))
+        }));
+        let err = result.unwrap_err();
+        let exceeded = err.downcast_ref::<DeadlineExceeded>().unwrap();
+        assert!(exceeded.attempts > 1 && exceeded.attempts < 10);
+    }
+}
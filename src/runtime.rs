@@ -0,0 +1,84 @@
+use ext_php_rs::flags::IniEntryPermission;
+use ext_php_rs::prelude::*;
+use ext_php_rs::zend::{ExecutorGlobals, IniEntryDef};
+use std::sync::OnceLock;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::config::{
+    APP_NAME_INI, APP_VERSION_INI, CACHE_DIR_INI, MAX_TOOL_LOOPS_INI, RATE_LIMIT_DIR_INI,
+    USER_AGENT_INI,
+};
+use crate::tool_builder::DEFAULT_MAX_TOOL_LOOPS;
+
+/// INI directive controlling the number of runtime worker threads (0 = one per CPU)
+const WORKER_THREADS_INI: &str = "llm.worker_threads";
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// Register the extension's INI directives. Called from MINIT.
+pub(crate) fn register_ini(module_number: i32) {
+    let mut entries = vec![
+        IniEntryDef::new(
+            WORKER_THREADS_INI.to_owned(),
+            "0".to_owned(),
+            &IniEntryPermission::System,
+        ),
+        IniEntryDef::new(
+            MAX_TOOL_LOOPS_INI.to_owned(),
+            DEFAULT_MAX_TOOL_LOOPS.to_string(),
+            &IniEntryPermission::System,
+        ),
+    ];
+    for name in [CACHE_DIR_INI, RATE_LIMIT_DIR_INI] {
+        entries.push(IniEntryDef::new(
+            name.to_owned(),
+            String::new(),
+            &IniEntryPermission::System,
+        ));
+    }
+    for name in [USER_AGENT_INI, APP_NAME_INI, APP_VERSION_INI] {
+        entries.push(IniEntryDef::new(
+            name.to_owned(),
+            String::new(),
+            &IniEntryPermission::All,
+        ));
+    }
+    IniEntryDef::register(entries, module_number);
+}
+
+fn worker_threads() -> usize {
+    ExecutorGlobals::get()
+        .ini_values()
+        .get(WORKER_THREADS_INI)
+        .cloned()
+        .flatten()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Process-wide Tokio runtime shared by every LLM, builder and client.
+///
+/// Created on first use rather than at MINIT: PHP-FPM forks workers after
+/// module startup, and runtime threads do not survive a fork.
+pub(crate) fn runtime() -> PhpResult<&'static Runtime> {
+    if let Some(rt) = RUNTIME.get() {
+        return Ok(rt);
+    }
+
+    let mut builder = Builder::new_multi_thread();
+    builder.enable_all().thread_name("llm-worker");
+    let threads = worker_threads();
+    if threads > 0 {
+        builder.worker_threads(threads);
+    }
+
+    let rt = builder.build().map_err(|e| {
+        PhpException::from_class::<crate::error::LLMException>(format!(
+            "Failed to create runtime: {e}"
+        ))
+    })?;
+
+    // Another thread may have won the race; its runtime is kept and ours dropped
+    let _ = RUNTIME.set(rt);
+    Ok(RUNTIME.get().expect("runtime initialized above"))
+}
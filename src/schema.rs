@@ -0,0 +1,429 @@
+use ext_php_rs::convert::{FromZval, IntoZval};
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendCallable, ZendClassObject, ZendHashTable as PhpArray, Zval};
+use ext_php_rs::zend::ClassEntry;
+use serde_json::{json, Map, Value};
+
+use crate::convert::{json_value_to_php, zval_to_json_value};
+
+/// Scalar types accepted as array items by name
+const ITEM_TYPES: [&str; 4] = ["string", "integer", "number", "boolean"];
+
+/// Schema keyword naming the PHP enum a property's values map to. Removed
+/// before the schema is sent to a provider, as is PHP_CLASS_KEY.
+pub(crate) const PHP_ENUM_KEY: &str = "x-php-enum";
+
+/// Schema keyword naming the PHP class an object maps to, see
+/// Schema::fromClass()
+pub(crate) const PHP_CLASS_KEY: &str = "x-php-class";
+
+/// System prompt for LLM::extract()
+pub(crate) const EXTRACT_PROMPT: &str = "Extract the information described by the schema \
+from the text below. Use only what the text states: leave out optional fields it does not \
+mention, or set them to null where allowed, instead of guessing. Copy names, numbers and \
+quotes exactly as written.";
+
+/// JSON schema for an object, built property by property
+#[php_class]
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    /// Class the schema was derived from, see fromClass()
+    class: Option<String>,
+    description: Option<String>,
+    properties: Map<String, Value>,
+    required: Vec<String>,
+}
+
+// Internal methods - not exposed to PHP
+impl Schema {
+    pub(crate) fn to_value(&self) -> Value {
+        let mut schema = json!({
+            "type": "object",
+            "properties": self.properties,
+        });
+        if !self.required.is_empty() {
+            schema["required"] = json!(self.required);
+        }
+        if let Some(description) = &self.description {
+            schema["description"] = json!(description);
+        }
+        if let Some(class) = &self.class {
+            schema[PHP_CLASS_KEY] = json!(class);
+        }
+        schema
+    }
+
+    fn add(
+        &mut self,
+        name: String,
+        mut property: Value,
+        description: Option<String>,
+        required: Option<bool>,
+    ) {
+        if let Some(description) = description {
+            property["description"] = json!(description);
+        }
+        self.required.retain(|r| *r != name);
+        if required.unwrap_or(false) {
+            self.required.push(name.clone());
+        }
+        self.properties.insert(name, property);
+    }
+
+    fn add_scalar(
+        self_: &mut ZendClassObject<Schema>,
+        kind: &str,
+        name: String,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> &mut ZendClassObject<Schema> {
+        self_.add(name, json!({ "type": kind }), description, required);
+        self_
+    }
+}
+
+/// Schema JSON from a JSON string, a Schema or a PHP array, for the APIs
+/// that take a schema. Strings are passed through unchecked.
+pub(crate) fn schema_json(schema: &Zval) -> PhpResult<String> {
+    if let Some(schema) = <&Schema>::from_zval(schema) {
+        return Ok(schema.to_value().to_string());
+    }
+    if let Some(s) = schema.string() {
+        return Ok(s);
+    }
+    if schema.array().is_some() {
+        return Ok(zval_to_json_value(schema).to_string());
+    }
+    Err(PhpException::from_class::<
+        crate::error::LLMValidationException,
+    >(
+        "Schema must be a JSON string, an array or a Schema".to_string(),
+    ))
+}
+
+/// Case values of a backed enum, in declaration order
+fn backed_enum_values(class: &str) -> PhpResult<Vec<Value>> {
+    let backed = ClassEntry::try_find(class).is_some_and(|ce| {
+        ClassEntry::try_find("BackedEnum").is_some_and(|backed| ce.instance_of(backed))
+    });
+    if !backed {
+        return Err(PhpException::from_class::<
+            crate::error::LLMValidationException,
+        >(format!("'{class}' is not a backed enum")));
+    }
+    let cases = ZendCallable::try_from_name(&format!("{class}::cases"))?.try_call(Vec::new())?;
+    let mut values = Vec::new();
+    for (_, case) in cases.array().into_iter().flat_map(|cases| cases.iter()) {
+        if let Some(case) = case.object() {
+            values.push(zval_to_json_value(case.get_property::<&Zval>("value")?));
+        }
+    }
+    Ok(values)
+}
+
+/// Property limited to the case values of a backed enum
+pub(crate) fn backed_enum_schema(class: &str) -> PhpResult<Value> {
+    let values = backed_enum_values(class)?;
+    let kind = if values.iter().all(Value::is_i64) {
+        "integer"
+    } else {
+        "string"
+    };
+    Ok(json!({ "type": kind, "enum": values, PHP_ENUM_KEY: class }))
+}
+
+/// Remove the PHP enum and class markers from a schema, returning whether
+/// it had any
+pub(crate) fn strip_php_markers(schema: &mut Value) -> bool {
+    match schema {
+        Value::Object(map) => {
+            let found = map.remove(PHP_ENUM_KEY).is_some() | map.remove(PHP_CLASS_KEY).is_some();
+            map.values_mut()
+                .fold(found, |found, value| strip_php_markers(value) || found)
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .fold(false, |found, value| strip_php_markers(value) || found),
+        _ => false,
+    }
+}
+
+/// Structured output as PHP values, with the properties the schema maps to
+/// a PHP enum turned into enum cases, and with `objects` the objects it maps
+/// to a PHP class turned into instances
+pub(crate) fn hydrate(value: &Value, schema: &Value, objects: bool) -> PhpResult<Zval> {
+    if let Some(class) = schema.get(PHP_ENUM_KEY).and_then(Value::as_str) {
+        if value.is_string() || value.is_i64() {
+            let value = json_value_to_php(value)?;
+            return Ok(
+                ZendCallable::try_from_name(&format!("{class}::from"))?.try_call(vec![&value])?
+            );
+        }
+    }
+    match value {
+        Value::Object(map) => {
+            let properties = schema.get("properties");
+            let mut arr = PhpArray::new();
+            for (key, value) in map {
+                let value = match properties.and_then(|p| p.get(key)) {
+                    Some(schema) => hydrate(value, schema, objects)?,
+                    None => json_value_to_php(value)?,
+                };
+                arr.insert(key.as_str(), value)?;
+            }
+            match schema.get(PHP_CLASS_KEY).and_then(Value::as_str) {
+                Some(class) if objects => crate::reflection::instantiate(class, arr),
+                _ => Ok(arr.into_zval(false)?),
+            }
+        }
+        Value::Array(items) => {
+            let item_schema = schema.get("items").unwrap_or(&Value::Null);
+            let mut arr = PhpArray::new();
+            for (idx, value) in items.iter().enumerate() {
+                arr.insert(idx as u64, hydrate(value, item_schema, objects)?)?;
+            }
+            Ok(arr.into_zval(false)?)
+        }
+        value => json_value_to_php(value),
+    }
+}
+
+#[php_impl]
+impl Schema {
+    /// Empty object schema
+    pub fn object(description: Option<String>) -> Self {
+        Self {
+            description,
+            ..Self::default()
+        }
+    }
+
+    /// Schema for the public properties of a class, see the README for the
+    /// supported types and attributes. StructuredResponse::into() creates an
+    /// instance from the output.
+    pub fn from_class(class_name: String) -> PhpResult<Self> {
+        let schema = crate::reflection::class_schema(&class_name)?;
+        let text = |key: &str| schema.get(key).and_then(Value::as_str).map(str::to_string);
+        Ok(Self {
+            class: text(PHP_CLASS_KEY),
+            description: text("description"),
+            properties: schema["properties"]
+                .as_object()
+                .cloned()
+                .unwrap_or_default(),
+            required: schema["required"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|name| name.as_str().map(str::to_string))
+                .collect(),
+        })
+    }
+
+    pub fn string(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> &mut ZendClassObject<Schema> {
+        Self::add_scalar(self_, "string", name, description, required)
+    }
+
+    pub fn integer(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> &mut ZendClassObject<Schema> {
+        Self::add_scalar(self_, "integer", name, description, required)
+    }
+
+    pub fn number(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> &mut ZendClassObject<Schema> {
+        Self::add_scalar(self_, "number", name, description, required)
+    }
+
+    pub fn boolean(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> &mut ZendClassObject<Schema> {
+        Self::add_scalar(self_, "boolean", name, description, required)
+    }
+
+    /// String property limited to the given values
+    #[php(name = "enum")]
+    pub fn enum_of(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        values: &PhpArray,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> PhpResult<&mut ZendClassObject<Schema>> {
+        let values: Vec<String> = values
+            .iter()
+            .map(|(_, v)| v.string())
+            .collect::<Option<_>>()
+            .ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(
+                    "Enum values must be strings".to_string(),
+                )
+            })?;
+        let property = json!({ "type": "string", "enum": values });
+        self_.add(name, property, description, required);
+        Ok(self_)
+    }
+
+    /// Property limited to the cases of a backed enum. StructuredResponse's
+    /// getStructured() returns the matching enum case for it.
+    pub fn enum_from_class(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        class: String,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> PhpResult<&mut ZendClassObject<Schema>> {
+        let property = backed_enum_schema(&class)?;
+        self_.add(name, property, description, required);
+        Ok(self_)
+    }
+
+    /// Array property whose items are a scalar type ('string', 'integer',
+    /// 'number' or 'boolean') or objects described by a Schema
+    pub fn array_of(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        items: &Zval,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> PhpResult<&mut ZendClassObject<Schema>> {
+        let items = match (<&Schema>::from_zval(items), items.string()) {
+            (Some(schema), _) => schema.to_value(),
+            (None, Some(kind)) if ITEM_TYPES.contains(&kind.as_str()) => json!({ "type": kind }),
+            _ => {
+                return Err(PhpException::from_class::<
+                    crate::error::LLMValidationException,
+                >(format!(
+                    "Array items must be a Schema or one of: {}",
+                    ITEM_TYPES.join(", ")
+                )))
+            }
+        };
+        let property = json!({ "type": "array", "items": items });
+        self_.add(name, property, description, required);
+        Ok(self_)
+    }
+
+    /// Nested object property
+    pub fn property(
+        self_: &mut ZendClassObject<Schema>,
+        name: String,
+        schema: &Schema,
+        description: Option<String>,
+        required: Option<bool>,
+    ) -> &mut ZendClassObject<Schema> {
+        self_.add(name, schema.to_value(), description, required);
+        self_
+    }
+
+    /// Names of the required properties
+    pub fn get_required(&self) -> Vec<String> {
+        self.required.clone()
+    }
+
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        json_value_to_php(&self.to_value())
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    pub fn __to_string(&self) -> String {
+        self.to_json()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compiles_to_json_schema() {
+        let mut address = Schema::object(None);
+        address.add(
+            "city".to_string(),
+            json!({"type": "string"}),
+            None,
+            Some(true),
+        );
+
+        let mut person = Schema::object(Some("A person".to_string()));
+        person.add("name".to_string(), json!({"type": "string"}), None, None);
+        person.add(
+            "age".to_string(),
+            json!({"type": "integer"}),
+            Some("Years".to_string()),
+            Some(true),
+        );
+        person.add("address".to_string(), address.to_value(), None, None);
+
+        assert_eq!(
+            person.to_value(),
+            json!({
+                "type": "object",
+                "description": "A person",
+                "properties": {
+                    "name": {"type": "string"},
+                    "age": {"type": "integer", "description": "Years"},
+                    "address": {
+                        "type": "object",
+                        "properties": {"city": {"type": "string"}},
+                        "required": ["city"]
+                    }
+                },
+                "required": ["age"]
+            })
+        );
+    }
+
+    #[test]
+    fn test_strip_php_markers() {
+        let mut schema = json!({
+            "type": "object",
+            "properties": {
+                "status": {"type": "string", "enum": ["new"], "x-php-enum": "Status"},
+                "items": {"type": "array", "items": {"type": "integer", "x-php-enum": "Size"}}
+            }
+        });
+        assert!(strip_php_markers(&mut schema));
+        assert_eq!(
+            schema["properties"]["status"],
+            json!({"type": "string", "enum": ["new"]})
+        );
+        assert_eq!(
+            schema["properties"]["items"]["items"],
+            json!({"type": "integer"})
+        );
+        assert!(!strip_php_markers(&mut schema));
+    }
+
+    #[test]
+    fn test_redefining_a_property_updates_required() {
+        let mut schema = Schema::object(None);
+        schema.add(
+            "name".to_string(),
+            json!({"type": "string"}),
+            None,
+            Some(true),
+        );
+        schema.add("name".to_string(), json!({"type": "string"}), None, None);
+        assert!(schema.required.is_empty());
+        assert_eq!(schema.properties.len(), 1);
+    }
+}
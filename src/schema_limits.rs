@@ -0,0 +1,510 @@
+use ext_php_rs::prelude::*;
+use serde_json::{json, Map, Value};
+
+/// Published limits on JSON schemas for structured output and tools
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SchemaLimits {
+    provider: &'static str,
+    max_depth: usize,
+    max_properties: usize,
+    max_enum_values: usize,
+    /// Property names, definition names, enum and const values together
+    max_string_chars: usize,
+}
+
+/// OpenAI's limits for strict schemas
+const OPENAI: SchemaLimits = SchemaLimits {
+    provider: "openai",
+    max_depth: 10,
+    max_properties: 5000,
+    max_enum_values: 1000,
+    max_string_chars: 120_000,
+};
+
+/// Providers that resolve "$ref" themselves. Schemas for the others are
+/// sent with their references inlined.
+const REF_PROVIDERS: &[&str] = &["openai", "anthropic"];
+
+/// Provider part of a "provider:model" string, lowercased
+fn provider_of(model: &str) -> String {
+    model
+        .split_once(':')
+        .map_or(model, |(p, _)| p)
+        .trim()
+        .to_lowercase()
+}
+
+/// Limits for a "provider:model" string; None where none are published
+fn limits_for(model: &str) -> Option<SchemaLimits> {
+    match provider_of(model).as_str() {
+        "openai" => Some(OPENAI),
+        _ => None,
+    }
+}
+
+/// Annotations that don't constrain the output, dropped by simplify()
+const ANNOTATIONS: &[&str] = &["description", "title", "examples", "$comment"];
+
+/// Keys holding one subschema
+const SCHEMA_KEYS: &[&str] = &["items", "additionalProperties", "not"];
+/// Keys holding a list of subschemas
+const SCHEMA_LIST_KEYS: &[&str] = &["anyOf", "oneOf", "allOf", "prefixItems"];
+/// Keys holding a map of named subschemas
+const SCHEMA_MAP_KEYS: &[&str] = &["properties", "$defs", "definitions"];
+
+/// Counts the limits apply to
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SchemaStats {
+    depth: usize,
+    properties: usize,
+    enum_values: usize,
+    string_chars: usize,
+}
+
+fn measure(schema: &Value, level: usize, stats: &mut SchemaStats) {
+    let Value::Object(node) = schema else {
+        return;
+    };
+    stats.depth = stats.depth.max(level);
+    for key in ["enum", "const"] {
+        let values = match node.get(key) {
+            Some(Value::Array(values)) => values.iter().collect(),
+            Some(value) => vec![value],
+            None => Vec::new(),
+        };
+        if key == "enum" {
+            stats.enum_values += values.len();
+        }
+        stats.string_chars += values
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(str::len)
+            .sum::<usize>();
+    }
+    for key in SCHEMA_MAP_KEYS {
+        if let Some(Value::Object(children)) = node.get(*key) {
+            let is_properties = *key == "properties";
+            for (name, child) in children {
+                stats.string_chars += name.len();
+                if is_properties {
+                    stats.properties += 1;
+                }
+                // Definitions are measured where they sit, not where used
+                measure(child, if is_properties { level + 1 } else { level }, stats);
+            }
+        }
+    }
+    for key in SCHEMA_KEYS {
+        if let Some(child) = node.get(*key) {
+            measure(child, level + 1, stats);
+        }
+    }
+    for key in SCHEMA_LIST_KEYS {
+        if let Some(Value::Array(children)) = node.get(*key) {
+            for child in children {
+                measure(child, level, stats);
+            }
+        }
+    }
+}
+
+/// Name of the local definition a "$ref" points to
+fn ref_name(reference: &str) -> Option<&str> {
+    reference
+        .strip_prefix("#/$defs/")
+        .or_else(|| reference.strip_prefix("#/definitions/"))
+}
+
+/// Replace local "$ref"s by the definitions they point to, dropping
+/// annotations if `strip`. Recursive definitions are left as references.
+fn resolve_node(
+    schema: &mut Value,
+    defs: &Map<String, Value>,
+    strip: bool,
+    expanding: &mut Vec<String>,
+) {
+    let Value::Object(node) = schema else {
+        return;
+    };
+    let target = node
+        .get("$ref")
+        .and_then(Value::as_str)
+        .and_then(ref_name)
+        .filter(|name| !expanding.iter().any(|n| n == name))
+        .and_then(|name| Some((name.to_string(), defs.get(name)?.clone())));
+    if let Some((name, mut definition)) = target {
+        expanding.push(name);
+        resolve_node(&mut definition, defs, strip, expanding);
+        expanding.pop();
+        *schema = definition;
+        return;
+    }
+
+    if strip {
+        for key in ANNOTATIONS {
+            node.remove(*key);
+        }
+    }
+    for key in SCHEMA_KEYS {
+        if let Some(child) = node.get_mut(*key) {
+            resolve_node(child, defs, strip, expanding);
+        }
+    }
+    for key in SCHEMA_LIST_KEYS {
+        if let Some(Value::Array(children)) = node.get_mut(*key) {
+            for child in children {
+                resolve_node(child, defs, strip, expanding);
+            }
+        }
+    }
+    if let Some(Value::Object(properties)) = node.get_mut("properties") {
+        for child in properties.values_mut() {
+            resolve_node(child, defs, strip, expanding);
+        }
+    }
+}
+
+/// The schema with local references inlined, and descriptive annotations
+/// removed if `strip`. Definitions are kept only when a recursive reference
+/// needs them.
+fn resolve(schema: &Value, strip: bool) -> Value {
+    let mut simplified = schema.clone();
+    let Value::Object(root) = &mut simplified else {
+        return simplified;
+    };
+    let mut defs = Map::new();
+    for key in ["$defs", "definitions"] {
+        if let Some(Value::Object(d)) = root.remove(key) {
+            defs.extend(d);
+        }
+    }
+    resolve_node(&mut simplified, &defs, strip, &mut Vec::new());
+
+    // References left are recursive: keep what they point to
+    let text = simplified.to_string();
+    let kept: Map<String, Value> = defs
+        .iter()
+        .filter(|(name, _)| {
+            text.contains(&format!("\"#/$defs/{name}\""))
+                || text.contains(&format!("\"#/definitions/{name}\""))
+        })
+        .map(|(name, definition)| {
+            let mut definition = definition.clone();
+            resolve_node(&mut definition, &defs, strip, &mut vec![name.clone()]);
+            (name.clone(), definition)
+        })
+        .collect();
+    if kept.is_empty() {
+        return simplified;
+    }
+    // Recursive references keep their own spelling
+    let key = if text.contains("\"#/definitions/") {
+        "definitions"
+    } else {
+        "$defs"
+    };
+    if let Value::Object(root) = &mut simplified {
+        root.insert(key.to_string(), Value::Object(kept));
+    }
+    simplified
+}
+
+/// The schema with local references inlined and descriptive annotations
+/// removed
+fn simplify(schema: &Value) -> Value {
+    resolve(schema, true)
+}
+
+/// First "$ref" left anywhere in the schema
+fn first_ref(schema: &Value) -> Option<&str> {
+    match schema {
+        Value::Object(node) => node
+            .get("$ref")
+            .and_then(Value::as_str)
+            .or_else(|| node.values().find_map(first_ref)),
+        Value::Array(items) => items.iter().find_map(first_ref),
+        _ => None,
+    }
+}
+
+/// First limit the schema exceeds, as a message
+fn exceeded(schema: &Value, limits: &SchemaLimits) -> Option<String> {
+    let mut stats = SchemaStats::default();
+    measure(schema, 1, &mut stats);
+    let checks = [
+        (stats.depth, limits.max_depth, "levels of nesting"),
+        (stats.properties, limits.max_properties, "object properties"),
+        (stats.enum_values, limits.max_enum_values, "enum values"),
+        (
+            stats.string_chars,
+            limits.max_string_chars,
+            "characters of property names, definition names, enum and const values",
+        ),
+    ];
+    checks
+        .into_iter()
+        .find(|(found, max, _)| found > max)
+        .map(|(found, max, what)| {
+            format!(
+                "exceeds {}'s limit of {max} {what} (has {found})",
+                limits.provider
+            )
+        })
+}
+
+/// Schema `node` allowing null as well
+fn nullable(node: Value) -> Value {
+    match node {
+        Value::Object(mut map) => match map.get_mut("type") {
+            Some(Value::String(kind)) => {
+                let kind = std::mem::take(kind);
+                map.insert("type".to_string(), json!([kind, "null"]));
+                Value::Object(map)
+            }
+            Some(Value::Array(kinds)) => {
+                if !kinds.iter().any(|k| k == "null") {
+                    kinds.push(json!("null"));
+                }
+                Value::Object(map)
+            }
+            _ => json!({ "anyOf": [Value::Object(map), { "type": "null" }] }),
+        },
+        node => node,
+    }
+}
+
+/// Make a schema acceptable to OpenAI's strict mode: every object gets
+/// "additionalProperties": false and lists all its properties as required,
+/// with the ones that were optional made nullable instead
+pub(crate) fn strict(schema: &mut Value) {
+    let Value::Object(node) = schema else {
+        return;
+    };
+    for key in SCHEMA_KEYS {
+        if let Some(child) = node.get_mut(*key) {
+            strict(child);
+        }
+    }
+    for key in SCHEMA_LIST_KEYS {
+        if let Some(Value::Array(children)) = node.get_mut(*key) {
+            children.iter_mut().for_each(strict);
+        }
+    }
+    for key in SCHEMA_MAP_KEYS {
+        if let Some(Value::Object(children)) = node.get_mut(*key) {
+            children.values_mut().for_each(strict);
+        }
+    }
+
+    let required: Vec<String> = node
+        .get("required")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|name| name.as_str().map(str::to_string))
+        .collect();
+    let Some(Value::Object(properties)) = node.get_mut("properties") else {
+        return;
+    };
+    for (name, property) in properties.iter_mut() {
+        if !required.contains(name) {
+            *property = nullable(std::mem::take(property));
+        }
+    }
+    let names: Vec<Value> = properties.keys().map(|name| json!(name)).collect();
+    node.insert("required".to_string(), Value::Array(names));
+    node.insert("additionalProperties".to_string(), json!(false));
+}
+
+/// Schema as it should be sent to `model`: simplified when asked, with
+/// references inlined for providers that don't resolve them, and checked
+/// against the provider's limits. `what` names the schema in the error,
+/// e.g. "JSON schema" or "Parameters of tool 'search'".
+pub(crate) fn prepare(
+    model: &str,
+    schema: &Value,
+    simplify_schema: bool,
+    what: &str,
+) -> PhpResult<Value> {
+    let provider = provider_of(model);
+    let inline = !REF_PROVIDERS.contains(&provider.as_str());
+    let schema = if simplify_schema || inline {
+        resolve(schema, simplify_schema)
+    } else {
+        schema.clone()
+    };
+    if inline {
+        if let Some(reference) = first_ref(&schema) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "{what} has a $ref to '{reference}' that cannot be inlined for \
+                 {provider}: only non-recursive references to $defs or definitions are supported"
+            )));
+        }
+    }
+    if let Some(problem) = limits_for(model).and_then(|limits| exceeded(&schema, &limits)) {
+        let hint = if simplify_schema {
+            ""
+        } else {
+            "; LLM::simplifySchemas() may bring it within limits"
+        };
+        return Err(PhpException::from_class::<
+            crate::error::LLMValidationException,
+        >(format!("{what} {problem}{hint}")));
+    }
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(levels: usize) -> Value {
+        (1..levels).fold(
+            json!({"type": "string"}),
+            |inner, _| json!({"type": "object", "properties": {"a": inner}}),
+        )
+    }
+
+    #[test]
+    fn test_measure() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "status": {"type": "string", "enum": ["open", "closed"]},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}},
+            },
+            "$defs": {"tag": {"type": "object", "properties": {"name": {"const": "x"}}}},
+        });
+        let mut stats = SchemaStats::default();
+        measure(&schema, 1, &mut stats);
+        assert_eq!(
+            stats,
+            SchemaStats {
+                depth: 3,
+                properties: 3,
+                enum_values: 2,
+                string_chars: "statustagsopenclosedtagnamex".len(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_depth_limit_names_provider() {
+        assert_eq!(exceeded(&nested(10), &OPENAI), None);
+        assert_eq!(
+            exceeded(&nested(11), &OPENAI).as_deref(),
+            Some("exceeds openai's limit of 10 levels of nesting (has 11)")
+        );
+        assert_eq!(limits_for("anthropic:claude-sonnet-4"), None);
+    }
+
+    #[test]
+    fn test_simplify_inlines_refs_and_drops_annotations() {
+        let schema = json!({
+            "title": "Order",
+            "type": "object",
+            "properties": {
+                "description": {"type": "string", "description": "Free text"},
+                "item": {"$ref": "#/$defs/item"},
+            },
+            "$defs": {"item": {"type": "object", "description": "x", "properties": {"sku": {"type": "string"}}}},
+        });
+        assert_eq!(
+            simplify(&schema),
+            json!({
+                "type": "object",
+                "properties": {
+                    "description": {"type": "string"},
+                    "item": {"type": "object", "properties": {"sku": {"type": "string"}}},
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_simplify_keeps_recursive_defs() {
+        let schema = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {"node": {
+                "type": "object",
+                "properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}},
+            }},
+        });
+        let simplified = simplify(&schema);
+        assert_eq!(simplified["type"], "object");
+        assert_eq!(
+            simplified["properties"]["children"]["items"]["$ref"],
+            "#/$defs/node"
+        );
+        assert_eq!(simplified["$defs"]["node"]["type"], "object");
+        assert_eq!(first_ref(&simplified), Some("#/$defs/node"));
+    }
+
+    #[test]
+    fn test_resolve_keeps_annotations() {
+        let schema = json!({
+            "type": "object",
+            "properties": {"a": {"$ref": "#/definitions/a"}, "b": {"$ref": "#/definitions/a"}},
+            "definitions": {"a": {"type": "integer", "description": "Count"}},
+        });
+        let resolved = resolve(&schema, false);
+        assert_eq!(
+            resolved["properties"]["b"],
+            json!({"type": "integer", "description": "Count"})
+        );
+        assert!(resolved.get("definitions").is_none());
+        assert_eq!(first_ref(&resolved), None);
+    }
+
+    #[test]
+    fn test_unresolvable_ref_is_left() {
+        let schema = json!({"properties": {"a": {"$ref": "https://example.com/a.json"}}});
+        assert_eq!(
+            first_ref(&resolve(&schema, false)),
+            Some("https://example.com/a.json")
+        );
+    }
+
+    #[test]
+    fn test_strict_requires_every_property() {
+        let mut schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"},
+                "tags": {"type": "array", "items": {
+                    "type": "object",
+                    "properties": {"label": {"enum": ["a", "b"]}}
+                }}
+            },
+            "required": ["name"]
+        });
+        strict(&mut schema);
+        let mut required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+        required.sort_unstable();
+        assert_eq!(required, ["age", "name", "tags"]);
+        assert_eq!(schema["additionalProperties"], json!(false));
+        assert_eq!(schema["properties"]["name"], json!({"type": "string"}));
+        assert_eq!(
+            schema["properties"]["age"]["type"],
+            json!(["integer", "null"])
+        );
+        assert_eq!(
+            schema["properties"]["tags"]["type"],
+            json!(["array", "null"])
+        );
+        let item = &schema["properties"]["tags"]["items"];
+        assert_eq!(item["additionalProperties"], json!(false));
+        assert_eq!(
+            item["properties"]["label"],
+            json!({"anyOf": [{"enum": ["a", "b"]}, {"type": "null"}]})
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use serde_json::Value;
+
+/// Ways `value` violates the JSON schema, as "/path: message" with the path
+/// a JSON pointer into the value ("/" for the value itself). Empty when the
+/// value matches; a schema that cannot be compiled is reported as the only
+/// violation.
+pub(crate) fn violations(value: &Value, schema: &Value) -> Vec<String> {
+    let validator = match jsonschema::validator_for(schema) {
+        Ok(validator) => validator,
+        Err(e) => return vec![format!("Invalid JSON schema: {e}")],
+    };
+    validator
+        .iter_errors(value)
+        .map(|error| {
+            let path = error.instance_path.to_string();
+            let path = if path.is_empty() { "/" } else { path.as_str() };
+            format!("{path}: {error}")
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_violations_list_every_path() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "tags": {"type": "array", "items": {"type": "string"}}
+            },
+            "required": ["name"],
+            "additionalProperties": false
+        });
+        assert!(violations(&json!({"name": "x", "tags": ["a"]}), &schema).is_empty());
+
+        let found = violations(&json!({"tags": ["a", 1, 2]}), &schema);
+        assert_eq!(found.len(), 3);
+        let at = |path: &str| found.iter().filter(|v| v.starts_with(path)).count();
+        assert_eq!((at("/: "), at("/tags/1: "), at("/tags/2: ")), (1, 1, 1));
+        assert!(found
+            .iter()
+            .any(|v| v.starts_with("/: ") && v.contains("name")));
+    }
+
+    #[test]
+    fn test_invalid_schema_is_reported() {
+        let found = violations(&json!("x"), &json!({"type": "no-such-type"}));
+        assert_eq!(found.len(), 1);
+        assert!(found[0].starts_with("Invalid JSON schema: "));
+    }
+}
@@ -0,0 +1,205 @@
+use ext_php_rs::prelude::*;
+use octolib::llm::Message as OctoMessage;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::ProviderConfig;
+
+/// Upper bound on indexed prompts; expired entries are evicted first, then
+/// the ones closest to expiry
+const MAX_ENTRIES: usize = 4096;
+
+/// Cosine similarity of two vectors; 0 when their lengths differ or either
+/// is zero
+pub(crate) fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm =
+        a.iter().map(|x| x * x).sum::<f32>().sqrt() * b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        dot / norm
+    } else {
+        0.0
+    }
+}
+
+struct Entry {
+    /// Everything but the prompt that must match exactly
+    context: u64,
+    vector: Vec<f32>,
+    response: String,
+    expires: Instant,
+}
+
+/// In-process vector index of prompt embeddings. Lookups scan the entries
+/// of the same context, which stays fast at the size the index is capped to.
+pub(crate) struct VectorIndex {
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl VectorIndex {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Most similar unexpired entry of `context` at or above `threshold`,
+    /// with its similarity
+    pub(crate) fn nearest(
+        &self,
+        context: u64,
+        vector: &[f32],
+        threshold: f32,
+    ) -> Option<(f32, String)> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|e| e.expires > now);
+        entries
+            .iter()
+            .filter(|e| e.context == context)
+            .map(|e| (cosine(&e.vector, vector), e))
+            .filter(|(similarity, _)| *similarity >= threshold)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(similarity, e)| (similarity, e.response.clone()))
+    }
+
+    pub(crate) fn insert(&self, context: u64, vector: Vec<f32>, response: String, ttl: Duration) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|e| e.expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                if let Some(oldest) = (0..entries.len()).min_by_key(|&i| entries[i].expires) {
+                    entries.swap_remove(oldest);
+                }
+            }
+        }
+        entries.push(Entry {
+            context,
+            vector,
+            response,
+            expires: now + ttl,
+        });
+    }
+}
+
+/// Serialized responses indexed by `LLM::withSemanticCache()`
+static INDEX: LazyLock<VectorIndex> = LazyLock::new(VectorIndex::new);
+
+/// The prompt a semantic cache compares: the text of the final user message.
+/// None when the conversation doesn't end in a plain-text user message.
+pub(crate) fn split_prompt(messages: &[OctoMessage]) -> Option<(&[OctoMessage], &str)> {
+    let (last, context) = messages.split_last()?;
+    let text = last.content.trim();
+    let has_images = last
+        .images
+        .as_ref()
+        .is_some_and(|images| !images.is_empty());
+    (last.role == "user" && !text.is_empty() && !has_images).then_some((context, text))
+}
+
+/// Cache that answers prompts similar to one already answered, within the
+/// same context (model, parameters and earlier messages)
+#[derive(Debug, Clone)]
+pub(crate) struct SemanticCache {
+    embedding_model: String,
+    threshold: f32,
+    ttl: Duration,
+}
+
+impl SemanticCache {
+    pub(crate) fn new(
+        threshold: f64,
+        ttl: Duration,
+        embedding_model: String,
+        config: &ProviderConfig,
+    ) -> PhpResult<Self> {
+        if !(threshold > 0.0 && threshold <= 1.0) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Similarity threshold must be greater than 0 and at most 1".to_string(),
+            ));
+        }
+        crate::embeddings::check_model(&embedding_model, config)?;
+        Ok(Self {
+            embedding_model,
+            threshold: threshold as f32,
+            ttl,
+        })
+    }
+
+    /// Context key that also separates the vector spaces of embedding models
+    pub(crate) fn context(&self, key: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.embedding_model.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Embedding of a prompt; None when the embedding request fails, which
+    /// reads as a cache miss
+    pub(crate) fn embed(&self, prompt: &str, config: &ProviderConfig) -> Option<Vec<f32>> {
+        crate::embeddings::embed_text(&self.embedding_model, config, prompt).ok()
+    }
+
+    pub(crate) fn get(&self, context: u64, vector: &[f32]) -> Option<String> {
+        INDEX
+            .nearest(context, vector, self.threshold)
+            .map(|(_, response)| response)
+    }
+
+    pub(crate) fn put(&self, context: u64, vector: Vec<f32>, response: String) {
+        INDEX.insert(context, vector, response, self.ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octolib::llm::MessageBuilder;
+
+    #[test]
+    fn test_cosine() {
+        assert!((cosine(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine(&[1.0, 0.0], &[1.0, 0.0, 0.0]), 0.0);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    fn test_nearest_within_context_and_threshold() {
+        let index = VectorIndex::new();
+        let ttl = Duration::from_secs(60);
+        index.insert(1, vec![1.0, 0.0], "east".to_string(), ttl);
+        index.insert(1, vec![0.6, 0.8], "north-east".to_string(), ttl);
+        index.insert(2, vec![1.0, 0.0], "other context".to_string(), ttl);
+        index.insert(1, vec![0.99, 0.1], "expired".to_string(), Duration::ZERO);
+
+        let (similarity, response) = index.nearest(1, &[0.95, 0.1], 0.9).unwrap();
+        assert_eq!(response, "east");
+        assert!(similarity > 0.99);
+        assert_eq!(index.nearest(1, &[0.0, 1.0], 0.9), None);
+        assert_eq!(index.nearest(3, &[1.0, 0.0], 0.5), None);
+    }
+
+    #[test]
+    fn test_split_prompt_needs_final_user_text() {
+        let messages = vec![
+            MessageBuilder::system("Be brief").build().unwrap(),
+            MessageBuilder::user(" What is Rust? ").build().unwrap(),
+        ];
+        let (context, prompt) = split_prompt(&messages).unwrap();
+        assert_eq!(prompt, "What is Rust?");
+        assert_eq!(context.len(), 1);
+
+        let messages = vec![MessageBuilder::assistant("Hi").build().unwrap()];
+        assert!(split_prompt(&messages).is_none());
+        assert!(split_prompt(&[]).is_none());
+    }
+}
@@ -0,0 +1,65 @@
+use ext_php_rs::boxed::ZBox;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendCallable, ZendHashTable as PhpArray, Zval};
+use ext_php_rs::zend::ClassEntry;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Version of the array written by __serialize()
+const PAYLOAD_VERSION: i64 = 1;
+
+/// Array returned by __serialize(): the format version and the object's
+/// state as JSON, so nested values survive unchanged
+pub(crate) fn payload(state: &Value) -> PhpResult<ZBox<PhpArray>> {
+    let mut arr = PhpArray::new();
+    arr.insert("version", PAYLOAD_VERSION)?;
+    arr.insert("state", state.to_string())?;
+    Ok(arr)
+}
+
+/// payload() of a class that derives Serialize
+pub(crate) fn save<T: Serialize>(value: &T) -> PhpResult<ZBox<PhpArray>> {
+    let state = serde_json::to_value(value)
+        .map_err(|e| PhpException::default(format!("Failed to serialize: {e}")))?;
+    payload(&state)
+}
+
+/// State saved by payload(), for the __unserialize() of `class`
+pub(crate) fn state(data: &PhpArray, class: &str) -> PhpResult<Value> {
+    let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+    let version = data.get("version").and_then(Zval::long);
+    if version != Some(PAYLOAD_VERSION) {
+        return Err(invalid(format!(
+            "Unsupported serialized {class} version {}",
+            version.map_or_else(|| "(none)".to_string(), |v| v.to_string())
+        )));
+    }
+    let json = data
+        .get("state")
+        .and_then(Zval::str)
+        .ok_or_else(|| invalid(format!("Serialized {class} has no state")))?;
+    serde_json::from_str(json).map_err(|e| invalid(format!("Invalid serialized {class}: {e}")))
+}
+
+/// State of a class that derives Deserialize
+pub(crate) fn restore<T: DeserializeOwned>(data: &PhpArray, class: &str) -> PhpResult<T> {
+    serde_json::from_value(state(data, class)?).map_err(|e| {
+        PhpException::from_class::<crate::error::LLMValidationException>(format!(
+            "Invalid serialized {class}: {e}"
+        ))
+    })
+}
+
+/// PHP's JsonSerializable interface. The JSON extension is always built
+/// in, and registers it before any loadable extension starts.
+pub(crate) fn json_serializable() -> &'static ClassEntry {
+    ClassEntry::try_find("JsonSerializable").expect("JsonSerializable interface is missing")
+}
+
+/// jsonSerialize() result for a class's toJson() output. PHP decodes it
+/// with objects as stdClass, so json_encode() gives back the same JSON,
+/// empty objects included.
+pub(crate) fn json_data(json: String) -> PhpResult<Zval> {
+    Ok(ZendCallable::try_from_name("json_decode")?.try_call(vec![&json])?)
+}
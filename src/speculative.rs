@@ -0,0 +1,289 @@
+use anyhow::Result;
+use ext_php_rs::prelude::*;
+use octolib::llm::{Message as OctoMessage, ProviderFactory, TokenUsage};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::attribution::Attribution;
+use crate::llm_class::{Completion, Response, Usage};
+
+/// Which of the two dispatched models produced the answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Winner {
+    Strong,
+    Fast,
+}
+
+/// Completions collected from a race. The other model's is present when it
+/// had already finished; `aborted` tells that its request was cut off instead.
+pub(crate) struct Outcome {
+    pub winner: Winner,
+    pub chosen: Completion,
+    pub other: Option<Completion>,
+    pub aborted: bool,
+}
+
+fn flatten(joined: Result<Result<Completion>, JoinError>) -> Result<Completion> {
+    joined.unwrap_or_else(|e| Err(anyhow::anyhow!("Completion task failed: {e}")))
+}
+
+/// Prefer the strong completion if it succeeds before `deadline`; after the
+/// deadline, use the fast one. The model not used is read too when it has
+/// already finished and aborted otherwise. Either model stands in when the
+/// other fails; fails only when both fail, with the strong model's error.
+pub(crate) async fn race(
+    mut strong: JoinHandle<Result<Completion>>,
+    mut fast: JoinHandle<Result<Completion>>,
+    deadline: Duration,
+) -> Result<Outcome> {
+    let early = match tokio::time::timeout(deadline, &mut strong).await {
+        Ok(joined) => Some(flatten(joined)),
+        Err(_) => None,
+    };
+
+    let (winner, chosen, loser) = match early {
+        Some(Ok(strong_done)) => (Winner::Strong, strong_done, fast),
+        Some(Err(strong_err)) => {
+            return Ok(Outcome {
+                winner: Winner::Fast,
+                chosen: flatten(fast.await).map_err(|_| strong_err)?,
+                other: None,
+                aborted: false,
+            })
+        }
+        None => match flatten((&mut fast).await) {
+            Ok(fast_done) => (Winner::Fast, fast_done, strong),
+            Err(_) => {
+                return Ok(Outcome {
+                    winner: Winner::Strong,
+                    chosen: flatten(strong.await)?,
+                    other: None,
+                    aborted: false,
+                })
+            }
+        },
+    };
+
+    // Keep the other model's usage if it is already in, never wait for it
+    let (other, aborted) = if loser.is_finished() {
+        (flatten(loser.await).ok(), false)
+    } else {
+        loser.abort();
+        (None, true)
+    };
+    Ok(Outcome {
+        winner,
+        chosen,
+        other,
+        aborted,
+    })
+}
+
+/// Usage charged for a request aborted before it answered: its prompt,
+/// counted locally and priced as input. The provider has usually started on
+/// the request by then and bills it whether or not the answer is read.
+pub(crate) fn prompt_usage(rt: &Runtime, model: &str, messages: &[OctoMessage]) -> TokenUsage {
+    let input_tokens = crate::context_window::prompt_tokens(model, messages, "") as u64;
+    let pricing = rt
+        .block_on(async { ProviderFactory::get_provider_for_model(model) })
+        .ok()
+        .and_then(|(provider, name)| provider.get_model_pricing(&name));
+    let mut usage = crate::stream::token_usage(input_tokens, 0);
+    usage.cost = pricing.map(|pricing| pricing.calculate_cost(input_tokens, 0, 0, 0));
+    usage
+}
+
+/// Answer from a speculative dual dispatch, with the usage of both models
+#[php_class]
+pub struct SpeculativeResponse {
+    response: Response,
+    winner: Winner,
+    strong_usage: Option<Usage>,
+    fast_usage: Option<Usage>,
+}
+
+// Internal constructor - not exposed to PHP
+impl SpeculativeResponse {
+    /// Charge both models' usage; an aborted request is charged `aborted`,
+    /// the estimate of its prompt
+    pub(crate) fn new(
+        outcome: Outcome,
+        strong_model: String,
+        fast_model: String,
+        aborted: TokenUsage,
+        attribution: &Attribution,
+    ) -> Self {
+        let (chosen_model, other_model) = match outcome.winner {
+            Winner::Strong => (strong_model, fast_model),
+            Winner::Fast => (fast_model, strong_model),
+        };
+        let response = Response::from_completion(outcome.chosen, chosen_model, attribution);
+        let other_usage = match outcome.other {
+            Some(other) => {
+                Some(Response::from_completion(other, other_model, attribution).get_usage())
+            }
+            None if outcome.aborted => {
+                attribution.record(&other_model, &aborted);
+                Some(Usage::from_octo(aborted))
+            }
+            None => None,
+        };
+
+        let (strong_usage, fast_usage) = match outcome.winner {
+            Winner::Strong => (Some(response.get_usage()), other_usage),
+            Winner::Fast => (other_usage, Some(response.get_usage())),
+        };
+
+        Self {
+            response,
+            winner: outcome.winner,
+            strong_usage,
+            fast_usage,
+        }
+    }
+}
+
+#[php_impl]
+impl SpeculativeResponse {
+    /// The answer that was chosen
+    pub fn get_response(&self) -> Response {
+        self.response.clone()
+    }
+
+    pub fn get_content(&self) -> String {
+        self.response.get_content()
+    }
+
+    /// 'strong' or 'fast'
+    pub fn get_winner(&self) -> String {
+        match self.winner {
+            Winner::Strong => "strong",
+            Winner::Fast => "fast",
+        }
+        .to_string()
+    }
+
+    /// Whether the fast model's answer was used
+    pub fn is_fallback(&self) -> bool {
+        self.winner == Winner::Fast
+    }
+
+    /// Usage of the strong model, or null when its request failed. An
+    /// aborted request reports its prompt, estimated locally.
+    pub fn get_strong_usage(&self) -> Option<Usage> {
+        self.strong_usage.clone()
+    }
+
+    /// Usage of the fast model, or null when its request failed. An aborted
+    /// request reports its prompt, estimated locally.
+    pub fn get_fast_usage(&self) -> Option<Usage> {
+        self.fast_usage.clone()
+    }
+
+    /// Usage of both models together, as charged to budgets and totals
+    pub fn get_usage(&self) -> Usage {
+        let mut usage = self.response.get_usage();
+        let other = match self.winner {
+            Winner::Strong => &self.fast_usage,
+            Winner::Fast => &self.strong_usage,
+        };
+        if let Some(other) = other {
+            usage.add(other);
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use tokio::runtime::Runtime;
+
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    fn task(
+        rt: &Runtime,
+        delay_ms: u64,
+        result: Result<&'static str, &'static str>,
+    ) -> JoinHandle<Result<Completion>> {
+        rt.spawn(async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            result
+                .map(|content| Completion {
+                    content: content.to_string(),
+                    usage: None,
+                    finish_reason: None,
+                    id: None,
+                    raw: Value::Null,
+                    exchange: None,
+                })
+                .map_err(|e| anyhow::anyhow!(e))
+        })
+    }
+
+    fn run(
+        strong: (u64, Result<&'static str, &'static str>),
+        fast: (u64, Result<&'static str, &'static str>),
+        deadline_ms: u64,
+    ) -> Result<(Winner, String)> {
+        let outcome = race_outcome(strong, fast, deadline_ms)?;
+        Ok((outcome.winner, outcome.chosen.content))
+    }
+
+    fn race_outcome(
+        strong: (u64, Result<&'static str, &'static str>),
+        fast: (u64, Result<&'static str, &'static str>),
+        deadline_ms: u64,
+    ) -> Result<Outcome> {
+        let rt = runtime();
+        let strong = task(&rt, strong.0, strong.1);
+        let fast = task(&rt, fast.0, fast.1);
+        rt.block_on(race(strong, fast, Duration::from_millis(deadline_ms)))
+    }
+
+    #[test]
+    fn test_strong_within_deadline_wins() {
+        let (winner, content) = run((10, Ok("strong")), (1, Ok("fast")), 200).unwrap();
+        assert_eq!(winner, Winner::Strong);
+        assert_eq!(content, "strong");
+    }
+
+    #[test]
+    fn test_fast_wins_after_deadline() {
+        let (winner, content) = run((500, Ok("strong")), (1, Ok("fast")), 20).unwrap();
+        assert_eq!(winner, Winner::Fast);
+        assert_eq!(content, "fast");
+        // Even when the strong answer comes in first after the deadline
+        let (winner, _) = run((40, Ok("strong")), (120, Ok("fast")), 10).unwrap();
+        assert_eq!(winner, Winner::Fast);
+    }
+
+    #[test]
+    fn test_other_read_or_aborted() {
+        let outcome = race_outcome((500, Ok("strong")), (1, Ok("fast")), 20).unwrap();
+        assert!(outcome.aborted);
+        assert!(outcome.other.is_none());
+
+        let outcome = race_outcome((40, Ok("strong")), (120, Ok("fast")), 10).unwrap();
+        assert!(!outcome.aborted);
+        assert_eq!(outcome.other.unwrap().content, "strong");
+    }
+
+    #[test]
+    fn test_falls_back_on_errors() {
+        let (winner, _) = run((1, Err("down")), (10, Ok("fast")), 200).unwrap();
+        assert_eq!(winner, Winner::Fast);
+        let (winner, _) = run((50, Ok("strong")), (1, Err("down")), 10).unwrap();
+        assert_eq!(winner, Winner::Strong);
+
+        let err = run((1, Err("strong down")), (1, Err("fast down")), 10).unwrap_err();
+        assert_eq!(err.to_string(), "strong down");
+    }
+}
@@ -0,0 +1,185 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::ZendHashTable as PhpArray;
+
+use crate::tokenizer::count_tokens;
+
+/// Default chunk size of LLM::mapReduce() in tokens
+pub(crate) const DEFAULT_CHUNK_TOKENS: usize = 4000;
+
+/// Smallest chunk size accepted, leaving room for the part markers
+pub(crate) const MIN_CHUNK_TOKENS: usize = 100;
+
+/// Tokens kept free in each part for its markers
+const MARKER_TOKENS: usize = 24;
+
+/// Rounds of reducing results in groups before the rest is combined in one
+/// request whatever its size
+pub(crate) const MAX_REDUCE_ROUNDS: usize = 3;
+
+/// Options accepted by LLM::mapReduce()
+pub(crate) struct MapReduceOptions {
+    pub chunk_tokens: usize,
+    /// System prompt of the combining requests, replacing reduce_prompt()
+    pub reduce_instruction: Option<String>,
+}
+
+impl MapReduceOptions {
+    pub(crate) fn parse(options: Option<&PhpArray>) -> PhpResult<Self> {
+        let invalid = |msg: &str| {
+            PhpException::from_class::<crate::error::LLMValidationException>(msg.into())
+        };
+        let mut parsed = Self {
+            chunk_tokens: DEFAULT_CHUNK_TOKENS,
+            reduce_instruction: None,
+        };
+        let Some(options) = options else {
+            return Ok(parsed);
+        };
+
+        if let Some(size) = options.get("chunk_tokens") {
+            parsed.chunk_tokens = chunk_tokens(size.long()).ok_or_else(|| {
+                invalid("Option 'chunk_tokens' must be an integer of at least 100")
+            })?;
+        }
+        if let Some(instruction) = options.get("reduce_instruction") {
+            parsed.reduce_instruction = Some(
+                instruction
+                    .string()
+                    .ok_or_else(|| invalid("Option 'reduce_instruction' must be a string"))?,
+            );
+        }
+        Ok(parsed)
+    }
+}
+
+/// A chunk size from PHP, if it is at least MIN_CHUNK_TOKENS
+pub(crate) fn chunk_tokens(size: Option<i64>) -> Option<usize> {
+    size.and_then(|n| usize::try_from(n).ok())
+        .filter(|n| *n >= MIN_CHUNK_TOKENS)
+}
+
+/// Split `text` into contiguous chunks of at most `max_tokens` tokens for
+/// `model`, at the boundaries translate::chunks() prefers. Concatenating the
+/// chunks gives back `text`.
+pub(crate) fn token_chunks<'a>(text: &'a str, max_tokens: usize, model: &str) -> Vec<&'a str> {
+    // Start from four characters per token and shrink the chunks by the
+    // worst ratio seen until every chunk fits
+    let mut max_chars = max_tokens.saturating_mul(4).max(1);
+    loop {
+        let chunks = crate::translate::chunks(text, max_chars);
+        let worst = chunks
+            .iter()
+            .map(|chunk| count_tokens(chunk, model))
+            .max()
+            .unwrap_or(0);
+        if worst <= max_tokens || max_chars == 1 {
+            return chunks;
+        }
+        max_chars = (max_chars * max_tokens / worst).clamp(1, max_chars - 1);
+    }
+}
+
+/// Contents of the messages Message::split() creates: the text itself when
+/// it fits, otherwise parts of at most `max_tokens` tokens, each marked with
+/// its position and, except the last, a note that more follows
+pub(crate) fn parts(text: &str, max_tokens: usize, model: &str) -> Vec<String> {
+    if count_tokens(text, model) <= max_tokens {
+        return vec![text.to_string()];
+    }
+    let chunks = token_chunks(text, max_tokens.saturating_sub(MARKER_TOKENS), model);
+    let count = chunks.len();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut part = format!("[Part {} of {count}]\n{}", i + 1, chunk.trim());
+            if i + 1 < count {
+                part.push_str("\n[Continued in the next message]");
+            }
+            part
+        })
+        .collect()
+}
+
+/// System prompt for one chunk of a map-reduce run
+pub(crate) fn map_prompt(instruction: &str, part: usize, count: usize) -> String {
+    format!(
+        "{instruction}\n\nThe text is too long to process at once, so the user's message \
+        holds part {part} of {count}. Work on this part only; the results of all parts \
+        are combined afterwards."
+    )
+}
+
+/// Default system prompt for combining the results of the chunks
+pub(crate) fn reduce_prompt(instruction: &str) -> String {
+    format!(
+        "A long text was processed in parts with this instruction:\n\n{instruction}\n\n\
+        The user's message holds the result for each part, in order. Combine them into \
+        one result that follows the instruction, as if the whole text had been processed \
+        at once, without repeating what several parts share."
+    )
+}
+
+/// Results of the chunks as the user message of a combining request
+pub(crate) fn combine(results: &[String]) -> String {
+    let count = results.len();
+    results
+        .iter()
+        .enumerate()
+        .map(|(i, result)| format!("[Part {} of {count}]\n{}", i + 1, result.trim()))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn long_text() -> String {
+        (0..200)
+            .map(|i| format!("Sentence number {i} talks about the weather in town. "))
+            .collect()
+    }
+
+    #[test]
+    fn test_token_chunks_fit_and_reassemble() {
+        let text = long_text();
+        let chunks = token_chunks(&text, 150, "o200k_base");
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|c| count_tokens(c, "o200k_base") <= 150));
+        assert_eq!(chunks.concat(), text);
+    }
+
+    #[test]
+    fn test_parts_markers() {
+        assert_eq!(
+            parts("Short question", 100, "o200k_base"),
+            vec!["Short question"]
+        );
+
+        let parts = parts(&long_text(), 200, "o200k_base");
+        let count = parts.len();
+        assert!(count > 1);
+        assert!(parts[0].starts_with(&format!("[Part 1 of {count}]\n")));
+        assert!(parts[0].ends_with("\n[Continued in the next message]"));
+        assert!(!parts[count - 1].contains("[Continued"));
+        assert!(parts.iter().all(|p| count_tokens(p, "o200k_base") <= 200));
+    }
+
+    #[test]
+    fn test_combine() {
+        let results = vec!["First ".to_string(), "Second".to_string()];
+        assert_eq!(
+            combine(&results),
+            "[Part 1 of 2]\nFirst\n\n[Part 2 of 2]\nSecond"
+        );
+    }
+
+    #[test]
+    fn test_chunk_tokens() {
+        assert_eq!(chunk_tokens(Some(100)), Some(100));
+        assert_eq!(chunk_tokens(Some(99)), None);
+        assert_eq!(chunk_tokens(Some(-5)), None);
+        assert_eq!(chunk_tokens(None), None);
+    }
+}
@@ -0,0 +1,1462 @@
+use anyhow::Result;
+use ext_php_rs::prelude::*;
+use ext_php_rs::zend::ce;
+use futures_util::StreamExt;
+use octolib::errors::ProviderError;
+use octolib::llm::{
+    FunctionDefinition, ImageData, Message as OctoMessage, ModelPricing, OutputFormat,
+    ProviderExchange, ProviderResponse, ResponseMode, StructuredOutputRequest, TokenUsage,
+    ToolCall as OctoToolCall,
+};
+use serde_json::{json, Value};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::attribution::Attribution;
+use crate::citations::{attach_documents, Citation, Document};
+use crate::config::{request_error, ProviderConfig};
+use crate::error::{IntoPhpException, RequestId};
+use crate::llm_class::Response;
+use crate::params::{Penalties, ToolChoice};
+use crate::rate_limit::RateLimitInfo;
+use crate::refusal::detect_refusal;
+use crate::retry::{RetryAfter, RetryPolicy};
+use crate::runtime::runtime;
+
+/// Number of undelivered events buffered between the HTTP stream and PHP.
+/// When PHP falls behind, the network task waits instead of growing memory.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Event produced while streaming a completion
+pub(crate) enum StreamEvent {
+    /// A chunk of assistant content
+    Delta(String),
+    /// A tool call whose argument fragments have all arrived
+    ToolCall {
+        id: String,
+        name: String,
+        arguments: String,
+    },
+    /// A document citation, sent once the text it supports is complete
+    Citation(Citation),
+    /// Stream finished
+    Done {
+        finish_reason: String,
+        usage: Option<TokenUsage>,
+        rate_limit: Option<RateLimitInfo>,
+    },
+}
+
+/// Request body wire format spoken by the provider
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WireFormat {
+    OpenAi,
+    Anthropic,
+}
+
+/// Sampling parameters for a request sent directly, streamed or not
+pub(crate) struct StreamParams {
+    pub temperature: f32,
+    pub top_p: f32,
+    pub max_tokens: u32,
+    pub penalties: Penalties,
+    pub stop: Vec<String>,
+    /// Only sent in the OpenAI wire format
+    pub seed: Option<i64>,
+    pub tools: Vec<FunctionDefinition>,
+    pub tool_choice: ToolChoice,
+    /// None leaves the provider default (parallel calls allowed)
+    pub parallel_tool_calls: Option<bool>,
+    /// Citable documents, only sent in the Anthropic wire format
+    pub documents: Vec<Document>,
+    /// JSON mode, only sent in the OpenAI wire format
+    pub structured: Option<StructuredOutputRequest>,
+}
+
+/// Resolved HTTP endpoint for a request sent directly.
+///
+/// octolib only exposes request/response completions with a fixed set of
+/// parameters, so streaming, and requests with parameters octolib cannot
+/// pass (see Backend), talk to the provider's HTTP API directly, using the
+/// instance's provider config with the same `<PROVIDER>_API_KEY` and
+/// `<PROVIDER>_API_URL` fallbacks as octolib.
+pub(crate) struct StreamTarget {
+    url: String,
+    api_key: String,
+    model: String,
+    provider: String,
+    format: WireFormat,
+    config: ProviderConfig,
+}
+
+impl StreamTarget {
+    /// Resolve the endpoint for a "provider:model" string.
+    /// Returns None for providers without SSE support in this extension.
+    pub(crate) fn for_model(model: &str, config: &ProviderConfig) -> Option<Self> {
+        let (provider, model_name) = model.split_once(':')?;
+        let provider = provider.trim().to_lowercase();
+
+        let (default_url, format) = match provider.as_str() {
+            "openai" => ("https://api.openai.com/v1", WireFormat::OpenAi),
+            "anthropic" => ("https://api.anthropic.com/v1", WireFormat::Anthropic),
+            "openrouter" => ("https://openrouter.ai/api/v1", WireFormat::OpenAi),
+            "deepseek" => ("https://api.deepseek.com/v1", WireFormat::OpenAi),
+            "moonshot" | "kimi" => ("https://api.moonshot.ai/v1", WireFormat::OpenAi),
+            "cerebras" => ("https://api.cerebras.ai/v1", WireFormat::OpenAi),
+            "minimax" => ("https://api.minimax.io/v1", WireFormat::OpenAi),
+            "zai" => ("https://api.z.ai/api/paas/v4", WireFormat::OpenAi),
+            "ollama" => ("http://localhost:11434/v1", WireFormat::OpenAi),
+            "local" => ("", WireFormat::OpenAi),
+            _ => return None,
+        };
+
+        let base_url = config
+            .base_url(model)
+            .unwrap_or_else(|| default_url.to_string());
+        if base_url.is_empty() {
+            return None;
+        }
+
+        let endpoint = match format {
+            WireFormat::OpenAi => "/chat/completions",
+            WireFormat::Anthropic => "/messages",
+        };
+        let base_url = base_url.trim_end_matches('/');
+        let url = if base_url.ends_with(endpoint) {
+            base_url.to_string()
+        } else {
+            format!("{base_url}{endpoint}")
+        };
+
+        Some(Self {
+            url,
+            api_key: config.api_key(model),
+            model: model_name.trim().to_string(),
+            provider,
+            format,
+            config: config.clone(),
+        })
+    }
+
+    pub(crate) fn model_name(&self) -> &str {
+        &self.model
+    }
+
+    /// Only the OpenAI wire format has frequency and presence penalties, and
+    /// a seed
+    pub(crate) fn supports_penalties(&self) -> bool {
+        self.format == WireFormat::OpenAi
+    }
+
+    /// Only the OpenAI wire format has a JSON mode; Anthropic replies get
+    /// the JSON asked for in the prompt
+    pub(crate) fn supports_json_mode(&self) -> bool {
+        self.format == WireFormat::OpenAi
+    }
+
+    /// Only the Anthropic wire format has citable documents
+    pub(crate) fn supports_documents(&self) -> bool {
+        self.format == WireFormat::Anthropic
+    }
+
+    /// Request body in the provider's wire format, asking for SSE when
+    /// `stream` is set
+    fn request_body(&self, messages: &[OctoMessage], params: &StreamParams, stream: bool) -> Value {
+        let mut body = self.base_body(messages, params);
+        if stream {
+            body["stream"] = Value::Bool(true);
+            if self.format == WireFormat::OpenAi {
+                body["stream_options"] = json!({ "include_usage": true });
+            }
+        }
+        body
+    }
+
+    fn base_body(&self, messages: &[OctoMessage], params: &StreamParams) -> Value {
+        match self.format {
+            WireFormat::OpenAi => {
+                let messages: Vec<Value> = messages.iter().map(openai_message).collect();
+                let mut body = json!({
+                    "model": self.model,
+                    "messages": messages,
+                });
+                if is_openai_reasoning_model(&self.model) {
+                    if params.max_tokens > 0 {
+                        body["max_completion_tokens"] = json!(params.max_tokens);
+                    }
+                } else {
+                    body["temperature"] = json!(params.temperature);
+                    if params.top_p != 1.0 {
+                        body["top_p"] = json!(params.top_p);
+                    }
+                    if params.max_tokens > 0 {
+                        body["max_tokens"] = json!(params.max_tokens);
+                    }
+                }
+                if params.penalties.frequency != 0.0 {
+                    body["frequency_penalty"] = json!(params.penalties.frequency);
+                }
+                if params.penalties.presence != 0.0 {
+                    body["presence_penalty"] = json!(params.penalties.presence);
+                }
+                if !params.stop.is_empty() {
+                    body["stop"] = json!(params.stop);
+                }
+                if let Some(seed) = params.seed {
+                    body["seed"] = json!(seed);
+                }
+                if !params.tools.is_empty() {
+                    let tools: Vec<Value> = params
+                        .tools
+                        .iter()
+                        .map(|t| {
+                            json!({
+                                "type": "function",
+                                "function": {
+                                    "name": t.name,
+                                    "description": t.description,
+                                    "parameters": t.parameters,
+                                },
+                            })
+                        })
+                        .collect();
+                    body["tools"] = Value::Array(tools);
+                    if let Some(parallel) = params.parallel_tool_calls {
+                        body["parallel_tool_calls"] = Value::Bool(parallel);
+                    }
+                }
+                if let Some(choice) = params.tool_choice.openai_value() {
+                    body["tool_choice"] = choice;
+                }
+                if let Some(request) = &params.structured {
+                    self.add_response_format(&mut body, request);
+                }
+                body
+            }
+            WireFormat::Anthropic => {
+                let system: Vec<&str> = messages
+                    .iter()
+                    .filter(|m| m.role == "system")
+                    .map(|m| m.content.as_str())
+                    .collect();
+                let system_cached = messages.iter().any(|m| m.role == "system" && m.cached);
+                let mut messages: Vec<Value> = messages
+                    .iter()
+                    .filter(|m| m.role != "system")
+                    .map(anthropic_message)
+                    .collect();
+                attach_documents(&mut messages, &params.documents);
+                let mut body = json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": params.temperature,
+                });
+                if params.top_p != 1.0 && anthropic_takes_top_p(&self.model) {
+                    body["top_p"] = json!(params.top_p);
+                }
+                if params.max_tokens > 0 {
+                    body["max_tokens"] = json!(params.max_tokens);
+                }
+                if !system.is_empty() {
+                    let system = system.join("\n\n");
+                    body["system"] = if system_cached {
+                        json!([{ "type": "text", "text": system, "cache_control": cache_control() }])
+                    } else {
+                        Value::String(system)
+                    };
+                }
+                if !params.stop.is_empty() {
+                    body["stop_sequences"] = json!(params.stop);
+                }
+                if !params.tools.is_empty() {
+                    let tools: Vec<Value> = params
+                        .tools
+                        .iter()
+                        .map(|t| {
+                            json!({
+                                "name": t.name,
+                                "description": t.description,
+                                "input_schema": t.parameters,
+                            })
+                        })
+                        .collect();
+                    body["tools"] = Value::Array(tools);
+                }
+                let mut choice = params.tool_choice.anthropic_value();
+                // Anthropic disables parallel calls through the tool choice
+                if params.parallel_tool_calls == Some(false)
+                    && !params.tools.is_empty()
+                    && params.tool_choice != ToolChoice::None
+                {
+                    let choice = choice.get_or_insert_with(|| json!({ "type": "auto" }));
+                    choice["disable_parallel_tool_use"] = Value::Bool(true);
+                }
+                if let Some(choice) = choice {
+                    body["tool_choice"] = choice;
+                }
+                body
+            }
+        }
+    }
+
+    /// JSON mode the way octolib's OpenAI-compatible providers send it,
+    /// with the schema name OpenAI requires: the schema's title when it is a
+    /// valid name, as withSchemaName() sets it. Ollama and local servers take
+    /// a top-level `format`.
+    fn add_response_format(&self, body: &mut Value, request: &StructuredOutputRequest) {
+        let ollama_like = matches!(self.provider.as_str(), "ollama" | "local");
+        match (&request.format, &request.schema, ollama_like) {
+            (OutputFormat::JsonSchema, Some(schema), true) => body["format"] = schema.clone(),
+            (_, _, true) => body["format"] = json!("json"),
+            (OutputFormat::JsonSchema, Some(schema), false) => {
+                let name = schema["title"]
+                    .as_str()
+                    .filter(|title| is_schema_name(title))
+                    .unwrap_or("response_schema");
+                let mut format = json!({
+                    "type": "json_schema",
+                    "json_schema": { "name": name, "schema": schema },
+                });
+                if matches!(request.mode, ResponseMode::Strict) {
+                    format["json_schema"]["strict"] = Value::Bool(true);
+                }
+                body["response_format"] = format;
+            }
+            (OutputFormat::Json, _, false) => {
+                body["response_format"] = json!({ "type": "json_object" });
+            }
+            (OutputFormat::JsonSchema, None, false) => {}
+        }
+    }
+}
+
+/// Whether `name` is accepted as a response format name: 1 to 64 letters,
+/// digits, underscores or dashes
+pub(crate) fn is_schema_name(name: &str) -> bool {
+    (1..=64).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Text plus image parts in OpenAI's multi-part content format
+fn openai_content(msg: &OctoMessage) -> Value {
+    let Some(images) = msg.images.as_ref().filter(|i| !i.is_empty()) else {
+        return Value::String(msg.content.clone());
+    };
+    let mut parts = vec![json!({ "type": "text", "text": msg.content })];
+    for image in images {
+        let url = match &image.data {
+            ImageData::Url(url) => url.clone(),
+            ImageData::Base64(data) => format!("data:{};base64,{data}", image.media_type),
+        };
+        parts.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+    }
+    Value::Array(parts)
+}
+
+/// Text plus image parts in Anthropic's content block format
+fn anthropic_content(msg: &OctoMessage) -> Value {
+    let Some(images) = msg.images.as_ref().filter(|i| !i.is_empty()) else {
+        return Value::String(msg.content.clone());
+    };
+    let mut parts: Vec<Value> = images
+        .iter()
+        .map(|image| {
+            let source = match &image.data {
+                ImageData::Url(url) => json!({ "type": "url", "url": url }),
+                ImageData::Base64(data) => json!({
+                    "type": "base64",
+                    "media_type": image.media_type,
+                    "data": data,
+                }),
+            };
+            json!({ "type": "image", "source": source })
+        })
+        .collect();
+    parts.push(json!({ "type": "text", "text": msg.content }));
+    Value::Array(parts)
+}
+
+/// Tool calls of an assistant message, stored as `{id, name, arguments}`
+/// or already in the OpenAI wire format
+fn history_tool_calls(msg: &OctoMessage) -> impl Iterator<Item = (&str, &str, &Value)> {
+    msg.tool_calls
+        .as_ref()
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .map(|call| {
+            let function = call.get("function").unwrap_or(call);
+            (
+                call["id"].as_str().unwrap_or_default(),
+                function["name"].as_str().unwrap_or_default(),
+                &function["arguments"],
+            )
+        })
+}
+
+/// OpenAI reasoning models (o1 to o4, gpt-5) reject temperature and top_p
+/// and take `max_completion_tokens`, going by the same names as octolib
+fn is_openai_reasoning_model(model: &str) -> bool {
+    // OpenRouter style "openai/o3" names
+    let name = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+    ["o1", "o2", "o3", "o4", "gpt-5"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+}
+
+/// Newer Claude models reject temperature and top_p in the same request;
+/// like octolib, they only get the temperature
+fn anthropic_takes_top_p(model: &str) -> bool {
+    ![
+        "opus-4-1",
+        "opus-4-5",
+        "opus-4-6",
+        "sonnet-4-5",
+        "sonnet-4-6",
+        "haiku-4-5",
+    ]
+    .iter()
+    .any(|name| model.contains(name))
+}
+
+fn openai_message(msg: &OctoMessage) -> Value {
+    let mut value = json!({ "role": msg.role, "content": openai_content(msg) });
+    if let Some(ref id) = msg.tool_call_id {
+        value["tool_call_id"] = Value::String(id.clone());
+    }
+    let calls: Vec<Value> = history_tool_calls(msg)
+        .map(|(id, name, arguments)| {
+            let arguments = match arguments {
+                Value::String(raw) => raw.clone(),
+                Value::Null => "{}".to_string(),
+                other => other.to_string(),
+            };
+            json!({
+                "id": id,
+                "type": "function",
+                "function": { "name": name, "arguments": arguments },
+            })
+        })
+        .collect();
+    if !calls.is_empty() {
+        value["tool_calls"] = Value::Array(calls);
+    }
+    value
+}
+
+/// Anthropic's marker for the end of a cacheable prompt prefix
+fn cache_control() -> Value {
+    json!({ "type": "ephemeral" })
+}
+
+fn anthropic_message(msg: &OctoMessage) -> Value {
+    let mut value = anthropic_message_content(msg);
+    if msg.cached {
+        mark_cached(&mut value);
+    }
+    value
+}
+
+/// Put `cache_control` on the last content block of a message, turning
+/// plain text content into a block first
+fn mark_cached(message: &mut Value) {
+    if let Value::String(text) = &message["content"] {
+        message["content"] = json!([{ "type": "text", "text": text }]);
+    }
+    if let Some(last) = message["content"].as_array_mut().and_then(|c| c.last_mut()) {
+        last["cache_control"] = cache_control();
+    }
+}
+
+fn anthropic_message_content(msg: &OctoMessage) -> Value {
+    match (msg.role.as_str(), &msg.tool_call_id) {
+        ("tool", Some(id)) => json!({
+            "role": "user",
+            "content": [{ "type": "tool_result", "tool_use_id": id, "content": msg.content }],
+        }),
+        _ => {
+            let calls: Vec<Value> = history_tool_calls(msg)
+                .map(|(id, name, arguments)| {
+                    let input = match arguments {
+                        Value::String(raw) => serde_json::from_str(raw).unwrap_or(json!({})),
+                        Value::Null => json!({}),
+                        other => other.clone(),
+                    };
+                    json!({ "type": "tool_use", "id": id, "name": name, "input": input })
+                })
+                .collect();
+            if calls.is_empty() {
+                return json!({ "role": msg.role, "content": anthropic_content(msg) });
+            }
+            let mut content = Vec::new();
+            if !msg.content.is_empty() {
+                content.push(json!({ "type": "text", "text": msg.content }));
+            }
+            content.extend(calls);
+            json!({ "role": msg.role, "content": content })
+        }
+    }
+}
+
+/// Start streaming a completion on the runtime.
+///
+/// Events are delivered through a bounded channel so that PHP callbacks are
+/// always invoked from the calling PHP thread while draining it.
+pub(crate) fn spawn(
+    runtime: &Runtime,
+    target: StreamTarget,
+    messages: &[OctoMessage],
+    params: &StreamParams,
+    retry: RetryPolicy,
+) -> mpsc::Receiver<Result<StreamEvent>> {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let body = target.request_body(messages, params, true);
+
+    runtime.spawn(async move {
+        if let Err(e) = run(&target, &body, &retry, &tx).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+
+    rx
+}
+
+/// Send the request, failing on a non-success status. Streams retry this
+/// part only, before any delta has been delivered.
+async fn connect(
+    target: &StreamTarget,
+    body: &Value,
+    streaming: bool,
+) -> Result<reqwest::Response> {
+    let client = target.config.http_client(streaming)?;
+    let mut request = client.post(&target.url).json(body);
+    request = match target.format {
+        WireFormat::OpenAi => request.bearer_auth(&target.api_key),
+        WireFormat::Anthropic => request
+            .header("x-api-key", &target.api_key)
+            .header("anthropic-version", "2023-06-01"),
+    };
+    request = target.config.apply_headers(&target.provider, request);
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| request_error(&target.provider, e))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let retry_after = RetryAfter::from_header(response.headers().get("retry-after"));
+        let request_id = RequestId::from_headers(response.headers());
+        let message = response.text().await.unwrap_or_default();
+        let mut err = anyhow::Error::from(ProviderError::ApiError {
+            provider: target.provider.clone(),
+            status: status.as_u16(),
+            message,
+        });
+        if let Some(id) = request_id {
+            err = err.context(id);
+        }
+        return Err(match retry_after {
+            Some(delay) => err.context(delay),
+            None => err,
+        });
+    }
+
+    Ok(response)
+}
+
+async fn run(
+    target: &StreamTarget,
+    body: &Value,
+    retry: &RetryPolicy,
+    tx: &mpsc::Sender<Result<StreamEvent>>,
+) -> Result<()> {
+    let response = retry.run(|| connect(target, body, true)).await?;
+
+    let mut parser = SseParser::new(target.format);
+    parser.rate_limit = RateLimitInfo::from_headers(response.headers());
+    let mut bytes = response.bytes_stream();
+    while let Some(chunk) = bytes.next().await {
+        let chunk = chunk.map_err(|e| request_error(&target.provider, e))?;
+        for event in parser.feed(&chunk) {
+            if tx.send(Ok(event)).await.is_err() {
+                // Receiver dropped (PHP side stopped reading), abort the request
+                return Ok(());
+            }
+        }
+    }
+
+    for event in parser.flush_tool_calls() {
+        if tx.send(Ok(event)).await.is_err() {
+            return Ok(());
+        }
+    }
+    let _ = tx.send(Ok(parser.finish())).await;
+    Ok(())
+}
+
+/// Send a non-streaming completion directly, for requests with parameters
+/// or connection settings octolib has no way to pass. One attempt: the
+/// caller's retry policy runs around it. The cost is priced with `pricing`.
+pub(crate) async fn complete(
+    target: &StreamTarget,
+    messages: &[OctoMessage],
+    params: &StreamParams,
+    pricing: Option<ModelPricing>,
+) -> Result<ProviderResponse> {
+    let body = target.request_body(messages, params, false);
+    let started = Instant::now();
+    let response = connect(target, &body, false).await?;
+    let text = response
+        .text()
+        .await
+        .map_err(|e| request_error(&target.provider, e))?;
+    let raw: Value = serde_json::from_str(&text)
+        .map_err(|e| anyhow::anyhow!("Invalid response from {}: {e}", target.provider))?;
+
+    let mut reply = match target.format {
+        WireFormat::OpenAi => parse_openai_reply(&raw),
+        WireFormat::Anthropic => parse_anthropic_reply(&raw),
+    };
+    reply.usage.request_time_ms = Some(started.elapsed().as_millis() as u64);
+    reply.usage.cost = pricing.map(|pricing| {
+        pricing.calculate_cost(
+            reply.usage.input_tokens,
+            reply.usage.cache_write_tokens,
+            reply.usage.cache_read_tokens,
+            reply.usage.output_tokens,
+        )
+    });
+    let structured_output = serde_json::from_str::<Value>(reply.content.trim())
+        .ok()
+        .filter(|v| v.is_object() || v.is_array());
+    Ok(ProviderResponse {
+        id: raw["id"].as_str().map(str::to_string),
+        content: reply.content,
+        thinking: None,
+        exchange: ProviderExchange::new(body, raw, Some(reply.usage), &target.provider),
+        tool_calls: (!reply.tool_calls.is_empty()).then_some(reply.tool_calls),
+        finish_reason: reply.finish_reason,
+        structured_output,
+    })
+}
+
+/// Fields of a non-streaming reply
+struct Reply {
+    content: String,
+    tool_calls: Vec<OctoToolCall>,
+    finish_reason: Option<String>,
+    usage: TokenUsage,
+}
+
+/// Arguments sent as a JSON string, kept as the raw string when they do not
+/// parse so that malformed calls can be detected and repaired
+fn parse_arguments(arguments: &Value) -> Value {
+    match arguments {
+        Value::String(raw) => serde_json::from_str(raw).unwrap_or_else(|_| arguments.clone()),
+        other => other.clone(),
+    }
+}
+
+fn parse_openai_reply(raw: &Value) -> Reply {
+    let choice = &raw["choices"][0];
+    let message = &choice["message"];
+    let tool_calls = message["tool_calls"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|call| OctoToolCall {
+            id: call["id"].as_str().unwrap_or_default().to_string(),
+            name: call["function"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            arguments: parse_arguments(&call["function"]["arguments"]),
+        })
+        .collect();
+
+    let usage = &raw["usage"];
+    let input = usage["prompt_tokens"].as_u64().unwrap_or(0);
+    let output = usage["completion_tokens"].as_u64().unwrap_or(0);
+    let cache_read = usage["prompt_tokens_details"]["cached_tokens"]
+        .as_u64()
+        .unwrap_or(0);
+    let mut token_usage = token_usage(input.saturating_sub(cache_read), output);
+    token_usage.cache_read_tokens = cache_read;
+    token_usage.reasoning_tokens = usage["completion_tokens_details"]["reasoning_tokens"]
+        .as_u64()
+        .unwrap_or(0);
+    token_usage.total_tokens = usage["total_tokens"].as_u64().unwrap_or(input + output);
+
+    Reply {
+        content: message["content"].as_str().unwrap_or_default().to_string(),
+        tool_calls,
+        finish_reason: choice["finish_reason"].as_str().map(str::to_string),
+        usage: token_usage,
+    }
+}
+
+fn parse_anthropic_reply(raw: &Value) -> Reply {
+    let mut content = String::new();
+    let mut tool_calls = Vec::new();
+    for block in raw["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => content.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(OctoToolCall {
+                id: block["id"].as_str().unwrap_or_default().to_string(),
+                name: block["name"].as_str().unwrap_or_default().to_string(),
+                arguments: block["input"].clone(),
+            }),
+            _ => {}
+        }
+    }
+
+    let usage = &raw["usage"];
+    let input = usage["input_tokens"].as_u64().unwrap_or(0);
+    let output = usage["output_tokens"].as_u64().unwrap_or(0);
+    let cache_read = usage["cache_read_input_tokens"].as_u64().unwrap_or(0);
+    let cache_write = usage["cache_creation_input_tokens"].as_u64().unwrap_or(0);
+    let mut token_usage = token_usage(input, output);
+    token_usage.cache_read_tokens = cache_read;
+    token_usage.cache_write_tokens = cache_write;
+    token_usage.total_tokens = input + output + cache_read + cache_write;
+
+    Reply {
+        content,
+        tool_calls,
+        finish_reason: raw["stop_reason"].as_str().map(str::to_string),
+        usage: token_usage,
+    }
+}
+
+/// Pull-based iterator over streamed content deltas.
+///
+/// Each `next()` blocks on the runtime until the next delta arrives; the bounded
+/// channel behind it applies backpressure to the HTTP stream.
+#[php_class]
+#[php(implements(ce = ce::iterator, stub = "\\Iterator"))]
+pub struct StreamIterator {
+    rx: Option<mpsc::Receiver<Result<StreamEvent>>>,
+    model: String,
+    content: String,
+    current: Option<String>,
+    pending: Option<String>,
+    position: i64,
+    started: bool,
+    response: Option<Response>,
+    attribution: Attribution,
+    citations: Vec<Citation>,
+}
+
+// Internal constructors - not exposed to PHP
+impl StreamIterator {
+    pub(crate) fn new(
+        rx: mpsc::Receiver<Result<StreamEvent>>,
+        model: String,
+        attribution: Attribution,
+    ) -> Self {
+        Self {
+            rx: Some(rx),
+            model,
+            content: String::new(),
+            current: None,
+            pending: None,
+            position: 0,
+            started: false,
+            response: None,
+            attribution,
+            citations: Vec::new(),
+        }
+    }
+
+    /// Wrap an already completed response as a single-delta stream
+    pub(crate) fn from_response(response: Response) -> Self {
+        Self {
+            rx: None,
+            model: response.get_model(),
+            content: String::new(),
+            current: None,
+            pending: Some(response.get_content()),
+            position: 0,
+            started: false,
+            response: Some(response),
+            attribution: Attribution::default(),
+            citations: Vec::new(),
+        }
+    }
+
+    /// Pull the next delta into `current`, finishing the response on Done
+    fn advance(&mut self) -> PhpResult<()> {
+        self.current = None;
+
+        if let Some(delta) = self.pending.take() {
+            self.current = Some(delta);
+            return Ok(());
+        }
+
+        let Some(rx) = self.rx.as_mut() else {
+            return Ok(());
+        };
+
+        let rt = runtime()?;
+        let event = loop {
+            match rt.block_on(rx.recv()) {
+                // Tool calls are only requested through ToolBuilder::stream()
+                Some(Ok(StreamEvent::ToolCall { .. })) => continue,
+                Some(Ok(StreamEvent::Citation(citation))) => self.citations.push(citation),
+                event => break event,
+            }
+        };
+
+        match event {
+            Some(Ok(StreamEvent::Delta(delta))) => {
+                self.content.push_str(&delta);
+                self.current = Some(delta);
+                Ok(())
+            }
+            Some(Ok(StreamEvent::Done {
+                finish_reason,
+                usage,
+                rate_limit,
+            })) => {
+                self.rx = None;
+                let refusal = detect_refusal(&finish_reason, &Value::Null);
+                let usage = usage.unwrap_or(TokenUsage {
+                    input_tokens: 0,
+                    output_tokens: 0,
+                    reasoning_tokens: 0,
+                    total_tokens: 0,
+                    cache_read_tokens: 0,
+                    cache_write_tokens: 0,
+                    cost: None,
+                    request_time_ms: None,
+                });
+                self.attribution.record(&self.model, &usage);
+                self.response = Some(
+                    Response::new(
+                        std::mem::take(&mut self.content),
+                        usage,
+                        self.model.clone(),
+                        finish_reason,
+                    )
+                    .with_refusal(refusal)
+                    .with_citations(std::mem::take(&mut self.citations))
+                    .with_rate_limit(rate_limit),
+                );
+                Ok(())
+            }
+            Some(Err(e)) => {
+                self.rx = None;
+                Err(e.into_php_exception())
+            }
+            None => {
+                self.rx = None;
+                Err(PhpException::from_class::<
+                    crate::error::LLMConnectionException,
+                >("Stream ended unexpectedly".to_string()))
+            }
+        }
+    }
+}
+
+#[php_impl]
+impl StreamIterator {
+    /// Current content delta
+    pub fn current(&self) -> Option<String> {
+        self.current.clone()
+    }
+
+    /// Index of the current delta
+    pub fn key(&self) -> i64 {
+        self.position
+    }
+
+    /// Advance to the next delta
+    pub fn next(&mut self) -> PhpResult<()> {
+        self.advance()?;
+        self.position += 1;
+        Ok(())
+    }
+
+    /// Start the stream; a stream can only be iterated once
+    pub fn rewind(&mut self) -> PhpResult<()> {
+        if self.started {
+            if self.position == 0 {
+                return Ok(());
+            }
+            return Err(PhpException::from_class::<crate::error::LLMException>(
+                "Cannot rewind a stream that was already consumed".to_string(),
+            ));
+        }
+        self.started = true;
+        self.advance()
+    }
+
+    /// Whether a delta is available
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Final Response, available once the stream has been fully consumed
+    pub fn get_response(&self) -> Option<Response> {
+        self.response.clone()
+    }
+}
+
+/// Tool call still receiving argument fragments
+struct PartialToolCall {
+    index: u64,
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+/// Incremental parser turning SSE `data:` lines into stream events
+struct SseParser {
+    format: WireFormat,
+    /// Bytes of the incomplete last line; a chunk may end inside a
+    /// multi-byte character, so lines are only decoded once complete
+    buffer: Vec<u8>,
+    finish_reason: Option<String>,
+    usage: Option<TokenUsage>,
+    /// Rate-limit headers of the response, passed on with Done
+    rate_limit: Option<RateLimitInfo>,
+    tool_calls: Vec<PartialToolCall>,
+    /// Characters of content so far, to place citations in the answer
+    content_chars: u64,
+    /// Citations of the current text block, completed when it stops
+    citations: Vec<Citation>,
+}
+
+impl SseParser {
+    fn new(format: WireFormat) -> Self {
+        Self {
+            format,
+            buffer: Vec::new(),
+            finish_reason: None,
+            usage: None,
+            rate_limit: None,
+            tool_calls: Vec::new(),
+            content_chars: 0,
+            citations: Vec::new(),
+        }
+    }
+
+    /// Emit every tool call assembled so far
+    fn flush_tool_calls(&mut self) -> Vec<StreamEvent> {
+        self.tool_calls
+            .drain(..)
+            .map(|call| StreamEvent::ToolCall {
+                id: call.id,
+                name: call.name,
+                arguments: call.arguments,
+            })
+            .collect()
+    }
+
+    /// Merge OpenAI `delta.tool_calls` fragments. Calls arrive one after
+    /// another, so the start of a new index completes the earlier ones.
+    fn merge_openai_tool_calls(&mut self, fragments: &[Value], events: &mut Vec<StreamEvent>) {
+        for fragment in fragments {
+            let index = fragment["index"].as_u64().unwrap_or(0);
+            if !self.tool_calls.iter().any(|c| c.index == index) {
+                events.extend(self.flush_tool_calls());
+                self.tool_calls.push(PartialToolCall {
+                    index,
+                    id: String::new(),
+                    name: String::new(),
+                    arguments: String::new(),
+                });
+            }
+            let Some(call) = self.tool_calls.iter_mut().find(|c| c.index == index) else {
+                continue;
+            };
+            if let Some(id) = fragment["id"].as_str() {
+                call.id = id.to_string();
+            }
+            if let Some(name) = fragment["function"]["name"].as_str() {
+                call.name.push_str(name);
+            }
+            if let Some(arguments) = fragment["function"]["arguments"].as_str() {
+                call.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    /// Feed a chunk of the response body, returning the content events
+    /// completed by it
+    fn feed(&mut self, chunk: &[u8]) -> Vec<StreamEvent> {
+        self.buffer.extend_from_slice(chunk);
+        let mut events = Vec::new();
+
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let bytes: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&bytes);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                continue;
+            }
+            if let Ok(value) = serde_json::from_str::<Value>(data) {
+                if let Some(delta) = self.handle(&value, &mut events) {
+                    events.push(StreamEvent::Delta(delta));
+                }
+            }
+        }
+
+        events
+    }
+
+    /// Returns the content delta carried by `value`; completed tool calls
+    /// are pushed onto `events`
+    fn handle(&mut self, value: &Value, events: &mut Vec<StreamEvent>) -> Option<String> {
+        match self.format {
+            WireFormat::OpenAi => {
+                if let Some(usage) = value.get("usage").filter(|u| u.is_object()) {
+                    let input = usage["prompt_tokens"].as_u64().unwrap_or(0);
+                    let output = usage["completion_tokens"].as_u64().unwrap_or(0);
+                    self.usage = Some(token_usage(input, output));
+                }
+                let choice = value.get("choices")?.get(0)?;
+                if let Some(fragments) = choice["delta"]["tool_calls"].as_array() {
+                    self.merge_openai_tool_calls(fragments, events);
+                }
+                if let Some(reason) = choice["finish_reason"].as_str() {
+                    self.finish_reason = Some(reason.to_string());
+                    events.extend(self.flush_tool_calls());
+                }
+                choice["delta"]["content"]
+                    .as_str()
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+            }
+            WireFormat::Anthropic => match value["type"].as_str()? {
+                "message_start" => {
+                    let input = value["message"]["usage"]["input_tokens"]
+                        .as_u64()
+                        .unwrap_or(0);
+                    self.usage = Some(token_usage(input, 0));
+                    None
+                }
+                "content_block_start" => {
+                    let block = &value["content_block"];
+                    if block["type"] == "tool_use" {
+                        self.tool_calls.push(PartialToolCall {
+                            index: value["index"].as_u64().unwrap_or(0),
+                            id: block["id"].as_str().unwrap_or_default().to_string(),
+                            name: block["name"].as_str().unwrap_or_default().to_string(),
+                            arguments: String::new(),
+                        });
+                    }
+                    None
+                }
+                "content_block_delta" => {
+                    if let Some(partial) = value["delta"]["partial_json"].as_str() {
+                        let index = value["index"].as_u64().unwrap_or(0);
+                        if let Some(call) = self.tool_calls.iter_mut().find(|c| c.index == index) {
+                            call.arguments.push_str(partial);
+                        }
+                        return None;
+                    }
+                    // Citations arrive before the text of the block they support
+                    if value["delta"]["type"] == "citations_delta" {
+                        self.citations.extend(Citation::from_anthropic(
+                            &value["delta"]["citation"],
+                            self.content_chars,
+                        ));
+                        return None;
+                    }
+                    let text = value["delta"]["text"].as_str()?;
+                    self.content_chars += text.chars().count() as u64;
+                    Some(text.to_string())
+                }
+                "content_block_stop" => {
+                    events.extend(self.flush_tool_calls());
+                    for mut citation in self.citations.drain(..) {
+                        citation.answer_end = self.content_chars;
+                        events.push(StreamEvent::Citation(citation));
+                    }
+                    None
+                }
+                "message_delta" => {
+                    if let Some(reason) = value["delta"]["stop_reason"].as_str() {
+                        self.finish_reason = Some(reason.to_string());
+                    }
+                    if let Some(output) = value["usage"]["output_tokens"].as_u64() {
+                        let input = self.usage.as_ref().map_or(0, |u| u.input_tokens as u64);
+                        self.usage = Some(token_usage(input, output));
+                    }
+                    None
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn finish(self) -> StreamEvent {
+        StreamEvent::Done {
+            finish_reason: self.finish_reason.unwrap_or_else(|| "stop".to_string()),
+            usage: self.usage,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+pub(crate) fn token_usage(input: u64, output: u64) -> TokenUsage {
+    TokenUsage {
+        input_tokens: input as _,
+        output_tokens: output as _,
+        reasoning_tokens: 0,
+        total_tokens: (input + output) as _,
+        cache_read_tokens: 0,
+        cache_write_tokens: 0,
+        cost: None,
+        request_time_ms: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deltas(events: Vec<StreamEvent>) -> Vec<String> {
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                StreamEvent::Delta(text) => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn tool_calls(events: Vec<StreamEvent>) -> Vec<(String, String, String)> {
+        events
+            .into_iter()
+            .filter_map(|e| match e {
+                StreamEvent::ToolCall {
+                    id,
+                    name,
+                    arguments,
+                } => Some((id, name, arguments)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_openai_sse_deltas() {
+        let mut parser = SseParser::new(WireFormat::OpenAi);
+        let events = parser.feed(
+            b"data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choices\":[{\"del",
+        );
+        assert_eq!(deltas(events), vec!["Hel"]);
+
+        let events = parser
+            .feed(b"ta\":{\"content\":\"lo\"},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n");
+        assert_eq!(deltas(events), vec!["lo"]);
+
+        let StreamEvent::Done { finish_reason, .. } = parser.finish() else {
+            panic!("expected Done event");
+        };
+        assert_eq!(finish_reason, "stop");
+    }
+
+    #[test]
+    fn test_multibyte_character_split_across_chunks() {
+        let mut parser = SseParser::new(WireFormat::OpenAi);
+        let line = "data: {\"choices\":[{\"delta\":{\"content\":\"Größe €\"}}]}\n\n";
+        let bytes = line.as_bytes();
+        // Split inside the three bytes of the euro sign
+        let split = line.find('€').unwrap() + 1;
+        assert!(parser.feed(&bytes[..split]).is_empty());
+        assert_eq!(deltas(parser.feed(&bytes[split..])), vec!["Größe €"]);
+    }
+
+    #[test]
+    fn test_anthropic_sse_deltas() {
+        let mut parser = SseParser::new(WireFormat::Anthropic);
+        let events = parser.feed(concat!(
+            "event: message_start\n",
+            "data: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":12}}}\n\n",
+            "event: content_block_delta\n",
+            "data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"Hi\"}}\n\n",
+            "data: {\"type\":\"message_delta\",\"delta\":{\"stop_reason\":\"end_turn\"},\"usage\":{\"output_tokens\":3}}\n\n",
+        ).as_bytes());
+        assert_eq!(deltas(events), vec!["Hi"]);
+
+        let StreamEvent::Done {
+            finish_reason,
+            usage,
+            ..
+        } = parser.finish()
+        else {
+            panic!("expected Done event");
+        };
+        assert_eq!(finish_reason, "end_turn");
+        let usage = usage.unwrap();
+        assert_eq!(usage.input_tokens, 12);
+        assert_eq!(usage.total_tokens, 15);
+    }
+
+    #[test]
+    fn test_anthropic_citations_span_their_text_block() {
+        let mut parser = SseParser::new(WireFormat::Anthropic);
+        let events = parser.feed(concat!(
+            "data: {\"type\":\"content_block_start\",\"index\":0,\"content_block\":{\"type\":\"text\",\"text\":\"\"}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Yes. \"}}\n\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":0}\n\n",
+            "data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"text\",\"text\":\"\",\"citations\":[]}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"citations_delta\",\"citation\":{\"type\":\"char_location\",\"cited_text\":\"The grass is green.\",\"document_index\":0,\"document_title\":null,\"start_char_index\":0,\"end_char_index\":19}}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"text_delta\",\"text\":\"The grass is green\"}}\n\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":1}\n\n",
+        ).as_bytes());
+        let citations: Vec<Citation> = events
+            .into_iter()
+            .filter_map(|e| match e {
+                StreamEvent::Citation(citation) => Some(citation),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(citations.len(), 1);
+        assert_eq!((citations[0].start, citations[0].end), (0, 19));
+        assert_eq!(
+            (citations[0].answer_start, citations[0].answer_end),
+            (5, 23)
+        );
+    }
+
+    #[test]
+    fn test_openai_tool_call_fragments() {
+        let mut parser = SseParser::new(WireFormat::OpenAi);
+        let mut events = parser.feed(concat!(
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"id\":\"call_1\",\"function\":{\"name\":\"weather\",\"arguments\":\"\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"{\\\"city\\\":\"}}]}}]}\n\n",
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":0,\"function\":{\"arguments\":\"\\\"Oslo\\\"}\"}}]}}]}\n\n",
+        ).as_bytes());
+        assert!(tool_calls(events).is_empty());
+
+        events = parser.feed(concat!(
+            "data: {\"choices\":[{\"delta\":{\"tool_calls\":[{\"index\":1,\"id\":\"call_2\",\"function\":{\"name\":\"time\",\"arguments\":\"{}\"}}]}}]}\n\n",
+        ).as_bytes());
+        assert_eq!(
+            tool_calls(events),
+            vec![(
+                "call_1".to_string(),
+                "weather".to_string(),
+                "{\"city\":\"Oslo\"}".to_string()
+            )]
+        );
+
+        events = parser
+            .feed(b"data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"tool_calls\"}]}\n\n");
+        assert_eq!(
+            tool_calls(events),
+            vec![("call_2".to_string(), "time".to_string(), "{}".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_anthropic_tool_use_blocks() {
+        let mut parser = SseParser::new(WireFormat::Anthropic);
+        let events = parser.feed(concat!(
+            "data: {\"type\":\"content_block_start\",\"index\":1,\"content_block\":{\"type\":\"tool_use\",\"id\":\"toolu_1\",\"name\":\"weather\",\"input\":{}}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"{\\\"city\\\": \"}}\n\n",
+            "data: {\"type\":\"content_block_delta\",\"index\":1,\"delta\":{\"type\":\"input_json_delta\",\"partial_json\":\"\\\"Oslo\\\"}\"}}\n\n",
+            "data: {\"type\":\"content_block_stop\",\"index\":1}\n\n",
+        ).as_bytes());
+        assert_eq!(
+            tool_calls(events),
+            vec![(
+                "toolu_1".to_string(),
+                "weather".to_string(),
+                "{\"city\": \"Oslo\"}".to_string()
+            )]
+        );
+    }
+
+    fn target(format: WireFormat) -> StreamTarget {
+        StreamTarget {
+            url: String::new(),
+            api_key: String::new(),
+            model: "test-model".to_string(),
+            provider: "openai".to_string(),
+            format,
+            config: ProviderConfig::default(),
+        }
+    }
+
+    fn params() -> StreamParams {
+        StreamParams {
+            temperature: 0.7,
+            top_p: 1.0,
+            max_tokens: 100,
+            penalties: Penalties {
+                frequency: 0.5,
+                presence: 0.0,
+            },
+            stop: Vec::new(),
+            seed: None,
+            tools: Vec::new(),
+            tool_choice: ToolChoice::Auto,
+            parallel_tool_calls: None,
+            documents: Vec::new(),
+            structured: None,
+        }
+    }
+
+    #[test]
+    fn test_direct_request_body() {
+        let messages = vec![octolib::llm::MessageBuilder::user("hi").build().unwrap()];
+        let body = target(WireFormat::OpenAi).request_body(&messages, &params(), false);
+        assert_eq!(body["frequency_penalty"], json!(0.5));
+        assert!(body.get("presence_penalty").is_none());
+        assert!(body.get("stream").is_none());
+
+        let body = target(WireFormat::OpenAi).request_body(&messages, &params(), true);
+        assert_eq!(body["stream"], json!(true));
+        assert_eq!(body["stream_options"]["include_usage"], json!(true));
+    }
+
+    #[test]
+    fn test_sampling_fields_by_model() {
+        let messages = vec![octolib::llm::MessageBuilder::user("hi").build().unwrap()];
+        let mut reasoning = target(WireFormat::OpenAi);
+        reasoning.model = "o3-mini".to_string();
+        let body = reasoning.request_body(&messages, &params(), false);
+        assert!(body.get("temperature").is_none());
+        assert!(body.get("top_p").is_none());
+        assert!(body.get("max_tokens").is_none());
+        assert_eq!(body["max_completion_tokens"], json!(100));
+
+        // top_p left at 1.0 is not sent
+        let body = target(WireFormat::OpenAi).request_body(&messages, &params(), false);
+        assert!(body.get("top_p").is_none());
+        assert_eq!(body["max_tokens"], json!(100));
+
+        let mut claude = target(WireFormat::Anthropic);
+        claude.model = "claude-opus-4-1".to_string();
+        let mut sampled = params();
+        sampled.top_p = 0.9;
+        let body = claude.request_body(&messages, &sampled, false);
+        assert!(body.get("temperature").is_some());
+        assert!(body.get("top_p").is_none());
+        claude.model = "claude-3-5-haiku-latest".to_string();
+        let body = claude.request_body(&messages, &sampled, false);
+        assert!(body.get("top_p").is_some());
+    }
+
+    #[test]
+    fn test_response_format_schema_name() {
+        let messages = vec![octolib::llm::MessageBuilder::user("hi").build().unwrap()];
+        let name_for = |schema: Value| {
+            let mut params = params();
+            params.structured = Some(StructuredOutputRequest::json_schema(schema));
+            target(WireFormat::OpenAi).request_body(&messages, &params, false)["response_format"]
+                ["json_schema"]["name"]
+                .clone()
+        };
+        assert_eq!(
+            name_for(json!({ "title": "invoice", "type": "object" })),
+            "invoice"
+        );
+        assert_eq!(name_for(json!({ "type": "object" })), "response_schema");
+        assert_eq!(
+            name_for(json!({ "title": "Invoice record", "type": "object" })),
+            "response_schema"
+        );
+    }
+
+    #[test]
+    fn test_anthropic_cache_control() {
+        let mut system = octolib::llm::MessageBuilder::system("rules")
+            .build()
+            .unwrap();
+        system.cached = true;
+        let mut user = octolib::llm::MessageBuilder::user("context")
+            .build()
+            .unwrap();
+        user.cached = true;
+        let question = octolib::llm::MessageBuilder::user("question")
+            .build()
+            .unwrap();
+        let body =
+            target(WireFormat::Anthropic).request_body(&[system, user, question], &params(), false);
+        assert_eq!(body["system"][0]["text"], "rules");
+        assert_eq!(body["system"][0]["cache_control"]["type"], "ephemeral");
+        assert_eq!(body["messages"][0]["content"][0]["text"], "context");
+        assert_eq!(
+            body["messages"][0]["content"][0]["cache_control"]["type"],
+            "ephemeral"
+        );
+        assert_eq!(body["messages"][1]["content"], "question");
+    }
+
+    #[test]
+    fn test_history_tool_calls_in_wire_format() {
+        let mut msg = octolib::llm::MessageBuilder::assistant("").build().unwrap();
+        msg.tool_calls = Some(json!([
+            { "id": "call_1", "name": "weather", "arguments": { "city": "Oslo" } }
+        ]));
+        let openai = openai_message(&msg);
+        assert_eq!(openai["tool_calls"][0]["type"], "function");
+        assert_eq!(
+            openai["tool_calls"][0]["function"]["arguments"],
+            "{\"city\":\"Oslo\"}"
+        );
+        let anthropic = anthropic_message(&msg);
+        assert_eq!(anthropic["content"][0]["type"], "tool_use");
+        assert_eq!(anthropic["content"][0]["input"]["city"], "Oslo");
+    }
+
+    #[test]
+    fn test_openai_reply() {
+        let reply = parse_openai_reply(&json!({
+            "id": "chatcmpl-1",
+            "choices": [{
+                "message": {
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "weather", "arguments": "{\"city\":\"Oslo\"}" },
+                    }],
+                },
+                "finish_reason": "tool_calls",
+            }],
+            "usage": {
+                "prompt_tokens": 120,
+                "completion_tokens": 15,
+                "total_tokens": 135,
+                "prompt_tokens_details": { "cached_tokens": 100 },
+            },
+        }));
+        assert_eq!(reply.content, "");
+        assert_eq!(reply.tool_calls[0].arguments["city"], "Oslo");
+        assert_eq!(reply.finish_reason.as_deref(), Some("tool_calls"));
+        assert_eq!(reply.usage.input_tokens, 20);
+        assert_eq!(reply.usage.cache_read_tokens, 100);
+        assert_eq!(reply.usage.total_tokens, 135);
+    }
+
+    #[test]
+    fn test_anthropic_reply() {
+        let reply = parse_anthropic_reply(&json!({
+            "id": "msg_1",
+            "content": [
+                { "type": "text", "text": "Checking." },
+                { "type": "tool_use", "id": "toolu_1", "name": "weather", "input": { "city": "Oslo" } },
+            ],
+            "stop_reason": "tool_use",
+            "usage": { "input_tokens": 30, "output_tokens": 12, "cache_read_input_tokens": 50 },
+        }));
+        assert_eq!(reply.content, "Checking.");
+        assert_eq!(reply.tool_calls[0].id, "toolu_1");
+        assert_eq!(reply.usage.cache_read_tokens, 50);
+        assert_eq!(reply.usage.total_tokens, 92);
+    }
+}
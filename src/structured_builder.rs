@@ -1,13 +1,27 @@
 use ext_php_rs::convert::IntoZval;
 use ext_php_rs::prelude::*;
-use ext_php_rs::types::{ZendClassObject, ZendHashTable as PhpArray, Zval};
-use octolib::llm::{ChatCompletionParams, ProviderFactory, StructuredOutputRequest, TokenUsage};
-use std::sync::Arc;
+use ext_php_rs::types::{ZendCallable, ZendClassObject, ZendHashTable as PhpArray, Zval};
+use octolib::llm::{Message as OctoMessage, MessageBuilder, StructuredOutputRequest, TokenUsage};
+use serde_json::Value;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
 
-use crate::convert::{json_value_to_php, php_to_messages};
-use crate::error::IntoPhpException;
-use crate::llm_class::Usage;
+use crate::cache::MemoryCache;
+use crate::convert::{json_value_to_php, php_to_messages, prepend_system};
+use crate::error::{IntoPhpException, InvalidStructuredOutput, SchemaViolations};
+use crate::field_stream::FieldStream;
+use crate::json_fallback::FallbackStrategy;
+use crate::llm_class::{CallSettings, Usage};
+use crate::params::{parse_stop, remap_system_role, Penalties, ToolChoice};
+use crate::reasoning::extract_reasoning;
+use crate::runtime::runtime;
+use crate::stream::{StreamEvent, StreamParams, StreamTarget};
+
+/// Structured responses cached by builders with `withCache()`
+static CACHE: LazyLock<MemoryCache<StructuredResponse>> = LazyLock::new(MemoryCache::new);
 
 /// Builder for structured output
 #[php_class]
@@ -16,48 +30,172 @@ pub struct StructuredBuilder {
     temperature: f32,
     max_tokens: u32,
     top_p: f32,
+    top_k: u32,
+    penalties: Penalties,
+    stop: Vec<String>,
+    seed: Option<i64>,
     schema: Option<String>,
+    system_prompt: Option<String>,
+    settings: CallSettings,
     format: String,
-    runtime: Arc<Runtime>,
+    cache_ttl: Option<Duration>,
+    fallback: FallbackStrategy,
+    /// Re-asks allowed for a reply that is not valid structured output
+    auto_repair: u32,
+    /// Repair almost-valid JSON before rejecting a reply
+    lenient: bool,
+    /// Whether the provider is asked to enforce the schema, see withStrict()
+    strict: bool,
+    schema_name: Option<String>,
 }
 
 // Internal constructor - not exposed to PHP
 impl StructuredBuilder {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         model: String,
         temperature: f32,
         max_tokens: u32,
         top_p: f32,
+        top_k: u32,
+        penalties: Penalties,
+        stop: Vec<String>,
+        seed: Option<i64>,
         schema: Option<String>,
-        runtime: Arc<Runtime>,
+        settings: CallSettings,
     ) -> Self {
         Self {
             model,
             temperature,
             max_tokens,
             top_p,
+            top_k,
+            penalties,
+            stop,
+            seed,
             schema,
+            system_prompt: None,
+            settings,
             format: "json".to_string(),
-            runtime,
+            cache_ttl: None,
+            fallback: FallbackStrategy::default(),
+            auto_repair: 0,
+            lenient: false,
+            strict: false,
+            schema_name: None,
         }
     }
-}
 
-#[php_impl]
-impl StructuredBuilder {
-    /// Complete with structured output
-    pub fn complete(&self, messages: &Zval) -> PhpResult<StructuredResponse> {
-        let this = self;
-        let rt = this.runtime.clone();
+    /// Set the system prompt, for helpers built on structured output
+    pub(crate) fn with_instruction(mut self, prompt: String) -> Self {
+        self.system_prompt = Some(prompt);
+        self
+    }
+
+    /// Cache key over the model, provider identity, schema, sampling
+    /// parameters and messages
+    fn cache_key(&self, messages: &[OctoMessage]) -> String {
+        crate::cache::request_key(
+            &self.model,
+            &[
+                &self.settings.config.identity(&self.model),
+                self.schema.as_deref().unwrap_or_default(),
+                &self.format,
+                &self.temperature.to_string(),
+                &self.top_p.to_string(),
+                &self.top_k.to_string(),
+                &self.max_tokens.to_string(),
+                &self.penalties.frequency.to_string(),
+                &self.penalties.presence.to_string(),
+                &self.stop.join("\u{0}"),
+                &format!("{:?}", self.seed),
+                if self.strict { "strict" } else { "" },
+                self.schema_name.as_deref().unwrap_or_default(),
+            ],
+            messages,
+        )
+    }
+
+    /// Messages for one request, ready to send: system prompt added,
+    /// templates applied and the parameter policy checked. Invalid input
+    /// throws here.
+    fn prepare(&self, mut messages_vec: Vec<OctoMessage>) -> PhpResult<Vec<OctoMessage>> {
+        prepend_system(&mut messages_vec, self.system_prompt.as_deref())?;
+        self.settings
+            .shape_messages(&self.model, &mut messages_vec)?;
+        Ok(messages_vec)
+    }
+
+    /// Schema as written, including the PHP enum markers
+    fn raw_schema(&self) -> PhpResult<Option<Value>> {
+        let Some(schema) = &self.schema else {
+            return Ok(None);
+        };
+        serde_json::from_str(schema).map(Some).map_err(|e| {
+            PhpException::from_class::<crate::error::LLMStructuredOutputException>(format!(
+                "Invalid JSON schema: {e}"
+            ))
+        })
+    }
+
+    /// Schema sent to the provider and validated against, made strict with
+    /// withStrict()
+    fn schema_value(&self) -> PhpResult<Option<Value>> {
+        Ok(self.raw_schema()?.map(|mut schema| {
+            crate::schema::strip_php_markers(&mut schema);
+            if self.strict {
+                crate::schema_limits::strict(&mut schema);
+            }
+            schema
+        }))
+    }
+
+    /// Schema for mapping properties back to PHP enum cases, when it has any
+    fn enum_schema(&self) -> PhpResult<Option<Arc<Value>>> {
+        Ok(self
+            .raw_schema()?
+            .filter(|schema| crate::schema::strip_php_markers(&mut schema.clone()))
+            .map(Arc::new))
+    }
+
+    /// Spawn a structured completion on the runtime, optionally limited by a
+    /// semaphore. Cache hits resolve without a request. Errors that apply to
+    /// every request (unknown model, no JSON mode with the 'error' strategy,
+    /// invalid schema) throw here; the task fails only for this request.
+    /// The task also returns the usage to charge, None for cache hits, so it
+    /// is recorded on the PHP thread with finish().
+    fn spawn_request(
+        &self,
+        rt: &Runtime,
+        mut messages_vec: Vec<OctoMessage>,
+        limit: Option<Arc<Semaphore>>,
+    ) -> PhpResult<JoinHandle<anyhow::Result<(StructuredResponse, Option<TokenUsage>)>>> {
+        let cache_key = self.cache_ttl.map(|_| self.cache_key(&messages_vec));
+        if let Some(cached) = cache_key.as_deref().and_then(|key| CACHE.get(key)) {
+            return Ok(rt.spawn(async move { Ok((cached.into_cached(), None)) }));
+        }
 
-        let messages_vec = php_to_messages(messages)?;
+        self.settings.attribution.check()?;
 
-        let (provider, model) = rt
-            .block_on(async { ProviderFactory::get_provider_for_model(&this.model) })
-            .map_err(|e| e.into_php_exception())?;
+        let mut params = StreamParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.max_tokens,
+            penalties: self.penalties,
+            stop: self.stop.clone(),
+            seed: self.seed,
+            tools: Vec::new(),
+            tool_choice: ToolChoice::Auto,
+            parallel_tool_calls: None,
+            documents: Vec::new(),
+            structured: None,
+        };
+        let backend = self.settings.backend(rt, &self.model, &mut params)?;
+        let model = backend.model().to_string();
 
-        // Check if provider supports structured output
-        if !provider.supports_structured_output(&model) {
+        // Without native JSON mode, ask for JSON in the prompt instead
+        let native = backend.supports_structured_output();
+        if !native && self.fallback == FallbackStrategy::Error {
             return Err(PhpException::from_class::<
                 crate::error::LLMStructuredOutputException,
             >(
@@ -65,45 +203,103 @@ impl StructuredBuilder {
             ));
         }
 
+        let schema_value = self
+            .schema_value()?
+            .map(|schema| {
+                crate::schema_limits::prepare(
+                    &self.model,
+                    &schema,
+                    self.settings.simplify_schemas,
+                    "JSON schema",
+                )
+            })
+            .transpose()?
+            .map(|mut schema| {
+                // The request's schema name is set by the provider layer, so
+                // the name goes in the schema itself
+                if let (Some(name), Some(node)) = (&self.schema_name, schema.as_object_mut()) {
+                    node.insert("title".to_string(), Value::String(name.clone()));
+                }
+                schema
+            });
+        if self.strict && schema_value.is_none() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >("Strict mode needs a schema".to_string()));
+        }
+
         // Create structured output request
-        let structured_request = if let Some(schema) = &this.schema {
-            let schema_value: serde_json::Value = serde_json::from_str(schema).map_err(|e| {
-                PhpException::from_class::<crate::error::LLMStructuredOutputException>(format!(
-                    "Invalid JSON schema: {e}"
-                ))
-            })?;
-            StructuredOutputRequest::json_schema(schema_value)
-        } else {
-            StructuredOutputRequest::json()
+        let structured_request = match (&schema_value, native) {
+            (_, false) => None,
+            (Some(schema), true) if self.strict => {
+                Some(StructuredOutputRequest::json_schema(schema.clone()).with_strict_mode())
+            }
+            (Some(schema), true) => Some(StructuredOutputRequest::json_schema(schema.clone())),
+            (None, true) => Some(StructuredOutputRequest::json()),
         };
+        if !native {
+            crate::json_fallback::add_instruction(&mut messages_vec, schema_value.as_ref())?;
+            remap_system_role(&self.model, &mut messages_vec);
+        }
 
-        let params = ChatCompletionParams::new(
+        // A native schema is sent beside the messages, a fallback one is in them
+        let schema_text = match (&schema_value, native) {
+            (Some(schema), true) => schema.to_string(),
+            _ => String::new(),
+        };
+        params.max_tokens = self.settings.max_tokens_for(
+            &self.model,
             &messages_vec,
-            &model,
-            this.temperature,
-            this.top_p,
-            50,
-            this.max_tokens,
-        )
-        .with_structured_output(structured_request);
-
-        let response = rt
-            .block_on(async { provider.chat_completion(params).await })
-            .map_err(|e| e.into_php_exception())?;
-
-        // Extract structured output
-        let structured = response.structured_output.ok_or_else(|| {
-            PhpException::from_class::<crate::error::LLMStructuredOutputException>(
-                "No structured output in response".to_string(),
-            )
-        })?;
+            &schema_text,
+            self.max_tokens,
+        )?;
+        params.structured = structured_request;
+        let top_k = self.top_k;
+        let retry = self.settings.retry.clone();
+        let config = self.settings.config.clone();
+        let cache_ttl = self.cache_ttl;
+        let full_model = self.model.clone();
+        let lenient = self.lenient;
+        let enum_schema = self.enum_schema()?;
+        Ok(rt.spawn(async move {
+            let _permit = match limit {
+                Some(limit) => Some(limit.acquire_owned().await?),
+                None => None,
+            };
+            let response = retry
+                .run(|| backend.complete(&full_model, &messages_vec, &params, top_k, &config))
+                .await?;
 
-        let structured_php = json_value_to_php(&structured)?;
+            // Extract structured output, keeping a rejected reply for
+            // withAutoRepair()
+            let structured: anyhow::Result<Value> = match response.structured_output {
+                Some(structured) => Ok(structured),
+                None if !native => parse_content(&response.content, lenient)
+                    .map_err(|e| InvalidStructuredOutput(e).into()),
+                None => lenient
+                    .then(|| crate::json_repair::parse_lenient(&response.content))
+                    .flatten()
+                    .ok_or_else(|| {
+                        InvalidStructuredOutput("No structured output in response".to_string())
+                            .into()
+                    }),
+            };
+            let structured = structured.and_then(|structured| {
+                check_schema(&structured, schema_value.as_ref())?;
+                Ok(structured)
+            });
+            let structured = match structured {
+                Ok(structured) => structured,
+                Err(e) => {
+                    return Err(e.context(RejectedReply {
+                        content: response.content,
+                        usage: response.exchange.usage,
+                        model,
+                    }))
+                }
+            };
 
-        Ok(StructuredResponse::new(
-            response.content,
-            structured_php,
-            response.exchange.usage.unwrap_or(TokenUsage {
+            let usage = response.exchange.usage.unwrap_or(TokenUsage {
                 input_tokens: 0,
                 output_tokens: 0,
                 reasoning_tokens: 0,
@@ -112,17 +308,367 @@ impl StructuredBuilder {
                 cache_write_tokens: 0,
                 cost: None,
                 request_time_ms: None,
-            }),
-            model,
-        ))
+            });
+            let reasoning = extract_reasoning(&response.exchange.response);
+            let (finish_reason, response_id) = (response.finish_reason, response.id);
+
+            let mut response =
+                StructuredResponse::new(response.content, structured, usage.clone(), model)
+                    .with_reasoning(reasoning);
+            response.fallback = !native;
+            if let Some(finish_reason) = finish_reason {
+                response.finish_reason = finish_reason;
+            }
+            response.response_id = response_id;
+            response.enum_schema = enum_schema;
+            if let (Some(key), Some(ttl)) = (cache_key, cache_ttl) {
+                CACHE.put(key, response.clone(), ttl);
+            }
+            Ok((response, Some(usage)))
+        }))
+    }
+
+    /// Stream the reply with the JSON asked for in the prompt, calling
+    /// `on_delta` with each chunk and the content so far. None when the
+    /// provider does not support streaming.
+    fn stream_json(
+        &self,
+        messages: &Zval,
+        mut on_delta: impl FnMut(&str, &str) -> PhpResult<()>,
+    ) -> PhpResult<Option<StructuredResponse>> {
+        let Some(target) = StreamTarget::for_model(&self.model, &self.settings.config) else {
+            return Ok(None);
+        };
+
+        let mut messages_vec = self.prepare(php_to_messages(messages)?)?;
+        self.settings.attribution.check()?;
+        let schema_value = self.schema_value()?;
+        crate::json_fallback::add_instruction(&mut messages_vec, schema_value.as_ref())?;
+        remap_system_role(&self.model, &mut messages_vec);
+        let (mut penalties, mut seed) = (self.penalties, self.seed);
+        if !target.supports_penalties() {
+            let mut unsupported = penalties.set_names();
+            if seed.is_some() {
+                unsupported.push("seed");
+            }
+            self.settings
+                .param_policy
+                .enforce(&self.model, &unsupported)?;
+            penalties = Penalties::default();
+            seed = None;
+        }
+        let params = StreamParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.settings.max_tokens_for(
+                &self.model,
+                &messages_vec,
+                "",
+                self.max_tokens,
+            )?,
+            penalties,
+            stop: self.stop.clone(),
+            seed,
+            tools: Vec::new(),
+            tool_choice: ToolChoice::Auto,
+            parallel_tool_calls: None,
+            documents: Vec::new(),
+            structured: None,
+        };
+        let model = target.model_name().to_string();
+        let rt = runtime()?;
+        let mut rx = crate::stream::spawn(
+            rt,
+            target,
+            &messages_vec,
+            &params,
+            self.settings.retry.clone(),
+        );
+
+        let mut content = String::new();
+        let usage = loop {
+            match rt.block_on(rx.recv()) {
+                Some(Ok(StreamEvent::Delta(delta))) => {
+                    content.push_str(&delta);
+                    on_delta(&delta, &content)?;
+                }
+                Some(Ok(StreamEvent::Done { usage, .. })) => {
+                    break usage.unwrap_or_else(|| crate::stream::token_usage(0, 0))
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into_php_exception()),
+                None => {
+                    return Err(PhpException::from_class::<
+                        crate::error::LLMConnectionException,
+                    >(
+                        "Stream ended unexpectedly".to_string()
+                    ))
+                }
+            }
+        };
+        self.settings.attribution.record(&model, &usage);
+
+        let structured = parse_content(&content, self.lenient)
+            .map_err(PhpException::from_class::<crate::error::LLMStructuredOutputException>)?;
+        check_schema(&structured, schema_value.as_ref()).map_err(|e| e.into_php_exception())?;
+        let mut response = StructuredResponse::new(content, structured, usage, model);
+        response.fallback = true;
+        response.enum_schema = self.enum_schema()?;
+        Ok(Some(response))
+    }
+
+    /// Charge the usage of a finished request, also of a rejected reply,
+    /// and hand back its response
+    fn finish(
+        &self,
+        result: anyhow::Result<(StructuredResponse, Option<TokenUsage>)>,
+    ) -> anyhow::Result<StructuredResponse> {
+        let (response, usage) = match result {
+            Ok(finished) => finished,
+            Err(e) => {
+                if let Some(RejectedReply {
+                    usage: Some(usage),
+                    model,
+                    ..
+                }) = e.downcast_ref::<RejectedReply>()
+                {
+                    self.settings.attribution.record(model, usage);
+                }
+                return Err(e);
+            }
+        };
+        if let Some(usage) = usage {
+            self.settings.attribution.record(&response.model, &usage);
+        }
+        Ok(response)
+    }
+
+    /// finish() a request, re-asking the model with the error up to the
+    /// withAutoRepair() limit while its reply cannot be parsed or does not
+    /// match the schema. Requests that cannot be sent at all throw.
+    fn finish_repairing(
+        &self,
+        rt: &Runtime,
+        mut messages_vec: Vec<OctoMessage>,
+        mut result: anyhow::Result<(StructuredResponse, Option<TokenUsage>)>,
+        limit: Option<Arc<Semaphore>>,
+    ) -> PhpResult<anyhow::Result<StructuredResponse>> {
+        for _ in 0..self.auto_repair {
+            let repair = match &result {
+                Err(e) => e
+                    .downcast_ref::<RejectedReply>()
+                    .map(|rejected| (rejected.content.clone(), e.root_cause().to_string())),
+                Ok(_) => None,
+            };
+            let Some((content, error)) = repair else {
+                break;
+            };
+            // Charges the rejected reply
+            let _ = self.finish(result);
+            crate::logger::info(&format!("Repairing structured output: {error}"));
+            messages_vec.push(MessageBuilder::assistant(&content).build().map_err(|e| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "Failed to build message: {e}"
+                ))
+            })?);
+            messages_vec.push(
+                MessageBuilder::user(&repair_prompt(&error))
+                    .build()
+                    .map_err(|e| {
+                        PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                            "Failed to build message: {e}"
+                        ))
+                    })?,
+            );
+            let handle = self.spawn_request(rt, messages_vec.clone(), limit.clone())?;
+            result = rt
+                .block_on(handle)
+                .unwrap_or_else(|e| Err(anyhow::anyhow!("Completion task failed: {e}")));
+        }
+        Ok(self.finish(result))
+    }
+}
+
+/// Reply whose structured output was rejected, attached as context to the
+/// error so withAutoRepair() can show it to the model and its usage is
+/// still charged
+#[derive(Debug)]
+struct RejectedReply {
+    content: String,
+    usage: Option<TokenUsage>,
+    model: String,
+}
+
+impl std::fmt::Display for RejectedReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rejected reply of {}", self.model)
+    }
+}
+
+/// Follow-up asking the model to fix its previous reply
+fn repair_prompt(error: &str) -> String {
+    format!(
+        "Your previous reply could not be used: {error}\n\nReply again with the \
+         corrected JSON only, conforming to the schema."
+    )
+}
+
+/// JSON value in a reply without native structured output, repaired when
+/// lenient parsing is on and the reply is not valid JSON
+fn parse_content(content: &str, lenient: bool) -> Result<Value, String> {
+    crate::json_fallback::parse_reply(content, None).or_else(|e| {
+        lenient
+            .then(|| crate::json_repair::parse_lenient(content))
+            .flatten()
+            .ok_or(e)
+    })
+}
+
+/// Validate structured output against the builder's schema, listing every
+/// violation
+fn check_schema(value: &Value, schema: Option<&Value>) -> Result<(), SchemaViolations> {
+    let Some(schema) = schema else {
+        return Ok(());
+    };
+    let violations = crate::schema_validation::violations(value, schema);
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(SchemaViolations(violations))
+    }
+}
+
+#[php_impl]
+impl StructuredBuilder {
+    /// Complete with structured output
+    pub fn complete(&self, messages: &Zval) -> PhpResult<StructuredResponse> {
+        let messages_vec = self.prepare(php_to_messages(messages)?)?;
+        let rt = runtime()?;
+        let handle = self.spawn_request(rt, messages_vec.clone(), None)?;
+        let result = rt
+            .block_on(handle)
+            .unwrap_or_else(|e| Err(anyhow::anyhow!("Completion task failed: {e}")));
+        self.finish_repairing(rt, messages_vec, result, None)?
+            .map_err(|e| e.into_php_exception())
+    }
+
+    /// Stream the structured output, calling `fn(string $field, mixed
+    /// $value)` as soon as each top-level field of the JSON object is
+    /// complete, e.g. to fill in a form progressively. The JSON is asked for
+    /// in the prompt, so isFallback() is true. Providers without streaming
+    /// support answer in one piece, and the fields are reported once it has
+    /// arrived. Returns the StructuredResponse, checked against the schema.
+    pub fn stream(&self, messages: &Zval, on_field: ZendCallable) -> PhpResult<StructuredResponse> {
+        let mut fields = FieldStream::new();
+        let streamed = self.stream_json(messages, |delta, _| {
+            for (field, value) in fields.feed(delta) {
+                on_field.try_call(vec![&field, &json_value_to_php(&value)?])?;
+            }
+            Ok(())
+        })?;
+        if let Some(response) = streamed {
+            return Ok(response);
+        }
+
+        let response = self.complete(messages)?;
+        if let Value::Object(fields) = &response.structured {
+            for (field, value) in fields {
+                on_field.try_call(vec![field, &json_value_to_php(value)?])?;
+            }
+        }
+        Ok(response)
+    }
+
+    /// Stream the structured output, calling `fn(array $partial)` with the
+    /// object parsed so far whenever it grows, so a UI can render the result
+    /// while the model is writing. Strings and numbers may be cut short and
+    /// a member appears once its value has started; the last call receives
+    /// the complete object. Otherwise works like stream().
+    pub fn stream_partial(
+        &self,
+        messages: &Zval,
+        on_partial: ZendCallable,
+    ) -> PhpResult<StructuredResponse> {
+        let mut reported: Option<Value> = None;
+        let streamed = self.stream_json(messages, |_, content| {
+            if let Some(partial) = crate::json_repair::parse_partial(content) {
+                if reported.as_ref() != Some(&partial) {
+                    on_partial.try_call(vec![&json_value_to_php(&partial)?])?;
+                    reported = Some(partial);
+                }
+            }
+            Ok(())
+        })?;
+        let response = match streamed {
+            Some(response) => response,
+            None => self.complete(messages)?,
+        };
+        if reported.as_ref() != Some(&response.structured) {
+            on_partial.try_call(vec![&json_value_to_php(&response.structured)?])?;
+        }
+        Ok(response)
+    }
+
+    /// Apply the schema to many inputs concurrently, at most `concurrency`
+    /// at a time. Each input is a conversation or a string sent as a user
+    /// message. Returns an array with the input keys, holding a
+    /// StructuredResponse or the exception for each input that failed.
+    pub fn complete_many(&self, inputs: &PhpArray, concurrency: Option<i64>) -> PhpResult<Zval> {
+        let rt = runtime()?;
+        let limit = Arc::new(Semaphore::new(concurrency.unwrap_or(4).max(1) as usize));
+
+        // Invalid inputs throw here, before any request is sent
+        let mut prepared = Vec::new();
+        for (key, input) in inputs.iter() {
+            let messages_vec = match input.string() {
+                Some(text) => vec![MessageBuilder::user(&text).build().map_err(|e| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                        "Failed to build message: {e}"
+                    ))
+                })?],
+                None => php_to_messages(input)?,
+            };
+            prepared.push((key, self.prepare(messages_vec)?));
+        }
+
+        let mut tasks = Vec::new();
+        for (key, messages_vec) in prepared {
+            let handle = self.spawn_request(rt, messages_vec.clone(), Some(limit.clone()))?;
+            tasks.push((key, messages_vec, handle));
+        }
+
+        let mut results = PhpArray::new();
+        for (key, messages_vec, handle) in tasks {
+            let result = match rt.block_on(handle) {
+                Ok(result) => result,
+                Err(e) => Err(anyhow::anyhow!("Completion task failed: {e}")),
+            };
+            let value =
+                match self.finish_repairing(rt, messages_vec, result, Some(limit.clone()))? {
+                    Ok(response) => response.into_zval(false)?,
+                    Err(e) => crate::error::exception_object(&e)?,
+                };
+            results.insert(key, value)?;
+        }
+        Ok(results.into_zval(false)?)
     }
 
     /// Set JSON schema
-    pub fn with_schema(
+    pub fn with_schema<'a>(
+        self_: &'a mut ZendClassObject<StructuredBuilder>,
+        schema: &Zval,
+    ) -> PhpResult<&'a mut ZendClassObject<StructuredBuilder>> {
+        self_.schema = Some(crate::schema::schema_json(schema)?);
+        Ok(self_)
+    }
+
+    /// System prompt sent ahead of the messages, merged with a leading system
+    /// message if they have one. An empty string removes it.
+    pub fn with_system_prompt(
         self_: &mut ZendClassObject<StructuredBuilder>,
-        schema: String,
+        prompt: String,
     ) -> &mut ZendClassObject<StructuredBuilder> {
-        self_.schema = Some(schema);
+        self_.system_prompt = (!prompt.is_empty()).then_some(prompt);
         self_
     }
 
@@ -135,6 +681,117 @@ impl StructuredBuilder {
         self_
     }
 
+    /// Ask the provider to enforce the schema exactly (OpenAI's strict
+    /// structured outputs). The schema is adjusted to what strict mode
+    /// accepts: objects allow no additional properties and list every
+    /// property as required, with optional ones made nullable, so they come
+    /// back as null rather than missing.
+    pub fn with_strict(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.strict = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// Name what the schema describes, e.g. 'invoice': letters, digits,
+    /// underscores and dashes, at most 64. Sent as the schema's title, which
+    /// direct requests also use as the response format's name; octolib fills
+    /// that field itself.
+    pub fn with_schema_name(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        name: String,
+    ) -> PhpResult<&mut ZendClassObject<StructuredBuilder>> {
+        if !crate::stream::is_schema_name(&name) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Invalid schema name '{name}': use 1 to 64 letters, digits, underscores or dashes"
+            )));
+        }
+        self_.schema_name = Some(name);
+        Ok(self_)
+    }
+
+    /// When a reply is not valid JSON or does not match the schema, send the
+    /// errors back to the model and ask again, up to `maxRetries` times
+    /// before throwing LLMStructuredOutputException. Applies to complete()
+    /// and completeMany(); 0 (default) throws on the first invalid reply.
+    pub fn with_auto_repair(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        max_retries: i64,
+    ) -> PhpResult<&mut ZendClassObject<StructuredBuilder>> {
+        if max_retries < 0 {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Number of repair retries must not be negative".to_string(),
+            ));
+        }
+        self_.auto_repair = max_retries.min(u32::MAX as i64) as u32;
+        Ok(self_)
+    }
+
+    /// Repair almost-valid JSON (code fences, trailing commas, unquoted
+    /// keys, single quotes, unclosed brackets) instead of rejecting the reply
+    pub fn with_lenient_parsing(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.lenient = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// What to do when the model has no native JSON mode: 'prompt' (default)
+    /// asks for JSON in the system prompt and parses and validates the reply
+    /// locally, 'error' throws LLMStructuredOutputException
+    pub fn set_fallback_strategy(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        strategy: String,
+    ) -> PhpResult<&mut ZendClassObject<StructuredBuilder>> {
+        self_.fallback = FallbackStrategy::parse(&strategy)?;
+        Ok(self_)
+    }
+
+    /// Cache responses in process memory for `ttl` seconds, keyed on the
+    /// model, provider account, schema, parameters and messages. Pass 0 to
+    /// disable.
+    pub fn with_cache(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        ttl: i64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.cache_ttl = (ttl > 0).then(|| Duration::from_secs(ttl as u64));
+        self_
+    }
+
+    /// Limit each attempt of a request to this many seconds (0 for no limit)
+    pub fn set_timeout(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.settings.config.set_timeout(seconds);
+        self_
+    }
+
+    /// Limit a request to this many seconds over all of its retry attempts
+    /// (0 for no limit)
+    pub fn set_deadline(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.settings.retry.set_deadline(seconds);
+        self_
+    }
+
+    /// Limit connection setup to this many seconds (0 for no limit)
+    pub fn set_connect_timeout(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.settings.config.set_connect_timeout(seconds);
+        self_
+    }
+
     /// Set temperature
     pub fn set_temperature(
         self_: &mut ZendClassObject<StructuredBuilder>,
@@ -150,29 +807,112 @@ impl StructuredBuilder {
         max_tokens: i64,
     ) -> &mut ZendClassObject<StructuredBuilder> {
         self_.max_tokens = max_tokens as u32;
+        self_.settings.max_tokens_auto = None;
+        self_
+    }
+
+    /// Set top_k
+    pub fn set_top_k(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        top_k: i64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.top_k = top_k as u32;
+        self_
+    }
+
+    /// Set frequency penalty
+    pub fn set_frequency_penalty(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        penalty: f64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.penalties.frequency = penalty as f32;
+        self_
+    }
+
+    /// Set presence penalty
+    pub fn set_presence_penalty(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        penalty: f64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.penalties.presence = penalty as f32;
+        self_
+    }
+
+    /// Stop generating at any of these sequences (a string or an array of
+    /// strings; an empty array clears them)
+    pub fn set_stop<'a>(
+        self_: &'a mut ZendClassObject<StructuredBuilder>,
+        stop: &Zval,
+    ) -> PhpResult<&'a mut ZendClassObject<StructuredBuilder>> {
+        self_.stop = parse_stop(stop)?;
+        Ok(self_)
+    }
+
+    /// Sample with a fixed seed, so repeated requests with the same input
+    /// return the same output where the provider supports it
+    pub fn set_seed(
+        self_: &mut ZendClassObject<StructuredBuilder>,
+        seed: i64,
+    ) -> &mut ZendClassObject<StructuredBuilder> {
+        self_.seed = Some(seed);
         self_
     }
 }
 
 /// Structured response with JSON output
 #[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[php(implements(ce = crate::completion_result::interface, stub = "\\CompletionResultInterface"))]
+#[derive(Clone)]
 pub struct StructuredResponse {
     content: String,
-    structured: Zval,
+    structured: serde_json::Value,
     usage: Usage,
     model: String,
+    finish_reason: String,
+    response_id: Option<String>,
+    reasoning: Option<String>,
+    cached: bool,
+    fallback: bool,
+    /// Schema with PHP enum markers, for getStructured()
+    enum_schema: Option<Arc<Value>>,
 }
 
 // Internal constructor - not exposed to PHP
 impl StructuredResponse {
-    pub(crate) fn new(content: String, structured: Zval, usage: TokenUsage, model: String) -> Self {
+    pub(crate) fn new(
+        content: String,
+        structured: serde_json::Value,
+        usage: TokenUsage,
+        model: String,
+    ) -> Self {
         Self {
             content,
             structured,
             usage: Usage::from_octo(usage),
             model,
+            finish_reason: "stop".to_string(),
+            response_id: None,
+            reasoning: None,
+            cached: false,
+            fallback: false,
+            enum_schema: None,
         }
     }
+
+    fn into_cached(mut self) -> Self {
+        self.cached = true;
+        self
+    }
+
+    pub(crate) fn with_reasoning(mut self, reasoning: Option<String>) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    pub(crate) fn structured_value(&self) -> serde_json::Value {
+        self.structured.clone()
+    }
 }
 
 #[php_impl]
@@ -181,10 +921,35 @@ impl StructuredResponse {
         self.content.clone()
     }
 
-    pub fn get_structured(&self) -> Zval {
-        // Simple approach: return empty Zval for now
-        // TODO: Fix Zval cloning issue in ext-php-rs 0.15.x
-        Zval::new()
+    /// Structured output as a PHP array (rebuilt on every call), with
+    /// properties declared with Schema::enumFromClass() as enum cases
+    pub fn get_structured(&self) -> PhpResult<Zval> {
+        match &self.enum_schema {
+            Some(schema) => crate::schema::hydrate(&self.structured, schema, false),
+            None => json_value_to_php(&self.structured),
+        }
+    }
+
+    /// Instance of `className` created from the structured output, with
+    /// nested objects and enums typed as the class declares them (see
+    /// Schema::fromClass())
+    #[php(name = "into")]
+    pub fn to_instance(&self, class_name: String) -> PhpResult<Zval> {
+        if !self.structured.is_object() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMStructuredOutputException,
+            >(
+                "Structured output is not an object".to_string()
+            ));
+        }
+        let schema = crate::reflection::class_schema(&class_name)?;
+        crate::schema::hydrate(&self.structured, &schema, true)
+    }
+
+    /// Structured output as a raw JSON string
+    pub fn get_structured_json(&self) -> PhpResult<String> {
+        serde_json::to_string(&self.structured)
+            .map_err(|e| PhpException::default(format!("Failed to serialize to JSON: {e}")))
     }
 
     pub fn get_usage(&self) -> Usage {
@@ -195,84 +960,63 @@ impl StructuredResponse {
         self.model.clone()
     }
 
+    pub fn get_finish_reason(&self) -> String {
+        self.finish_reason.clone()
+    }
+
+    /// Provider's id of the response; null when the provider sent none or
+    /// the response was streamed
+    pub fn get_response_id(&self) -> Option<String> {
+        self.response_id.clone()
+    }
+
+    /// Reasoning ("thinking") text, for models and providers that return it
+    pub fn get_reasoning(&self) -> Option<String> {
+        self.reasoning.clone()
+    }
+
+    /// Tokens spent on reasoning (shortcut for getUsage()->getReasoningTokens())
+    pub fn get_reasoning_tokens(&self) -> i64 {
+        self.usage.get_reasoning_tokens()
+    }
+
+    /// Whether this response was served from the cache. Usage is that of the
+    /// original request; a cached response costs nothing.
+    pub fn is_cached(&self) -> bool {
+        self.cached
+    }
+
+    /// Whether the JSON came from prompting rather than the provider's native
+    /// JSON mode
+    pub fn is_fallback(&self) -> bool {
+        self.fallback
+    }
+
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("content", self.content.clone())?;
-
-        // Recreate structured data since we can't clone Zval
-        if let Some(json_str) = self.structured.string() {
-            let mut nested = PhpArray::new();
-            let _ = nested.insert("json", json_str);
-            arr.insert("structured", nested)?;
-        } else if let Some(inner_arr) = self.structured.array() {
-            let mut nested = PhpArray::new();
-            for (k, v) in inner_arr.iter() {
-                // Extract values and re-insert them
-                if let Some(s) = v.string() {
-                    let _ = match k {
-                        ext_php_rs::types::ArrayKey::String(key) => nested.insert(key, s),
-                        ext_php_rs::types::ArrayKey::Long(idx) => nested.insert(idx, s),
-                        ext_php_rs::types::ArrayKey::Str(_) => nested.insert(k, s),
-                    };
-                } else if let Some(i) = v.long() {
-                    let _ = match k {
-                        ext_php_rs::types::ArrayKey::String(key) => nested.insert(key, i),
-                        ext_php_rs::types::ArrayKey::Long(idx) => nested.insert(idx, i),
-                        ext_php_rs::types::ArrayKey::Str(_) => nested.insert(k, i),
-                    };
-                } else if let Some(b) = v.bool() {
-                    let _ = match k {
-                        ext_php_rs::types::ArrayKey::String(key) => nested.insert(key, b),
-                        ext_php_rs::types::ArrayKey::Long(idx) => nested.insert(idx, b),
-                        ext_php_rs::types::ArrayKey::Str(_) => nested.insert(k, b),
-                    };
-                }
-            }
-            arr.insert("structured", nested)?;
-        }
-
-        arr.insert("usage", self.usage.clone())?;
+        arr.insert("structured", json_value_to_php(&self.structured)?)?;
+        arr.insert("usage", self.usage.to_array()?)?;
         arr.insert("model", self.model.clone())?;
+        arr.insert("finish_reason", self.finish_reason.clone())?;
+        if let Some(ref response_id) = self.response_id {
+            arr.insert("response_id", &**response_id)?;
+        }
+        if let Some(ref reasoning) = self.reasoning {
+            arr.insert("reasoning", &**reasoning)?;
+        }
         Ok(arr.into_zval(false)?)
     }
 
     pub fn to_json(&self) -> PhpResult<String> {
-        // Zval doesn't implement Serialize, so extract value manually
-        let structured_value = if let Some(json_str) = self.structured.string() {
-            serde_json::Value::String(json_str.to_string())
-        } else if let Some(arr) = self.structured.array() {
-            let mut map = serde_json::Map::new();
-            for (k, v) in arr.iter() {
-                let key = match k {
-                    ext_php_rs::types::ArrayKey::Str(s) => s.to_string(),
-                    ext_php_rs::types::ArrayKey::String(s) => s,
-                    ext_php_rs::types::ArrayKey::Long(i) => i.to_string(),
-                };
-                let value = if let Some(s) = v.string() {
-                    serde_json::Value::String(s.to_string())
-                } else if let Some(i) = v.long() {
-                    serde_json::Value::Number(i.into())
-                } else if let Some(b) = v.bool() {
-                    serde_json::Value::Bool(b)
-                } else {
-                    serde_json::Value::Null
-                };
-                map.insert(key, value);
-            }
-            serde_json::Value::Object(map)
-        } else {
-            serde_json::Value::Null
-        };
-
         match serde_json::to_string(&serde_json::json!({
             "content": self.content,
-            "structured": structured_value,
-            "usage": {
-                "prompt_tokens": self.usage.get_prompt_tokens(),
-                "output_tokens": self.usage.get_output_tokens(),
-                "total_tokens": self.usage.get_total_tokens(),
-            },
+            "structured": self.structured,
+            "usage": self.usage.json_value(),
             "model": self.model,
+            "finish_reason": self.finish_reason,
+            "response_id": self.response_id,
+            "reasoning": self.reasoning,
         })) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
@@ -280,4 +1024,9 @@ impl StructuredResponse {
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
 }
@@ -0,0 +1,130 @@
+use ext_php_rs::prelude::*;
+use octolib::llm::Message as OctoMessage;
+use std::collections::HashMap;
+
+/// Provider key used for templates that apply to every provider
+const ANY_PROVIDER: &str = "*";
+
+/// Message kinds whose rendering can be overridden
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum TemplateKind {
+    System,
+    ToolResult,
+}
+
+impl TemplateKind {
+    pub(crate) fn parse(kind: &str) -> PhpResult<Self> {
+        match kind {
+            "system" => Ok(Self::System),
+            "tool_result" => Ok(Self::ToolResult),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown template kind '{other}', expected 'system' or 'tool_result'"
+            ))),
+        }
+    }
+
+    fn role(self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::ToolResult => "tool",
+        }
+    }
+}
+
+/// User overrides for how system prompts and tool results are rendered.
+///
+/// Templates are plain strings with `{content}`, `{tool_call_id}`, `{provider}`
+/// and `{model}` placeholders. They are applied to the message content right
+/// before it is handed to octolib, so OpenAI and Anthropic tool loops can be
+/// tuned independently.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PromptTemplates {
+    templates: HashMap<(TemplateKind, String), String>,
+}
+
+impl PromptTemplates {
+    /// Set a template for one provider, or for all providers when None
+    pub(crate) fn set(&mut self, kind: TemplateKind, provider: Option<&str>, template: String) {
+        let provider = provider.map_or(ANY_PROVIDER.to_string(), |p| p.to_lowercase());
+        self.templates.insert((kind, provider), template);
+    }
+
+    /// Render matching messages in place for the given "provider:model" string
+    pub(crate) fn apply(&self, model: &str, messages: &mut [OctoMessage]) {
+        if self.templates.is_empty() {
+            return;
+        }
+
+        let (provider, model_name) = model.split_once(':').unwrap_or(("", model));
+        let provider = provider.trim().to_lowercase();
+
+        for kind in [TemplateKind::System, TemplateKind::ToolResult] {
+            let Some(template) = self
+                .templates
+                .get(&(kind, provider.clone()))
+                .or_else(|| self.templates.get(&(kind, ANY_PROVIDER.to_string())))
+            else {
+                continue;
+            };
+
+            for msg in messages.iter_mut().filter(|m| m.role == kind.role()) {
+                msg.content = render(
+                    template,
+                    &msg.content,
+                    msg.tool_call_id.as_deref().unwrap_or(""),
+                    &provider,
+                    model_name,
+                );
+            }
+        }
+    }
+}
+
+fn render(
+    template: &str,
+    content: &str,
+    tool_call_id: &str,
+    provider: &str,
+    model: &str,
+) -> String {
+    template
+        .replace("{tool_call_id}", tool_call_id)
+        .replace("{provider}", provider)
+        .replace("{model}", model)
+        .replace("{content}", content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+
+    #[test]
+    fn test_render_placeholders() {
+        assert_eq!(
+            render(
+                "<result id=\"{tool_call_id}\">{content}</result>",
+                "42",
+                "call_1",
+                "anthropic",
+                "claude"
+            ),
+            "<result id=\"call_1\">42</result>"
+        );
+    }
+
+    #[test]
+    fn test_render_does_not_expand_content_placeholders() {
+        assert_eq!(
+            render(
+                "[{provider}] {content}",
+                "literal {model}",
+                "",
+                "openai",
+                "gpt-4o"
+            ),
+            "[openai] literal {model}"
+        );
+    }
+}
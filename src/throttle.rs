@@ -0,0 +1,460 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use octolib::llm::TokenUsage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::RATE_LIMIT_DIR_INI;
+
+/// Seconds over which a bucket refills from empty to full
+const REFILL_WINDOW_SECS: f64 = 60.0;
+
+/// Per-tenant buckets, shared by every request served by this process
+static BUCKETS: LazyLock<Mutex<HashMap<String, TenantBucket>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A single token bucket. The balance may go negative: usage is only known
+/// after a call returns, so an expensive call is charged in full and the
+/// tenant is blocked until the bucket refills past zero.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+}
+
+impl Bucket {
+    fn new(capacity: f64) -> Self {
+        Self {
+            capacity,
+            available: capacity,
+        }
+    }
+
+    fn refill(&mut self, elapsed_secs: f64) {
+        let rate = self.capacity / REFILL_WINDOW_SECS;
+        self.available = (self.available + rate * elapsed_secs).min(self.capacity);
+    }
+
+    /// Seconds until at least one unit is available
+    fn wait_secs(&self) -> f64 {
+        let rate = self.capacity / REFILL_WINDOW_SECS;
+        ((1.0 - self.available) / rate).max(0.0)
+    }
+}
+
+/// Token and cost budgets of one tenant, each refilling per minute
+#[derive(Debug, Clone)]
+struct TenantBucket {
+    tokens: Option<Bucket>,
+    cost: Option<Bucket>,
+    last_refill: Instant,
+}
+
+impl TenantBucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.last_refill = now;
+        for bucket in [&mut self.tokens, &mut self.cost].into_iter().flatten() {
+            bucket.refill(elapsed);
+        }
+    }
+
+    fn exhausted(&self) -> Option<&'static str> {
+        if self.tokens.as_ref().is_some_and(|b| b.available <= 0.0) {
+            return Some("token");
+        }
+        if self.cost.as_ref().is_some_and(|b| b.available <= 0.0) {
+            return Some("cost");
+        }
+        None
+    }
+
+    fn charge(&mut self, tokens: f64, cost: f64) {
+        if let Some(bucket) = self.tokens.as_mut() {
+            bucket.available -= tokens;
+        }
+        if let Some(bucket) = self.cost.as_mut() {
+            bucket.available -= cost;
+        }
+    }
+}
+
+fn with_buckets<R>(f: impl FnOnce(&mut HashMap<String, TenantBucket>) -> R) -> R {
+    let mut buckets = BUCKETS.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut buckets)
+}
+
+/// Set (or replace) a tenant's per-minute budgets. Passing no limits removes it.
+pub(crate) fn set_budget(key: &str, tokens_per_minute: Option<u64>, cost_per_minute: Option<f64>) {
+    with_buckets(|buckets| {
+        if tokens_per_minute.is_none() && cost_per_minute.is_none() {
+            buckets.remove(key);
+            return;
+        }
+        buckets.insert(
+            key.to_string(),
+            TenantBucket {
+                tokens: tokens_per_minute.map(|t| Bucket::new(t as f64)),
+                cost: cost_per_minute.map(Bucket::new),
+                last_refill: Instant::now(),
+            },
+        );
+    });
+}
+
+/// Fail if the tenant has used up its budget. Tenants without a budget pass.
+pub(crate) fn check(key: Option<&str>) -> PhpResult<()> {
+    let Some(key) = key else {
+        return Ok(());
+    };
+
+    let exhausted = with_buckets(|buckets| {
+        let bucket = buckets.get_mut(key)?;
+        bucket.refill(Instant::now());
+        bucket.exhausted()
+    });
+
+    match exhausted {
+        Some(kind) => Err(PhpException::from_class::<
+            crate::error::LLMBudgetExceededException,
+        >(format!(
+            "Tenant '{key}' exceeded its {kind} budget"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Charge a finished call's usage against the tenant's budget
+pub(crate) fn record(key: Option<&str>, usage: &TokenUsage) {
+    let Some(key) = key else {
+        return;
+    };
+
+    with_buckets(|buckets| {
+        if let Some(bucket) = buckets.get_mut(key) {
+            bucket.refill(Instant::now());
+            bucket.charge(usage.total_tokens as f64, usage.cost.unwrap_or(0.0));
+        }
+    });
+}
+
+/// Client-side limit on requests and tokens per minute, set with
+/// LLM::withRateLimit(). Every process of the same user on the host that
+/// calls the same provider with the same API key shares it, so PHP-FPM
+/// workers count against one quota.
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimit {
+    key: String,
+    requests_per_minute: Option<u64>,
+    tokens_per_minute: Option<u64>,
+    /// Longest time to wait for capacity; None waits as long as needed
+    max_wait: Option<Duration>,
+    /// Directory of the shared state files; None when it is unusable, so the
+    /// limit only holds within this process
+    dir: Option<PathBuf>,
+}
+
+/// Request and token buckets of one provider key, refilling per minute.
+/// Timestamps are wall-clock so the state means the same in every process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Limiter {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+    last_refill: SystemTime,
+}
+
+impl Limiter {
+    fn new(
+        requests_per_minute: Option<u64>,
+        tokens_per_minute: Option<u64>,
+        now: SystemTime,
+    ) -> Self {
+        Self {
+            requests: requests_per_minute.map(|r| Bucket::new(r as f64)),
+            tokens: tokens_per_minute.map(|t| Bucket::new(t as f64)),
+            last_refill: now,
+        }
+    }
+
+    fn capacities(&self) -> (Option<f64>, Option<f64>) {
+        (
+            self.requests.as_ref().map(|b| b.capacity),
+            self.tokens.as_ref().map(|b| b.capacity),
+        )
+    }
+
+    fn refill(&mut self, now: SystemTime) {
+        // A clock stepped backwards refills nothing
+        let elapsed = now
+            .duration_since(self.last_refill)
+            .unwrap_or_default()
+            .as_secs_f64();
+        self.last_refill = now;
+        for bucket in [&mut self.requests, &mut self.tokens].into_iter().flatten() {
+            bucket.refill(elapsed);
+        }
+    }
+
+    /// Take a request if one is available and the token balance is
+    /// positive; otherwise return the time until that is the case
+    fn try_acquire(&mut self) -> Option<Duration> {
+        let wait = [&self.requests, &self.tokens]
+            .into_iter()
+            .flatten()
+            .map(Bucket::wait_secs)
+            .fold(0.0, f64::max);
+        if wait > 0.0 {
+            return Some(Duration::from_secs_f64(wait));
+        }
+        if let Some(bucket) = self.requests.as_mut() {
+            bucket.available -= 1.0;
+        }
+        None
+    }
+}
+
+/// Rate limiters per provider key, used when the shared state file cannot
+/// be opened
+static LIMITERS: LazyLock<Mutex<HashMap<String, Limiter>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Open and lock the state file of limiter `key`. The lock is held until the
+/// file is dropped, so one process at a time reads and updates the state.
+fn lock_state_file(dir: &Path, key: &str) -> std::io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(key.replace(':', "-")))?;
+    file.lock()?;
+    Ok(file)
+}
+
+/// Run `f` on the state of limiter `key`, None when it has none yet. The
+/// state lives in a locked file in `dir`, shared by the user's processes on
+/// the host, or in this process when there is no directory or the file is
+/// unavailable.
+fn with_limiter<R>(dir: Option<&Path>, key: &str, f: impl FnOnce(&mut Option<Limiter>) -> R) -> R {
+    let Some(Ok(mut file)) = dir.map(|dir| lock_state_file(dir, key)) else {
+        let mut limiters = LIMITERS.lock().unwrap_or_else(|e| e.into_inner());
+        let mut limiter = limiters.remove(key);
+        let result = f(&mut limiter);
+        if let Some(limiter) = limiter {
+            limiters.insert(key.to_string(), limiter);
+        }
+        return result;
+    };
+
+    // An unreadable state starts over with full buckets
+    let mut content = String::new();
+    let mut limiter = file
+        .read_to_string(&mut content)
+        .ok()
+        .and_then(|_| serde_json::from_str(&content).ok());
+    let result = f(&mut limiter);
+    if let Some(limiter) = &limiter {
+        let written = serde_json::to_vec(limiter)
+            .map_err(std::io::Error::other)
+            .and_then(|json| {
+                file.set_len(0)?;
+                file.rewind()?;
+                file.write_all(&json)
+            });
+        if let Err(e) = written {
+            crate::logger::warn(&format!("Could not save rate limit state: {e}"));
+        }
+    }
+    result
+}
+
+/// Limiter key of a provider and API key; the key itself is not kept
+pub(crate) fn limiter_key(env_prefix: &str, api_key: &str) -> String {
+    let digest = Sha256::digest(api_key.as_bytes());
+    let hash: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+    format!("{env_prefix}:{hash}")
+}
+
+impl RateLimit {
+    /// Limit for `key`. An existing state with the same limits is kept, so
+    /// instances created per PHP request and other workers share it.
+    pub(crate) fn new(
+        key: String,
+        requests_per_minute: Option<u64>,
+        tokens_per_minute: Option<u64>,
+        max_wait: Option<Duration>,
+    ) -> Self {
+        let dir = crate::config::private_dir(RATE_LIMIT_DIR_INI, "llm-php-ext-ratelimit")
+            .inspect_err(|e| {
+                crate::logger::warn(&format!(
+                    "Rate limit state is kept per process, its directory is unusable: {e}"
+                ))
+            })
+            .ok();
+        Self {
+            key,
+            requests_per_minute,
+            tokens_per_minute,
+            max_wait,
+            dir,
+        }
+    }
+
+    /// Refilled limiter in `slot`, replaced when missing or when its limits
+    /// differ from this one's
+    fn current<'a>(&self, slot: &'a mut Option<Limiter>) -> &'a mut Limiter {
+        let now = SystemTime::now();
+        let fresh = Limiter::new(self.requests_per_minute, self.tokens_per_minute, now);
+        if slot
+            .as_ref()
+            .is_none_or(|current| current.capacities() != fresh.capacities())
+        {
+            *slot = Some(fresh);
+        }
+        let limiter = slot.as_mut().expect("limiter was just set");
+        limiter.refill(now);
+        limiter
+    }
+
+    /// Wait until a request may be sent. Fails with an LLMConnectionException
+    /// (code LLMException::ERR_RATE_LIMIT) when that takes longer than the
+    /// maximum wait.
+    pub(crate) fn acquire(&self) -> PhpResult<()> {
+        let mut waited = Duration::ZERO;
+        loop {
+            let wait = with_limiter(self.dir.as_deref(), &self.key, |slot| {
+                self.current(slot).try_acquire()
+            });
+            let Some(wait) = wait else {
+                return Ok(());
+            };
+            if self.max_wait.is_some_and(|max| waited + wait > max) {
+                return Err(crate::error::rate_limited(
+                    format!(
+                        "Client-side rate limit reached, next request possible in {:.1}s",
+                        wait.as_secs_f64()
+                    ),
+                    wait,
+                ));
+            }
+            std::thread::sleep(wait);
+            waited += wait;
+        }
+    }
+
+    /// Charge a finished call's tokens; the balance may go negative
+    pub(crate) fn record(&self, usage: &TokenUsage) {
+        with_limiter(self.dir.as_deref(), &self.key, |slot| {
+            if let Some(bucket) = self.current(slot).tokens.as_mut() {
+                bucket.available -= usage.total_tokens as f64;
+            }
+        });
+    }
+}
+
+/// Remaining budget of a tenant as ['tokens' => ?float, 'cost' => ?float],
+/// or null when no budget is set
+pub(crate) fn remaining(key: &str) -> PhpResult<Option<Zval>> {
+    let Some(bucket) = with_buckets(|buckets| {
+        let bucket = buckets.get_mut(key)?;
+        bucket.refill(Instant::now());
+        Some(bucket.clone())
+    }) else {
+        return Ok(None);
+    };
+
+    let mut arr = PhpArray::new();
+    arr.insert("tokens", bucket.tokens.map(|b| b.available))?;
+    arr.insert("cost", bucket.cost.map(|b| b.available))?;
+    Ok(Some(arr.into_zval(false)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn tenant(tokens: Option<f64>, cost: Option<f64>, now: Instant) -> TenantBucket {
+        TenantBucket {
+            tokens: tokens.map(Bucket::new),
+            cost: cost.map(Bucket::new),
+            last_refill: now,
+        }
+    }
+
+    #[test]
+    fn test_overdraw_blocks_until_refilled() {
+        let start = Instant::now();
+        let mut bucket = tenant(Some(600.0), None, start);
+
+        bucket.charge(900.0, 0.0);
+        assert_eq!(bucket.exhausted(), Some("token"));
+
+        // 600 tokens/minute refills 10 per second; 30s brings -300 back to 0
+        bucket.refill(start + Duration::from_secs(30));
+        assert_eq!(bucket.exhausted(), Some("token"));
+
+        bucket.refill(start + Duration::from_secs(31));
+        assert_eq!(bucket.exhausted(), None);
+    }
+
+    #[test]
+    fn test_refill_caps_at_capacity() {
+        let start = Instant::now();
+        let mut bucket = tenant(None, Some(1.0), start);
+
+        bucket.charge(0.0, 0.25);
+        bucket.refill(start + Duration::from_secs(3600));
+        assert_eq!(bucket.cost.unwrap().available, 1.0);
+    }
+
+    #[test]
+    fn test_limiter_waits_for_requests_and_tokens() {
+        let start = SystemTime::now();
+        let mut limiter = Limiter::new(Some(2), Some(600), start);
+        assert_eq!(limiter.try_acquire(), None);
+        assert_eq!(limiter.try_acquire(), None);
+        // 2 requests/minute refill one every 30s
+        assert_eq!(limiter.try_acquire(), Some(Duration::from_secs(30)));
+
+        limiter.refill(start + Duration::from_secs(30));
+        limiter.tokens.as_mut().unwrap().available = -99.0;
+        // 600 tokens/minute refill 10 per second; 10s brings -99 back to 1
+        assert_eq!(limiter.try_acquire(), Some(Duration::from_secs(10)));
+        limiter.refill(start + Duration::from_secs(40));
+        assert_eq!(limiter.try_acquire(), None);
+    }
+
+    #[test]
+    fn test_limiter_state_round_trips() {
+        let start = SystemTime::now();
+        let mut limiter = Limiter::new(Some(2), Some(600), start);
+        assert_eq!(limiter.try_acquire(), None);
+        limiter.tokens.as_mut().unwrap().available = -50.0;
+
+        let json = serde_json::to_string(&limiter).unwrap();
+        let mut restored: Limiter = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.capacities(), limiter.capacities());
+        assert_eq!(restored.requests.as_ref().unwrap().available, 1.0);
+        restored.refill(start + Duration::from_secs(5));
+        assert_eq!(restored.tokens.unwrap().available, 0.0);
+    }
+
+    #[test]
+    fn test_limiter_key_hides_api_key() {
+        let key = limiter_key("OPENAI", "sk-secret");
+        assert!(key.starts_with("OPENAI:"));
+        assert!(!key.contains("secret"));
+        assert_eq!(key, limiter_key("OPENAI", "sk-secret"));
+        assert_ne!(key, limiter_key("OPENAI", "sk-other"));
+    }
+}
@@ -0,0 +1,116 @@
+use octolib::llm::Message as OctoMessage;
+
+/// System prompt for title generation
+pub(crate) const PROMPT: &str = "Write a short title of at most six words for the \
+conversation below. Use the language of the conversation. Reply with the title only, \
+without quotes or trailing punctuation.";
+
+/// Completion budget for a title
+pub(crate) const MAX_TOKENS: u32 = 24;
+
+const MAX_TITLE_CHARS: usize = 80;
+const MAX_MESSAGE_CHARS: usize = 600;
+const MAX_TRANSCRIPT_CHARS: usize = 4000;
+
+/// Cut `text` to at most `max_chars`, preferably at a word boundary
+pub(crate) fn truncate(text: &str, max_chars: usize) -> String {
+    let Some((idx, _)) = text.char_indices().nth(max_chars) else {
+        return text.to_string();
+    };
+    let cut = &text[..idx];
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(space) if space > idx / 2 => &cut[..space],
+        _ => cut,
+    };
+    format!("{}…", cut.trim_end())
+}
+
+/// Compact transcript to title: the user and assistant text from the start
+/// of the conversation, where the topic is set, with each message truncated
+/// and the whole kept within a fixed budget
+pub(crate) fn transcript(messages: &[OctoMessage]) -> String {
+    let mut out = String::new();
+    for msg in messages {
+        let label = match msg.role.as_str() {
+            "user" => "User",
+            "assistant" => "Assistant",
+            _ => continue,
+        };
+        let content = msg.content.trim();
+        if content.is_empty() {
+            continue;
+        }
+
+        let entry = format!("{label}: {}\n\n", truncate(content, MAX_MESSAGE_CHARS));
+        if !out.is_empty() && out.len() + entry.len() > MAX_TRANSCRIPT_CHARS {
+            break;
+        }
+        out.push_str(&entry);
+    }
+    out.trim_end().to_string()
+}
+
+/// Title from the model's reply, without the labels, quotes and trailing
+/// punctuation models tend to add despite the prompt
+pub(crate) fn clean(reply: &str) -> String {
+    let line = reply
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty())
+        .unwrap_or_default();
+    let line = match line.get(..6) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("title:") => line[6..].trim_start(),
+        _ => line,
+    };
+    let decoration = |c: char| matches!(c, '"' | '\'' | '*' | '#' | '`' | '“' | '”');
+    let title = line
+        .trim_start_matches(|c: char| decoration(c) || c.is_whitespace())
+        .trim_end_matches(|c: char| {
+            decoration(c) || c.is_whitespace() || matches!(c, '.' | '!' | '。')
+        });
+    truncate(title, MAX_TITLE_CHARS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use octolib::llm::MessageBuilder;
+
+    #[test]
+    fn test_clean_strips_decoration() {
+        assert_eq!(
+            clean("Title: \"Rust Borrow Checker Help\"."),
+            "Rust Borrow Checker Help"
+        );
+        assert_eq!(
+            clean("\n**Trip to Lisbon**\nSome explanation"),
+            "Trip to Lisbon"
+        );
+        assert_eq!(clean("Plain title"), "Plain title");
+    }
+
+    #[test]
+    fn test_truncate_at_word_boundary() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("one two three four", 12), "one two…");
+    }
+
+    #[test]
+    fn test_transcript_keeps_opening_within_budget() {
+        let long = "word ".repeat(1000);
+        let mut messages = vec![
+            MessageBuilder::system("You are helpful").build().unwrap(),
+            MessageBuilder::user("How do I bake bread?")
+                .build()
+                .unwrap(),
+        ];
+        for _ in 0..20 {
+            messages.push(MessageBuilder::assistant(&long).build().unwrap());
+        }
+
+        let transcript = transcript(&messages);
+        assert!(transcript.starts_with("User: How do I bake bread?\n\nAssistant: word"));
+        assert!(!transcript.contains("You are helpful"));
+        assert!(transcript.len() <= MAX_TRANSCRIPT_CHARS);
+    }
+}
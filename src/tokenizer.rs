@@ -0,0 +1,168 @@
+use ext_php_rs::prelude::*;
+use tiktoken_rs::CoreBPE;
+
+/// BPE vocabularies bundled with the extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    O200k,
+    Cl100k,
+    P50k,
+    R50k,
+}
+
+impl Encoding {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "o200k_base" => Some(Self::O200k),
+            "cl100k_base" => Some(Self::Cl100k),
+            "p50k_base" => Some(Self::P50k),
+            "r50k_base" => Some(Self::R50k),
+            _ => None,
+        }
+    }
+
+    /// Encoding for an encoding name, or a "provider:model" or bare model
+    /// string. Models without a published vocabulary get o200k_base, which
+    /// approximates modern tokenizers well enough for budgeting.
+    fn for_model(model: &str) -> Self {
+        if let Some(encoding) = Self::from_name(model) {
+            return encoding;
+        }
+        let name = model
+            .split_once(':')
+            .map_or(model, |(_, m)| m)
+            .trim()
+            .to_lowercase();
+        let starts = |prefixes: &[&str]| prefixes.iter().any(|p| name.starts_with(p));
+        if starts(&[
+            "gpt-4o",
+            "gpt-4.1",
+            "gpt-4.5",
+            "gpt-5",
+            "chatgpt-4o",
+            "gpt-oss",
+            "o1",
+            "o3",
+            "o4",
+        ]) {
+            Self::O200k
+        } else if starts(&[
+            "gpt-4",
+            "gpt-3.5",
+            "text-embedding-3",
+            "text-embedding-ada-002",
+        ]) {
+            Self::Cl100k
+        } else if starts(&["text-davinci-002", "text-davinci-003", "code-"]) {
+            Self::P50k
+        } else if starts(&["davinci", "curie", "babbage", "ada", "text-davinci-001"]) {
+            Self::R50k
+        } else {
+            Self::O200k
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::O200k => "o200k_base",
+            Self::Cl100k => "cl100k_base",
+            Self::P50k => "p50k_base",
+            Self::R50k => "r50k_base",
+        }
+    }
+
+    fn bpe(self) -> &'static CoreBPE {
+        match self {
+            Self::O200k => tiktoken_rs::o200k_base_singleton(),
+            Self::Cl100k => tiktoken_rs::cl100k_base_singleton(),
+            Self::P50k => tiktoken_rs::p50k_base_singleton(),
+            Self::R50k => tiktoken_rs::r50k_base_singleton(),
+        }
+    }
+}
+
+/// Number of tokens in `text` for a model or encoding name
+pub(crate) fn count_tokens(text: &str, model: &str) -> usize {
+    Encoding::for_model(model).bpe().encode_ordinary(text).len()
+}
+
+/// Offline token counting with the BPE vocabularies of OpenAI models.
+/// `$model` is a model ("openai:gpt-4o", "gpt-3.5-turbo") or an encoding
+/// name ("o200k_base", "cl100k_base", "p50k_base", "r50k_base"); other
+/// providers' models are approximated with o200k_base.
+#[php_class]
+pub struct Tokenizer;
+
+#[php_impl]
+impl Tokenizer {
+    /// Number of tokens in `text`. Special tokens such as <|endoftext|> are
+    /// counted as plain text, as the APIs do for message content.
+    pub fn count(text: String, model: String) -> i64 {
+        count_tokens(&text, &model) as i64
+    }
+
+    /// Token ids of `text`
+    pub fn encode(text: String, model: String) -> Vec<i64> {
+        Encoding::for_model(&model)
+            .bpe()
+            .encode_ordinary(&text)
+            .into_iter()
+            .map(i64::from)
+            .collect()
+    }
+
+    /// Text of the token ids. Fails on ids outside the vocabulary and on
+    /// sequences that cut a character in half.
+    pub fn decode(tokens: Vec<i64>, model: String) -> PhpResult<String> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let tokens = tokens
+            .into_iter()
+            .map(|t| u32::try_from(t).map_err(|_| invalid(format!("Invalid token id {t}"))))
+            .collect::<PhpResult<Vec<u32>>>()?;
+        Encoding::for_model(&model)
+            .bpe()
+            .decode(tokens)
+            .map_err(|e| invalid(format!("Cannot decode tokens: {e}")))
+    }
+
+    /// Name of the encoding used for `model`
+    pub fn encoding_for(model: String) -> String {
+        Encoding::for_model(&model).name().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding_for_model() {
+        assert_eq!(Encoding::for_model("openai:gpt-4o-mini"), Encoding::O200k);
+        assert_eq!(Encoding::for_model("gpt-4-turbo"), Encoding::Cl100k);
+        assert_eq!(Encoding::for_model("openai:o3-mini"), Encoding::O200k);
+        assert_eq!(
+            Encoding::for_model("openai:text-embedding-3-small"),
+            Encoding::Cl100k
+        );
+        assert_eq!(Encoding::for_model("p50k_base"), Encoding::P50k);
+        assert_eq!(
+            Encoding::for_model("anthropic:claude-sonnet-4"),
+            Encoding::O200k
+        );
+    }
+
+    #[test]
+    fn test_encode_decode_round_trip() {
+        let model = "cl100k_base".to_string();
+        let tokens = Tokenizer::encode("hello world".to_string(), model.clone());
+        assert_eq!(tokens, vec![15339, 1917]);
+        assert_eq!(
+            Tokenizer::count("hello world".to_string(), model.clone()),
+            2
+        );
+        assert_eq!(
+            Tokenizer::decode(tokens, model).unwrap_or_default(),
+            "hello world"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendCallable, Zval};
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Callable behind a tool, with its parameter schema marked for hydrate()
+struct Bound {
+    callable: Zval,
+    schema: Value,
+}
+
+// Callables of Tool::fromCallable() and fromMethod(). Like registry
+// handlers, they belong to the thread and request that created them.
+thread_local! {
+    static BOUND: RefCell<HashMap<u64, Bound>> = RefCell::new(HashMap::new());
+}
+
+/// Drop the bound callables of the ending request
+pub(crate) fn reset() {
+    // Released after the borrow ends, as freeing them may drop more tools
+    let bound = BOUND.with(|b| std::mem::take(&mut *b.borrow_mut()));
+    drop(bound);
+}
+
+/// Handle to a bound callable, shared by the clones of its Tool; the
+/// callable is released with the last clone
+#[derive(Debug)]
+pub(crate) struct Binding(u64);
+
+impl Drop for Binding {
+    fn drop(&mut self) {
+        let removed = BOUND
+            .try_with(|b| b.try_borrow_mut().ok().and_then(|mut b| b.remove(&self.0)))
+            .ok()
+            .flatten();
+        drop(removed);
+    }
+}
+
+/// Bind a callable to a tool
+pub(crate) fn bind(callable: Zval, schema: Value) -> Arc<Binding> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    BOUND.with(|b| b.borrow_mut().insert(id, Bound { callable, schema }));
+    Arc::new(Binding(id))
+}
+
+/// Whether the callable can run here: not when it was bound on another
+/// thread or in an earlier request
+pub(crate) fn is_bound(binding: &Binding) -> bool {
+    BOUND.with(|b| b.borrow().contains_key(&binding.0))
+}
+
+/// Call the bound callable with the arguments of a tool call passed by
+/// name, enums and objects typed as its parameters declare. Arguments it
+/// does not declare are left out. None when the callable is not bound here.
+pub(crate) fn invoke(binding: &Binding, arguments: &Value) -> Option<PhpResult<Zval>> {
+    let (callable, schema) = BOUND.with(|b| {
+        b.borrow()
+            .get(&binding.0)
+            .map(|bound| (bound.callable.shallow_clone(), bound.schema.clone()))
+    })?;
+    Some(call(&callable, &schema, arguments))
+}
+
+fn call(callable: &Zval, schema: &Value, arguments: &Value) -> PhpResult<Zval> {
+    let declared = schema.get("properties").and_then(Value::as_object);
+    let known: Map<String, Value> = arguments
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter(|(name, _)| declared.is_some_and(|d| d.contains_key(*name)))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    let args = crate::schema::hydrate(&Value::Object(known), schema, true)?;
+    // String keys are passed as named arguments
+    Ok(ZendCallable::try_from_name("call_user_func_array")?.try_call(vec![callable, &args])?)
+}
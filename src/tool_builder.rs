@@ -1,72 +1,153 @@
+use ext_php_rs::boxed::ZBox;
 use ext_php_rs::convert::IntoZval;
 use ext_php_rs::prelude::*;
-use ext_php_rs::types::{ZendClassObject, ZendHashTable as PhpArray, Zval};
-use octolib::llm::{ChatCompletionParams, FunctionDefinition, ProviderFactory, TokenUsage};
+use ext_php_rs::types::{ZendCallable, ZendClassObject, ZendHashTable as PhpArray, Zval};
+use octolib::errors::ToolCallError;
+use octolib::llm::{FunctionDefinition, Message as OctoMessage, MessageBuilder, TokenUsage};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 
-use crate::convert::php_to_messages;
+use crate::backend::Backend;
+use crate::convert::{php_to_messages, prepend_system, zval_to_json_value};
 use crate::error::IntoPhpException;
-use crate::llm_class::Usage;
-
-/// Recursively convert PHP Zval to serde_json::Value
-fn zval_to_json_value(zval: &Zval) -> serde_json::Value {
-    if let Some(s) = zval.string() {
-        serde_json::Value::String(s.to_string())
-    } else if let Some(i) = zval.long() {
-        serde_json::Value::Number(i.into())
-    } else if let Some(f) = zval.double() {
-        serde_json::Number::from_f64(f)
-            .map(serde_json::Value::Number)
-            .unwrap_or(serde_json::Value::Null)
-    } else if let Some(b) = zval.bool() {
-        serde_json::Value::Bool(b)
-    } else if let Some(arr) = zval.array() {
-        // Check if it's an associative array (object) or indexed array
-        let mut is_object = false;
-        for (k, _) in arr.iter() {
-            match k {
-                ext_php_rs::types::ArrayKey::Str(_) | ext_php_rs::types::ArrayKey::String(_) => {
-                    is_object = true;
-                    break;
-                }
-                _ => {}
-            }
-        }
+use crate::future::LLMFuture;
+use crate::llm_class::{CallSettings, Usage, LLM};
+use crate::params::{parse_stop, Penalties, ToolChoice};
+use crate::reasoning::extract_reasoning;
+use crate::runtime::runtime;
+use crate::stream::{StreamEvent, StreamParams, StreamTarget};
+use crate::tool_registry::{upsert, ToolRegistry};
+use crate::tool_select::ToolSelection;
 
-        if is_object {
-            // Convert to JSON object
-            let mut map = serde_json::Map::new();
-            for (k, v) in arr.iter() {
-                let key = match k {
-                    ext_php_rs::types::ArrayKey::Str(s) => s.to_string(),
-                    ext_php_rs::types::ArrayKey::String(s) => s,
-                    ext_php_rs::types::ArrayKey::Long(i) => i.to_string(),
-                };
-                map.insert(key, zval_to_json_value(v));
-            }
-            serde_json::Value::Object(map)
+/// Whether tool-call arguments came back as a JSON string that does not parse
+fn is_malformed_arguments(arguments: &Value) -> bool {
+    match arguments {
+        Value::String(raw) => !raw.trim().is_empty() && serde_json::from_str::<Value>(raw).is_err(),
+        _ => false,
+    }
+}
+
+/// Arguments some providers send as a JSON-encoded string, decoded to the
+/// object they contain; anything else is returned unchanged
+fn decode_arguments(arguments: Value) -> Value {
+    match arguments {
+        Value::String(ref raw) => match serde_json::from_str::<Value>(raw) {
+            Ok(decoded @ (Value::Object(_) | Value::Array(_))) => decoded,
+            _ => arguments,
+        },
+        other => other,
+    }
+}
+
+/// Scalar type an argument is coerced to, taken from the caller's default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Coercion {
+    Int,
+    Float,
+    Bool,
+}
+
+impl Coercion {
+    fn for_default(default: &Zval) -> Option<Self> {
+        if default.is_long() {
+            Some(Self::Int)
+        } else if default.is_double() {
+            Some(Self::Float)
+        } else if default.is_bool() {
+            Some(Self::Bool)
         } else {
-            // Convert to JSON array
-            let mut vec = Vec::new();
-            for (_, v) in arr.iter() {
-                vec.push(zval_to_json_value(v));
+            None
+        }
+    }
+
+    /// Coerce numbers, numeric strings and booleans; None when the value
+    /// cannot represent the type
+    fn apply(self, value: &Value) -> Option<Value> {
+        let number = || match value {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) => s.trim().parse::<f64>().ok(),
+            Value::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            _ => None,
+        };
+        match self {
+            Self::Int => {
+                if value.is_i64() {
+                    return Some(value.clone());
+                }
+                if let Some(n) = value.as_str().and_then(|s| s.trim().parse::<i64>().ok()) {
+                    return Some(Value::from(n));
+                }
+                number()
+                    .filter(|n| n.fract() == 0.0)
+                    .map(|n| Value::from(n as i64))
             }
-            serde_json::Value::Array(vec)
+            Self::Float => number().map(Value::from),
+            Self::Bool => match value {
+                Value::Bool(_) => Some(value.clone()),
+                Value::String(s) => match s.trim().to_lowercase().as_str() {
+                    "true" | "1" | "yes" | "on" => Some(Value::Bool(true)),
+                    "false" | "0" | "no" | "off" | "" => Some(Value::Bool(false)),
+                    _ => None,
+                },
+                Value::Number(n) => n.as_f64().map(|n| Value::Bool(n != 0.0)),
+                _ => None,
+            },
         }
-    } else {
-        serde_json::Value::Null
     }
 }
 
+/// Replace malformed string arguments with their repaired JSON, when they
+/// can be repaired
+fn repair_arguments(arguments: &mut Value) {
+    if !is_malformed_arguments(arguments) {
+        return;
+    }
+    if let Some(repaired) = arguments
+        .as_str()
+        .and_then(crate::json_repair::parse_lenient)
+    {
+        *arguments = repaired;
+    }
+}
+
+/// Parse the accumulated arguments of a streamed tool call, repairing
+/// almost-valid JSON when `lenient`
+fn parse_streamed_arguments(name: &str, arguments: &str, lenient: bool) -> PhpResult<Value> {
+    if arguments.trim().is_empty() {
+        return Ok(Value::Object(Default::default()));
+    }
+    serde_json::from_str(arguments)
+        .or_else(|e| {
+            lenient
+                .then(|| crate::json_repair::parse_lenient(arguments))
+                .flatten()
+                .ok_or(e)
+        })
+        .map_err(|e| {
+            PhpException::from_class::<crate::error::LLMToolCallException>(format!(
+                "Malformed arguments for tool call '{name}': {e}"
+            ))
+        })
+}
+
 /// Tool definition
 #[php_class]
-#[derive(Clone)]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Tool {
     pub(crate) name: String,
     pub(crate) description: String,
-    pub(crate) parameters: String, // JSON string
+    /// JSON string, interned as the same schemas recur across tools
+    #[serde(deserialize_with = "crate::intern::deserialize")]
+    pub(crate) parameters: Arc<str>,
+    /// Callable of fromCallable() and fromMethod(), run for the tool's calls
+    #[serde(skip)]
+    pub(crate) binding: Option<Arc<crate::tool_binding::Binding>>,
 }
 
 #[php_impl]
@@ -78,36 +159,24 @@ impl Tool {
         description: String,
         parameters: &mut Zval,
     ) -> PhpResult<Self> {
-        // Convert Zval to JSON string using recursive conversion
-        let params_json = if let Some(s) = parameters.string() {
-            s.to_string()
-        } else if parameters.array().is_some() {
-            // Use recursive conversion for nested arrays
-            let json_value = zval_to_json_value(parameters);
-            serde_json::to_string(&json_value).map_err(|e| {
-                PhpException::from_class::<crate::error::LLMValidationException>(format!(
-                    "Invalid JSON schema: {e}"
-                ))
-            })?
-        } else {
-            return Err(PhpException::from_class::<
-                crate::error::LLMValidationException,
-            >(
-                "Parameters must be a string or array".to_string()
-            ));
-        };
+        let mut params_json = crate::schema::schema_json(parameters)?;
 
         // Validate it's valid JSON
-        serde_json::from_str::<Value>(&params_json).map_err(|e| {
+        let mut params = serde_json::from_str::<Value>(&params_json).map_err(|e| {
             PhpException::from_class::<crate::error::LLMValidationException>(format!(
                 "Invalid JSON schema: {e}"
             ))
         })?;
+        // Tool arguments stay plain values
+        if crate::schema::strip_php_markers(&mut params) {
+            params_json = params.to_string();
+        }
 
         Ok(Self {
             name,
             description,
-            parameters: params_json,
+            parameters: crate::intern::intern(params_json),
+            binding: None,
         })
     }
 
@@ -139,31 +208,71 @@ impl Tool {
             )
         })?;
 
-        // Convert Zval to JSON string using recursive conversion
-        let params_json = if let Some(s) = parameters.string() {
-            s.to_string()
-        } else if parameters.array().is_some() {
-            let json_value = zval_to_json_value(parameters);
-            serde_json::to_string(&json_value).map_err(|e| {
-                PhpException::from_class::<crate::error::LLMValidationException>(format!(
-                    "Invalid JSON schema: {e}"
-                ))
-            })?
-        } else {
+        let params_json = crate::schema::schema_json(parameters)?;
+
+        Ok(Self {
+            name,
+            description,
+            parameters: crate::intern::intern(params_json),
+            binding: None,
+        })
+    }
+
+    /// Tool calling `callable`, with the parameters derived from its
+    /// signature: names, types (also backed enums and classes, as with
+    /// Schema::fromClass()), defaults and #[Description] / #[Enum]
+    /// attributes. Named functions give the default name; the description
+    /// defaults to a #[Description] attribute or the doc comment. ToolBuilder
+    /// calls it with the arguments by name, unless a handler is set.
+    pub fn from_callable(
+        callable: &Zval,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> PhpResult<Self> {
+        if !callable.is_callable() {
             return Err(PhpException::from_class::<
                 crate::error::LLMValidationException,
-            >(
-                "Parameters must be a string or array".to_string()
-            ));
-        };
+            >("Tool callable is not callable".to_string()));
+        }
+        let tool = crate::reflection::callable_tool(callable)?;
+        let name = name.or(tool.name).ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMValidationException>(
+                "A tool for an anonymous function needs a name".to_string(),
+            )
+        })?;
+        let mut parameters = tool.schema.clone();
+        crate::schema::strip_php_markers(&mut parameters);
 
         Ok(Self {
             name,
-            description,
-            parameters: params_json,
+            description: description.or(tool.description).unwrap_or_default(),
+            parameters: crate::intern::intern(parameters.to_string()),
+            binding: Some(crate::tool_binding::bind(tool.closure, tool.schema)),
         })
     }
 
+    /// fromCallable() for a public method of an object, named after the
+    /// method by default
+    pub fn from_method(
+        object: &Zval,
+        method: String,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> PhpResult<Self> {
+        let mut callable = PhpArray::new();
+        callable.push(object.shallow_clone())?;
+        callable.push(method.as_str())?;
+        let callable = callable.into_zval(false)?;
+        if object.object().is_none() || !callable.is_callable() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Method '{method}' is not a public method of the object"
+            )));
+        }
+        Self::from_callable(&callable, Some(name.unwrap_or(method)), description)
+    }
+
     pub fn get_name(&self) -> String {
         self.name.clone()
     }
@@ -173,14 +282,14 @@ impl Tool {
     }
 
     pub fn get_parameters(&self) -> String {
-        self.parameters.clone()
+        self.parameters.to_string()
     }
 
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("name", self.name.clone())?;
         arr.insert("description", self.description.clone())?;
-        arr.insert("parameters", self.parameters.clone())?;
+        arr.insert("parameters", &*self.parameters)?;
         Ok(arr.into_zval(false)?)
     }
 
@@ -188,7 +297,7 @@ impl Tool {
         match serde_json::to_string(&serde_json::json!({
             "name": self.name,
             "description": self.description,
-            "parameters": self.parameters,
+            "parameters": &*self.parameters,
         })) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
@@ -196,6 +305,21 @@ impl Tool {
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+
+    /// State for serialize(), e.g. to keep the object in a session or queue
+    pub fn __serialize(&self) -> PhpResult<ZBox<PhpArray>> {
+        crate::serialization::save(self)
+    }
+
+    pub fn __unserialize(self_: &mut ZendClassObject<Tool>, data: &PhpArray) -> PhpResult<()> {
+        self_.initialize(crate::serialization::restore(data, "Tool")?);
+        Ok(())
+    }
 }
 
 // Internal methods - not exposed to PHP
@@ -218,6 +342,8 @@ impl Tool {
 
 /// Tool call from LLM
 #[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[derive(Serialize, Deserialize)]
 pub struct ToolCall {
     id: String,
     name: String,
@@ -238,8 +364,8 @@ impl Clone for ToolCall {
 // Internal constructor - not exposed to PHP
 impl ToolCall {
     pub(crate) fn new(id: String, name: String, arguments: Value) -> PhpResult<Self> {
-        // Store arguments as JSON string
-        let arguments_json = serde_json::to_string(&arguments)
+        // Store arguments as JSON string, decoding providers' double encoding first
+        let arguments_json = serde_json::to_string(&decode_arguments(arguments))
             .map_err(|e| PhpException::default(format!("Failed to serialize arguments: {e}")))?;
 
         Ok(Self {
@@ -248,11 +374,6 @@ impl ToolCall {
             arguments_json,
         })
     }
-
-    // Internal method to get arguments as JSON string
-    pub(crate) fn get_arguments_json(&self) -> &str {
-        &self.arguments_json
-    }
 }
 
 #[php_impl]
@@ -276,6 +397,31 @@ impl ToolCall {
         }
     }
 
+    /// Arguments as the raw JSON object string
+    pub fn get_arguments_json(&self) -> String {
+        self.arguments_json.clone()
+    }
+
+    /// A single argument, or `$default` when it is missing. An int, float or
+    /// bool default also sets the type: numeric strings and booleans are
+    /// coerced to it, and values that cannot be coerced yield the default.
+    pub fn get_argument(&self, key: String, default: Option<&Zval>) -> PhpResult<Zval> {
+        let default_zval = || default.map_or_else(Zval::new, |d| d.shallow_clone());
+
+        let arguments: Value = serde_json::from_str(&self.arguments_json).unwrap_or(Value::Null);
+        let Some(value) = arguments.get(&key).filter(|v| !v.is_null()) else {
+            return Ok(default_zval());
+        };
+
+        match default.and_then(Coercion::for_default) {
+            Some(coercion) => match coercion.apply(value) {
+                Some(coerced) => crate::convert::json_value_to_php(&coerced),
+                None => Ok(default_zval()),
+            },
+            None => crate::convert::json_value_to_php(value),
+        }
+    }
+
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("id", self.id.clone())?;
@@ -303,16 +449,43 @@ impl ToolCall {
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+
+    /// State for serialize(), e.g. to keep the object in a session or queue
+    pub fn __serialize(&self) -> PhpResult<ZBox<PhpArray>> {
+        crate::serialization::save(self)
+    }
+
+    pub fn __unserialize(self_: &mut ZendClassObject<ToolCall>, data: &PhpArray) -> PhpResult<()> {
+        self_.initialize(crate::serialization::restore(data, "ToolCall")?);
+        Ok(())
+    }
 }
 
 /// Response with tool calls
 #[php_class]
+#[php(implements(ce = crate::serialization::json_serializable, stub = "\\JsonSerializable"))]
+#[php(implements(ce = crate::completion_result::interface, stub = "\\CompletionResultInterface"))]
 pub struct ToolResponse {
     content: String,
     tool_calls: Vec<ToolCall>,
     usage: Usage,
     model: String,
+    finish_reason: String,
     id: Option<String>,
+    reasoning: Option<String>,
+    /// Paused tool loop, when the tool calls have not been answered yet
+    pending: Option<PendingLoop>,
+    /// Whether dry-run is holding the tool calls for approval
+    awaiting_approval: bool,
+    /// Tools sent with the request, when selectTools() picked a subset
+    selected_tools: Option<Vec<String>>,
+    /// Tool calls run by the auto-execute loop
+    trace: Vec<TraceEntry>,
 }
 
 // Internal constructor - not exposed to PHP
@@ -334,14 +507,44 @@ impl ToolResponse {
             cost: None,
             request_time_ms: None,
         });
+        let finish_reason = default_finish_reason(&tool_calls);
         Self {
             content,
             tool_calls,
             usage: Usage::from_octo(usage),
             model,
+            finish_reason,
             id,
+            reasoning: None,
+            pending: None,
+            awaiting_approval: false,
+            selected_tools: None,
+            trace: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_reasoning(mut self, reasoning: Option<String>) -> Self {
+        self.reasoning = reasoning;
+        self
+    }
+
+    /// Finish reason the provider reported, if any
+    pub(crate) fn with_finish_reason(mut self, finish_reason: Option<String>) -> Self {
+        if let Some(finish_reason) = finish_reason {
+            self.finish_reason = finish_reason;
         }
+        self
+    }
+}
+
+/// Finish reason of a response whose provider reported none
+fn default_finish_reason(tool_calls: &[ToolCall]) -> String {
+    if tool_calls.is_empty() {
+        "stop"
+    } else {
+        "tool_calls"
     }
+    .to_string()
 }
 
 #[php_impl]
@@ -362,14 +565,117 @@ impl ToolResponse {
         self.model.clone()
     }
 
+    /// 'tool_calls' when the model wants tools run, otherwise the provider's
+    /// reason, e.g. 'stop' or 'length'
+    pub fn get_finish_reason(&self) -> String {
+        self.finish_reason.clone()
+    }
+
     pub fn get_id(&self) -> Option<String> {
         self.id.clone()
     }
 
+    /// Provider's id of the response (same as getId())
+    pub fn get_response_id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
     pub fn has_tool_calls(&self) -> bool {
         !self.tool_calls.is_empty()
     }
 
+    /// Whether dry-run is holding these tool calls for approval; continue
+    /// with ToolBuilder::resume()
+    pub fn requires_approval(&self) -> bool {
+        self.awaiting_approval
+    }
+
+    /// Serialize the paused tool loop (conversation, pending tool calls and
+    /// request options) so it can be resumed in a later PHP request with
+    /// ToolResponse::fromState() and ToolBuilder::resume()
+    pub fn get_state(&self) -> PhpResult<String> {
+        let Some(pending) = &self.pending else {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "ToolResponse has no pending tool calls".to_string()
+            ));
+        };
+        let state = LoopState {
+            version: STATE_VERSION,
+            options: pending.options.clone(),
+            conversation: pending.conversation.clone(),
+            content: self.content.clone(),
+            tool_calls: self.tool_calls.clone(),
+            usage: self.usage.clone(),
+            model: self.model.clone(),
+            finish_reason: Some(self.finish_reason.clone()),
+            id: self.id.clone(),
+            reasoning: self.reasoning.clone(),
+            awaiting_approval: self.awaiting_approval,
+        };
+        serde_json::to_string(&state)
+            .map_err(|e| PhpException::default(format!("Failed to serialize state: {e}")))
+    }
+
+    /// Restore a paused tool loop saved with getState()
+    pub fn from_state(state: String) -> PhpResult<ToolResponse> {
+        let invalid = |msg: String| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Invalid tool loop state: {msg}"
+            ))
+        };
+        let state: LoopState = serde_json::from_str(&state).map_err(|e| invalid(e.to_string()))?;
+        if state.version != STATE_VERSION {
+            return Err(invalid(format!("unsupported version {}", state.version)));
+        }
+        let finish_reason = state
+            .finish_reason
+            .unwrap_or_else(|| default_finish_reason(&state.tool_calls));
+        Ok(Self {
+            content: state.content,
+            tool_calls: state.tool_calls,
+            usage: state.usage,
+            model: state.model,
+            finish_reason,
+            id: state.id,
+            reasoning: state.reasoning,
+            selected_tools: state.options.tools.clone(),
+            pending: Some(PendingLoop {
+                options: state.options,
+                conversation: state.conversation,
+            }),
+            awaiting_approval: state.awaiting_approval,
+            trace: Vec::new(),
+        })
+    }
+
+    /// Names of the tools sent with the request when selectTools() picked a
+    /// subset of them; null when every tool was sent
+    pub fn get_selected_tools(&self) -> Option<Vec<String>> {
+        self.selected_tools.clone()
+    }
+
+    /// Tool calls run by the auto-execute loop of this complete() or
+    /// resume() call, in order: id, name, round (0 for calls approved in
+    /// resume()), started_ms since the loop started, handler_ms spent in the
+    /// PHP handler, duration_ms until the result was ready, deferred when the
+    /// handler returned an LLMFuture, and summarized
+    pub fn get_execution_trace(&self) -> PhpResult<Zval> {
+        let trace = serde_json::to_value(&self.trace).unwrap_or_default();
+        crate::convert::json_value_to_php(&trace)
+    }
+
+    /// Reasoning ("thinking") text, for models and providers that return it
+    pub fn get_reasoning(&self) -> Option<String> {
+        self.reasoning.clone()
+    }
+
+    /// Tokens spent on reasoning (shortcut for getUsage()->getReasoningTokens())
+    pub fn get_reasoning_tokens(&self) -> i64 {
+        self.usage.get_reasoning_tokens()
+    }
+
     pub fn to_array(&self) -> PhpResult<Zval> {
         let mut arr = PhpArray::new();
         arr.insert("content", self.content.clone())?;
@@ -382,9 +688,13 @@ impl ToolResponse {
 
         arr.insert("usage", self.usage.clone())?;
         arr.insert("model", self.model.clone())?;
+        arr.insert("finish_reason", self.finish_reason.clone())?;
         if let Some(ref resp_id) = self.id {
             arr.insert("id", &**resp_id)?;
         }
+        if let Some(ref reasoning) = self.reasoning {
+            arr.insert("reasoning", &**reasoning)?;
+        }
         Ok(arr.into_zval(false)?)
     }
 
@@ -404,13 +714,11 @@ impl ToolResponse {
         match serde_json::to_string(&serde_json::json!({
             "content": self.content,
             "tool_calls": calls,
-            "usage": {
-                "prompt_tokens": self.usage.get_prompt_tokens(),
-                "output_tokens": self.usage.get_output_tokens(),
-                "total_tokens": self.usage.get_total_tokens(),
-            },
+            "usage": self.usage.json_value(),
             "model": self.model,
+            "finish_reason": self.finish_reason,
             "id": self.id,
+            "reasoning": self.reasoning,
         })) {
             Ok(json) => Ok(json),
             Err(e) => Err(PhpException::default(format!(
@@ -418,6 +726,233 @@ impl ToolResponse {
             ))),
         }
     }
+
+    /// Data for json_encode(), which then gives the same JSON as toJson()
+    pub fn json_serialize(&self) -> PhpResult<Zval> {
+        crate::serialization::json_data(self.to_json()?)
+    }
+}
+
+/// Default cap on provider round trips in the auto-execute loop
+const DEFAULT_MAX_TOOL_ROUNDS: u32 = 10;
+
+/// Tool result sent for calls rejected during dry-run approval
+const REJECTED_RESULT: &str = "Tool call was not approved";
+
+/// System prompt for summarizing a long tool result
+pub(crate) const RESULT_SUMMARY_PROMPT: &str = "Summarize the tool result below for the \
+assistant that called the tool. Keep every name, figure, identifier and error that bears \
+on the call's arguments; drop repetition and formatting. Reply with the summary only.";
+
+/// First line of a tool result replaced by its summary
+const RESULT_SUMMARY_PREFIX: &str = "Summary of the tool result:";
+
+/// Model summarizing a tool's results above a token threshold, see
+/// ToolBuilder::summarizeResults()
+struct ResultSummarizer {
+    max_tokens: u32,
+    llm: LLM,
+}
+
+/// Format version of serialized tool loop state
+const STATE_VERSION: u32 = 1;
+
+/// Default of `llm.max_tool_loops`
+pub(crate) const DEFAULT_MAX_TOOL_LOOPS: usize = 64;
+
+/// Tool loops running in this process, including loops started by tool
+/// handlers of another loop
+static ACTIVE_LOOPS: AtomicUsize = AtomicUsize::new(0);
+
+/// A running tool loop, counted against `llm.max_tool_loops` until dropped
+struct LoopSlot;
+
+impl LoopSlot {
+    /// Claim a slot, or None when `limit` loops are running (0 = no limit)
+    fn try_acquire(limit: usize) -> Option<Self> {
+        ACTIVE_LOOPS
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (limit == 0 || active < limit).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+
+    /// Claim a slot under the configured limit, failing with
+    /// LLMToolCallException when every slot is taken
+    fn acquire() -> PhpResult<Self> {
+        let limit = crate::config::ini(crate::config::MAX_TOOL_LOOPS_INI)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_TOOL_LOOPS);
+        Self::try_acquire(limit).ok_or_else(|| {
+            PhpException::from_class::<crate::error::LLMToolCallException>(format!(
+                "Too many tool loops running at once (limit {limit}, see llm.max_tool_loops); \
+                 a tool handler may be starting loops recursively"
+            ))
+        })
+    }
+}
+
+impl Drop for LoopSlot {
+    fn drop(&mut self) {
+        ACTIVE_LOOPS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Request options a tool loop was started with; a resumed loop keeps them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LoopOptions {
+    model: String,
+    temperature: f32,
+    max_tokens: u32,
+    top_p: f32,
+    top_k: u32,
+    penalties: Penalties,
+    stop: Vec<String>,
+    #[serde(default)]
+    seed: Option<i64>,
+    /// Names of the tools sent, when selectTools() picked a subset
+    #[serde(default)]
+    tools: Option<Vec<String>>,
+}
+
+/// Tool call run by the auto-execute loop, see
+/// ToolResponse::getExecutionTrace()
+#[derive(Debug, Clone, Serialize)]
+struct TraceEntry {
+    id: String,
+    name: String,
+    round: u32,
+    started_ms: f64,
+    handler_ms: f64,
+    duration_ms: f64,
+    deferred: bool,
+    summarized: bool,
+}
+
+/// Tool calls run so far by a complete() or resume() call
+struct ToolTrace {
+    start: Instant,
+    entries: Vec<TraceEntry>,
+}
+
+impl ToolTrace {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            entries: Vec::new(),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Tool loop paused with unanswered tool calls
+#[derive(Clone)]
+struct PendingLoop {
+    options: LoopOptions,
+    /// Conversation up to and including the assistant turn making the calls
+    conversation: Vec<OctoMessage>,
+}
+
+/// Serialized form of a paused tool loop
+#[derive(Serialize, Deserialize)]
+struct LoopState {
+    version: u32,
+    options: LoopOptions,
+    conversation: Vec<OctoMessage>,
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Usage,
+    model: String,
+    #[serde(default)]
+    finish_reason: Option<String>,
+    id: Option<String>,
+    reasoning: Option<String>,
+    awaiting_approval: bool,
+}
+
+/// A single provider response within the tool loop
+struct Round {
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Option<TokenUsage>,
+    finish_reason: Option<String>,
+    id: Option<String>,
+    reasoning: Option<String>,
+}
+
+impl Round {
+    fn into_response(self, usage: Option<TokenUsage>, model: String) -> ToolResponse {
+        ToolResponse::new_with_opt_usage(self.content, self.tool_calls, usage, model, self.id)
+            .with_reasoning(self.reasoning)
+            .with_finish_reason(self.finish_reason)
+    }
+}
+
+/// Tool definitions as text for counting their prompt tokens
+fn definitions_text(tools: &[FunctionDefinition]) -> String {
+    tools
+        .iter()
+        .map(|tool| format!("{} {} {}\n", tool.name, tool.description, tool.parameters))
+        .collect()
+}
+
+/// Sum the usage of consecutive requests
+fn add_usage(total: Option<TokenUsage>, usage: Option<TokenUsage>) -> Option<TokenUsage> {
+    let (Some(a), Some(b)) = (&total, &usage) else {
+        return total.or(usage);
+    };
+    Some(TokenUsage {
+        input_tokens: a.input_tokens + b.input_tokens,
+        output_tokens: a.output_tokens + b.output_tokens,
+        reasoning_tokens: a.reasoning_tokens + b.reasoning_tokens,
+        total_tokens: a.total_tokens + b.total_tokens,
+        cache_read_tokens: a.cache_read_tokens + b.cache_read_tokens,
+        cache_write_tokens: a.cache_write_tokens + b.cache_write_tokens,
+        cost: match (a.cost, b.cost) {
+            (None, None) => None,
+            (x, y) => Some(x.unwrap_or_default() + y.unwrap_or_default()),
+        },
+        request_time_ms: match (a.request_time_ms, b.request_time_ms) {
+            (None, None) => None,
+            (x, y) => Some(x.unwrap_or_default() + y.unwrap_or_default()),
+        },
+    })
+}
+
+/// Content of the Response a handler's LLMFuture resolves to; None for
+/// other results
+fn future_content(result: &Zval) -> PhpResult<Option<String>> {
+    if result.extract::<&ZendClassObject<LLMFuture>>().is_none() {
+        return Ok(None);
+    }
+    let Some(future) = result.object() else {
+        return Ok(None);
+    };
+    let response = future.try_call_method("wait", vec![])?;
+    let content = match response.object() {
+        Some(response) => response.try_call_method("getContent", vec![])?.string(),
+        None => None,
+    };
+    Ok(Some(content.unwrap_or_default()))
+}
+
+/// Handler return value as tool result content: strings as-is, anything
+/// else JSON-encoded
+fn tool_result_text(result: &Zval) -> String {
+    match result.string() {
+        Some(text) => text,
+        None => serde_json::to_string(&zval_to_json_value(result)).unwrap_or_default(),
+    }
+}
+
+fn message_error(e: octolib::errors::MessageError) -> PhpException {
+    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+        "Failed to build message: {e}"
+    ))
 }
 
 /// Builder for tool calling
@@ -427,63 +962,190 @@ pub struct ToolBuilder {
     temperature: f32,
     max_tokens: u32,
     top_p: f32,
+    top_k: u32,
+    penalties: Penalties,
+    stop: Vec<String>,
+    seed: Option<i64>,
     tools: Vec<Tool>,
+    system_prompt: Option<String>,
+    tool_choice: ToolChoice,
+    parallel_tool_calls: Option<bool>,
+    settings: CallSettings,
     auto_execute: bool,
-    runtime: Arc<Runtime>,
+    tool_call_retries: u32,
+    /// Repair almost-valid tool-call arguments instead of rejecting them
+    lenient_parsing: bool,
+    handlers: HashMap<String, Zval>,
+    dry_run: bool,
+    max_tool_rounds: u32,
+    tool_selection: Option<ToolSelection>,
+    result_summarizers: HashMap<String, ResultSummarizer>,
 }
 
 // Internal constructor - not exposed to PHP
 impl ToolBuilder {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         model: String,
         temperature: f32,
         max_tokens: u32,
         top_p: f32,
+        top_k: u32,
+        penalties: Penalties,
+        stop: Vec<String>,
+        seed: Option<i64>,
         tools: Vec<Tool>,
-        runtime: Arc<Runtime>,
+        settings: CallSettings,
     ) -> Self {
         Self {
             model,
             temperature,
             max_tokens,
             top_p,
+            top_k,
+            penalties,
+            stop,
+            seed,
             tools,
+            system_prompt: None,
+            tool_choice: ToolChoice::Auto,
+            parallel_tool_calls: None,
+            settings,
             auto_execute: false,
-            runtime,
+            tool_call_retries: 0,
+            lenient_parsing: false,
+            handlers: HashMap::new(),
+            dry_run: false,
+            max_tool_rounds: DEFAULT_MAX_TOOL_ROUNDS,
+            tool_selection: None,
+            result_summarizers: HashMap::new(),
         }
     }
-}
 
-#[php_impl]
-impl ToolBuilder {
-    /// Complete with tool calling
-    pub fn complete(&self, messages: &Zval) -> PhpResult<ToolResponse> {
-        let this = self;
-        let rt = this.runtime.clone();
+    /// Names of the tools selectTools() picks for a conversation; None sends
+    /// every tool
+    fn select_tools_for(&self, conversation: &[OctoMessage]) -> Option<Vec<String>> {
+        let required = match &self.tool_choice {
+            ToolChoice::Function(name) => Some(name.as_str()),
+            _ => None,
+        };
+        self.tool_selection.as_ref()?.select(
+            &self.tools,
+            conversation,
+            required,
+            &self.settings.config,
+        )
+    }
 
-        let messages_vec = php_to_messages(messages)?;
+    /// Tool definitions as sent to `model`, see LLM::simplifySchemas(),
+    /// limited to `selected` when given
+    fn octo_tools(
+        &self,
+        model: &str,
+        selected: Option<&[String]>,
+    ) -> PhpResult<Vec<FunctionDefinition>> {
+        self.tools
+            .iter()
+            .filter(|tool| selected.is_none_or(|names| names.contains(&tool.name)))
+            .map(|tool| {
+                let mut definition = tool.to_octo()?;
+                definition.parameters = crate::schema_limits::prepare(
+                    model,
+                    &definition.parameters,
+                    self.settings.simplify_schemas,
+                    &format!("Parameters of tool '{}'", tool.name),
+                )?;
+                Ok(definition)
+            })
+            .collect()
+    }
 
-        let (provider, model) = rt
-            .block_on(async { ProviderFactory::get_provider_for_model(&this.model) })
-            .map_err(|e| e.into_php_exception())?;
+    fn loop_options(&self) -> LoopOptions {
+        LoopOptions {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            penalties: self.penalties,
+            stop: self.stop.clone(),
+            seed: self.seed,
+            tools: None,
+        }
+    }
 
-        // Convert tools to octolib format
-        let octo_tools: Result<Vec<_>, _> = this.tools.iter().map(|t| t.to_octo()).collect();
-        let octo_tools = octo_tools?;
+    /// One provider request, re-sent while tool-call arguments come back malformed
+    fn request_round(
+        &self,
+        rt: &Runtime,
+        backend: &Backend,
+        options: &LoopOptions,
+        params: &mut StreamParams,
+        messages_vec: &[OctoMessage],
+    ) -> PhpResult<(Round, String)> {
+        let this = self;
+        let model = backend.model().to_string();
 
-        let params = ChatCompletionParams::new(
-            &messages_vec,
-            &model,
-            this.temperature,
-            this.top_p,
-            50,
-            this.max_tokens,
-        )
-        .with_tools(octo_tools);
+        // Re-send the request while the provider returns malformed tool-call JSON
+        let definitions = definitions_text(&params.tools);
+        params.max_tokens = this.settings.max_tokens_for(
+            &options.model,
+            messages_vec,
+            &definitions,
+            options.max_tokens,
+        )?;
+        let params = &*params;
+        let mut attempt = 0;
+        let response = loop {
+            let mut result = rt.block_on(this.settings.retry.run(|| {
+                backend.complete(
+                    &options.model,
+                    messages_vec,
+                    params,
+                    options.top_k,
+                    &this.settings.config,
+                )
+            }));
+            if let (true, Ok(response)) = (this.lenient_parsing, &mut result) {
+                for call in response.tool_calls.iter_mut().flatten() {
+                    repair_arguments(&mut call.arguments);
+                }
+            }
+            let malformed = match &result {
+                Ok(response) => {
+                    if let Some(usage) = response.exchange.usage.as_ref() {
+                        this.settings.attribution.record(&model, usage);
+                    }
+                    response
+                        .tool_calls
+                        .iter()
+                        .flatten()
+                        .any(|c| is_malformed_arguments(&c.arguments))
+                }
+                Err(e) => e.downcast_ref::<ToolCallError>().is_some(),
+            };
+
+            if malformed && attempt < this.tool_call_retries {
+                attempt += 1;
+                continue;
+            }
+            break result.map_err(|e| e.into_php_exception())?;
+        };
 
-        let response = rt
-            .block_on(async { provider.chat_completion(params).await })
-            .map_err(|e| e.into_php_exception())?;
+        if let Some(call) = response
+            .tool_calls
+            .iter()
+            .flatten()
+            .find(|c| is_malformed_arguments(&c.arguments))
+        {
+            return Err(
+                PhpException::from_class::<crate::error::LLMToolCallException>(format!(
+                    "Malformed arguments for tool call '{}' after {} attempt(s)",
+                    call.name,
+                    attempt + 1
+                )),
+            );
+        }
 
         // Convert tool calls
         let tool_calls = if let Some(calls) = response.tool_calls {
@@ -495,24 +1157,585 @@ impl ToolBuilder {
             Vec::new()
         };
 
-        Ok(ToolResponse::new_with_opt_usage(
-            response.content,
+        let round = Round {
+            content: response.content,
             tool_calls,
-            response.exchange.usage,
-            model,
-            response.id,
-        ))
+            usage: response.exchange.usage,
+            finish_reason: response.finish_reason,
+            id: response.id,
+            reasoning: extract_reasoning(&response.exchange.response),
+        };
+        Ok((round, model))
     }
 
-    /// Add a tool
+    /// Request completions until the model stops calling tools, a call has no
+    /// handler, dry-run holds the calls for approval, or the round limit is hit.
+    /// Responses with unanswered tool calls keep the loop so it can be resumed.
+    /// `choice` applies to the first request only; once tool results are in,
+    /// a forced call is relaxed to 'auto' so the model can answer.
+    fn run(
+        &self,
+        options: LoopOptions,
+        choice: ToolChoice,
+        mut conversation: Vec<OctoMessage>,
+        mut trace: ToolTrace,
+    ) -> PhpResult<ToolResponse> {
+        if let ToolChoice::Function(name) = &choice {
+            if !self.tools.iter().any(|t| &t.name == name) {
+                return Err(PhpException::from_class::<
+                    crate::error::LLMValidationException,
+                >(format!(
+                    "Tool choice forces '{name}', which is not among the tools"
+                )));
+            }
+        }
+        let _slot = LoopSlot::acquire()?;
+        let rt = runtime()?;
+        let mut params = StreamParams {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            max_tokens: options.max_tokens,
+            penalties: options.penalties,
+            stop: options.stop.clone(),
+            seed: options.seed,
+            tools: self.octo_tools(&options.model, options.tools.as_deref())?,
+            tool_choice: choice,
+            parallel_tool_calls: self.parallel_tool_calls,
+            documents: Vec::new(),
+            structured: None,
+        };
+        let backend = self.settings.backend(rt, &options.model, &mut params)?;
+
+        let mut usage = None;
+        let mut rounds = 0;
+        loop {
+            self.settings.attribution.check()?;
+            let (mut round, model) =
+                self.request_round(rt, &backend, &options, &mut params, &conversation)?;
+            usage = add_usage(usage, round.usage.take());
+            rounds += 1;
+
+            if round.tool_calls.is_empty() {
+                let mut response = round.into_response(usage, model);
+                response.selected_tools = options.tools;
+                response.trace = trace.entries;
+                return Ok(response);
+            }
+            conversation.push(assistant_turn(&round.content, &round.tool_calls)?);
+
+            let executable =
+                self.auto_execute && round.tool_calls.iter().all(|c| self.has_handler(&c.name));
+            if self.dry_run || !executable || rounds >= self.max_tool_rounds {
+                let mut response = round.into_response(usage, model);
+                response.selected_tools = options.tools.clone();
+                response.pending = Some(PendingLoop {
+                    options,
+                    conversation,
+                });
+                response.awaiting_approval = self.dry_run;
+                response.trace = trace.entries;
+                return Ok(response);
+            }
+
+            let calls: Vec<&ToolCall> = round.tool_calls.iter().collect();
+            let executed = self.execute_calls(&calls, rounds, &mut trace)?;
+            let results: Vec<_> = calls.into_iter().zip(executed).collect();
+            self.push_results(&options.model, &mut conversation, &results)?;
+            params.tool_choice = params.tool_choice.after_tool_results();
+        }
+    }
+
+    /// Callable bound to a tool by Tool::fromCallable()
+    fn binding(&self, name: &str) -> Option<&crate::tool_binding::Binding> {
+        self.tools
+            .iter()
+            .find(|t| t.name == name)
+            .and_then(|t| t.binding.as_deref())
+    }
+
+    /// Whether a tool's calls can be executed: it has a handler or a
+    /// callable bound in this request
+    fn has_handler(&self, name: &str) -> bool {
+        self.handlers.contains_key(name)
+            || self
+                .binding(name)
+                .is_some_and(crate::tool_binding::is_bound)
+    }
+
+    /// Call the handler registered for a tool call, or else the callable
+    /// bound to the tool
+    fn invoke(&self, call: &ToolCall) -> PhpResult<Zval> {
+        match self.handlers.get(&call.name) {
+            Some(handler) => Ok(ZendCallable::new(handler)?.try_call(vec![call])?),
+            None => {
+                let arguments: Value =
+                    serde_json::from_str(&call.arguments_json).unwrap_or(Value::Null);
+                self.binding(&call.name)
+                    .and_then(|binding| crate::tool_binding::invoke(binding, &arguments))
+                    .ok_or_else(|| {
+                        PhpException::from_class::<crate::error::LLMToolCallException>(format!(
+                            "No handler registered for tool '{}'",
+                            call.name
+                        ))
+                    })?
+            }
+        }
+    }
+
+    /// Results of a round's tool calls, in call order. PHP runs the handlers
+    /// one after another, so what overlaps is the waiting: LLMFutures returned
+    /// by handlers are awaited only once every handler has been called, and
+    /// long results are summarized concurrently.
+    fn execute_calls(
+        &self,
+        calls: &[&ToolCall],
+        round: u32,
+        trace: &mut ToolTrace,
+    ) -> PhpResult<Vec<String>> {
+        let mut invoked = Vec::with_capacity(calls.len());
+        for call in calls {
+            let started = Instant::now();
+            let result = self.invoke(call)?;
+            invoked.push((started, started.elapsed(), result));
+        }
+
+        let mut results = Vec::with_capacity(calls.len());
+        let mut ready = Vec::with_capacity(calls.len());
+        let mut deferred = Vec::with_capacity(calls.len());
+        for (_, _, result) in &invoked {
+            let awaited = future_content(result)?;
+            deferred.push(awaited.is_some());
+            results.push(awaited.unwrap_or_else(|| tool_result_text(result)));
+            ready.push(Instant::now());
+        }
+        let summarized = self.summarize_results_of(calls, &mut results, &mut ready)?;
+
+        for (i, (started, handler, _)) in invoked.iter().enumerate() {
+            trace.entries.push(TraceEntry {
+                id: calls[i].id.clone(),
+                name: calls[i].name.clone(),
+                round,
+                started_ms: millis(started.duration_since(trace.start)),
+                handler_ms: millis(*handler),
+                duration_ms: millis(ready[i].duration_since(*started)),
+                deferred: deferred[i],
+                summarized: summarized[i],
+            });
+        }
+        Ok(results)
+    }
+
+    /// Replace results of tools with summarizeResults() set that are longer
+    /// than the threshold by summaries, all requested at once. A result whose
+    /// summary fails is kept in full. Returns which results were replaced and
+    /// updates when each was ready.
+    fn summarize_results_of(
+        &self,
+        calls: &[&ToolCall],
+        results: &mut [String],
+        ready: &mut [Instant],
+    ) -> PhpResult<Vec<bool>> {
+        let mut summaries = Vec::new();
+        for (i, call) in calls.iter().enumerate() {
+            let Some(summarizer) = self.result_summarizers.get(&call.name) else {
+                continue;
+            };
+            let tokens = crate::tokenizer::count_tokens(&results[i], &self.model);
+            if tokens <= summarizer.max_tokens as usize {
+                continue;
+            }
+            let text = format!(
+                "Tool: {}\nArguments: {}\n\nResult:\n{}",
+                call.name, call.arguments_json, results[i]
+            );
+            let summary = summarizer
+                .llm
+                .spawn_tool_summary(&text, summarizer.max_tokens);
+            summaries.push((i, summary));
+        }
+
+        let mut summarized = vec![false; calls.len()];
+        if summaries.is_empty() {
+            return Ok(summarized);
+        }
+        let rt = runtime()?;
+        for (i, summary) in summaries {
+            let completion = summary
+                .ok()
+                .and_then(|handle| rt.block_on(handle).ok())
+                .and_then(Result::ok);
+            match completion {
+                Some(completion) => {
+                    results[i] = format!("{RESULT_SUMMARY_PREFIX}\n{}", completion.content.trim());
+                    summarized[i] = true;
+                }
+                None => crate::logger::warn(&format!(
+                    "Summarizing the result of tool '{}' failed; sending it in full",
+                    calls[i].name
+                )),
+            }
+            ready[i] = Instant::now();
+        }
+        Ok(summarized)
+    }
+
+    /// Append tool result messages, rendered through the tool_result template
+    fn push_results(
+        &self,
+        model: &str,
+        conversation: &mut Vec<OctoMessage>,
+        results: &[(&ToolCall, String)],
+    ) -> PhpResult<()> {
+        let mut messages = results
+            .iter()
+            .map(|(call, result)| {
+                MessageBuilder::tool(result, &call.id, &call.name)
+                    .build()
+                    .map_err(message_error)
+            })
+            .collect::<PhpResult<Vec<_>>>()?;
+        self.settings.templates.apply(model, &mut messages);
+        conversation.extend(messages);
+        Ok(())
+    }
+}
+
+/// Assistant message carrying the tool calls it made, in the shape
+/// `Message::fromResponse()` produces
+fn assistant_turn(content: &str, calls: &[ToolCall]) -> PhpResult<OctoMessage> {
+    let mut msg = MessageBuilder::assistant(content)
+        .build()
+        .map_err(message_error)?;
+    let calls: Vec<Value> = calls
+        .iter()
+        .map(|call| {
+            serde_json::json!({
+                "id": call.id,
+                "name": call.name,
+                "arguments": serde_json::from_str::<Value>(&call.arguments_json)
+                    .unwrap_or(Value::Null),
+            })
+        })
+        .collect();
+    msg.tool_calls = Some(Value::Array(calls));
+    Ok(msg)
+}
+
+#[php_impl]
+impl ToolBuilder {
+    /// Complete with tool calling. With auto-execute on, tool calls are
+    /// answered by their registered handlers and the conversation continues
+    /// until the model stops calling tools.
+    pub fn complete(&self, messages: &Zval) -> PhpResult<ToolResponse> {
+        let mut conversation = php_to_messages(messages)?;
+        prepend_system(&mut conversation, self.system_prompt.as_deref())?;
+        self.settings
+            .shape_messages(&self.model, &mut conversation)?;
+        let mut options = self.loop_options();
+        options.tools = self.select_tools_for(&conversation);
+        self.run(
+            options,
+            self.tool_choice.clone(),
+            conversation,
+            ToolTrace::new(),
+        )
+    }
+
+    /// Continue a tool loop paused with unanswered tool calls, e.g. held for
+    /// dry-run approval or restored with ToolResponse::fromState(). `$approvals`
+    /// maps tool call ids to `true` (run the registered handler), a result
+    /// (string or array) to send instead, or `false` to reject the call.
+    /// Calls missing from the map are rejected. The loop keeps the model and
+    /// sampling options it was started with.
+    pub fn resume(&self, pending: &ToolResponse, approvals: &PhpArray) -> PhpResult<ToolResponse> {
+        let Some(PendingLoop {
+            options,
+            mut conversation,
+        }) = pending.pending.clone()
+        else {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "ToolResponse has no pending tool calls".to_string()
+            ));
+        };
+
+        let approved = |call: &ToolCall| {
+            approvals
+                .get(call.id.as_str())
+                .is_some_and(|approval| approval.bool() == Some(true))
+        };
+        let calls: Vec<&ToolCall> = pending.tool_calls.iter().filter(|c| approved(c)).collect();
+        let mut trace = ToolTrace::new();
+        let mut executed = self.execute_calls(&calls, 0, &mut trace)?.into_iter();
+
+        let mut results = Vec::new();
+        for call in &pending.tool_calls {
+            let result = match approvals.get(call.id.as_str()) {
+                Some(_) if approved(call) => executed.next().unwrap_or_default(),
+                Some(approval) if approval.is_null() || approval.bool() == Some(false) => {
+                    REJECTED_RESULT.to_string()
+                }
+                Some(result) => tool_result_text(result),
+                None => REJECTED_RESULT.to_string(),
+            };
+            results.push((call, result));
+        }
+        self.push_results(&options.model, &mut conversation, &results)?;
+
+        self.run(
+            options,
+            self.tool_choice.after_tool_results(),
+            conversation,
+            trace,
+        )
+    }
+
+    /// Stream a tool-calling completion. `$onDelta` receives content chunks as
+    /// they arrive; `$onToolCall` receives each ToolCall once all of its
+    /// argument fragments have been assembled. Returns the final ToolResponse.
+    pub fn stream(
+        &self,
+        messages: &Zval,
+        on_delta: ZendCallable,
+        on_tool_call: ZendCallable,
+    ) -> PhpResult<ToolResponse> {
+        let Some(target) = StreamTarget::for_model(&self.model, &self.settings.config) else {
+            // No SSE support for this provider: deliver the full completion at once
+            let response = self.complete(messages)?;
+            if !response.content.is_empty() {
+                on_delta.try_call(vec![&response.content])?;
+            }
+            for call in &response.tool_calls {
+                on_tool_call.try_call(vec![call])?;
+            }
+            return Ok(response);
+        };
+        let (mut penalties, mut seed) = (self.penalties, self.seed);
+        if !target.supports_penalties() {
+            let mut unsupported = penalties.set_names();
+            if seed.is_some() {
+                unsupported.push("seed");
+            }
+            self.settings
+                .param_policy
+                .enforce(&self.model, &unsupported)?;
+            penalties = Penalties::default();
+            seed = None;
+        }
+        let _slot = LoopSlot::acquire()?;
+        self.settings.attribution.check()?;
+
+        let mut messages_vec = php_to_messages(messages)?;
+        prepend_system(&mut messages_vec, self.system_prompt.as_deref())?;
+        self.settings
+            .shape_messages(&self.model, &mut messages_vec)?;
+        let selected = self.select_tools_for(&messages_vec);
+        let tools = self.octo_tools(&self.model, selected.as_deref())?;
+        let definitions = definitions_text(&tools);
+        let params = StreamParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_tokens: self.settings.max_tokens_for(
+                &self.model,
+                &messages_vec,
+                &definitions,
+                self.max_tokens,
+            )?,
+            penalties,
+            stop: self.stop.clone(),
+            seed,
+            tools,
+            tool_choice: self.tool_choice.clone(),
+            parallel_tool_calls: self.parallel_tool_calls,
+            documents: Vec::new(),
+            structured: None,
+        };
+        let model = target.model_name().to_string();
+        let rt = runtime()?;
+        let mut rx = crate::stream::spawn(
+            rt,
+            target,
+            &messages_vec,
+            &params,
+            self.settings.retry.clone(),
+        );
+
+        let mut content = String::new();
+        let mut tool_calls = Vec::new();
+        loop {
+            match rt.block_on(rx.recv()) {
+                Some(Ok(StreamEvent::Delta(delta))) => {
+                    on_delta.try_call(vec![&delta])?;
+                    content.push_str(&delta);
+                }
+                Some(Ok(StreamEvent::ToolCall {
+                    id,
+                    name,
+                    arguments,
+                })) => {
+                    let arguments =
+                        parse_streamed_arguments(&name, &arguments, self.lenient_parsing)?;
+                    let call = ToolCall::new(id, name, arguments)?;
+                    on_tool_call.try_call(vec![&call])?;
+                    tool_calls.push(call);
+                }
+                Some(Ok(StreamEvent::Citation(_))) => {}
+                Some(Ok(StreamEvent::Done { usage, .. })) => {
+                    if let Some(ref usage) = usage {
+                        self.settings.attribution.record(&model, usage);
+                    }
+                    let mut response =
+                        ToolResponse::new_with_opt_usage(content, tool_calls, usage, model, None);
+                    response.selected_tools = selected;
+                    return Ok(response);
+                }
+                Some(Err(e)) => return Err(e.into_php_exception()),
+                None => {
+                    return Err(PhpException::from_class::<
+                        crate::error::LLMConnectionException,
+                    >(
+                        "Stream ended unexpectedly".to_string()
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Add a tool, optionally with the handler that executes it
     pub fn add_tool<'a>(
         self_: &'a mut ZendClassObject<ToolBuilder>,
         tool: &mut Tool,
-    ) -> &'a mut ZendClassObject<ToolBuilder> {
+        handler: Option<&Zval>,
+    ) -> PhpResult<&'a mut ZendClassObject<ToolBuilder>> {
+        if let Some(handler) = handler {
+            Self::set_handler(self_, tool.name.clone(), handler)?;
+        }
         self_.tools.push(tool.clone());
+        Ok(self_)
+    }
+
+    /// Add every tool of a registry with its handler, replacing tools of
+    /// the same name. The builder gets its own copy: later changes to the
+    /// registry don't affect it.
+    pub fn add_registry<'a>(
+        self_: &'a mut ZendClassObject<ToolBuilder>,
+        registry: &ToolRegistry,
+    ) -> PhpResult<&'a mut ZendClassObject<ToolBuilder>> {
+        for (tool, handler) in registry.entries()? {
+            if let Some(handler) = handler {
+                self_.handlers.insert(tool.name.clone(), handler);
+            }
+            upsert(&mut self_.tools, tool);
+        }
+        Ok(self_)
+    }
+
+    /// Register the callable executing a tool in the auto-execute loop.
+    /// It receives the ToolCall; non-string results are JSON-encoded.
+    pub fn set_handler(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        name: String,
+        handler: &Zval,
+    ) -> PhpResult<&mut ZendClassObject<ToolBuilder>> {
+        if !handler.is_callable() {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Handler for tool '{name}' must be callable"
+            )));
+        }
+        self_.handlers.insert(name, handler.shallow_clone());
+        Ok(self_)
+    }
+
+    /// Summarize results of tool `name` longer than `max_tokens` with
+    /// `summarizer`, usually a cheaper model, before they are fed back in the
+    /// auto-execute loop. The summary is limited to `max_tokens`, so long
+    /// results cannot crowd the context window over many rounds.
+    pub fn summarize_results<'a>(
+        self_: &'a mut ZendClassObject<ToolBuilder>,
+        name: String,
+        max_tokens: i64,
+        summarizer: &LLM,
+    ) -> PhpResult<&'a mut ZendClassObject<ToolBuilder>> {
+        let max_tokens = u32::try_from(max_tokens)
+            .ok()
+            .filter(|tokens| *tokens > 0)
+            .ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "Summary threshold of tool '{name}' must be positive"
+                ))
+            })?;
+        self_.result_summarizers.insert(
+            name,
+            ResultSummarizer {
+                max_tokens,
+                llm: summarizer.clone(),
+            },
+        );
+        Ok(self_)
+    }
+
+    /// Hold tool calls for approval instead of executing them: complete()
+    /// returns a ToolResponse with requiresApproval() set, to be continued
+    /// with resume()
+    pub fn set_dry_run(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        dry_run: bool,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.dry_run = dry_run;
         self_
     }
 
+    /// Maximum provider requests per complete() or resume() call in the
+    /// tool loop (default 10)
+    pub fn set_max_tool_rounds(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        rounds: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.max_tool_rounds = rounds.max(1) as u32;
+        self_
+    }
+
+    /// Send only the `topK` tools whose descriptions are most similar to the
+    /// last user message, by embedding similarity, to keep prompts small when
+    /// many tools are registered. A tool forced by setToolChoice() is always
+    /// sent. The embedding model defaults to the provider's (see
+    /// embeddings()). Pass 0 to send every tool again.
+    pub fn select_tools(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        top_k: i64,
+        embedding_model: Option<String>,
+    ) -> PhpResult<&mut ZendClassObject<ToolBuilder>> {
+        if top_k < 0 {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(
+                "Number of tools must not be negative".to_string()
+            ));
+        }
+        if top_k == 0 {
+            self_.tool_selection = None;
+            return Ok(self_);
+        }
+        let embedding_model = match embedding_model {
+            Some(model) => model,
+            None => crate::embeddings::default_model_for(&self_.model).ok_or_else(|| {
+                PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                    "No default embedding model for '{}', pass one explicitly",
+                    self_.model
+                ))
+            })?,
+        };
+        self_.tool_selection = Some(ToolSelection::new(
+            top_k as usize,
+            embedding_model,
+            &self_.settings.config,
+        )?);
+        Ok(self_)
+    }
+
     /// Set all tools
     pub fn set_tools<'a>(
         self_: &'a mut ZendClassObject<ToolBuilder>,
@@ -530,6 +1753,16 @@ impl ToolBuilder {
         self_
     }
 
+    /// System prompt sent ahead of the messages, merged with a leading system
+    /// message if they have one. An empty string removes it.
+    pub fn with_system_prompt(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        prompt: String,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.system_prompt = (!prompt.is_empty()).then_some(prompt);
+        self_
+    }
+
     /// Set auto execute
     pub fn set_auto_execute(
         self_: &mut ZendClassObject<ToolBuilder>,
@@ -539,6 +1772,55 @@ impl ToolBuilder {
         self_
     }
 
+    /// Re-send the request up to this many times when the provider returns
+    /// tool-call arguments that are not valid JSON (default 0)
+    pub fn set_tool_call_retries(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        attempts: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.tool_call_retries = attempts.max(0) as u32;
+        self_
+    }
+
+    /// Repair almost-valid tool-call arguments (code fences, trailing
+    /// commas, unquoted keys, single quotes, unclosed brackets) before
+    /// treating them as malformed
+    pub fn with_lenient_parsing(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        enabled: Option<bool>,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.lenient_parsing = enabled.unwrap_or(true);
+        self_
+    }
+
+    /// Limit each attempt of a request to this many seconds (0 for no limit)
+    pub fn set_timeout(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.settings.config.set_timeout(seconds);
+        self_
+    }
+
+    /// Limit a request to this many seconds over all of its retry attempts
+    /// (0 for no limit)
+    pub fn set_deadline(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.settings.retry.set_deadline(seconds);
+        self_
+    }
+
+    /// Limit connection setup to this many seconds (0 for no limit)
+    pub fn set_connect_timeout(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        seconds: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.settings.config.set_connect_timeout(seconds);
+        self_
+    }
+
     /// Set temperature
     pub fn set_temperature(
         self_: &mut ZendClassObject<ToolBuilder>,
@@ -554,6 +1836,220 @@ impl ToolBuilder {
         max_tokens: i64,
     ) -> &mut ZendClassObject<ToolBuilder> {
         self_.max_tokens = max_tokens as u32;
+        self_.settings.max_tokens_auto = None;
+        self_
+    }
+
+    /// Set top_k
+    pub fn set_top_k(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        top_k: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.top_k = top_k as u32;
+        self_
+    }
+
+    /// Set frequency penalty
+    pub fn set_frequency_penalty(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        penalty: f64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.penalties.frequency = penalty as f32;
+        self_
+    }
+
+    /// Set presence penalty
+    pub fn set_presence_penalty(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        penalty: f64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.penalties.presence = penalty as f32;
+        self_
+    }
+
+    /// Stop generating at any of these sequences (a string or an array of
+    /// strings; an empty array clears them)
+    pub fn set_stop<'a>(
+        self_: &'a mut ZendClassObject<ToolBuilder>,
+        stop: &Zval,
+    ) -> PhpResult<&'a mut ZendClassObject<ToolBuilder>> {
+        self_.stop = parse_stop(stop)?;
+        Ok(self_)
+    }
+
+    /// Sample with a fixed seed, so repeated requests with the same input
+    /// return the same output where the provider supports it
+    pub fn set_seed(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        seed: i64,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.seed = Some(seed);
+        self_
+    }
+
+    /// Control tool use: 'auto' (the default), 'none', 'required', or
+    /// 'function' with `$toolName` to force a call to that tool. A forced
+    /// call applies to the first request only; after tool results the model
+    /// is free to answer.
+    pub fn set_tool_choice<'a>(
+        self_: &'a mut ZendClassObject<ToolBuilder>,
+        mode: String,
+        tool_name: Option<String>,
+    ) -> PhpResult<&'a mut ZendClassObject<ToolBuilder>> {
+        self_.tool_choice = ToolChoice::parse(&mode, tool_name)?;
+        Ok(self_)
+    }
+
+    /// Allow or forbid several tool calls in one response. With `false` the
+    /// model calls at most one tool at a time, for workflows whose calls
+    /// depend on each other. Unset, the provider default applies.
+    pub fn set_parallel_tool_calls(
+        self_: &mut ZendClassObject<ToolBuilder>,
+        enabled: bool,
+    ) -> &mut ZendClassObject<ToolBuilder> {
+        self_.parallel_tool_calls = Some(enabled);
         self_
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_usage, decode_arguments, is_malformed_arguments, parse_streamed_arguments, Coercion,
+        LoopOptions, PendingLoop, ToolCall, ToolResponse,
+    };
+    use crate::params::Penalties;
+    use octolib::llm::{MessageBuilder, TokenUsage};
+    use serde_json::json;
+
+    #[test]
+    fn test_malformed_arguments() {
+        assert!(is_malformed_arguments(&json!("{\"city\": \"Oslo\"")));
+        assert!(!is_malformed_arguments(&json!("{\"city\": \"Oslo\"}")));
+        assert!(!is_malformed_arguments(&json!({"city": "Oslo"})));
+        assert!(!is_malformed_arguments(&json!("")));
+    }
+
+    #[test]
+    fn test_parse_streamed_arguments() {
+        assert_eq!(
+            parse_streamed_arguments("weather", "{\"city\": \"Oslo\"}", false).unwrap(),
+            json!({"city": "Oslo"})
+        );
+        assert_eq!(
+            parse_streamed_arguments("time", "", false).unwrap(),
+            json!({})
+        );
+        assert!(parse_streamed_arguments("weather", "{city: 'Oslo',}", false).is_err());
+        assert_eq!(
+            parse_streamed_arguments("weather", "{city: 'Oslo',}", true).unwrap(),
+            json!({"city": "Oslo"})
+        );
+    }
+
+    #[test]
+    fn test_decode_double_encoded_arguments() {
+        assert_eq!(
+            decode_arguments(json!("{\"city\": \"Oslo\"}")),
+            json!({"city": "Oslo"})
+        );
+        assert_eq!(
+            decode_arguments(json!({"city": "Oslo"})),
+            json!({"city": "Oslo"})
+        );
+        assert_eq!(decode_arguments(json!("not json")), json!("not json"));
+    }
+
+    #[test]
+    fn test_coercion() {
+        assert_eq!(Coercion::Int.apply(&json!("42")), Some(json!(42)));
+        assert_eq!(Coercion::Int.apply(&json!(3.0)), Some(json!(3)));
+        assert_eq!(Coercion::Int.apply(&json!(3.5)), None);
+        assert_eq!(Coercion::Float.apply(&json!("2.5")), Some(json!(2.5)));
+        assert_eq!(Coercion::Bool.apply(&json!("false")), Some(json!(false)));
+        assert_eq!(Coercion::Bool.apply(&json!(1)), Some(json!(true)));
+        assert_eq!(Coercion::Bool.apply(&json!("maybe")), None);
+    }
+
+    #[test]
+    fn test_add_usage() {
+        let usage = |tokens: u32, cost: Option<f64>| TokenUsage {
+            input_tokens: tokens as _,
+            output_tokens: tokens as _,
+            reasoning_tokens: 0,
+            total_tokens: (2 * tokens) as _,
+            cache_read_tokens: 0,
+            cache_write_tokens: 0,
+            cost,
+            request_time_ms: None,
+        };
+        assert!(add_usage(None, None).is_none());
+        assert_eq!(
+            add_usage(None, Some(usage(3, None))).unwrap().total_tokens,
+            6
+        );
+
+        let total = add_usage(Some(usage(3, Some(0.5))), Some(usage(4, None))).unwrap();
+        assert_eq!(total.input_tokens, 7);
+        assert_eq!(total.total_tokens, 14);
+        assert_eq!(total.cost, Some(0.5));
+        assert_eq!(total.request_time_ms, None);
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let call = ToolCall::new(
+            "call_1".into(),
+            "get_weather".into(),
+            json!({"city": "Oslo"}),
+        )
+        .unwrap();
+        let mut response = ToolResponse::new_with_opt_usage(
+            String::new(),
+            vec![call],
+            None,
+            "gpt-4o".into(),
+            None,
+        );
+        response.pending = Some(PendingLoop {
+            options: LoopOptions {
+                model: "openai:gpt-4o".into(),
+                temperature: 0.2,
+                max_tokens: 500,
+                top_p: 1.0,
+                top_k: 50,
+                penalties: Penalties::default(),
+                stop: Vec::new(),
+                tools: Some(vec!["get_weather".into()]),
+            },
+            conversation: vec![MessageBuilder::user("Weather in Oslo?").build().unwrap()],
+        });
+        response.awaiting_approval = true;
+
+        let restored = ToolResponse::from_state(response.get_state().unwrap()).unwrap();
+        assert!(restored.requires_approval());
+        assert_eq!(restored.tool_calls[0].name, "get_weather");
+        assert_eq!(restored.tool_calls[0].arguments_json, "{\"city\":\"Oslo\"}");
+        let pending = restored.pending.unwrap();
+        assert_eq!(pending.options.model, "openai:gpt-4o");
+        assert_eq!(pending.options.max_tokens, 500);
+        assert_eq!(
+            restored.selected_tools,
+            Some(vec!["get_weather".to_string()])
+        );
+        assert_eq!(pending.conversation[0].content, "Weather in Oslo?");
+    }
+
+    #[test]
+    fn test_loop_slots_are_limited() {
+        let base = ACTIVE_LOOPS.load(Ordering::Acquire);
+        let first = LoopSlot::try_acquire(base + 2).unwrap();
+        let second = LoopSlot::try_acquire(base + 2).unwrap();
+        assert!(LoopSlot::try_acquire(base + 2).is_none());
+        drop(first);
+        let third = LoopSlot::try_acquire(base + 2).unwrap();
+        assert!(LoopSlot::try_acquire(0).is_some());
+        drop((second, third));
+        assert_eq!(ACTIVE_LOOPS.load(Ordering::Acquire), base);
+    }
+}
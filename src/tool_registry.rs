@@ -0,0 +1,259 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendClassObject, Zval};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+
+use crate::tool_builder::Tool;
+
+/// Tool definitions of the global registry, shared by every thread of the
+/// process. Writers swap in a new list, so readers keep a consistent snapshot.
+static GLOBAL_TOOLS: LazyLock<RwLock<Arc<Vec<Tool>>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(Vec::new())));
+
+/// Global tools registered with a handler. Their definitions outlive the
+/// handler, so a thread without one fails instead of offering the tool with
+/// nothing to run it.
+static GLOBAL_HANDLED: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+// Handlers of the global registry. PHP callables belong to the thread and
+// request that created them, so they are kept per thread and dropped at
+// request shutdown; worker-mode scripts keep theirs for the worker's life.
+thread_local! {
+    static GLOBAL_HANDLERS: RefCell<HashMap<String, Zval>> = RefCell::new(HashMap::new());
+}
+
+/// Drop the global registry's handlers for the ending request
+pub(crate) fn reset() {
+    GLOBAL_HANDLERS.with(|h| h.borrow_mut().clear());
+}
+
+fn global_tools() -> Arc<Vec<Tool>> {
+    Arc::clone(&GLOBAL_TOOLS.read().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Add a tool, replacing one with the same name in place
+pub(crate) fn upsert(tools: &mut Vec<Tool>, tool: Tool) {
+    match tools.iter_mut().find(|t| t.name == tool.name) {
+        Some(existing) => *existing = tool,
+        None => tools.push(tool),
+    }
+}
+
+fn remove(tools: &mut Vec<Tool>, name: &str) {
+    tools.retain(|t| t.name != name);
+}
+
+/// Named tools with their handlers, registered once and reused by many
+/// ToolBuilders. ToolRegistry::global() is shared across requests and LLM
+/// instances; fork() and new registries are private copies.
+#[php_class]
+pub struct ToolRegistry {
+    /// Whether this is a handle to the process-wide registry
+    global: bool,
+    /// Definitions of a local registry, shared with the registry it was
+    /// forked from until either is modified
+    tools: Arc<Vec<Tool>>,
+    handlers: HashMap<String, Zval>,
+}
+
+// Internal methods - not exposed to PHP
+impl ToolRegistry {
+    fn snapshot(&self) -> Arc<Vec<Tool>> {
+        if self.global {
+            global_tools()
+        } else {
+            Arc::clone(&self.tools)
+        }
+    }
+
+    /// Handler of a tool. Fails for a global tool registered with a handler
+    /// by another thread or an earlier request, which this one can't call.
+    fn handler(&self, name: &str) -> PhpResult<Option<Zval>> {
+        if !self.global {
+            return Ok(self.handlers.get(name).map(Zval::shallow_clone));
+        }
+        let handler = GLOBAL_HANDLERS.with(|h| h.borrow().get(name).map(Zval::shallow_clone));
+        let handled = GLOBAL_HANDLED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(name);
+        if handler.is_none() && handled {
+            return Err(PhpException::from_class::<crate::error::LLMException>(
+                format!(
+                    "Handler for global tool '{name}' is not available in this request; \
+                     handlers last until the request that registered them ends"
+                ),
+            ));
+        }
+        Ok(handler)
+    }
+
+    /// Every tool with its handler, in registration order
+    pub(crate) fn entries(&self) -> PhpResult<Vec<(Tool, Option<Zval>)>> {
+        self.snapshot()
+            .iter()
+            .map(|tool| Ok((tool.clone(), self.handler(&tool.name)?)))
+            .collect()
+    }
+}
+
+#[php_impl]
+impl ToolRegistry {
+    /// Create an empty registry local to the current request
+    #[php(constructor)]
+    pub fn __construct() -> Self {
+        Self {
+            global: false,
+            tools: Arc::new(Vec::new()),
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// The process-wide registry. Definitions registered here are visible
+    /// to every request and thread; handlers stay with the thread that
+    /// registered them until its request ends, so register them at
+    /// worker start-up. Using a tool registered with a handler where that
+    /// handler is gone throws an LLMException.
+    pub fn global() -> Self {
+        Self {
+            global: true,
+            ..Self::__construct()
+        }
+    }
+
+    /// Register a tool, optionally with the handler that executes it. A
+    /// tool with the same name is replaced.
+    pub fn register<'a>(
+        self_: &'a mut ZendClassObject<ToolRegistry>,
+        tool: &Tool,
+        handler: Option<&Zval>,
+    ) -> PhpResult<&'a mut ZendClassObject<ToolRegistry>> {
+        if handler.is_some_and(|h| !h.is_callable()) {
+            return Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Handler for tool '{}' must be callable",
+                tool.name
+            )));
+        }
+        let handler = handler.map(Zval::shallow_clone);
+        if self_.global {
+            let mut tools = GLOBAL_TOOLS.write().unwrap_or_else(|e| e.into_inner());
+            upsert(Arc::make_mut(&mut *tools), tool.clone());
+            drop(tools);
+            let mut handled = GLOBAL_HANDLED.lock().unwrap_or_else(|e| e.into_inner());
+            match handler {
+                Some(_) => handled.insert(tool.name.clone()),
+                None => handled.remove(&tool.name),
+            };
+            drop(handled);
+            GLOBAL_HANDLERS.with(|h| {
+                let mut handlers = h.borrow_mut();
+                match handler {
+                    Some(handler) => handlers.insert(tool.name.clone(), handler),
+                    None => handlers.remove(&tool.name),
+                };
+            });
+        } else {
+            upsert(Arc::make_mut(&mut self_.tools), tool.clone());
+            match handler {
+                Some(handler) => self_.handlers.insert(tool.name.clone(), handler),
+                None => self_.handlers.remove(&tool.name),
+            };
+        }
+        Ok(self_)
+    }
+
+    /// Remove a tool and its handler
+    pub fn unregister(
+        self_: &mut ZendClassObject<ToolRegistry>,
+        name: String,
+    ) -> &mut ZendClassObject<ToolRegistry> {
+        if self_.global {
+            let mut tools = GLOBAL_TOOLS.write().unwrap_or_else(|e| e.into_inner());
+            remove(Arc::make_mut(&mut *tools), &name);
+            drop(tools);
+            GLOBAL_HANDLED
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&name);
+            GLOBAL_HANDLERS.with(|h| h.borrow_mut().remove(&name));
+        } else {
+            remove(Arc::make_mut(&mut self_.tools), &name);
+            self_.handlers.remove(&name);
+        }
+        self_
+    }
+
+    /// Copy of this registry for the current request. Changes to the copy
+    /// don't reach the original, nor the other way round; the definitions
+    /// are only copied once one side is modified.
+    pub fn fork(&self) -> PhpResult<Self> {
+        let tools = self.snapshot();
+        let mut handlers = HashMap::new();
+        for tool in tools.iter() {
+            if let Some(handler) = self.handler(&tool.name)? {
+                handlers.insert(tool.name.clone(), handler);
+            }
+        }
+        Ok(Self {
+            global: false,
+            tools,
+            handlers,
+        })
+    }
+
+    pub fn has(&self, name: String) -> bool {
+        self.snapshot().iter().any(|t| t.name == name)
+    }
+
+    pub fn get(&self, name: String) -> Option<Tool> {
+        self.snapshot().iter().find(|t| t.name == name).cloned()
+    }
+
+    /// Names of the registered tools, in registration order
+    pub fn names(&self) -> Vec<String> {
+        self.snapshot().iter().map(|t| t.name.clone()).collect()
+    }
+
+    pub fn count(&self) -> i64 {
+        self.snapshot().len() as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool(name: &str, description: &str) -> Tool {
+        Tool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters: "{}".into(),
+            binding: None,
+        }
+    }
+
+    #[test]
+    fn test_upsert_replaces_by_name() {
+        let mut tools = vec![tool("weather", "old"), tool("time", "")];
+        upsert(&mut tools, tool("weather", "new"));
+        upsert(&mut tools, tool("search", ""));
+        let names: Vec<&str> = tools.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["weather", "time", "search"]);
+        assert_eq!(tools[0].description, "new");
+        remove(&mut tools, "time");
+        assert_eq!(tools.len(), 2);
+    }
+
+    #[test]
+    fn test_forked_changes_stay_local() {
+        let shared = Arc::new(vec![tool("weather", "")]);
+        let mut fork = Arc::clone(&shared);
+        upsert(Arc::make_mut(&mut fork), tool("search", ""));
+        assert_eq!(shared.len(), 1);
+        assert_eq!(fork.len(), 2);
+    }
+}
@@ -0,0 +1,169 @@
+use ext_php_rs::prelude::*;
+use octolib::llm::Message as OctoMessage;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{LazyLock, Mutex};
+
+use crate::config::ProviderConfig;
+use crate::semantic_cache::cosine;
+use crate::tool_builder::Tool;
+
+/// Embeddings of tool descriptions by (embedding model, tool hash), shared by
+/// every request served by this process so each tool is embedded once
+static TOOL_VECTORS: LazyLock<Mutex<HashMap<(String, u64), Vec<f32>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Text embedded for a tool
+fn tool_text(tool: &Tool) -> String {
+    format!("{}: {}", tool.name, tool.description)
+}
+
+fn tool_hash(text: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    text.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Indices of the `k` highest scores, in their original order
+fn top_k(scores: &[f32], k: usize) -> Vec<usize> {
+    let mut ranked: Vec<usize> = (0..scores.len()).collect();
+    ranked.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+    ranked.truncate(k);
+    ranked.sort_unstable();
+    ranked
+}
+
+/// Text of the last user message, which the tools are matched against
+fn query(conversation: &[OctoMessage]) -> Option<&str> {
+    conversation
+        .iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .map(|m| m.content.as_str())
+        .filter(|text| !text.trim().is_empty())
+}
+
+/// Send only the tools most relevant to the request, see
+/// ToolBuilder::selectTools()
+#[derive(Debug, Clone)]
+pub(crate) struct ToolSelection {
+    top_k: usize,
+    embedding_model: String,
+}
+
+impl ToolSelection {
+    pub(crate) fn new(
+        top_k: usize,
+        embedding_model: String,
+        config: &ProviderConfig,
+    ) -> PhpResult<Self> {
+        crate::embeddings::check_model(&embedding_model, config)?;
+        Ok(Self {
+            top_k,
+            embedding_model,
+        })
+    }
+
+    /// Vectors of the tools, embedding those not seen before in one request
+    fn tool_vectors(&self, tools: &[Tool], config: &ProviderConfig) -> PhpResult<Vec<Vec<f32>>> {
+        let texts: Vec<String> = tools.iter().map(tool_text).collect();
+        let keys: Vec<(String, u64)> = texts
+            .iter()
+            .map(|text| (self.embedding_model.clone(), tool_hash(text)))
+            .collect();
+
+        let missing: Vec<usize> = {
+            let vectors = TOOL_VECTORS.lock().unwrap_or_else(|e| e.into_inner());
+            (0..keys.len())
+                .filter(|&i| !vectors.contains_key(&keys[i]))
+                .collect()
+        };
+        if !missing.is_empty() {
+            let batch: Vec<String> = missing.iter().map(|&i| texts[i].clone()).collect();
+            let embedded = crate::embeddings::embed_texts(&self.embedding_model, config, &batch)?;
+            let mut vectors = TOOL_VECTORS.lock().unwrap_or_else(|e| e.into_inner());
+            for (i, vector) in missing.into_iter().zip(embedded) {
+                vectors.insert(keys[i].clone(), vector);
+            }
+        }
+
+        let vectors = TOOL_VECTORS.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(keys
+            .iter()
+            .map(|key| vectors.get(key).cloned().unwrap_or_default())
+            .collect())
+    }
+
+    /// Names of the tools to send for `conversation`: the `top_k` whose
+    /// descriptions are closest to the last user message, plus `required`
+    /// (a tool the choice forces). None when every tool is sent, because
+    /// there are few enough or the embedding request failed.
+    pub(crate) fn select(
+        &self,
+        tools: &[Tool],
+        conversation: &[OctoMessage],
+        required: Option<&str>,
+        config: &ProviderConfig,
+    ) -> Option<Vec<String>> {
+        if tools.len() <= self.top_k {
+            return None;
+        }
+        let query = query(conversation)?;
+        let scores =
+            crate::embeddings::embed_text(&self.embedding_model, config, query).and_then(|query| {
+                let vectors = self.tool_vectors(tools, config)?;
+                Ok(vectors
+                    .iter()
+                    .map(|v| cosine(&query, v))
+                    .collect::<Vec<_>>())
+            });
+        let scores = match scores {
+            Ok(scores) => scores,
+            Err(_) => {
+                crate::logger::warn("Tool selection failed to embed; sending every tool");
+                return None;
+            }
+        };
+
+        let mut selected: Vec<String> = top_k(&scores, self.top_k)
+            .into_iter()
+            .map(|i| tools[i].name.clone())
+            .collect();
+        if let Some(name) = required {
+            if !selected.iter().any(|s| s == name) {
+                selected.push(name.to_string());
+            }
+        }
+        Some(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k_keeps_original_order() {
+        assert_eq!(top_k(&[0.1, 0.9, 0.5, 0.7], 2), vec![1, 3]);
+        assert_eq!(top_k(&[0.3, 0.2], 5), vec![0, 1]);
+        assert!(top_k(&[0.3, 0.2], 0).is_empty());
+    }
+
+    #[test]
+    fn test_query_is_last_user_message() {
+        let message = |role: &str, content: &str| {
+            let mut message = octolib::llm::MessageBuilder::user(content).build().unwrap();
+            message.role = role.to_string();
+            message
+        };
+        let conversation = vec![
+            message("user", "What's the weather?"),
+            message("assistant", "Where?"),
+            message("user", "In Berlin"),
+            message("tool", "{}"),
+        ];
+        assert_eq!(query(&conversation), Some("In Berlin"));
+        assert_eq!(query(&[message("system", "Be brief")]), None);
+    }
+}
@@ -0,0 +1,221 @@
+use serde_json::Value;
+
+/// One message as it appears in a rendered transcript
+pub(crate) struct Turn<'a> {
+    pub role: &'a str,
+    pub content: &'a str,
+    pub tool_call_id: Option<&'a str>,
+    /// Tool calls as stored on the message: a JSON array in either the
+    /// extension's `{id, name, arguments}` shape or OpenAI's `function` shape
+    pub tool_calls: Option<&'a str>,
+    pub images: usize,
+}
+
+/// Tool call normalized for display
+struct CallSummary {
+    id: String,
+    name: String,
+    arguments: String,
+}
+
+fn tool_calls(raw: Option<&str>) -> Vec<CallSummary> {
+    let Some(Value::Array(calls)) = raw.and_then(|r| serde_json::from_str(r).ok()) else {
+        return Vec::new();
+    };
+    calls
+        .iter()
+        .map(|call| {
+            let function = call.get("function").unwrap_or(call);
+            let arguments = match &function["arguments"] {
+                Value::String(raw) => serde_json::from_str::<Value>(raw)
+                    .ok()
+                    .and_then(|v| serde_json::to_string_pretty(&v).ok())
+                    .unwrap_or_else(|| raw.clone()),
+                Value::Null => "{}".to_string(),
+                other => serde_json::to_string_pretty(other).unwrap_or_default(),
+            };
+            CallSummary {
+                id: call["id"].as_str().unwrap_or_default().to_string(),
+                name: function["name"].as_str().unwrap_or_default().to_string(),
+                arguments,
+            }
+        })
+        .collect()
+}
+
+fn role_label(role: &str) -> &str {
+    match role {
+        "system" => "System",
+        "user" => "User",
+        "assistant" => "Assistant",
+        "tool" => "Tool result",
+        other => other,
+    }
+}
+
+/// Fenced code block whose fence is longer than any backtick run inside
+fn fenced(content: &str, lang: &str) -> String {
+    let longest = content.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{lang}\n{content}\n{fence}")
+}
+
+fn images_note(count: usize) -> String {
+    match count {
+        1 => "[1 image]".to_string(),
+        n => format!("[{n} images]"),
+    }
+}
+
+pub(crate) fn to_markdown(turns: &[Turn]) -> String {
+    let sections: Vec<String> = turns
+        .iter()
+        .map(|turn| {
+            let mut parts = Vec::new();
+            match (turn.role, turn.tool_call_id) {
+                ("tool", Some(id)) => parts.push(format!("### Tool result (`{id}`)")),
+                (role, _) => parts.push(format!("### {}", role_label(role))),
+            }
+
+            if turn.role == "tool" {
+                parts.push(fenced(turn.content, ""));
+            } else if !turn.content.is_empty() {
+                parts.push(turn.content.to_string());
+            }
+            if turn.images > 0 {
+                parts.push(format!("_{}_", images_note(turn.images)));
+            }
+            for call in tool_calls(turn.tool_calls) {
+                parts.push(format!("**Tool call** `{}` (`{}`)", call.name, call.id));
+                parts.push(fenced(&call.arguments, "json"));
+            }
+            parts.join("\n\n")
+        })
+        .collect();
+
+    sections.join("\n\n---\n\n") + "\n"
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// HTML fragment with one `<section>` per message. Class names
+/// (`message-user`, `tool-call`, ...) are left for the caller to style.
+pub(crate) fn to_html(turns: &[Turn]) -> String {
+    let mut html = String::from("<div class=\"transcript\">\n");
+    for turn in turns {
+        html.push_str(&format!(
+            "<section class=\"message message-{}\">\n",
+            escape_html(turn.role)
+        ));
+        match (turn.role, turn.tool_call_id) {
+            ("tool", Some(id)) => html.push_str(&format!(
+                "<h3 class=\"role\">Tool result <code>{}</code></h3>\n",
+                escape_html(id)
+            )),
+            (role, _) => html.push_str(&format!(
+                "<h3 class=\"role\">{}</h3>\n",
+                escape_html(role_label(role))
+            )),
+        }
+
+        if turn.role == "tool" {
+            html.push_str(&format!(
+                "<pre class=\"content\">{}</pre>\n",
+                escape_html(turn.content)
+            ));
+        } else if !turn.content.is_empty() {
+            html.push_str(&format!(
+                "<div class=\"content\">{}</div>\n",
+                escape_html(turn.content).replace('\n', "<br>\n")
+            ));
+        }
+        if turn.images > 0 {
+            html.push_str(&format!(
+                "<p class=\"images\">{}</p>\n",
+                images_note(turn.images)
+            ));
+        }
+        for call in tool_calls(turn.tool_calls) {
+            html.push_str(&format!(
+                "<div class=\"tool-call\"><strong>{}</strong> <code>{}</code><pre>{}</pre></div>\n",
+                escape_html(&call.name),
+                escape_html(&call.id),
+                escape_html(&call.arguments)
+            ));
+        }
+        html.push_str("</section>\n");
+    }
+    html.push_str("</div>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn<'a>(role: &'a str, content: &'a str) -> Turn<'a> {
+        Turn {
+            role,
+            content,
+            tool_call_id: None,
+            tool_calls: None,
+            images: 0,
+        }
+    }
+
+    #[test]
+    fn test_markdown_transcript() {
+        let calls = r#"[{"id":"call_1","name":"get_weather","arguments":{"city":"Oslo"}}]"#;
+        let turns = [
+            turn("user", "Weather in Oslo?"),
+            Turn {
+                tool_calls: Some(calls),
+                ..turn("assistant", "")
+            },
+            Turn {
+                tool_call_id: Some("call_1"),
+                ..turn("tool", "{\"temp\": 4}")
+            },
+        ];
+        let markdown = to_markdown(&turns);
+        assert!(markdown.starts_with("### User\n\nWeather in Oslo?\n\n---\n\n### Assistant"));
+        assert!(markdown.contains("**Tool call** `get_weather` (`call_1`)"));
+        assert!(markdown.contains("\"city\": \"Oslo\""));
+        assert!(markdown.contains("### Tool result (`call_1`)\n\n```\n{\"temp\": 4}\n```"));
+    }
+
+    #[test]
+    fn test_openai_shaped_tool_calls() {
+        let calls = tool_calls(Some(
+            r#"[{"id":"c","type":"function","function":{"name":"f","arguments":"{\"a\":1}"}}]"#,
+        ));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "f");
+        assert_eq!(calls[0].arguments, "{\n  \"a\": 1\n}");
+    }
+
+    #[test]
+    fn test_fence_longer_than_content_backticks() {
+        assert_eq!(fenced("a ```` b", ""), "`````\na ```` b\n`````");
+    }
+
+    #[test]
+    fn test_html_escapes_content() {
+        let html = to_html(&[turn("user", "<b>hi</b>\nthere")]);
+        assert!(html.contains("<h3 class=\"role\">User</h3>"));
+        assert!(html.contains("&lt;b&gt;hi&lt;/b&gt;<br>\nthere"));
+    }
+}
@@ -0,0 +1,307 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use serde_json::{json, Value};
+
+use crate::llm_class::Usage;
+
+/// Default chunk size in characters, small enough for the translated chunk to
+/// fit the default completion budget
+pub(crate) const DEFAULT_CHUNK_CHARS: usize = 2000;
+
+/// Options accepted by LLM::translate()
+pub(crate) struct TranslateOptions {
+    /// Source term and its required translation
+    pub glossary: Vec<(String, String)>,
+    pub source_lang: Option<String>,
+    pub chunk_chars: usize,
+}
+
+impl TranslateOptions {
+    pub(crate) fn parse(options: Option<&PhpArray>) -> PhpResult<Self> {
+        let invalid = |msg: &str| {
+            PhpException::from_class::<crate::error::LLMValidationException>(msg.into())
+        };
+        let mut parsed = Self {
+            glossary: Vec::new(),
+            source_lang: None,
+            chunk_chars: DEFAULT_CHUNK_CHARS,
+        };
+        let Some(options) = options else {
+            return Ok(parsed);
+        };
+
+        if let Some(glossary) = options.get("glossary") {
+            let glossary = glossary.array().ok_or_else(|| {
+                invalid("Option 'glossary' must be an array of term => translation")
+            })?;
+            for (term, value) in glossary.iter() {
+                let translation = value.string().ok_or_else(|| {
+                    PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                        "Glossary translation for '{term}' must be a string"
+                    ))
+                })?;
+                let term = term.to_string();
+                if !term.trim().is_empty() {
+                    parsed.glossary.push((term, translation));
+                }
+            }
+        }
+        if let Some(lang) = options.get("source_lang") {
+            parsed.source_lang = Some(
+                lang.string()
+                    .ok_or_else(|| invalid("Option 'source_lang' must be a string"))?,
+            );
+        }
+        if let Some(size) = options.get("chunk_size") {
+            parsed.chunk_chars =
+                size.long().filter(|n| *n >= 100).ok_or_else(|| {
+                    invalid("Option 'chunk_size' must be an integer of at least 100")
+                })? as usize;
+        }
+        Ok(parsed)
+    }
+}
+
+/// Split `text` into contiguous chunks of at most `max_chars` characters,
+/// breaking between paragraphs, then between sentences, and only as a last
+/// resort inside a sentence. Concatenating the chunks gives back `text`.
+pub(crate) fn chunks(text: &str, max_chars: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    for paragraph in text.split_inclusive("\n\n") {
+        if paragraph.chars().count() <= max_chars {
+            pieces.push(paragraph);
+            continue;
+        }
+        for sentence in paragraph.split_inclusive(['.', '!', '?', '。', '\n']) {
+            let mut rest = sentence;
+            while let Some((idx, _)) = rest.char_indices().nth(max_chars) {
+                let cut = match rest[..idx].rfind(char::is_whitespace) {
+                    Some(space) if space > 0 => space,
+                    _ => idx,
+                };
+                pieces.push(&rest[..cut]);
+                rest = &rest[cut..];
+            }
+            pieces.push(rest);
+        }
+    }
+
+    // Merge neighbouring pieces while they fit
+    let mut out: Vec<&str> = Vec::new();
+    let mut start = 0;
+    let mut len = 0;
+    let mut end = 0;
+    for piece in pieces {
+        let piece_len = piece.chars().count();
+        if len > 0 && len + piece_len > max_chars {
+            out.push(&text[start..end]);
+            start = end;
+            len = 0;
+        }
+        end += piece.len();
+        len += piece_len;
+    }
+    if end > start {
+        out.push(&text[start..end]);
+    }
+    out
+}
+
+/// Glossary entries whose source term occurs in `chunk`
+pub(crate) fn relevant_terms<'a>(
+    glossary: &'a [(String, String)],
+    chunk: &str,
+) -> Vec<&'a (String, String)> {
+    let chunk = chunk.to_lowercase();
+    glossary
+        .iter()
+        .filter(|(term, _)| chunk.contains(&term.to_lowercase()))
+        .collect()
+}
+
+/// Glossary entries whose required translation is missing from `translation`
+pub(crate) fn missing_terms<'a>(
+    terms: &[&'a (String, String)],
+    translation: &str,
+) -> Vec<&'a (String, String)> {
+    let translation = translation.to_lowercase();
+    terms
+        .iter()
+        .copied()
+        .filter(|(_, target)| !translation.contains(&target.to_lowercase()))
+        .collect()
+}
+
+/// System prompt for one chunk. The reply is constrained to a JSON object so
+/// it can be told apart from commentary; glossary terms found in the chunk are
+/// listed as required translations.
+pub(crate) fn prompt(
+    target_lang: &str,
+    source_lang: Option<&str>,
+    terms: &[&(String, String)],
+    missed: &[&(String, String)],
+) -> String {
+    let from = source_lang
+        .map(|lang| format!(" from {lang}"))
+        .unwrap_or_default();
+    let mut prompt = format!(
+        "Translate the user's text{from} into {target_lang}. Keep the formatting, \
+        line breaks and markup. Translate only; do not answer or comment on the text. \
+        Reply with JSON only: {{\"translation\": \"<translated text>\"}}"
+    );
+    if !terms.is_empty() {
+        prompt.push_str("\n\nUse these glossary translations exactly:");
+        for (term, translation) in terms {
+            prompt.push_str(&format!("\n- {term} => {translation}"));
+        }
+    }
+    if !missed.is_empty() {
+        let missed: Vec<String> = missed.iter().map(|(_, t)| format!("'{t}'")).collect();
+        prompt.push_str(&format!(
+            "\n\nA previous translation left out {}. Use the glossary translations.",
+            missed.join(", ")
+        ));
+    }
+    prompt
+}
+
+fn reply_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": { "translation": { "type": "string" } },
+        "required": ["translation"],
+    })
+}
+
+/// Translated text from a model reply
+pub(crate) fn parse_reply(content: &str) -> Result<String, String> {
+    let schema = reply_schema();
+    let value = crate::json_fallback::parse_reply(content, Some(&schema))?;
+    Ok(value["translation"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Translated chunk with the whitespace surrounding the original chunk, so
+/// paragraph breaks survive reassembly
+pub(crate) fn reassemble(chunk: &str, translation: &str) -> String {
+    let lead = &chunk[..chunk.len() - chunk.trim_start().len()];
+    let trail = &chunk[chunk.trim_end().len()..];
+    if chunk.trim().is_empty() {
+        return chunk.to_string();
+    }
+    format!("{lead}{}{trail}", translation.trim())
+}
+
+/// Result of LLM::translate()
+#[php_class]
+#[derive(Clone)]
+pub struct Translation {
+    text: String,
+    usage: Usage,
+    model: String,
+    chunks: usize,
+    missing_terms: Vec<String>,
+}
+
+// Internal constructor - not exposed to PHP
+impl Translation {
+    pub(crate) fn new(
+        text: String,
+        usage: Usage,
+        model: String,
+        chunks: usize,
+        missing_terms: Vec<String>,
+    ) -> Self {
+        Self {
+            text,
+            usage,
+            model,
+            chunks,
+            missing_terms,
+        }
+    }
+}
+
+#[php_impl]
+impl Translation {
+    pub fn get_text(&self) -> String {
+        self.text.clone()
+    }
+
+    /// Usage summed over every request made for the translation
+    pub fn get_usage(&self) -> Usage {
+        self.usage.clone()
+    }
+
+    pub fn get_model(&self) -> String {
+        self.model.clone()
+    }
+
+    /// Number of chunks the text was translated in
+    pub fn get_chunk_count(&self) -> i64 {
+        self.chunks as i64
+    }
+
+    /// Glossary terms the model still left out after a corrective retry
+    pub fn get_missing_terms(&self) -> Vec<String> {
+        self.missing_terms.clone()
+    }
+
+    pub fn to_array(&self) -> PhpResult<Zval> {
+        let mut arr = PhpArray::new();
+        arr.insert("text", self.text.clone())?;
+        arr.insert("usage", self.usage.to_array()?)?;
+        arr.insert("model", self.model.clone())?;
+        arr.insert("chunks", self.chunks as i64)?;
+        arr.insert("missing_terms", self.missing_terms.clone())?;
+        Ok(arr.into_zval(false)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_rejoin_to_text() {
+        let text = format!(
+            "{}\n\n{}\n\nShort closing paragraph.",
+            "First sentence here. ".repeat(10),
+            "x".repeat(250)
+        );
+        let parts = chunks(&text, 100);
+        assert!(parts.len() > 3);
+        assert!(parts.iter().all(|c| c.chars().count() <= 100));
+        assert_eq!(parts.concat(), text);
+        assert_eq!(chunks("short", 100), vec!["short"]);
+    }
+
+    #[test]
+    fn test_glossary_terms() {
+        let glossary = vec![
+            ("Manticore".to_string(), "Manticore".to_string()),
+            ("full-text search".to_string(), "Volltextsuche".to_string()),
+            ("shard".to_string(), "Shard".to_string()),
+        ];
+        let terms = relevant_terms(&glossary, "Manticore offers Full-Text Search.");
+        assert_eq!(terms.len(), 2);
+        let missing = missing_terms(&terms, "Manticore bietet eine Textsuche.");
+        assert_eq!(missing, vec![&glossary[1]]);
+    }
+
+    #[test]
+    fn test_parse_reply_and_reassemble() {
+        assert_eq!(
+            parse_reply("{\"translation\": \"Hallo Welt\"}").unwrap(),
+            "Hallo Welt"
+        );
+        assert!(parse_reply("Hallo Welt").is_err());
+        assert_eq!(
+            reassemble("Hello world.\n\n", " Hallo Welt. "),
+            "Hallo Welt.\n\n"
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use ext_php_rs::prelude::*;
+
+/// Tokens a chat format adds around each message (role, separators)
+pub(crate) const MESSAGE_OVERHEAD: usize = 4;
+
+/// Tokens counted per attached image: the low-detail cost, as the real
+/// figure depends on the provider and the image size
+pub(crate) const IMAGE_TOKENS: usize = 85;
+
+/// Completion budget for a summary, reserved out of the token limit
+pub(crate) const SUMMARY_MAX_TOKENS: u32 = 300;
+
+/// System prompt for summarizing dropped messages
+pub(crate) const SUMMARY_PROMPT: &str = "Summarize the conversation below in a few \
+sentences for the assistant continuing it. Keep names, figures, decisions and open \
+questions. Use the language of the conversation. Reply with the summary only.";
+
+/// First line of the message holding a summary, used to find it again when
+/// the conversation is trimmed once more
+pub(crate) const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:";
+
+const MAX_MESSAGE_CHARS: usize = 4000;
+const MAX_TRANSCRIPT_CHARS: usize = 60_000;
+
+/// What to do with the messages trimmed from a conversation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TrimStrategy {
+    DropOldest,
+    Summarize,
+}
+
+impl TrimStrategy {
+    pub(crate) fn parse(strategy: &str) -> PhpResult<Self> {
+        match strategy {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "summarize" => Ok(Self::Summarize),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown truncation strategy '{other}', expected 'drop_oldest' or 'summarize'"
+            ))),
+        }
+    }
+}
+
+/// Messages to drop, oldest first, to bring the sum of `tokens` within
+/// `max`. System messages and the final message are kept; tool results go
+/// with the turn that called them, and the conversation is left starting
+/// with a user message where possible.
+pub(crate) fn plan_drop(roles: &[&str], tokens: &[usize], max: usize) -> Vec<bool> {
+    let mut total: usize = tokens.iter().sum();
+    let mut drop = vec![false; roles.len()];
+    let Some(last) = roles.len().checked_sub(1) else {
+        return drop;
+    };
+
+    let mut i = 0;
+    while i < last && (total > max || (drop.contains(&true) && roles[i] != "user")) {
+        if roles[i] != "system" {
+            drop[i] = true;
+            total -= tokens[i];
+        }
+        i += 1;
+    }
+    drop
+}
+
+/// Transcript of the dropped messages for the summarizer, each message
+/// truncated and the whole kept within a fixed budget
+pub(crate) fn transcript<'a>(messages: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let mut out = String::new();
+    for (role, content) in messages {
+        let content = content.trim();
+        if content.is_empty() {
+            continue;
+        }
+        let label = match role {
+            "user" => "User",
+            "assistant" => "Assistant",
+            "tool" => "Tool result",
+            _ => "Earlier summary",
+        };
+        let content = content
+            .strip_prefix(SUMMARY_PREFIX)
+            .unwrap_or(content)
+            .trim();
+        let entry = format!(
+            "{label}: {}\n\n",
+            crate::title::truncate(content, MAX_MESSAGE_CHARS)
+        );
+        if !out.is_empty() && out.len() + entry.len() > MAX_TRANSCRIPT_CHARS {
+            break;
+        }
+        out.push_str(&entry);
+    }
+    out.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_keeps_system_and_latest() {
+        let roles = ["system", "user", "assistant", "user", "assistant", "user"];
+        let tokens = [50, 100, 100, 100, 100, 100];
+        assert_eq!(
+            plan_drop(&roles, &tokens, 360),
+            [false, true, true, false, false, false]
+        );
+        assert_eq!(plan_drop(&roles, &tokens, 1000), [false; 6]);
+        // Over budget even with everything dropped: the last turn stays
+        assert_eq!(
+            plan_drop(&roles, &tokens, 10),
+            [false, true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_plan_drops_whole_tool_turns() {
+        let roles = ["user", "assistant", "tool", "tool", "assistant", "user"];
+        let tokens = [10, 10, 10, 10, 10, 10];
+        // Dropping one message would leave the tool calls' turn half gone
+        assert_eq!(
+            plan_drop(&roles, &tokens, 55),
+            [true, true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn test_transcript_labels() {
+        let summary = format!("{SUMMARY_PREFIX}\nThe user is Ann.");
+        let messages = [
+            ("system", summary.as_str()),
+            ("user", "Hi"),
+            ("assistant", " "),
+        ];
+        assert_eq!(
+            transcript(messages.into_iter()),
+            "Earlier summary: The user is Ann.\n\nUser: Hi"
+        );
+    }
+}
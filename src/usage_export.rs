@@ -0,0 +1,73 @@
+use ext_php_rs::convert::IntoZval;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendCallable, ZendHashTable as PhpArray, Zval};
+use octolib::llm::TokenUsage;
+use std::cell::{Cell, RefCell};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::attribution::Attribution;
+
+// Per-request usage hook, reset from the module's request shutdown hook
+thread_local! {
+    static HOOK: RefCell<Option<Zval>> = const { RefCell::new(None) };
+    /// Set while the hook runs, so calls made by the hook are not exported
+    static EXPORTING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub(crate) fn set_hook(hook: Option<Zval>) {
+    HOOK.with(|h| *h.borrow_mut() = hook);
+}
+
+/// Reset the per-request usage hook
+pub(crate) fn reset() {
+    set_hook(None);
+}
+
+/// Usage record passed to the hook
+fn record(model: &str, usage: &TokenUsage, attribution: &Attribution) -> PhpResult<Zval> {
+    let mut arr = PhpArray::new();
+    arr.insert("model", model)?;
+    arr.insert("input_tokens", usage.input_tokens as i64)?;
+    arr.insert("output_tokens", usage.output_tokens as i64)?;
+    arr.insert("reasoning_tokens", usage.reasoning_tokens as i64)?;
+    arr.insert("cached_tokens", usage.cache_read_tokens as i64)?;
+    arr.insert("total_tokens", usage.total_tokens as i64)?;
+    arr.insert("cost", usage.cost)?;
+    arr.insert("request_time_ms", usage.request_time_ms.map(|ms| ms as i64))?;
+    arr.insert("tenant", attribution.tenant.clone())?;
+    let mut tags = PhpArray::new();
+    for (tag, value) in &attribution.tags {
+        tags.insert(tag.as_str(), value.as_str())?;
+    }
+    arr.insert("tags", tags)?;
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    arr.insert("timestamp", timestamp)?;
+    Ok(arr.into_zval(false)?)
+}
+
+/// Pass a finished request's usage to the hook, if one is set. A failing
+/// hook is logged as a warning and does not fail the request.
+pub(crate) fn export(model: &str, usage: &TokenUsage, attribution: &Attribution) {
+    if EXPORTING.with(Cell::get) {
+        return;
+    }
+    // Clone the callable out, so the hook may replace itself
+    let Some(hook) = HOOK.with(|h| h.borrow().as_ref().map(Zval::shallow_clone)) else {
+        return;
+    };
+
+    EXPORTING.with(|e| e.set(true));
+    let exported = record(model, usage, attribution).is_ok_and(|record| {
+        ZendCallable::new(&hook)
+            .and_then(|f| f.try_call(vec![&record]))
+            .is_ok()
+    });
+    EXPORTING.with(|e| e.set(false));
+
+    if !exported {
+        crate::logger::warn("Usage hook failed; the usage record was not exported");
+    }
+}
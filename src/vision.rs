@@ -0,0 +1,324 @@
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use serde_json::{json, Value};
+
+/// Scale of the coordinates the model is asked for
+const MODEL_SCALE: f64 = 1000.0;
+
+/// Default cap on the objects returned by annotateImage()
+const DEFAULT_MAX_OBJECTS: usize = 50;
+
+/// Units of the returned boxes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coordinates {
+    /// Fractions of the image width and height, 0 to 1
+    Normalized,
+    /// Thousandths of the image width and height, 0 to 1000
+    PerMille,
+    Pixels,
+}
+
+impl Coordinates {
+    fn parse(mode: &str) -> PhpResult<Self> {
+        match mode {
+            "normalized" => Ok(Self::Normalized),
+            "per_mille" => Ok(Self::PerMille),
+            "pixels" => Ok(Self::Pixels),
+            other => Err(PhpException::from_class::<
+                crate::error::LLMValidationException,
+            >(format!(
+                "Unknown coordinates '{other}', expected 'normalized', 'per_mille' or 'pixels'"
+            ))),
+        }
+    }
+}
+
+/// Options for LLM::annotateImage()
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AnnotateOptions {
+    pub coordinates: Coordinates,
+    /// Image size given by the caller, for pixel coordinates
+    pub size: Option<(u32, u32)>,
+    pub max_objects: usize,
+}
+
+impl AnnotateOptions {
+    pub(crate) fn parse(options: Option<&PhpArray>) -> PhpResult<Self> {
+        let invalid = PhpException::from_class::<crate::error::LLMValidationException>;
+        let get = |key: &str| options.and_then(|o| o.get(key));
+        let coordinates = match get("coordinates") {
+            Some(mode) => Coordinates::parse(&mode.string().unwrap_or_default())?,
+            None => Coordinates::Normalized,
+        };
+        let size = match (get("width"), get("height")) {
+            (None, None) => None,
+            (Some(width), Some(height)) => match (width.long(), height.long()) {
+                (Some(w), Some(h)) if w > 0 && h > 0 => Some((w as u32, h as u32)),
+                _ => {
+                    return Err(invalid(
+                        "Options 'width' and 'height' must be positive integers".to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(invalid(
+                    "Options 'width' and 'height' must be given together".to_string(),
+                ))
+            }
+        };
+        let max_objects = match get("max_objects").map(Zval::long) {
+            None => DEFAULT_MAX_OBJECTS,
+            Some(Some(n)) if n > 0 => n as usize,
+            Some(_) => {
+                return Err(invalid(
+                    "Option 'max_objects' must be a positive integer".to_string(),
+                ))
+            }
+        };
+        Ok(Self {
+            coordinates,
+            size,
+            max_objects,
+        })
+    }
+}
+
+/// Schema of the model's reply. Boxes are [ymin, xmin, ymax, xmax] on a
+/// 0-1000 scale, the convention vision models are most often trained on.
+pub(crate) fn schema(labels: &[String]) -> Value {
+    let mut label = json!({"type": "string"});
+    if !labels.is_empty() {
+        label["enum"] = json!(labels);
+    }
+    json!({
+        "type": "object",
+        "properties": {
+            "objects": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "label": label,
+                        "box_2d": {"type": "array", "items": {"type": "number"}},
+                        "confidence": {"type": "number"},
+                    },
+                    "required": ["label", "box_2d", "confidence"],
+                    "additionalProperties": false,
+                },
+            },
+        },
+        "required": ["objects"],
+        "additionalProperties": false,
+    })
+}
+
+/// System prompt describing the annotation format
+pub(crate) fn instruction(labels: &[String], max_objects: usize) -> String {
+    let mut prompt = format!(
+        "Detect the objects in the image and list at most {max_objects} of them in \
+         \"objects\". For each, give a short \"label\", a \"box_2d\" of \
+         [ymin, xmin, ymax, xmax] with coordinates from 0 to 1000 relative to the image \
+         height and width, and a \"confidence\" from 0 to 1. List every instance \
+         separately. Reply with an empty list when nothing is found."
+    );
+    if !labels.is_empty() {
+        prompt.push_str("\nOnly detect objects with one of these labels:");
+        for label in labels {
+            prompt.push_str(&format!("\n- {label}"));
+        }
+    }
+    prompt
+}
+
+/// One detected object, its box as fractions of the image size
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Annotation {
+    pub label: String,
+    pub confidence: Option<f64>,
+    /// Left, top, right, bottom, from 0 to 1
+    pub bounds: [f64; 4],
+}
+
+/// Box from a model's [ymin, xmin, ymax, xmax] on the 0-1000 scale. Boxes
+/// given as fractions are accepted as well; corners are put in order and
+/// clamped to the image, and empty boxes dropped.
+fn bounds(raw: &[Value]) -> Option<[f64; 4]> {
+    let values: Vec<f64> = raw.iter().filter_map(Value::as_f64).collect();
+    let [ymin, xmin, ymax, xmax] = <[f64; 4]>::try_from(values).ok()?;
+    let scale = if [ymin, xmin, ymax, xmax].iter().all(|v| *v <= 1.0) {
+        1.0
+    } else {
+        MODEL_SCALE
+    };
+    let unit = |v: f64| (v / scale).clamp(0.0, 1.0);
+    let (left, right) = (unit(xmin.min(xmax)), unit(xmin.max(xmax)));
+    let (top, bottom) = (unit(ymin.min(ymax)), unit(ymin.max(ymax)));
+    (right > left && bottom > top).then_some([left, top, right, bottom])
+}
+
+/// Annotations in a structured reply, in the model's order. Entries without
+/// a label or a usable box are skipped.
+pub(crate) fn parse(structured: &Value, max_objects: usize) -> Vec<Annotation> {
+    structured["objects"]
+        .as_array()
+        .map(|objects| {
+            objects
+                .iter()
+                .filter_map(|object| {
+                    let label = object["label"].as_str()?.trim();
+                    if label.is_empty() {
+                        return None;
+                    }
+                    Some(Annotation {
+                        label: label.to_string(),
+                        confidence: object["confidence"].as_f64().map(|c| c.clamp(0.0, 1.0)),
+                        bounds: bounds(object["box_2d"].as_array()?)?,
+                    })
+                })
+                .take(max_objects)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Width and height from a PNG, GIF, JPEG or WebP header
+pub(crate) fn image_size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le16 = |i: usize| Some(u16::from_le_bytes(bytes.get(i..i + 2)?.try_into().ok()?) as u32);
+    let le24 = |i: usize| {
+        let b = bytes.get(i..i + 3)?;
+        Some(u32::from(b[0]) | (u32::from(b[1]) << 8) | (u32::from(b[2]) << 16))
+    };
+
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let be32 = |i: usize| Some(u32::from_be_bytes(bytes.get(i..i + 4)?.try_into().ok()?));
+        return Some((be32(16)?, be32(20)?));
+    }
+    if bytes.starts_with(b"GIF8") {
+        return Some((le16(6)?, le16(8)?));
+    }
+    if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(&b"WEBP"[..]) {
+        return match bytes.get(12..16)? {
+            b"VP8 " => Some((le16(26)? & 0x3FFF, le16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some((le24(24)? + 1, le24(27)? + 1)),
+            _ => None,
+        };
+    }
+    if bytes.starts_with(&[0xFF, 0xD8]) {
+        // Walk the segments up to the frame header
+        let mut i = 2;
+        while *bytes.get(i)? == 0xFF {
+            let marker = *bytes.get(i + 1)?;
+            let is_frame = matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC);
+            if is_frame {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+    None
+}
+
+/// Annotations as a list of ['label', 'confidence', 'box' => ['x', 'y',
+/// 'width', 'height']] arrays in the requested units. Pixels need the
+/// image size, checked by the caller before the request is sent.
+pub(crate) fn to_php(
+    annotations: &[Annotation],
+    coordinates: Coordinates,
+    size: Option<(u32, u32)>,
+) -> PhpResult<Zval> {
+    let (scale_x, scale_y) = match (coordinates, size) {
+        (Coordinates::Normalized, _) => (1.0, 1.0),
+        (Coordinates::PerMille, _) => (MODEL_SCALE, MODEL_SCALE),
+        (Coordinates::Pixels, Some((w, h))) => (f64::from(w), f64::from(h)),
+        (Coordinates::Pixels, None) => (1.0, 1.0),
+    };
+    let round = |v: f64| match coordinates {
+        Coordinates::Normalized => (v * 10_000.0).round() / 10_000.0,
+        _ => v.round(),
+    };
+
+    let mut list = PhpArray::new();
+    for annotation in annotations {
+        let [left, top, right, bottom] = annotation.bounds;
+        let mut bbox = PhpArray::new();
+        bbox.insert("x", round(left * scale_x))?;
+        bbox.insert("y", round(top * scale_y))?;
+        bbox.insert("width", round((right - left) * scale_x))?;
+        bbox.insert("height", round((bottom - top) * scale_y))?;
+
+        let mut entry = PhpArray::new();
+        entry.insert("label", annotation.label.as_str())?;
+        entry.insert("confidence", annotation.confidence)?;
+        entry.insert("box", bbox)?;
+        list.push(entry)?;
+    }
+    Ok(list.into_zval(false)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounds_from_model_scale() {
+        assert_eq!(
+            bounds(&[json!(100), json!(250), json!(500), json!(750)]),
+            Some([0.25, 0.1, 0.75, 0.5])
+        );
+        // Swapped corners and overshoot
+        assert_eq!(
+            bounds(&[json!(500), json!(1200), json!(100), json!(250)]),
+            Some([0.25, 0.1, 1.0, 0.5])
+        );
+        // Fractions, as some models give them
+        assert_eq!(
+            bounds(&[json!(0.1), json!(0.25), json!(0.5), json!(0.75)]),
+            Some([0.25, 0.1, 0.75, 0.5])
+        );
+        assert_eq!(
+            bounds(&[json!(100), json!(100), json!(100), json!(200)]),
+            None
+        );
+        assert_eq!(bounds(&[json!(1), json!(2), json!(3)]), None);
+    }
+
+    #[test]
+    fn test_parse_skips_unusable_objects() {
+        let structured = json!({"objects": [
+            {"label": "cat", "box_2d": [0, 0, 500, 500], "confidence": 0.9},
+            {"label": "", "box_2d": [0, 0, 500, 500], "confidence": 0.9},
+            {"label": "dog", "box_2d": "somewhere", "confidence": 0.5},
+            {"label": "dog", "box_2d": [500, 500, 1000, 1000]},
+        ]});
+        let annotations = parse(&structured, 10);
+        assert_eq!(annotations.len(), 2);
+        assert_eq!(annotations[0].confidence, Some(0.9));
+        assert_eq!(annotations[1].label, "dog");
+        assert_eq!(annotations[1].confidence, None);
+        assert_eq!(parse(&structured, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_image_size() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend_from_slice(&640u32.to_be_bytes());
+        png.extend_from_slice(&480u32.to_be_bytes());
+        assert_eq!(image_size(&png), Some((640, 480)));
+
+        let gif = b"GIF89a\x20\x03\x58\x02";
+        assert_eq!(image_size(gif), Some((800, 600)));
+
+        // APP0 segment, then a baseline frame header
+        let jpeg = [
+            0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x00, 0x00, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x01,
+            0xE0, 0x02, 0x80,
+        ];
+        assert_eq!(image_size(&jpeg), Some((640, 480)));
+        assert_eq!(image_size(b"not an image"), None);
+    }
+}
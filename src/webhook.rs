@@ -0,0 +1,207 @@
+use base64::Engine;
+use ext_php_rs::prelude::*;
+use ext_php_rs::types::{ZendHashTable as PhpArray, Zval};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::convert::json_value_to_php;
+
+/// Default allowed clock difference between sender and receiver, in seconds
+const DEFAULT_TOLERANCE: i64 = 300;
+
+/// Headers of a webhook delivery in the Standard Webhooks format, which
+/// OpenAI uses: `webhook-id`, `webhook-timestamp` and `webhook-signature`
+struct SignedHeaders {
+    id: String,
+    timestamp: String,
+    signatures: String,
+}
+
+impl SignedHeaders {
+    fn from_array(headers: &PhpArray) -> Result<Self, String> {
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(key, _)| key.to_string().eq_ignore_ascii_case(name))
+                .and_then(|(_, value)| value.string())
+                .ok_or_else(|| format!("Missing '{name}' header"))
+        };
+        Ok(Self {
+            id: header("webhook-id")?,
+            timestamp: header("webhook-timestamp")?,
+            signatures: header("webhook-signature")?,
+        })
+    }
+}
+
+/// Signing key from a webhook secret: the base64 part of a `whsec_` secret,
+/// or the raw bytes of any other string
+fn secret_key(secret: &str) -> Result<Vec<u8>, String> {
+    match secret.strip_prefix("whsec_") {
+        Some(encoded) => base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| "Webhook secret is not valid base64 after 'whsec_'".to_string()),
+        None => Ok(secret.as_bytes().to_vec()),
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Check the HMAC-SHA256 signature over `{id}.{timestamp}.{payload}` against
+/// each `v1,<base64>` entry of the signature header, in constant time, and
+/// reject timestamps further than `tolerance` seconds from `now` (0 skips
+/// the check)
+fn verify_signature(
+    payload: &str,
+    headers: &SignedHeaders,
+    secret: &str,
+    tolerance: i64,
+    now: i64,
+) -> Result<(), String> {
+    let timestamp: i64 = headers
+        .timestamp
+        .trim()
+        .parse()
+        .map_err(|_| "Invalid webhook timestamp".to_string())?;
+    if tolerance > 0 && now.abs_diff(timestamp) > tolerance as u64 {
+        return Err("Webhook timestamp is outside the tolerance".to_string());
+    }
+
+    let key = secret_key(secret)?;
+    let signed = format!("{}.{}.{payload}", headers.id, headers.timestamp.trim());
+    let valid = headers
+        .signatures
+        .split_whitespace()
+        .filter_map(|entry| entry.strip_prefix("v1,"))
+        .filter_map(|sig| base64::engine::general_purpose::STANDARD.decode(sig).ok())
+        .any(|sig| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes any key length");
+            mac.update(signed.as_bytes());
+            mac.verify_slice(&sig).is_ok()
+        });
+    if !valid {
+        return Err("No matching webhook signature".to_string());
+    }
+    Ok(())
+}
+
+fn verify(
+    payload: &str,
+    headers: &PhpArray,
+    secret: &str,
+    tolerance: Option<i64>,
+) -> Result<(), String> {
+    let headers = SignedHeaders::from_array(headers)?;
+    verify_signature(
+        payload,
+        &headers,
+        secret,
+        tolerance.unwrap_or(DEFAULT_TOLERANCE),
+        unix_now(),
+    )
+}
+
+/// Verification of provider webhook deliveries
+#[php_class]
+pub struct Webhook;
+
+#[php_impl]
+impl Webhook {
+    /// Whether a delivery is authentic. `$headers` maps header names (any
+    /// case) to values; `$secret` is the endpoint's signing secret
+    /// (`whsec_...`). Deliveries older or newer than `$tolerance` seconds
+    /// (default 300, 0 to skip) are rejected to stop replays.
+    pub fn verify(
+        payload: String,
+        headers: &PhpArray,
+        secret: String,
+        tolerance: Option<i64>,
+    ) -> bool {
+        verify(&payload, headers, &secret, tolerance).is_ok()
+    }
+
+    /// Verify a delivery and return its decoded JSON event. Throws
+    /// LLMValidationException with the reason when verification fails.
+    pub fn unwrap(
+        payload: String,
+        headers: &PhpArray,
+        secret: String,
+        tolerance: Option<i64>,
+    ) -> PhpResult<Zval> {
+        verify(&payload, headers, &secret, tolerance)
+            .map_err(PhpException::from_class::<crate::error::LLMValidationException>)?;
+        let event: serde_json::Value = serde_json::from_str(&payload).map_err(|e| {
+            PhpException::from_class::<crate::error::LLMValidationException>(format!(
+                "Webhook payload is not valid JSON: {e}"
+            ))
+        })?;
+        json_value_to_php(&event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example from the Standard Webhooks specification
+    const SECRET: &str = "whsec_MfKQ9r8GKYqrTwjUPD8ILPZIo2LaLaSw";
+    const PAYLOAD: &str = r#"{"test": 2432232314}"#;
+
+    fn headers(timestamp: &str, signatures: &str) -> SignedHeaders {
+        SignedHeaders {
+            id: "msg_p5jXN8AQM9LWM0D4loKWxJek".to_string(),
+            timestamp: timestamp.to_string(),
+            signatures: signatures.to_string(),
+        }
+    }
+
+    fn sign(headers: &SignedHeaders) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret_key(SECRET).unwrap()).unwrap();
+        mac.update(format!("{}.{}.{PAYLOAD}", headers.id, headers.timestamp).as_bytes());
+        let sig = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+        format!("v1,{sig}")
+    }
+
+    #[test]
+    fn test_accepts_any_matching_signature() {
+        let unsigned = headers("1614265330", "");
+        let signatures = format!("v1,bm90IGl0 {}", sign(&unsigned));
+        let signed = headers("1614265330", &signatures);
+        assert_eq!(
+            verify_signature(PAYLOAD, &signed, SECRET, 300, 1614265330),
+            Ok(())
+        );
+        assert_eq!(
+            verify_signature("{}", &signed, SECRET, 300, 1614265330).unwrap_err(),
+            "No matching webhook signature"
+        );
+    }
+
+    #[test]
+    fn test_rejects_stale_timestamp() {
+        let unsigned = headers("1614265330", "");
+        let signed = headers("1614265330", &sign(&unsigned));
+        assert_eq!(
+            verify_signature(PAYLOAD, &signed, SECRET, 300, 1614265330 + 301).unwrap_err(),
+            "Webhook timestamp is outside the tolerance"
+        );
+        assert!(verify_signature(PAYLOAD, &signed, SECRET, 0, 1614265330 + 301).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_extreme_timestamps() {
+        for (timestamp, now) in [(i64::MIN, 1614265330), (i64::MAX, i64::MIN)] {
+            let unsigned = headers(&timestamp.to_string(), "");
+            let signed = headers(&timestamp.to_string(), &sign(&unsigned));
+            assert_eq!(
+                verify_signature(PAYLOAD, &signed, SECRET, 300, now).unwrap_err(),
+                "Webhook timestamp is outside the tolerance"
+            );
+        }
+    }
+}
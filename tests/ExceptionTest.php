@@ -6,6 +6,7 @@
  * 1. Are registered (exist as classes)
  * 2. Extend \Exception (and therefore implement \Throwable)
  * 3. Can be instantiated and thrown/caught
+ * 4. Extend LLMException, keeping message, code and previous
  */
 
 class ExceptionTest {
@@ -15,6 +16,7 @@ class ExceptionTest {
         'LLMValidationException',
         'LLMStructuredOutputException',
         'LLMToolCallException',
+        'LLMBudgetExceededException',
     ];
 
     public static function testAllExceptionClassesExist(): void {
@@ -80,4 +82,62 @@ class ExceptionTest {
             TestAssert::assert($caught, "{$class} was not caught by catch(\\Throwable)");
         }
     }
+
+    public static function testExceptionClassesExtendLLMException(): void {
+        foreach (self::$exceptionClasses as $class) {
+            if ($class === 'LLMException') {
+                continue;
+            }
+            TestAssert::assertEquals('LLMException', get_parent_class($class), "{$class} parent mismatch");
+            $caught = false;
+            try {
+                throw new $class("hierarchy test");
+            } catch (LLMException $e) {
+                $caught = true;
+            }
+            TestAssert::assert($caught, "{$class} was not caught by catch(LLMException)");
+        }
+    }
+
+    public static function testExceptionCodeAndPrevious(): void {
+        $previous = new \RuntimeException('cause');
+        foreach (self::$exceptionClasses as $class) {
+            $e = new $class("with code", 42, $previous);
+            TestAssert::assertEquals("with code", $e->getMessage(), "{$class} message mismatch");
+            TestAssert::assertEquals(42, $e->getCode(), "{$class} code mismatch");
+            TestAssert::assert($e->getPrevious() === $previous, "{$class} lost its previous exception");
+        }
+    }
+
+    public static function testErrorCodeConstants(): void {
+        TestAssert::assertEquals(0, LLMException::ERR_UNKNOWN, "ERR_UNKNOWN mismatch");
+        TestAssert::assertEquals(2, LLMException::ERR_TIMEOUT, "ERR_TIMEOUT mismatch");
+        TestAssert::assertEquals(5, LLMException::ERR_RATE_LIMIT, "ERR_RATE_LIMIT mismatch");
+        TestAssert::assertEquals(
+            LLMException::ERR_TIMEOUT,
+            LLMConnectionException::ERR_TIMEOUT,
+            "Subclasses should inherit the error codes"
+        );
+    }
+
+    public static function testProviderDetailsAreNullForUserExceptions(): void {
+        foreach (self::$exceptionClasses as $class) {
+            $e = new $class("no provider");
+            TestAssert::assert($e->getProvider() === null, "{$class} has a provider");
+            TestAssert::assert($e->getHttpStatus() === null, "{$class} has an HTTP status");
+            TestAssert::assert($e->getRequestId() === null, "{$class} has a request id");
+            TestAssert::assert($e->getErrorType() === null, "{$class} has an error type");
+            TestAssert::assert($e->getRawBody() === null, "{$class} has a raw body");
+        }
+    }
+
+    public static function testRetryAfterIsNullForUserExceptions(): void {
+        $e = new LLMConnectionException("no provider");
+        TestAssert::assert($e->getRetryAfter() === null, "Retry delay without a provider");
+    }
+
+    public static function testViolationsAreEmptyForUserExceptions(): void {
+        $e = new LLMStructuredOutputException("bad output");
+        TestAssert::assertEquals([], $e->getViolations());
+    }
 }
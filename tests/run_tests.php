@@ -129,6 +129,12 @@ $runner->addTest('Exception classes extend \\Exception', [ExceptionTest::class,
 $runner->addTest('Exception classes implement Throwable', [ExceptionTest::class, 'testAllExceptionClassesImplementThrowable']);
 $runner->addTest('Exception classes can be thrown and caught', [ExceptionTest::class, 'testExceptionClassesCanBeThrown']);
 $runner->addTest('Exception classes caught as Throwable', [ExceptionTest::class, 'testExceptionClassesCaughtAsThrowable']);
+$runner->addTest('Exception classes extend LLMException', [ExceptionTest::class, 'testExceptionClassesExtendLLMException']);
+$runner->addTest('Exception code and previous', [ExceptionTest::class, 'testExceptionCodeAndPrevious']);
+$runner->addTest('Exception error code constants', [ExceptionTest::class, 'testErrorCodeConstants']);
+$runner->addTest('Exception provider details default to null', [ExceptionTest::class, 'testProviderDetailsAreNullForUserExceptions']);
+$runner->addTest('Exception retry delay defaults to null', [ExceptionTest::class, 'testRetryAfterIsNullForUserExceptions']);
+$runner->addTest('Exception violations default to empty', [ExceptionTest::class, 'testViolationsAreEmptyForUserExceptions']);
 
 // LLM tests
 $runner->addTest('LLM instantiation', function() {
@@ -236,6 +242,113 @@ $runner->addTest('MessageCollection fromArray', function() {
     TestAssert::assertEquals(2, $collection1->count());
 });
 
+$runner->addTest('MessageCollection snapshot', function() {
+    $messages = new MessageCollection();
+    $messages->addUser('Hello');
+    $snapshot = $messages->snapshot();
+    $messages->addAssistant('Hi!');
+
+    TestAssert::assertEquals(1, count($snapshot));
+    TestAssert::assertEquals('Hello', $snapshot[0]->getContent());
+    TestAssert::assertEquals(2, count($messages));
+    try {
+        $snapshot[] = Message::user('More');
+        TestAssert::assert(false, 'Expected LLMValidationException');
+    } catch (LLMValidationException $e) {
+        TestAssert::assertEquals(1, count($snapshot));
+    }
+});
+
+$runner->addTest('LLM classify validates labels', function() {
+    $llm = new LLM('openai:gpt-4o');
+    $rejected = 0;
+    foreach ([['spam'], ['spam', 'spam'], ['spam' => 'Unsolicited ads', 'ham' => 42]] as $labels) {
+        try {
+            $llm->classify('Win a free cruise!', $labels);
+        } catch (LLMValidationException $e) {
+            $rejected++;
+        }
+    }
+    TestAssert::assertEquals(3, $rejected);
+});
+
+$runner->addTest('LLM benchmark validates arguments', function() {
+    $llm = new LLM('openai:gpt-4o');
+    $rejected = 0;
+    foreach ([[[], 1], [['openai:gpt-4o', 'openai:gpt-4o'], 1], [['openai:gpt-4o'], 0]] as [$models, $iterations]) {
+        try {
+            $llm->benchmark($models, [Message::user('Hello')], $iterations);
+        } catch (LLMValidationException $e) {
+            $rejected++;
+        }
+    }
+    TestAssert::assertEquals(3, $rejected);
+});
+
+$runner->addTest('LLM extract rejects empty text', function() {
+    $llm = new LLM('openai:gpt-4o');
+    try {
+        $llm->extract('  ', Schema::object()->string('name'));
+        TestAssert::assert(false, 'Expected LLMValidationException');
+    } catch (LLMValidationException $e) {
+        TestAssert::assert(str_contains($e->getMessage(), 'empty'), $e->getMessage());
+    }
+});
+
+$runner->addTest('LLM setMaxTokensAuto validation', function() {
+    $llm = new LLM('ollama:llama3');
+    assert($llm->setMaxTokensAuto(256, 8192) === $llm);
+    try {
+        $llm->setMaxTokensAuto(1000, 500);
+        assert(false, 'Should throw for a window smaller than the margin');
+    } catch (LLMValidationException $e) {
+        assert(str_contains($e->getMessage(), 'safety margin'));
+    }
+    try {
+        (new LLM('ollama:llama3'))->setMaxTokensAuto()->complete([Message::user('Hi')]);
+        assert(false, 'Should throw for an unknown context window');
+    } catch (LLMValidationException $e) {
+        assert(str_contains($e->getMessage(), 'Context window'));
+    }
+});
+
+$runner->addTest('ToolBuilder summarizeResults validation', function() {
+    $builder = (new LLM('openai:gpt-4o'))->withTools([]);
+    $summarizer = new LLM('openai:gpt-4o-mini');
+    assert($builder->summarizeResults('search', 500, $summarizer) === $builder);
+    try {
+        $builder->summarizeResults('search', 0, $summarizer);
+        assert(false, 'Should throw for a non-positive threshold');
+    } catch (LLMValidationException $e) {
+        assert(str_contains($e->getMessage(), 'search'));
+    }
+});
+
+$runner->addTest('ToolResponse execution trace is empty without auto-execute', function() {
+    $state = json_encode([
+        'version' => 1,
+        'options' => [
+            'model' => 'openai:gpt-4o', 'temperature' => 0.7, 'max_tokens' => 1000,
+            'top_p' => 1.0, 'top_k' => 50,
+            'penalties' => ['frequency' => 0.0, 'presence' => 0.0],
+            'stop' => [],
+        ],
+        'conversation' => [],
+        'content' => '',
+        'tool_calls' => [],
+        'usage' => [
+            'prompt_tokens' => 0, 'output_tokens' => 0, 'reasoning_tokens' => 0,
+            'total_tokens' => 0, 'cached_tokens' => 0, 'cost' => null, 'request_time_ms' => null,
+        ],
+        'model' => 'gpt-4o',
+        'id' => null,
+        'reasoning' => null,
+        'awaiting_approval' => false,
+    ]);
+    $response = ToolResponse::fromState($state);
+    assert($response->getExecutionTrace() === []);
+});
+
 // Tool tests
 $runner->addTest('Tool creation', function() {
     $params = ['type' => 'object', 'properties' => []];
@@ -285,5 +398,84 @@ $runner->addTest('Tool JSON serialization', function() {
     TestAssert::assertEquals('test_func', $data['name']);
 });
 
+$runner->addTest('Tool with Schema parameters', function() {
+    $schema = Schema::object()
+        ->string('location', 'City name', required: true)
+        ->enum('unit', ['celsius', 'fahrenheit'])
+        ->arrayOf('days', 'integer');
+    $tool = new Tool('get_weather', 'Get weather', $schema);
+
+    $data = json_decode($tool->getParameters(), true);
+    TestAssert::assertEquals(['location'], $data['required']);
+    TestAssert::assertEquals('City name', $data['properties']['location']['description']);
+    TestAssert::assertEquals(['celsius', 'fahrenheit'], $data['properties']['unit']['enum']);
+    TestAssert::assertEquals('integer', $data['properties']['days']['items']['type']);
+});
+
+enum TestOrderStatus: string {
+    case Open = 'open';
+    case Shipped = 'shipped';
+}
+
+$runner->addTest('Schema enumFromClass', function() {
+    $data = Schema::object()
+        ->enumFromClass('status', TestOrderStatus::class, required: true)
+        ->toArray();
+    TestAssert::assertEquals('string', $data['properties']['status']['type']);
+    TestAssert::assertEquals(['open', 'shipped'], $data['properties']['status']['enum']);
+
+    try {
+        Schema::object()->enumFromClass('status', 'stdClass');
+        TestAssert::assert(false, 'Expected LLMValidationException');
+    } catch (LLMValidationException $e) {
+        TestAssert::assert(str_contains($e->getMessage(), 'backed enum'), $e->getMessage());
+    }
+});
+
+final class TestOrder {
+    public function __construct(
+        public string $id,
+        public TestOrderStatus $status,
+        public ?string $note = null,
+    ) {}
+}
+
+$runner->addTest('Schema fromClass', function() {
+    $data = Schema::fromClass(TestOrder::class)->toArray();
+    TestAssert::assertEquals(['id', 'status'], $data['required']);
+    TestAssert::assertEquals(['string', 'null'], $data['properties']['note']['type']);
+    TestAssert::assertEquals(['open', 'shipped'], $data['properties']['status']['enum']);
+});
+
+final class TestWeather {
+    /**
+     * Current weather for a city
+     */
+    public function current(#[Description('City name')] string $city, TestOrderStatus $status, int $days = 1): string {
+        return "$city:$days";
+    }
+}
+
+$runner->addTest('Tool fromCallable and fromMethod', function() {
+    $tool = Tool::fromMethod(new TestWeather(), 'current');
+    TestAssert::assertEquals('current', $tool->getName());
+    TestAssert::assertEquals('Current weather for a city', $tool->getDescription());
+    $params = json_decode($tool->getParameters(), true);
+    TestAssert::assertEquals(['city', 'status'], $params['required']);
+    TestAssert::assertEquals('City name', $params['properties']['city']['description']);
+    TestAssert::assertEquals(['open', 'shipped'], $params['properties']['status']['enum']);
+    TestAssert::assertEquals('integer', $params['properties']['days']['type']);
+
+    $tool = Tool::fromCallable(fn(float $a, float $b): float => $a + $b, 'add', 'Add two numbers');
+    TestAssert::assertEquals(['a', 'b'], json_decode($tool->getParameters(), true)['required']);
+
+    try {
+        Tool::fromCallable(fn() => null);
+        TestAssert::assert(false, 'Expected LLMValidationException');
+    } catch (LLMValidationException $e) {
+        TestAssert::assert(str_contains($e->getMessage(), 'name'), $e->getMessage());
+    }
+});
+
 // Run all tests
 $runner->run();